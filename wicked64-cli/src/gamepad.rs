@@ -0,0 +1,107 @@
+use gilrs_core::{native_ev_codes as codes, EvCode, EventType, Gilrs};
+use w64_core::io::{
+    controller::{buttons, CONTROLLER_PORTS},
+    ControllerState,
+};
+
+/// Real controllers don't reach the full `i8` range
+const STICK_RANGE: f32 = 80.0;
+/// Right stick deflection that presses the C buttons
+const C_THRESHOLD: f32 = 0.5;
+
+fn button(code: EvCode) -> Option<u16> {
+    let button = match code {
+        codes::BTN_SOUTH => buttons::A,
+        codes::BTN_WEST => buttons::B,
+        codes::BTN_LT2 => buttons::Z,
+        codes::BTN_START => buttons::START,
+        codes::BTN_LT => buttons::L,
+        codes::BTN_RT => buttons::R,
+        codes::BTN_DPAD_UP => buttons::D_UP,
+        codes::BTN_DPAD_DOWN => buttons::D_DOWN,
+        codes::BTN_DPAD_LEFT => buttons::D_LEFT,
+        codes::BTN_DPAD_RIGHT => buttons::D_RIGHT,
+        _ => return None,
+    };
+    Some(button)
+}
+
+/// Host gamepads, mapped in connection order to the controller ports. The
+/// left stick is the analog stick and the right one presses the C buttons
+pub struct Gamepads {
+    gilrs: Gilrs,
+    states: [ControllerState; CONTROLLER_PORTS],
+}
+
+impl Gamepads {
+    pub fn new() -> Option<Gamepads> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(Self {
+                gilrs,
+                states: Default::default(),
+            }),
+            Err(error) => {
+                tracing::warn!("Gamepads are not available: {error}");
+                None
+            }
+        }
+    }
+
+    /// Apply the pending gamepad events and return the controller states
+    pub fn poll(&mut self) -> [ControllerState; CONTROLLER_PORTS] {
+        while let Some(event) = self.gilrs.next_event() {
+            let Some(state) = self.states.get_mut(event.id) else {
+                continue;
+            };
+
+            match event.event {
+                EventType::ButtonPressed(code) => {
+                    state.buttons |= button(code).unwrap_or(0);
+                }
+                EventType::ButtonReleased(code) => {
+                    state.buttons &= !button(code).unwrap_or(0);
+                }
+                EventType::AxisValueChanged(value, code) => {
+                    let Some(info) = self
+                        .gilrs
+                        .gamepad(event.id)
+                        .and_then(|gamepad| gamepad.axis_info(code))
+                    else {
+                        continue;
+                    };
+                    let range = (info.max - info.min).max(1) as f32;
+                    let mut value = (value - info.min) as f32 / range * 2.0 - 1.0;
+                    if gilrs_core::IS_Y_AXIS_REVERSED
+                        && matches!(code, codes::AXIS_LSTICKY | codes::AXIS_RSTICKY)
+                    {
+                        value = -value;
+                    }
+                    Self::set_axis(state, code, value);
+                }
+                EventType::Disconnected => *state = ControllerState::default(),
+                _ => {}
+            }
+        }
+
+        self.states
+    }
+
+    fn set_axis(state: &mut ControllerState, code: EvCode, value: f32) {
+        let mut c_buttons = |negative: u16, positive: u16| {
+            state.buttons &= !(negative | positive);
+            if value <= -C_THRESHOLD {
+                state.buttons |= negative;
+            } else if value >= C_THRESHOLD {
+                state.buttons |= positive;
+            }
+        };
+
+        match code {
+            codes::AXIS_RSTICKX => c_buttons(buttons::C_LEFT, buttons::C_RIGHT),
+            codes::AXIS_RSTICKY => c_buttons(buttons::C_DOWN, buttons::C_UP),
+            codes::AXIS_LSTICKX => state.stick_x = (value * STICK_RANGE) as i8,
+            codes::AXIS_LSTICKY => state.stick_y = (value * STICK_RANGE) as i8,
+            _ => {}
+        }
+    }
+}
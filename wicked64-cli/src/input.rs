@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+
+use minifb::{Key, Window};
+use w64_core::io::{
+    controller::{buttons, CONTROLLER_PORTS},
+    ControllerState, InputSource,
+};
+
+/// Analog stick deflection of the arrow keys, close to a real stick range
+const KEYBOARD_STICK: i8 = 80;
+
+/// Keyboard mapping of the controller buttons
+const KEY_BUTTONS: [(Key, u16); 15] = [
+    (Key::X, buttons::A),
+    (Key::C, buttons::B),
+    (Key::Z, buttons::Z),
+    (Key::Enter, buttons::START),
+    (Key::A, buttons::L),
+    (Key::S, buttons::R),
+    (Key::I, buttons::C_UP),
+    (Key::K, buttons::C_DOWN),
+    (Key::J, buttons::C_LEFT),
+    (Key::L, buttons::C_RIGHT),
+    (Key::T, buttons::D_UP),
+    (Key::G, buttons::D_DOWN),
+    (Key::F, buttons::D_LEFT),
+    (Key::H, buttons::D_RIGHT),
+    (Key::Backspace, buttons::RESET),
+];
+
+/// Controller states shared between the window loop, which updates them
+/// once per frame, and the emulated PIF, which polls them
+#[derive(Debug, Clone, Default)]
+pub struct SharedInput(Arc<Mutex<[ControllerState; CONTROLLER_PORTS]>>);
+
+impl SharedInput {
+    pub fn set(&self, states: [ControllerState; CONTROLLER_PORTS]) {
+        *self.0.lock().unwrap() = states;
+    }
+}
+
+impl InputSource for SharedInput {
+    fn poll(&mut self, port: usize) -> ControllerState {
+        self.0.lock().unwrap()[port]
+    }
+}
+
+/// State of the controller mapped to the keyboard: the buttons to
+/// `KEY_BUTTONS` and the analog stick to the arrow keys
+pub fn keyboard_state(window: &Window) -> ControllerState {
+    let axis = |negative: Key, positive: Key| match (
+        window.is_key_down(negative),
+        window.is_key_down(positive),
+    ) {
+        (true, false) => -KEYBOARD_STICK,
+        (false, true) => KEYBOARD_STICK,
+        _ => 0,
+    };
+
+    ControllerState {
+        buttons: KEY_BUTTONS
+            .iter()
+            .filter(|(key, _)| window.is_key_down(*key))
+            .fold(0, |pressed, (_, button)| pressed | button),
+        stick_x: axis(Key::Left, Key::Right),
+        stick_y: axis(Key::Down, Key::Up),
+    }
+}
+
+/// Combine two sources of the same controller. The stick of `a` wins when
+/// both are deflected
+pub fn merge(a: ControllerState, b: ControllerState) -> ControllerState {
+    let stick = |a: i8, b: i8| if a == 0 { b } else { a };
+    ControllerState {
+        buttons: a.buttons | b.buttons,
+        stick_x: stick(a.stick_x, b.stick_x),
+        stick_y: stick(a.stick_y, b.stick_y),
+    }
+}
@@ -0,0 +1,385 @@
+#[cfg(feature = "gamepad")]
+mod gamepad;
+mod input;
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use byteorder::BigEndian;
+use clap::{Parser, Subcommand, ValueEnum};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use w64_core::{
+    cpu::signals::ResetKind,
+    io::{
+        controller::CONTROLLER_PORTS, video::Frame, Cartridge, Cic, ControllerState, RomDatabase,
+        SaveType,
+    },
+    jit::{BLOCK_CYCLES, CODE_BUDGET},
+    logging::LogTargets,
+    movie::Movie,
+    n64::{TraceOptions, N64},
+};
+
+use input::SharedInput;
+
+/// Size of the window before the first frame is displayed
+const DEFAULT_WIDTH: usize = 320;
+const DEFAULT_HEIGHT: usize = 240;
+
+/// Nintendo 64 emulator
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// ROM of the game to run
+    #[arg(required = true)]
+    rom: Option<PathBuf>,
+    /// Disable the high-level emulation of the RSP tasks
+    #[arg(long)]
+    no_hle: bool,
+    /// Window scale factor
+    #[arg(long, value_enum, default_value_t = WindowScale::X2)]
+    scale: WindowScale,
+    /// File of the quick savestate. Defaults to the ROM path with a `state`
+    /// extension
+    #[arg(long)]
+    state: Option<PathBuf>,
+    /// Boot with 4 megabytes of RDRAM instead of 8
+    #[arg(long)]
+    no_expansion_pak: bool,
+    /// Run this PIF boot ROM instead of simulating the boot process
+    #[arg(long)]
+    pif_rom: Option<PathBuf>,
+    /// Save chip of the cartridge. Defaults to the one from the ROM database
+    #[arg(long, value_enum)]
+    save_type: Option<CliSaveType>,
+    /// Model of the cartridge lockout chip, such as 6102. Defaults to the one
+    /// from the ROM database
+    #[arg(long)]
+    cic: Option<u16>,
+    /// Extra ROM database, whose entries replace the builtin ones
+    #[arg(long)]
+    rom_db: Option<PathBuf>,
+    /// Cycle budget of the compiled blocks
+    #[arg(long, default_value_t = BLOCK_CYCLES as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    jit_block_cycles: u64,
+    /// Bytes of code the compiled blocks may take before they are flushed
+    #[arg(long, default_value_t = CODE_BUDGET)]
+    jit_code_budget: usize,
+    /// Log the host code of the compiled blocks
+    #[arg(long)]
+    dump_jit_code: bool,
+    /// Write the symbols of the compiled blocks into /tmp/perf-<pid>.map,
+    /// for the profiles of Linux perf
+    #[arg(long)]
+    perf_map: bool,
+    /// Subsystems whose debug logs are emitted, as a comma separated list of
+    /// jit, cpu, mmu, vi, ai, pi and si. Defaults to all of them
+    #[arg(long, value_parser = parse_log_targets, default_value = "all")]
+    log: LogTargets,
+    /// Write the trace of the first instructions into this file, then exit
+    #[arg(long, conflicts_with = "compare_trace")]
+    trace: Option<PathBuf>,
+    /// Number of instructions written by `--trace`
+    #[arg(long, default_value_t = 100_000)]
+    trace_instructions: usize,
+    /// Compare the execution against this reference trace, then exit
+    #[arg(long)]
+    compare_trace: Option<PathBuf>,
+    /// Record the controllers input from the power-on into this movie file
+    #[arg(long, conflicts_with = "play")]
+    record: Option<PathBuf>,
+    /// Replay the input of this movie file from the power-on
+    #[arg(long)]
+    play: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Verify the CRC1 and CRC2 checksums in the header of a ROM
+    Checksum {
+        rom: PathBuf,
+        /// Model of the cartridge lockout chip, such as 6102. Defaults to the
+        /// one from the ROM database
+        #[arg(long)]
+        cic: Option<u16>,
+        /// Write the computed checksums into the ROM header, which is needed
+        /// after patching a ROM. The ROM is written back as a `.z64` ROM
+        #[arg(long)]
+        fix: bool,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum CliSaveType {
+    None,
+    Eeprom4k,
+    Eeprom16k,
+}
+
+impl From<CliSaveType> for SaveType {
+    fn from(save_type: CliSaveType) -> SaveType {
+        match save_type {
+            CliSaveType::None => SaveType::None,
+            CliSaveType::Eeprom4k => SaveType::Eeprom4k,
+            CliSaveType::Eeprom16k => SaveType::Eeprom16k,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum WindowScale {
+    X1,
+    X2,
+    X4,
+}
+
+impl From<WindowScale> for Scale {
+    fn from(scale: WindowScale) -> Scale {
+        match scale {
+            WindowScale::X1 => Scale::X1,
+            WindowScale::X2 => Scale::X2,
+            WindowScale::X4 => Scale::X4,
+        }
+    }
+}
+
+/// The displayed image, converted to the `0RGB` pixels of the window
+struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+impl Screen {
+    fn new() -> Screen {
+        Self {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            pixels: vec![0; DEFAULT_WIDTH * DEFAULT_HEIGHT],
+        }
+    }
+
+    fn draw(&mut self, frame: Option<Frame>) {
+        let Some(frame) = frame else {
+            self.pixels.fill(0);
+            return;
+        };
+
+        self.width = frame.width;
+        self.height = frame.height;
+        self.pixels.clear();
+        self.pixels.extend(
+            frame
+                .pixels
+                .chunks_exact(4)
+                .map(|rgba| u32::from_be_bytes([0, rgba[0], rgba[1], rgba[2]])),
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    if let Some(Command::Checksum { rom, cic, fix }) = &args.command {
+        return checksum(rom, *cic, *fix);
+    }
+    let rom = args.rom.context("Missing ROM")?;
+
+    let input = SharedInput::default();
+    let mut builder = N64::<BigEndian>::builder()
+        .input_source(input.clone())
+        .expansion_pak(!args.no_expansion_pak)
+        .jit_block_cycles(args.jit_block_cycles as usize)
+        .jit_code_budget(args.jit_code_budget)
+        .jit_perf_map(args.perf_map)
+        .trace(TraceOptions {
+            jit_code: args.dump_jit_code,
+            targets: args.log,
+        });
+    if let Some(pif_rom) = &args.pif_rom {
+        builder = builder.pif_rom(pif_rom).simulate_pif(false);
+    }
+    if let Some(save_type) = args.save_type {
+        builder = builder.save_type(save_type.into());
+    }
+    if let Some(model) = args.cic {
+        builder = builder.cic(parse_cic(model)?);
+    }
+    if let Some(path) = &args.rom_db {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let mut database = RomDatabase::builtin();
+        database.extend(RomDatabase::parse(&text)?);
+        builder = builder.rom_database(database);
+    }
+    let mut n64 = builder
+        .build(&rom)
+        .with_context(|| format!("Could not load {}", rom.display()))?;
+    n64.set_rsp_hle(!args.no_hle);
+
+    if let Some(path) = &args.trace {
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+        return n64.write_trace(args.trace_instructions, BufWriter::new(file));
+    }
+    if let Some(path) = &args.compare_trace {
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        match n64.compare_trace(BufReader::new(file))? {
+            Some(divergence) => anyhow::bail!("{divergence}"),
+            None => {
+                println!("The execution matches the reference trace");
+                return Ok(());
+            }
+        }
+    }
+
+    if args.record.is_some() {
+        n64.record_movie();
+    }
+    if let Some(path) = &args.play {
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        n64.play_movie(Movie::load(BufReader::new(file))?)?;
+    }
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = gamepad::Gamepads::new();
+
+    let mut window = Window::new(
+        "Wicked64",
+        DEFAULT_WIDTH,
+        DEFAULT_HEIGHT,
+        WindowOptions {
+            scale: args.scale.into(),
+            resize: true,
+            ..WindowOptions::default()
+        },
+    )?;
+    window.set_target_fps(60);
+
+    let state_path = args.state.unwrap_or_else(|| rom.with_extension("state"));
+    let mut screen = Screen::new();
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        #[allow(unused_mut)]
+        let mut states = [ControllerState::default(); CONTROLLER_PORTS];
+        #[cfg(feature = "gamepad")]
+        if let Some(gamepads) = gamepads.as_mut() {
+            states = gamepads.poll();
+        }
+        let [port0, ..] = &mut states;
+        *port0 = input::merge(input::keyboard_state(&window), *port0);
+        input.set(states);
+
+        handle_hotkeys(&window, &mut n64, &state_path);
+
+        n64.run_frame();
+        if let Some(unimplemented) = n64.take_unimplemented_instruction() {
+            tracing::error!("{unimplemented}, raised a reserved instruction exception");
+        }
+        screen.draw(n64.framebuffer());
+        window.update_with_buffer(&screen.pixels, screen.width, screen.height)?;
+    }
+
+    if let (Some(path), Some(movie)) = (&args.record, n64.stop_movie()) {
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+        movie.save(BufWriter::new(file))?;
+        tracing::info!(
+            "{} frames recorded into {}",
+            movie.frames().len(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn parse_log_targets(list: &str) -> Result<LogTargets, String> {
+    LogTargets::parse(list).ok_or_else(|| format!("Invalid log targets: {list}"))
+}
+
+fn parse_cic(model: u16) -> anyhow::Result<Cic> {
+    Cic::from_model(model).with_context(|| format!("Unknown CIC {model}"))
+}
+
+/// The `checksum` subcommand
+fn checksum(path: &Path, cic: Option<u16>, fix: bool) -> anyhow::Result<()> {
+    let mut cartridge =
+        Cartridge::open(path).with_context(|| format!("Could not read {}", path.display()))?;
+    cartridge.normalize_endianness();
+    let cic = match cic {
+        Some(model) => parse_cic(model)?,
+        None => RomDatabase::builtin()
+            .lookup(&cartridge)
+            .cic
+            .unwrap_or_default(),
+    };
+
+    let computed = cartridge.compute_crc(cic);
+    let header = cartridge.header_crc().context("Invalid ROM header")?;
+    println!("CIC:      {cic:?}");
+    println!(
+        "Header:   {:08X} {:08X}",
+        header >> 32,
+        header & 0xFFFF_FFFF
+    );
+    println!(
+        "Computed: {:08X} {:08X}",
+        computed >> 32,
+        computed & 0xFFFF_FFFF
+    );
+
+    if header == computed {
+        println!("The checksums are valid");
+    } else if fix {
+        cartridge.fix_crc(cic);
+        std::fs::write(path, cartridge.rom())
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        println!("The checksums were fixed");
+    } else {
+        anyhow::bail!("The checksums don't match the ROM contents. Bad dump or patched ROM?");
+    }
+    Ok(())
+}
+
+/// F1: soft reset, F2: power cycle, F5: save the state, F7: load it back
+fn handle_hotkeys(window: &Window, n64: &mut N64<BigEndian>, state_path: &Path) {
+    let pressed = |key| window.is_key_pressed(key, KeyRepeat::No);
+
+    if pressed(Key::F1) {
+        n64.reset(ResetKind::Soft);
+    }
+    if pressed(Key::F2) {
+        n64.reset(ResetKind::Power);
+    }
+    if pressed(Key::F5) {
+        let saved = File::create(state_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| n64.save_state(BufWriter::new(file)));
+        match saved {
+            Ok(()) => tracing::info!("State saved to {}", state_path.display()),
+            Err(error) => tracing::error!("Could not save the state: {error}"),
+        }
+    }
+    if pressed(Key::F7) {
+        let loaded = File::open(state_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| n64.load_state(BufReader::new(file)));
+        match loaded {
+            Ok(()) => tracing::info!("State loaded from {}", state_path.display()),
+            Err(error) => tracing::error!("Could not load the state: {error}"),
+        }
+    }
+}
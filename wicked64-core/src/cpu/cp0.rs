@@ -3,6 +3,10 @@
 pub mod config;
 pub mod status;
 
+use std::io::{Read, Write};
+
+use crate::savestate::{read_u64s, write_u64s, SaveStateResult, Snapshot};
+
 pub use self::{config::ConfigRegister, status::StatusRegister};
 
 /// MIPS' Coprocessor 0
@@ -111,3 +115,71 @@ impl Cp0 {
         }
     }
 }
+
+impl Snapshot for Cp0 {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u64s(
+            w,
+            &[
+                self.index,
+                self.random,
+                self.entry_lo0,
+                self.entry_lo1,
+                self.context,
+                self.page_mask,
+                self.wired,
+                self.bad_vaddr,
+                self.count,
+                self.entry_hi,
+                self.compare,
+                self.status.bits,
+                self.cause,
+                self.epc,
+                self.prid,
+                self.config.bits,
+                self.ll_addr,
+                self.watch_lo,
+                self.watch_hi,
+                self.xcontext,
+                self.parity_error,
+                self.cache_error,
+                self.tag_lo,
+                self.tag_hi,
+                self.error_epc,
+            ],
+        )
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 25];
+        read_u64s(r, &mut regs)?;
+        [
+            self.index,
+            self.random,
+            self.entry_lo0,
+            self.entry_lo1,
+            self.context,
+            self.page_mask,
+            self.wired,
+            self.bad_vaddr,
+            self.count,
+            self.entry_hi,
+            self.compare,
+            self.status.bits,
+            self.cause,
+            self.epc,
+            self.prid,
+            self.config.bits,
+            self.ll_addr,
+            self.watch_lo,
+            self.watch_hi,
+            self.xcontext,
+            self.parity_error,
+            self.cache_error,
+            self.tag_lo,
+            self.tag_hi,
+            self.error_epc,
+        ] = regs;
+        Ok(())
+    }
+}
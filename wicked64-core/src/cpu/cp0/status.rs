@@ -111,10 +111,42 @@ impl StatusRegister {
     pub fn get_bits<T: Integral>(&self, bits: RangeInclusive<usize>) -> T {
         self.bits.view_bits::<Lsb0>()[bits].load::<T>()
     }
+
+    /// Get the context the CPU runs in. The exception handlers run in kernel
+    /// mode, whatever KSU holds
+    pub fn context(&self) -> CpuContext {
+        let ksu = if self.get_bit(Self::BIT_EXL_OFFSET) || self.get_bit(Self::BIT_ERL_OFFSET) {
+            OperationMode::Kernel.into()
+        } else {
+            self.get_execution_mode_raw()
+        };
+        let (mode, extended_bit) = match ksu {
+            0 => (OperationMode::Kernel, Self::BIT_KX_OFFSET),
+            1 => (OperationMode::Supervisor, Self::BIT_SX_OFFSET),
+            _ => (OperationMode::User, Self::BIT_UX_OFFSET),
+        };
+
+        CpuContext {
+            mode,
+            extended_addressing: self.get_bit(extended_bit),
+            error_level: self.get_bit(Self::BIT_ERL_OFFSET),
+        }
+    }
+}
+
+/// The bits of the Status register the addressing depends on, which the
+/// compiled code is specific to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuContext {
+    pub mode: OperationMode,
+    /// 64-bit addressing is enabled in `mode`
+    pub extended_addressing: bool,
+    /// ERL is set, which leaves kuseg unmapped
+    pub error_level: bool,
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OperationMode {
     Kernel = 0,
     Supervisor = 1,
@@ -252,6 +252,22 @@ impl Instruction {
             _ => 5,
         }
     }
+
+    /// Whether the instruction loads from or stores into the memory
+    pub fn accesses_memory(&self) -> bool {
+        matches!(
+            self,
+            Instruction::LB(_)
+                | Instruction::LBU(_)
+                | Instruction::LH(_)
+                | Instruction::LHU(_)
+                | Instruction::LW(_)
+                | Instruction::LWL(_)
+                | Instruction::LWR(_)
+                | Instruction::LWU(_)
+                | Instruction::SW(_)
+        )
+    }
 }
 
 impl TryFrom<u32> for Instruction {
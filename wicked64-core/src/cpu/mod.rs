@@ -2,24 +2,43 @@ pub mod cp0;
 pub mod instruction;
 pub mod signals;
 
-use std::marker::PhantomData;
+use std::{
+    io::{Read, Write},
+    marker::PhantomData,
+};
 
 use bitvec::{field::BitField, order::Msb0, view::BitView};
-use byteorder::ByteOrder;
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
 
-use cp0::Cp0;
+use cp0::{Cp0, StatusRegister};
 use instruction::Instruction;
-use signals::reset_signal;
-
-use crate::mmu::{
-    map::{addr_map, VirtualMemoryMap},
-    MemoryUnit,
+use signals::{reset_signal, ResetKind};
+
+use crate::{
+    io::pif::CIC_SEED_OFFSET,
+    logging,
+    mmu::{
+        map::{addr_map, VirtualMemoryMap},
+        MemoryUnit,
+    },
+    savestate::{read_u64s, write_u64s, SaveStateResult, Snapshot},
 };
 
 /// CPU frequency in HZ
 #[allow(dead_code)]
 pub const CPU_FREQUENCY: u32 = 93_750_000; // 93.75MHz
 
+/// Exceptions taken through the general exception vector, with the code
+/// reported in the CP0 cause register
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    Interrupt = 0,
+    ReservedInstruction = 10,
+    /// Signed overflow of `ADD`, `ADDI` or `SUB`
+    Overflow = 12,
+}
+
 /// The N64 CPU (VR4300).
 ///
 /// The CPU has:
@@ -74,7 +93,7 @@ pub struct Cpu<O: ByteOrder> {
 impl<O: ByteOrder> Cpu<O> {
     /// Create a new CPU
     pub fn new<M: 'static + MemoryUnit + Sized>(simulate_pif: bool, mmu: &mut M) -> Self {
-        tracing::debug!("Creating the CPU");
+        logging::debug!(CPU, "Creating the CPU");
 
         let mut cpu = Self::default().power_on();
         if simulate_pif {
@@ -83,6 +102,36 @@ impl<O: ByteOrder> Cpu<O> {
         cpu
     }
 
+    /// Reset the CPU with the `kind` reset sequence, then simulate the PIF
+    /// boot process again if `simulate_pif` is set
+    pub fn reset<M: 'static + MemoryUnit + Sized>(
+        &mut self,
+        kind: ResetKind,
+        simulate_pif: bool,
+        mmu: &mut M,
+    ) {
+        logging::debug!(CPU, "Resetting the CPU: {kind:?}");
+
+        match kind {
+            ResetKind::Power => *self = Self::default().power_on(),
+            ResetKind::Cold => {
+                self.reset_signal = reset_signal::COLD_RESET;
+                self.handle_reset_signal();
+                self.pc = 0xBFC0_0000;
+            }
+            ResetKind::Soft => {
+                self.reset_signal = reset_signal::RESET;
+                self.handle_reset_signal();
+            }
+        }
+
+        if simulate_pif {
+            self.simulate_pif(mmu);
+            // `s5` tells the game whether the reset button was pressed
+            self.gpr[21] = u64::from(kind == ResetKind::Soft);
+        }
+    }
+
     /// Fetch a instructions at virtual address `addr`
     ///
     /// # Errors
@@ -109,6 +158,44 @@ impl<O: ByteOrder> Cpu<O> {
         }
     }
 
+    /// Whether an interrupt is pending and enabled, so that it's taken before
+    /// the next instruction
+    pub fn interrupt_pending(&self) -> bool {
+        let status = &self.cp0.status;
+        let pending = self.cp0.cause & status.bits & 0xFF00;
+        status.get_bit(StatusRegister::BIT_IE_OFFSET)
+            && !status.get_bit(StatusRegister::BIT_EXL_OFFSET)
+            && !status.get_bit(StatusRegister::BIT_ERL_OFFSET)
+            && pending != 0
+    }
+
+    /// Enter the general exception handler to take the pending interrupt
+    pub fn take_interrupt(&mut self) {
+        logging::debug!(CPU, "Taking an interrupt at 0x{:08x}", self.pc);
+        self.take_exception(Exception::Interrupt, false);
+    }
+
+    /// Enter the general exception handler for `exception`, raised by the
+    /// instruction at the PC, or by its delay slot. The handler returns to
+    /// the jump, which runs its delay slot again
+    pub fn take_exception(&mut self, exception: Exception, delay_slot: bool) {
+        const CAUSE_EXC_CODE: u64 = 0x7C;
+        const CAUSE_BD: u64 = 1 << 31;
+
+        self.cp0.epc = self.pc;
+        self.cp0.cause &= !(CAUSE_EXC_CODE | CAUSE_BD);
+        self.cp0.cause |= (exception as u64) << 2;
+        if delay_slot {
+            self.cp0.cause |= CAUSE_BD;
+        }
+        self.cp0.status.bits |= 1 << StatusRegister::BIT_EXL_OFFSET;
+        self.pc = if self.cp0.status.get_bit(StatusRegister::BIT_BEV_OFFSET) {
+            0xBFC0_0380
+        } else {
+            0x8000_0180
+        };
+    }
+
     /// Perform a Power-On-Reset procedure.
     fn power_on(mut self) -> Self {
         self.reset_signal = reset_signal::POWER_ON_RESET;
@@ -124,14 +211,16 @@ impl<O: ByteOrder> Cpu<O> {
     /// The side effects of this procedure
     /// [can be found in more details here](https://n64.readthedocs.io/#boot-process).
     fn simulate_pif<M: 'static + MemoryUnit + Sized>(&mut self, mmu: &mut M) {
-        tracing::debug!("Simulating PIF behavior");
+        logging::debug!(CPU, "Simulating PIF behavior");
 
+        // the CIC seed is left in the PIF RAM by the PIF
+        let seed = mmu.read::<u8, O>(*addr_map::phys::PIF_RAM_RANGE.start() + CIC_SEED_OFFSET + 2);
         self.gpr = {
             let mut gpr = [0; 32];
 
             gpr[11] = 0xffff_ffff_a400_0040;
             gpr[20] = 0x0000_0000_0000_0001;
-            gpr[22] = 0x0000_0000_0000_003f;
+            gpr[22] = u64::from(seed);
             gpr[29] = 0xffff_ffff_a400_1ff0;
 
             gpr
@@ -186,8 +275,7 @@ impl<O: ByteOrder> Cpu<O> {
             // part of the initial status of the processor can be retained
             // by using soft reset.
             reset_signal::RESET => {
-                self.reset_signal = 0;
-                todo!()
+                self.perform_soft_reset();
             }
             // Resets already performed.
             reset_signal::COLD_RESET_ACTIVE | reset_signal::RESET_ACTIVE | reset_signal::NONE => {}
@@ -278,6 +366,41 @@ impl<O: ByteOrder> Cpu<O> {
         self.clocks = 0;
     }
 
+    /// Perform a Soft-Reset.
+    ///
+    /// When the `Reset` signal is asserted active while the processor is
+    /// operating, the processor restarts without affecting the clocks. The
+    /// major part of its initial state is retained.
+    ///
+    /// # Procedure effect
+    ///
+    /// `cp0.status.{TS, RP} = 0`
+    ///
+    /// `cp0.status.{ERL, BEV, SR} = 1`
+    ///
+    /// `cp0.error_epc = pc`, then `pc` is set to the reset vector
+    fn perform_soft_reset(&mut self) {
+        {
+            let bits = self.cp0.status.bits.view_bits_mut::<Msb0>();
+
+            bits.set(cp0::StatusRegister::BIT_TS_OFFSET, false);
+            bits.set(cp0::StatusRegister::BIT_RP_OFFSET, false);
+
+            bits.set(cp0::StatusRegister::BIT_ERL_OFFSET, true);
+            bits.set(cp0::StatusRegister::BIT_BEV_OFFSET, true);
+            bits.set(cp0::StatusRegister::BIT_SR_OFFSET, true);
+        };
+
+        self.cp0.error_epc = self.pc;
+        self.pc = 0xBFC0_0000;
+
+        // disable Reset and enable ResetActive
+        self.reset_signal = signals::disable_signal(self.reset_signal, reset_signal::RESET)
+            | reset_signal::RESET_ACTIVE;
+
+        self.soft_reset_clocks = 16;
+    }
+
     /// Update the currently active reset signal by the given amount of clocks
     fn update_reset_signal(&mut self, clocks: u64) {
         // decrement Cold-Reset clocks
@@ -325,6 +448,49 @@ impl<O: ByteOrder> Cpu<O> {
     }
 }
 
+impl<O: ByteOrder> Snapshot for Cpu<O> {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u64s(w, &self.gpr)?;
+        write_u64s(w, &self.fgr)?;
+        write_u64s(
+            w,
+            &[
+                self.pc,
+                self.multi_hi,
+                self.multi_lo,
+                self.cold_reset_clocks,
+                self.soft_reset_clocks,
+                self.clocks,
+            ],
+        )?;
+        w.write_u8(self.ll)?;
+        w.write_u8(self.reset_signal)?;
+        w.write_u32::<byteorder::BigEndian>(self.fcr0)?;
+        w.write_u32::<byteorder::BigEndian>(self.fcr32)?;
+        self.cp0.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        read_u64s(r, &mut self.gpr)?;
+        read_u64s(r, &mut self.fgr)?;
+        let mut regs = [0; 6];
+        read_u64s(r, &mut regs)?;
+        [
+            self.pc,
+            self.multi_hi,
+            self.multi_lo,
+            self.cold_reset_clocks,
+            self.soft_reset_clocks,
+            self.clocks,
+        ] = regs;
+        self.ll = r.read_u8()?;
+        self.reset_signal = r.read_u8()?;
+        self.fcr0 = r.read_u32::<byteorder::BigEndian>()?;
+        self.fcr32 = r.read_u32::<byteorder::BigEndian>()?;
+        self.cp0.load(r)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bitvec::bits;
@@ -395,6 +561,36 @@ mod tests {
         );
     }
 
+    /// Checks CPU registers after a Soft reset
+    #[test]
+    fn it_should_perform_the_soft_reset_procedure() {
+        let mut dummy = Box::new([0u8; 100]) as Box<[u8]>;
+        let mut cpu = Cpu::<BigEndian>::new(false, &mut dummy);
+        cpu.pc = 0x8000_0400;
+        cpu.gpr[8] = 0xdead_beef;
+
+        cpu.reset(ResetKind::Soft, false, &mut dummy);
+
+        // the registers are kept, and the previous PC saved in ErrorEPC
+        assert_eq!(cpu.gpr[8], 0xdead_beef);
+        assert_eq!(cpu.cp0.error_epc, 0x8000_0400);
+        assert_eq!(cpu.pc, 0xBFC0_0000);
+
+        // cp0.status.{ERL, BEV, SR} = 1
+        let status_bits = cpu.cp0.status.bits.view_bits::<Msb0>();
+        for offset in [
+            StatusRegister::BIT_ERL_OFFSET,
+            StatusRegister::BIT_BEV_OFFSET,
+            StatusRegister::BIT_SR_OFFSET,
+        ] {
+            assert_eq!(status_bits.get(offset).as_deref(), Some(&true));
+        }
+
+        assert_ne!(cpu.reset_signal & reset_signal::RESET_ACTIVE, 0);
+        cpu.update_reset_signal(16);
+        assert_eq!(cpu.reset_signal & reset_signal::RESET_ACTIVE, 0);
+    }
+
     #[test]
     fn it_should_simulate_the_pif_rom_behavior() {
         crate::tests::init_trace();
@@ -1,3 +1,16 @@
+/// Reset sequences that can be requested on the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Power cycle: everything is initialized and the RDRAM is cleared
+    Power,
+    /// `ColdReset`: the processor and the devices are initialized, but the
+    /// RDRAM keeps its content
+    Cold,
+    /// The console reset button: a `Reset` of the processor, which keeps
+    /// most of its state, and the RDRAM content is kept for the game
+    Soft,
+}
+
 pub mod reset_signal {
     pub const NONE: u8 = 0;
 
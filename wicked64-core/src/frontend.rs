@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::io::video::Frame;
+pub use crate::io::{AudioSink, InputSource};
+
+/// Video output implemented by frontends, such as a window
+pub trait VideoSink {
+    /// Called once per displayed field (60Hz on NTSC, 50Hz on PAL) with the
+    /// current frame, or `None` while the VI is blanked. Frontends can use it
+    /// to pace the emulation
+    fn present(&mut self, frame: Option<Frame>);
+}
+
+impl<F: FnMut(Option<Frame>)> VideoSink for F {
+    fn present(&mut self, frame: Option<Frame>) {
+        self(frame);
+    }
+}
+
+impl fmt::Debug for dyn VideoSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VideoSink")
+    }
+}
+
+/// The frontend implementations given to `N64Builder`, attached once the
+/// machine is built
+#[derive(Default)]
+pub(crate) struct Frontend {
+    pub video: Option<Box<dyn VideoSink>>,
+    pub audio: Option<Box<dyn AudioSink>>,
+    pub input: Option<Box<dyn InputSource>>,
+}
+
+impl fmt::Debug for Frontend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frontend")
+            .field("video", &self.video.is_some())
+            .field("audio", &self.audio.is_some())
+            .field("input", &self.input.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    io::video::{VideoStandard, CPU_CLOCK_RATE},
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{read_bool, read_u32s, write_u32s, SaveStateError, SaveStateResult, Snapshot},
+};
+
+/// Audio Interface registers offsets
+pub mod ai_reg {
+    pub const DRAM_ADDR: usize = 0x00;
+    pub const LENGTH: usize = 0x04;
+    pub const CONTROL: usize = 0x08;
+    pub const STATUS: usize = 0x0C;
+    pub const DACRATE: usize = 0x10;
+    pub const BITRATE: usize = 0x14;
+}
+
+/// `AI_STATUS` bits
+pub mod ai_status {
+    pub const BUSY: u32 = 1 << 30;
+    pub const FULL: u32 = 1 << 31;
+}
+
+/// Maximum amount of buffered stereo frames (about 1s at 48KHz). When the
+/// frontend doesn't keep up, the oldest samples are dropped
+const RING_CAPACITY: usize = 48_000;
+
+/// A stereo frame (left, right)
+pub type StereoSample = [i16; 2];
+
+/// Ring buffer shared between the emulator, which pushes the resampled audio,
+/// and the frontend, which pulls it at its own pace
+#[derive(Debug, Clone, Default)]
+pub struct AudioBuffer {
+    samples: Arc<Mutex<VecDeque<StereoSample>>>,
+}
+
+impl AudioBuffer {
+    pub fn new() -> AudioBuffer {
+        Self::default()
+    }
+
+    /// Number of stereo frames available
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fill `out` with the buffered stereo frames, padding it with silence
+    /// if not enough samples are available. Returns the amount of frames
+    /// actually read
+    pub fn pull(&self, out: &mut [StereoSample]) -> usize {
+        let mut samples = self.lock();
+        let len = out.len().min(samples.len());
+        for (dst, src) in out.iter_mut().zip(samples.drain(..len)) {
+            *dst = src;
+        }
+        out[len..].fill([0, 0]);
+        len
+    }
+
+    fn push(&self, frames: impl IntoIterator<Item = StereoSample>) {
+        let mut samples = self.lock();
+        samples.extend(frames);
+        let overflow = samples.len().saturating_sub(RING_CAPACITY);
+        samples.drain(..overflow);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<StereoSample>> {
+        self.samples
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Audio output implemented by frontends.
+///
+/// The sink receives an `AudioBuffer` when attached to the emulator, and
+/// pulls samples from it, usually from the host audio callback.
+pub trait AudioSink {
+    /// Sample rate of the host audio output, in Hz
+    fn sample_rate(&self) -> u32;
+
+    /// Receive the buffer the emulator pushes the samples into
+    fn attach(&mut self, buffer: AudioBuffer);
+}
+
+/// Linear resampler from the AI DAC rate to the host rate
+#[derive(Debug, Clone)]
+struct Resampler {
+    output_rate: u32,
+    /// Position between the previous and the next input frame
+    phase: f64,
+    previous: StereoSample,
+}
+
+impl Resampler {
+    fn new(output_rate: u32) -> Resampler {
+        Self {
+            output_rate,
+            phase: 0.0,
+            previous: [0, 0],
+        }
+    }
+
+    fn resample(&mut self, input_rate: u32, input: &[StereoSample]) -> Vec<StereoSample> {
+        let step = f64::from(input_rate) / f64::from(self.output_rate);
+        let mut output = Vec::new();
+
+        for &next in input {
+            while self.phase < 1.0 {
+                let lerp = |a: i16, b: i16| {
+                    (f64::from(a) + (f64::from(b) - f64::from(a)) * self.phase) as i16
+                };
+                output.push([
+                    lerp(self.previous[0], next[0]),
+                    lerp(self.previous[1], next[1]),
+                ]);
+                self.phase += step;
+            }
+            self.phase -= 1.0;
+            self.previous = next;
+        }
+
+        output
+    }
+}
+
+/// A DMA transfer of a sample buffer requested by the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AiDma {
+    pub dram_addr: usize,
+    pub len: usize,
+}
+
+/// Audio Interface (AI). Plays the 16-bit stereo sample buffers read from
+/// RDRAM through DMA.
+///
+/// The transfers are only requested here, and performed by the memory
+/// manager, which copies the samples from RDRAM and calls `play`. The AI
+/// holds up to two buffers: the one being played, until its
+/// `AiBufferDrain` event, and the next one.
+#[derive(Debug, Default)]
+pub struct AudioInterface {
+    /// Lengths of the buffers being played and waiting to be played
+    queue: VecDeque<usize>,
+    dram_addr: u32,
+    dma_enabled: bool,
+    dac_rate: u32,
+    bit_rate: u32,
+    standard: VideoStandard,
+    pending_dma: Option<AiDma>,
+    output: Option<(AudioBuffer, Resampler)>,
+}
+
+impl AudioInterface {
+    pub fn new() -> AudioInterface {
+        Self::default()
+    }
+
+    /// Reset the registers and drop the queued buffers, keeping the TV
+    /// standard and the output
+    pub fn reset(&mut self) {
+        *self = Self {
+            standard: self.standard,
+            output: self.output.take(),
+            ..Self::new()
+        };
+    }
+
+    /// The DAC is clocked by the video clock, which depends on the TV standard
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        self.standard = standard;
+    }
+
+    /// Output the samples into `buffer`, resampled to `sample_rate`
+    pub fn set_output(&mut self, buffer: AudioBuffer, sample_rate: u32) {
+        self.output = Some((buffer, Resampler::new(sample_rate)));
+    }
+
+    /// Sample rate of the played buffers, in Hz
+    pub fn frequency(&self) -> u32 {
+        let video_clock = match self.standard {
+            VideoStandard::Ntsc => 48_681_812,
+            VideoStandard::Pal => 49_656_530,
+        };
+        video_clock / (self.dac_rate + 1)
+    }
+
+    /// Take the DMA transfer requested by the last register write
+    pub fn take_pending_dma(&mut self) -> Option<AiDma> {
+        self.pending_dma.take()
+    }
+
+    /// Queue a buffer of `len` bytes sent through DMA. Returns its playing
+    /// time in CPU cycles if it starts right away, which raises the AI
+    /// interrupt
+    pub fn enqueue(&mut self, len: usize) -> Option<u64> {
+        self.queue.push_back(len);
+        (self.queue.len() == 1).then(|| self.play_cycles(len))
+    }
+
+    /// The current buffer finished playing. Returns the playing time of the
+    /// next buffer, if any, which starts playing and raises the AI interrupt
+    pub fn drain(&mut self) -> Option<u64> {
+        self.queue.pop_front();
+        self.queue.front().map(|&len| self.play_cycles(len))
+    }
+
+    /// Number of CPU cycles taken to play a buffer of `len` bytes
+    fn play_cycles(&self, len: usize) -> u64 {
+        let frames = len as u64 / 4;
+        frames * CPU_CLOCK_RATE / u64::from(self.frequency().max(1))
+    }
+
+    /// Play a buffer of big endian 16-bit stereo samples
+    pub fn play(&mut self, data: &[u8]) {
+        let frequency = self.frequency();
+        let Some((buffer, resampler)) = self.output.as_mut() else {
+            return;
+        };
+
+        let input = data
+            .chunks_exact(4)
+            .map(|frame| {
+                [
+                    i16::from_be_bytes([frame[0], frame[1]]),
+                    i16::from_be_bytes([frame[2], frame[3]]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        buffer.push(resampler.resample(frequency, &input));
+    }
+}
+
+impl MemoryUnit for AudioInterface {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        let value = match addr {
+            ai_reg::DRAM_ADDR => self.dram_addr,
+            ai_reg::LENGTH => self.queue.front().map_or(0, |&len| len as u32),
+            ai_reg::STATUS => {
+                let mut status = 0;
+                if !self.queue.is_empty() {
+                    status |= ai_status::BUSY;
+                }
+                if self.queue.len() >= 2 {
+                    status |= ai_status::FULL;
+                }
+                status
+            }
+            _ => 0,
+        };
+        I::truncate_u64(value as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        let value = value.to_u64() as u32;
+        match addr {
+            ai_reg::DRAM_ADDR => self.dram_addr = value & 0x00FF_FFF8,
+            ai_reg::LENGTH => {
+                let len = (value & 0x0003_FFF8) as usize;
+                if self.dma_enabled && len > 0 && self.queue.len() < 2 {
+                    self.pending_dma = Some(AiDma {
+                        dram_addr: self.dram_addr as usize,
+                        len,
+                    });
+                }
+            }
+            ai_reg::CONTROL => self.dma_enabled = value & 1 != 0,
+            // writes acknowledge the interrupt, handled by the MI
+            ai_reg::STATUS => {}
+            ai_reg::DACRATE => self.dac_rate = value & 0x3FFF,
+            ai_reg::BITRATE => self.bit_rate = value & 0xF,
+            _ => logging::debug!(
+                AI,
+                "Unhandled AI register write at 0x{addr:02x}: 0x{value:08x}"
+            ),
+        }
+    }
+}
+
+impl Snapshot for AudioInterface {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &[self.dram_addr, self.dac_rate, self.bit_rate])?;
+        w.write_u8(u8::from(self.dma_enabled))?;
+        w.write_u8(self.queue.len() as u8)?;
+        let queue = self.queue.iter().map(|&len| len as u32).collect::<Vec<_>>();
+        write_u32s(w, &queue)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 3];
+        read_u32s(r, &mut regs)?;
+        [self.dram_addr, self.dac_rate, self.bit_rate] = regs;
+        self.dma_enabled = read_bool(r)?;
+
+        let mut queue = vec![0; usize::from(r.read_u8()?)];
+        if queue.len() > 2 {
+            return Err(SaveStateError::Invalid("too many AI buffers"));
+        }
+        read_u32s(r, &mut queue)?;
+        self.queue = queue.into_iter().map(|len| len as usize).collect();
+        self.pending_dma = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_resample_the_ai_buffers() {
+        let buffer = AudioBuffer::new();
+        let mut ai = AudioInterface::new();
+        ai.set_output(buffer.clone(), 22_050);
+        // 44100Hz, twice the output rate
+        ai.dac_rate = 48_681_812 / 44_100 - 1;
+
+        let data = (0..8i16)
+            .flat_map(|i| [(i * 100).to_be_bytes(), (-i * 100).to_be_bytes()])
+            .flatten()
+            .collect::<Vec<_>>();
+        ai.play(&data);
+
+        let mut out = [[0; 2]; 6];
+        assert_eq!(buffer.pull(&mut out), 4);
+        assert_eq!(out[1][0], 100);
+        assert_eq!(out[2][1], -300);
+        assert_eq!(out[5], [0, 0]);
+    }
+}
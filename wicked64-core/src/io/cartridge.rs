@@ -1,13 +1,24 @@
-use std::path::Path;
+use std::{ops::Range, path::Path};
 
-use byteorder::ByteOrder;
+use byteorder::{BigEndian, ByteOrder};
 
-use crate::mmu::{num::MemInteger, MemoryUnit};
+use crate::io::{
+    eeprom::{Eeprom, EepromKind},
+    pif::{joybus::JoybusDevice, Cic},
+    video::VideoStandard,
+};
+use crate::mmu::{check_alignment, num::MemInteger, MemError, MemResult, MemoryUnit};
 
 /// n64 cartridges may have more than 64 megabytes (ouch!).
 /// 38 megabytes should be enough to play most games.
 pub const CARTRIDGE_SIZE_IN_BYTES: usize = 38 * 1024 * 1024;
 
+/// ROM range covered by the CRC1 and CRC2 checksums, which is checked by the
+/// IPL3 boot code
+const CHECKSUM_RANGE: Range<usize> = 0x1000..0x10_1000;
+/// Offset of CRC1 and CRC2 in the ROM header
+const CRC_OFFSET: usize = 0x10;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CartridgeEndianness {
     /// Used by .z64 ROM
@@ -18,6 +29,25 @@ pub enum CartridgeEndianness {
     ByteSwapped,
 }
 
+/// Save chip of a cartridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveType {
+    None,
+    Eeprom4k,
+    Eeprom16k,
+}
+
+impl SaveType {
+    /// The device answering on the joybus channel 4 for this save type
+    pub fn joybus_device(self) -> JoybusDevice {
+        match self {
+            SaveType::None => JoybusDevice::None,
+            SaveType::Eeprom4k => JoybusDevice::Eeprom(Eeprom::new(EepromKind::Kb4)),
+            SaveType::Eeprom16k => JoybusDevice::Eeprom(Eeprom::new(EepromKind::Kb16)),
+        }
+    }
+}
+
 /// N64 Game Pak cartridge
 #[derive(Debug)]
 pub struct Cartridge {
@@ -46,6 +76,11 @@ impl Cartridge {
         Ok(Self { data })
     }
 
+    /// Contents of the ROM
+    pub fn rom(&self) -> &[u8] {
+        &self.data
+    }
+
     /// Get the endianness from the ROM header
     ///
     /// # Errors
@@ -59,6 +94,128 @@ impl Cartridge {
             _ => Err(()),
         }
     }
+
+    /// Convert a `.n64` or `.v64` ROM to the big endian layout of `.z64`
+    /// ROMs, which is how the CPU sees the cartridge
+    pub fn normalize_endianness(&mut self) {
+        match self.endianness() {
+            Ok(CartridgeEndianness::Little) => {
+                for word in self.data.chunks_exact_mut(4) {
+                    word.reverse();
+                }
+            }
+            Ok(CartridgeEndianness::ByteSwapped) => {
+                for half in self.data.chunks_exact_mut(2) {
+                    half.swap(0, 1);
+                }
+            }
+            Ok(CartridgeEndianness::Big) | Err(()) => {}
+        }
+    }
+}
+
+impl Cartridge {
+    /// Get the video standard of the region the game was released for, from
+    /// the country code in the ROM header
+    pub fn video_standard(&self) -> VideoStandard {
+        let country_code = match self.endianness() {
+            Ok(CartridgeEndianness::Big) => self.data.get(0x3E),
+            Ok(CartridgeEndianness::ByteSwapped) => self.data.get(0x3F),
+            _ => None,
+        };
+        match country_code {
+            Some(b'D' | b'F' | b'I' | b'P' | b'S' | b'U' | b'X' | b'Y') => VideoStandard::Pal,
+            _ => VideoStandard::Ntsc,
+        }
+    }
+
+    /// Get the CRC1 and CRC2 checksums stored in the ROM header, as a single
+    /// integer. `None` if the ROM is not big endian
+    pub fn header_crc(&self) -> Option<u64> {
+        match (self.endianness(), self.data.get(CRC_OFFSET..CRC_OFFSET + 8)) {
+            (Ok(CartridgeEndianness::Big), Some(crc)) => Some(BigEndian::read_u64(crc)),
+            _ => None,
+        }
+    }
+
+    /// Compute CRC1 and CRC2 as the IPL3 boot code of `cic` does, from the
+    /// first megabyte after the boot code. The ROM should be big endian
+    pub fn compute_crc(&self, cic: Cic) -> u64 {
+        ipl3_checksum(&self.data, cic)
+    }
+
+    /// Whether the checksums in the ROM header match the ROM contents. A
+    /// mismatch means a bad dump or a patched ROM
+    pub fn verify_crc(&self, cic: Cic) -> bool {
+        self.header_crc() == Some(self.compute_crc(cic))
+    }
+
+    /// Write the checksums computed for `cic` into the ROM header, as needed
+    /// after patching a ROM. The ROM should be big endian
+    pub fn fix_crc(&mut self, cic: Cic) {
+        let crc = self.compute_crc(cic);
+        if let Some(header) = self.data.get_mut(CRC_OFFSET..CRC_OFFSET + 8) {
+            BigEndian::write_u64(header, crc);
+        }
+    }
+
+    /// Get the game ID from the ROM header: the media format (`N` for
+    /// cartridges) followed by the two characters of the cartridge ID, such
+    /// as `NSM`. `None` if the ROM is not big endian
+    pub fn game_id(&self) -> Option<[u8; 3]> {
+        match (self.endianness(), self.data.get(0x3B..0x3E)) {
+            (Ok(CartridgeEndianness::Big), Some(id)) => id.try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The checksum algorithm of the IPL3 boot code. The missing bytes of small
+/// ROMs are read as zeros
+fn ipl3_checksum(data: &[u8], cic: Cic) -> u64 {
+    let word = |addr: usize| {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = data.get(addr + i).copied().unwrap_or_default();
+        }
+        u32::from_be_bytes(bytes)
+    };
+
+    let seed: u32 = match cic {
+        Cic::Cic6101 | Cic::Cic6102 => 0xF8CA_4DDC,
+        Cic::Cic6103 => 0xA388_6759,
+        Cic::Cic6105 => 0xDF26_F436,
+        Cic::Cic6106 => 0x1FEA_617A,
+    };
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+
+    for addr in CHECKSUM_RANGE.step_by(4) {
+        let d = word(addr);
+        let (sum, carry) = t6.overflowing_add(d);
+        if carry {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        t2 ^= if t2 > d { r } else { t6 ^ d };
+        t1 = t1.wrapping_add(if cic == Cic::Cic6105 {
+            word(0x750 + (addr & 0xFF)) ^ d
+        } else {
+            t5 ^ d
+        });
+    }
+
+    let (crc1, crc2) = match cic {
+        Cic::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        Cic::Cic6106 => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    };
+    (u64::from(crc1) << 32) | u64::from(crc2)
 }
 
 impl MemoryUnit for Cartridge {
@@ -68,6 +225,18 @@ impl MemoryUnit for Cartridge {
     fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
         I::write_to::<O>(&mut self.data[addr..addr + I::SIZE], value);
     }
+    fn try_read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> MemResult<I> {
+        check_alignment::<I>(addr)?;
+        self.data
+            .get(addr..addr + I::SIZE)
+            .map(I::read_from::<O>)
+            .ok_or(MemError::Unmapped(addr))
+    }
+    /// The cartridge ROM can't be written by the CPU
+    fn try_store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, _value: I) -> MemResult<()> {
+        check_alignment::<I>(addr)?;
+        Err(MemError::ReadOnly(addr))
+    }
     fn buffer(&self) -> &[u8] {
         &self.data
     }
@@ -80,6 +249,40 @@ impl MemoryUnit for Cartridge {
 mod tests {
     use super::*;
 
+    #[test]
+    fn it_should_normalize_the_rom_endianness() {
+        let z64 = [0x80, 0x37, 0x12, 0x40];
+        for rom in [[0x40, 0x12, 0x37, 0x80], [0x37, 0x80, 0x40, 0x12], z64] {
+            let mut cartridge = Cartridge {
+                data: Box::new(rom),
+            };
+            cartridge.normalize_endianness();
+            assert_eq!(*cartridge.data, z64);
+        }
+    }
+
+    #[test]
+    fn it_should_fix_the_header_checksums() {
+        let mut data = vec![0; 0x10_1000];
+        data[0] = 0x80;
+        for (i, byte) in data[0x1000..].iter_mut().enumerate() {
+            *byte = (i * 7 % 251) as u8;
+        }
+        let mut cartridge = Cartridge {
+            data: data.into_boxed_slice(),
+        };
+
+        for cic in [Cic::Cic6102, Cic::Cic6103, Cic::Cic6105, Cic::Cic6106] {
+            assert!(!cartridge.verify_crc(cic));
+            cartridge.fix_crc(cic);
+            assert!(cartridge.verify_crc(cic));
+            assert_eq!(cartridge.header_crc(), Some(cartridge.compute_crc(cic)));
+
+            cartridge.data[0x2000] ^= 0xFF;
+            assert!(!cartridge.verify_crc(cic));
+        }
+    }
+
     #[test]
     fn it_should_get_the_cartridge_endianness() {
         let cartridge = Cartridge::open("../assets/test-roms/dillonb/basic.z64").unwrap();
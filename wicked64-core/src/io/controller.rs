@@ -0,0 +1,128 @@
+pub mod pak;
+
+use std::fmt::Debug;
+
+use self::pak::{Pak, PAK_PAGE_SIZE};
+
+/// Number of controller ports
+pub const CONTROLLER_PORTS: usize = 4;
+
+/// Controller buttons, as reported by the joybus controller state command
+pub mod buttons {
+    pub const D_RIGHT: u16 = 1 << 8;
+    pub const D_LEFT: u16 = 1 << 9;
+    pub const D_DOWN: u16 = 1 << 10;
+    pub const D_UP: u16 = 1 << 11;
+    pub const START: u16 = 1 << 12;
+    pub const Z: u16 = 1 << 13;
+    pub const B: u16 = 1 << 14;
+    pub const A: u16 = 1 << 15;
+
+    pub const C_RIGHT: u16 = 1 << 0;
+    pub const C_LEFT: u16 = 1 << 1;
+    pub const C_DOWN: u16 = 1 << 2;
+    pub const C_UP: u16 = 1 << 3;
+    pub const R: u16 = 1 << 4;
+    pub const L: u16 = 1 << 5;
+    /// Set when L+R+START are pressed together (the stick gets recentered)
+    pub const RESET: u16 = 1 << 7;
+}
+
+/// Buttons and analog stick state of a controller
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerState {
+    /// Pressed buttons. Refer to `buttons`
+    pub buttons: u16,
+    /// Analog stick X axis. Positive values point to the right
+    pub stick_x: i8,
+    /// Analog stick Y axis. Positive values point up
+    pub stick_y: i8,
+}
+
+impl ControllerState {
+    pub fn is_pressed(&self, button: u16) -> bool {
+        self.buttons & button != 0
+    }
+
+    /// Encode the state as the joybus controller state response
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let [hi, lo] = self.buttons.to_be_bytes();
+        [hi, lo, self.stick_x as u8, self.stick_y as u8]
+    }
+}
+
+/// Source of controller input, implemented by frontends.
+///
+/// The emulator polls the state of every connected controller whenever the
+/// game reads them, which usually happens once per frame. The sources are
+/// `Send`, as the emulation may run on a thread of its own.
+pub trait InputSource: Send {
+    /// Get the current state of the controller plugged into `port`
+    fn poll(&mut self, port: usize) -> ControllerState;
+
+    /// Called when the rumble pak motor of the controller plugged into
+    /// `port` is turned on or off
+    fn set_rumble(&mut self, port: usize, on: bool) {
+        let _ = (port, on);
+    }
+}
+
+impl Debug for dyn InputSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InputSource")
+    }
+}
+
+/// A standard controller
+#[derive(Debug, Default, Clone)]
+pub struct Controller {
+    state: ControllerState,
+    pak: Option<Pak>,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Self::default()
+    }
+
+    pub fn state(&self) -> ControllerState {
+        self.state
+    }
+    pub fn set_state(&mut self, state: ControllerState) {
+        self.state = state;
+    }
+
+    pub fn pak(&self) -> Option<&Pak> {
+        self.pak.as_ref()
+    }
+    pub fn insert_pak(&mut self, pak: Pak) {
+        self.pak = Some(pak);
+    }
+    pub fn remove_pak(&mut self) -> Option<Pak> {
+        self.pak.take()
+    }
+
+    /// Read a page from the pak address space. Without a pak, zeroes are
+    /// returned
+    pub fn read_pak(&mut self, addr: u16, data: &mut [u8; PAK_PAGE_SIZE]) {
+        match &mut self.pak {
+            Some(pak) => pak.read(addr, data),
+            None => data.fill(0),
+        }
+    }
+
+    /// Write a page to the pak address space
+    pub fn write_pak(&mut self, addr: u16, data: &[u8; PAK_PAGE_SIZE]) {
+        if let Some(pak) = &mut self.pak {
+            pak.write(addr, data);
+        }
+    }
+
+    /// Take the new rumble motor state if it changed since the last call
+    pub fn take_rumble_change(&mut self) -> Option<bool> {
+        match &mut self.pak {
+            Some(Pak::Rumble(rumble)) => rumble.take_motor_change(),
+            _ => None,
+        }
+    }
+}
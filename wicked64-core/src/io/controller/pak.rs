@@ -0,0 +1,108 @@
+pub mod transfer;
+
+pub use self::transfer::{GbCartridge, TransferPak};
+
+/// Size of a pak address space page, transferred by each read/write
+pub const PAK_PAGE_SIZE: usize = 32;
+
+/// Address used to probe and identify the pak
+const PROBE_ADDR: u16 = 0x8000;
+/// Start of the rumble pak motor control
+const RUMBLE_MOTOR_ADDR: u16 = 0xC000;
+
+/// A controller pak (accessory inserted into the back of the controller)
+#[derive(Debug, Clone)]
+pub enum Pak {
+    Rumble(RumblePak),
+    Transfer(TransferPak),
+}
+
+impl Pak {
+    /// Read a page from the pak address space
+    pub fn read(&mut self, addr: u16, data: &mut [u8; PAK_PAGE_SIZE]) {
+        match self {
+            Pak::Rumble(rumble) => rumble.read(addr, data),
+            Pak::Transfer(transfer) => transfer.read(addr, data),
+        }
+    }
+
+    /// Write a page to the pak address space
+    pub fn write(&mut self, addr: u16, data: &[u8; PAK_PAGE_SIZE]) {
+        match self {
+            Pak::Rumble(rumble) => rumble.write(addr, data),
+            Pak::Transfer(transfer) => transfer.write(addr, data),
+        }
+    }
+}
+
+/// Rumble Pak.
+///
+/// Games identify it by writing `0x80` to the probe address and reading it
+/// back. Writing to `0xC000` and above turns the motor on or off.
+#[derive(Debug, Default, Clone)]
+pub struct RumblePak {
+    probe: u8,
+    motor: bool,
+    motor_changed: bool,
+}
+
+impl RumblePak {
+    pub fn new() -> RumblePak {
+        Self::default()
+    }
+
+    pub fn is_motor_on(&self) -> bool {
+        self.motor
+    }
+
+    /// Take the new motor state if it changed since the last call
+    pub fn take_motor_change(&mut self) -> Option<bool> {
+        std::mem::take(&mut self.motor_changed).then_some(self.motor)
+    }
+
+    fn read(&self, addr: u16, data: &mut [u8; PAK_PAGE_SIZE]) {
+        let value = if (PROBE_ADDR..RUMBLE_MOTOR_ADDR).contains(&addr) {
+            self.probe
+        } else {
+            0x00
+        };
+        data.fill(value);
+    }
+
+    fn write(&mut self, addr: u16, data: &[u8; PAK_PAGE_SIZE]) {
+        if addr >= RUMBLE_MOTOR_ADDR {
+            let motor = data[PAK_PAGE_SIZE - 1] & 1 != 0;
+            self.motor_changed |= motor != self.motor;
+            self.motor = motor;
+        } else if addr >= PROBE_ADDR {
+            // any other value identifies a different kind of pak
+            self.probe = if data[PAK_PAGE_SIZE - 1] == 0x80 {
+                0x80
+            } else {
+                0x00
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_toggle_the_rumble_motor() {
+        let mut pak = RumblePak::new();
+        let mut page = [0; PAK_PAGE_SIZE];
+
+        pak.write(PROBE_ADDR, &[0x80; PAK_PAGE_SIZE]);
+        pak.read(PROBE_ADDR, &mut page);
+        assert_eq!(page, [0x80; PAK_PAGE_SIZE]);
+
+        pak.write(RUMBLE_MOTOR_ADDR, &[0x01; PAK_PAGE_SIZE]);
+        assert_eq!(pak.take_motor_change(), Some(true));
+        assert_eq!(pak.take_motor_change(), None);
+
+        pak.write(RUMBLE_MOTOR_ADDR, &[0x00; PAK_PAGE_SIZE]);
+        assert_eq!(pak.take_motor_change(), Some(false));
+    }
+}
@@ -0,0 +1,298 @@
+use super::PAK_PAGE_SIZE;
+
+/// Size of the Game Boy address space window mapped by the transfer pak
+const BANK_SIZE: usize = 0x4000;
+
+/// Transfer pak registers, in the pak address space
+mod reg {
+    /// Enables/disables the transfer pak
+    pub const ENABLE: u16 = 0x8000;
+    /// Selects the Game Boy address space bank mapped at `WINDOW`
+    pub const BANK: u16 = 0xA000;
+    /// Cartridge access mode and status
+    pub const STATUS: u16 = 0xB000;
+    /// Window into the Game Boy address space
+    pub const WINDOW: u16 = 0xC000;
+}
+
+/// Transfer pak status flags
+mod status {
+    pub const ACCESS_MODE: u8 = 0x01;
+    pub const MODE_CHANGED: u8 = 0x04;
+    pub const ACCESS_ENABLED: u8 = 0x08;
+    pub const NO_CARTRIDGE: u8 = 0x40;
+    pub const POWERED: u8 = 0x80;
+}
+
+/// Transfer Pak.
+///
+/// Exposes the address space of a Game Boy cartridge in 16KB banks through
+/// the pak address range `0xC000..=0xFFFF`. The cartridge must be enabled
+/// and put into access mode before it can be accessed.
+#[derive(Debug, Default, Clone)]
+pub struct TransferPak {
+    cartridge: Option<Box<GbCartridge>>,
+    enabled: bool,
+    access_mode: bool,
+    mode_changed: bool,
+    bank: u8,
+}
+
+impl TransferPak {
+    pub fn new(cartridge: Option<GbCartridge>) -> TransferPak {
+        Self {
+            cartridge: cartridge.map(Box::new),
+            ..Self::default()
+        }
+    }
+
+    pub fn cartridge(&self) -> Option<&GbCartridge> {
+        self.cartridge.as_deref()
+    }
+    pub fn insert_cartridge(&mut self, cartridge: GbCartridge) {
+        self.cartridge = Some(Box::new(cartridge));
+    }
+    pub fn eject_cartridge(&mut self) -> Option<GbCartridge> {
+        self.access_mode = false;
+        self.cartridge.take().map(|cartridge| *cartridge)
+    }
+
+    pub(super) fn read(&mut self, addr: u16, data: &mut [u8; PAK_PAGE_SIZE]) {
+        match addr {
+            reg::ENABLE..reg::BANK => {
+                data.fill(if self.enabled { 0x84 } else { 0x00 });
+            }
+            _ if !self.enabled => data.fill(0x00),
+            reg::BANK..reg::STATUS => data.fill(self.bank),
+            reg::STATUS..reg::WINDOW => data.fill(self.status()),
+            reg::WINDOW.. => match &self.cartridge {
+                Some(cartridge) if self.access_mode => {
+                    let gb_addr = self.gb_address(addr);
+                    for (i, byte) in data.iter_mut().enumerate() {
+                        *byte = cartridge.read(gb_addr.wrapping_add(i as u16));
+                    }
+                }
+                _ => data.fill(0x00),
+            },
+            _ => data.fill(0x00),
+        }
+    }
+
+    pub(super) fn write(&mut self, addr: u16, data: &[u8; PAK_PAGE_SIZE]) {
+        let value = data[PAK_PAGE_SIZE - 1];
+        match addr {
+            reg::ENABLE..reg::BANK => match value {
+                0x84 => self.enabled = true,
+                0xFE => self.enabled = false,
+                _ => {}
+            },
+            _ if !self.enabled => {}
+            reg::BANK..reg::STATUS => self.bank = value & 0x3,
+            reg::STATUS..reg::WINDOW => {
+                let access_mode = value & status::ACCESS_MODE != 0 && self.cartridge.is_some();
+                self.mode_changed |= access_mode != self.access_mode;
+                self.access_mode = access_mode;
+            }
+            reg::WINDOW.. => {
+                let gb_addr = self.gb_address(addr);
+                if let Some(cartridge) = self.cartridge.as_mut().filter(|_| self.access_mode) {
+                    for (i, &byte) in data.iter().enumerate() {
+                        cartridge.write(gb_addr.wrapping_add(i as u16), byte);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        let mut value = status::POWERED;
+        if self.cartridge.is_none() {
+            value |= status::NO_CARTRIDGE;
+        }
+        if self.access_mode {
+            value |= status::ACCESS_MODE | status::ACCESS_ENABLED;
+        }
+        if std::mem::take(&mut self.mode_changed) {
+            value |= status::MODE_CHANGED;
+        }
+        value
+    }
+
+    fn gb_address(&self, addr: u16) -> u16 {
+        let offset = (addr - reg::WINDOW) as usize;
+        (self.bank as usize * BANK_SIZE + offset) as u16
+    }
+}
+
+/// Memory bank controller of a Game Boy cartridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mbc {
+    None,
+    Mbc1,
+    Mbc3,
+    Mbc5,
+}
+
+/// A Game Boy cartridge, with its ROM and battery backed RAM
+#[derive(Debug, Clone)]
+pub struct GbCartridge {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    mbc: Mbc,
+    rom_bank: usize,
+    ram_bank: usize,
+    ram_enabled: bool,
+}
+
+impl GbCartridge {
+    /// Load a Game Boy cartridge from its ROM and save file. The save file
+    /// is resized to the RAM size declared by the ROM header.
+    ///
+    /// # Errors
+    /// The ROM header is truncated or uses an unsupported cartridge type
+    pub fn new(rom: Box<[u8]>, save: Option<Box<[u8]>>) -> anyhow::Result<Self> {
+        let header = rom
+            .get(0x100..0x150)
+            .ok_or_else(|| anyhow::anyhow!("Game Boy ROM header is truncated"))?;
+
+        let mbc = match header[0x47] {
+            0x00 | 0x08 | 0x09 => Mbc::None,
+            0x01..=0x03 => Mbc::Mbc1,
+            0x0F..=0x13 => Mbc::Mbc3,
+            0x19..=0x1E => Mbc::Mbc5,
+            kind => anyhow::bail!("Unsupported Game Boy cartridge type: 0x{kind:02x}"),
+        };
+        let ram_size = match header[0x49] {
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x2_0000,
+            0x05 => 0x1_0000,
+            _ => 0,
+        };
+
+        let mut ram = save.map(Vec::from).unwrap_or_default();
+        ram.resize(ram_size, 0xFF);
+
+        Ok(Self {
+            rom,
+            ram: ram.into_boxed_slice(),
+            mbc,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+        })
+    }
+
+    pub fn mbc(&self) -> Mbc {
+        self.mbc
+    }
+
+    /// The cartridge RAM, to be persisted as the save file
+    pub fn save_data(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Read a byte from the Game Boy cartridge address space
+    pub fn read(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr).copied().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = (self.rom_bank * BANK_SIZE + addr - 0x4000) % self.rom.len();
+                self.rom[offset]
+            }
+            0xA000..=0xBFFF => match self.ram_offset(addr) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Write a byte to the Game Boy cartridge address space. Writes to the
+    /// ROM area control the MBC
+    pub fn write(&mut self, addr: u16, value: u8) {
+        let addr = addr as usize;
+        match (self.mbc, addr) {
+            (Mbc::None, 0x0000..=0x7FFF) => {}
+            (_, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (Mbc::Mbc1, 0x2000..=0x3FFF) => {
+                self.rom_bank = (self.rom_bank & !0x1F) | (value as usize & 0x1F).max(1);
+            }
+            (Mbc::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (value as usize & 0x7F).max(1),
+            (Mbc::Mbc5, 0x2000..=0x2FFF) => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as usize;
+            }
+            (Mbc::Mbc5, 0x3000..=0x3FFF) => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value as usize & 1) << 8);
+            }
+            (Mbc::Mbc1, 0x4000..=0x5FFF) => self.ram_bank = value as usize & 0x03,
+            // values 0x08..=0x0C select the RTC registers, which aren't emulated
+            (Mbc::Mbc3, 0x4000..=0x5FFF) => self.ram_bank = value as usize,
+            (Mbc::Mbc5, 0x4000..=0x5FFF) => self.ram_bank = value as usize & 0x0F,
+            (_, 0xA000..=0xBFFF) => {
+                if let Some(offset) = self.ram_offset(addr) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ram_offset(&self, addr: usize) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() || self.ram_bank > 0x07 {
+            return None;
+        }
+        Some((self.ram_bank * 0x2000 + addr - 0xA000) % self.ram.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gb_rom(kind: u8, ram_size: u8, banks: usize) -> Box<[u8]> {
+        let mut rom = vec![0; banks * BANK_SIZE];
+        for (bank, data) in rom.chunks_mut(BANK_SIZE).enumerate() {
+            data.fill(bank as u8);
+        }
+        rom[0x147] = kind;
+        rom[0x149] = ram_size;
+        rom.into_boxed_slice()
+    }
+
+    #[test]
+    fn it_should_access_the_gb_cartridge_through_the_transfer_pak() {
+        let cartridge = GbCartridge::new(gb_rom(0x13, 0x03, 8), None).unwrap();
+        let mut pak = TransferPak::new(Some(cartridge));
+        let mut page = [0; PAK_PAGE_SIZE];
+
+        pak.write(reg::ENABLE, &[0x84; PAK_PAGE_SIZE]);
+        pak.write(reg::STATUS, &[status::ACCESS_MODE; PAK_PAGE_SIZE]);
+        pak.read(reg::STATUS, &mut page);
+        assert_eq!(page[0] & status::MODE_CHANGED, status::MODE_CHANGED);
+
+        // select the MBC ROM bank 5, then read it through the GB 0x4000 window
+        pak.write(reg::BANK, &[0; PAK_PAGE_SIZE]);
+        pak.write(reg::WINDOW + 0x2000, &[5; PAK_PAGE_SIZE]);
+        pak.write(reg::BANK, &[1; PAK_PAGE_SIZE]);
+        pak.read(reg::WINDOW, &mut page);
+        assert_eq!(page, [5; PAK_PAGE_SIZE]);
+
+        // enable the cartridge RAM and write to it at GB address 0xA000
+        pak.write(reg::BANK, &[0; PAK_PAGE_SIZE]);
+        pak.write(reg::WINDOW, &[0x0A; PAK_PAGE_SIZE]);
+        pak.write(reg::BANK, &[2; PAK_PAGE_SIZE]);
+        pak.write(reg::WINDOW + 0x2000, &[0x42; PAK_PAGE_SIZE]);
+        pak.read(reg::WINDOW + 0x2000, &mut page);
+        assert_eq!(page, [0x42; PAK_PAGE_SIZE]);
+
+        let cartridge = pak.eject_cartridge().unwrap();
+        assert_eq!(
+            &cartridge.save_data()[..PAK_PAGE_SIZE],
+            &[0x42; PAK_PAGE_SIZE]
+        );
+    }
+}
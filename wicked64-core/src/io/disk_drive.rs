@@ -0,0 +1,57 @@
+use byteorder::ByteOrder;
+
+use crate::{
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+};
+
+/// N64DD (64DD) disk drive, mapped at Cartridge Domain 2 Address 1.
+///
+/// The drive controller is not emulated yet. The registers behave as if no
+/// drive was connected, returning all bits set on reads and ignoring writes,
+/// which is what games probing for the 64DD expect.
+#[derive(Debug, Default)]
+pub struct DiskDrive {
+    /// Disk image inserted into the drive
+    disk: Option<Box<[u8]>>,
+}
+
+impl DiskDrive {
+    pub fn new() -> DiskDrive {
+        Self::default()
+    }
+
+    /// Whether the drive is connected to the console
+    pub fn is_present(&self) -> bool {
+        // TODO: report the drive as connected once its controller is emulated
+        false
+    }
+
+    /// Insert a disk image into the drive
+    pub fn insert_disk(&mut self, image: Box<[u8]>) {
+        tracing::warn!(
+            target: logging::target::PI,
+            "64DD is not emulated yet, the disk will not be read"
+        );
+        self.disk = Some(image);
+    }
+
+    /// Remove the inserted disk image
+    pub fn eject_disk(&mut self) -> Option<Box<[u8]>> {
+        self.disk.take()
+    }
+}
+
+impl MemoryUnit for DiskDrive {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        logging::trace!(PI, "64DD register read at offset 0x{addr:06x}");
+        // "not present" pattern
+        I::truncate_u64(u64::MAX)
+    }
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        logging::trace!(
+            PI,
+            "Ignoring 64DD register write at offset 0x{addr:06x}: {value:x?}"
+        );
+    }
+}
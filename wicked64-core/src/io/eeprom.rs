@@ -0,0 +1,72 @@
+/// Size of an EEPROM block. Reads and writes are always done in whole blocks
+pub const EEPROM_BLOCK_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EepromKind {
+    /// 4 kilobits (512 bytes)
+    Kb4,
+    /// 16 kilobits (2 kilobytes)
+    Kb16,
+}
+
+impl EepromKind {
+    pub fn size(self) -> usize {
+        match self {
+            EepromKind::Kb4 => 512,
+            EepromKind::Kb16 => 2048,
+        }
+    }
+}
+
+/// Cartridge EEPROM, accessed through the PIF joybus channel 4
+#[derive(Debug, Clone)]
+pub struct Eeprom {
+    kind: EepromKind,
+    data: Box<[u8]>,
+}
+
+impl Eeprom {
+    /// Create a new erased EEPROM
+    pub fn new(kind: EepromKind) -> Eeprom {
+        Self {
+            kind,
+            data: vec![0xFF; kind.size()].into_boxed_slice(),
+        }
+    }
+
+    pub fn kind(&self) -> EepromKind {
+        self.kind
+    }
+
+    /// Joybus device identifier
+    pub fn id(&self) -> u16 {
+        match self.kind {
+            EepromKind::Kb4 => 0x0080,
+            EepromKind::Kb16 => 0x00C0,
+        }
+    }
+
+    /// Read the block `block` into `buf`
+    pub fn read_block(&self, block: u8, buf: &mut [u8; EEPROM_BLOCK_SIZE]) {
+        let start = self.block_offset(block);
+        buf.copy_from_slice(&self.data[start..start + EEPROM_BLOCK_SIZE]);
+    }
+
+    /// Write `buf` into the block `block`
+    pub fn write_block(&mut self, block: u8, buf: &[u8; EEPROM_BLOCK_SIZE]) {
+        let start = self.block_offset(block);
+        self.data[start..start + EEPROM_BLOCK_SIZE].copy_from_slice(buf);
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn block_offset(&self, block: u8) -> usize {
+        // the block index wraps around on the 4Kbit EEPROM
+        (block as usize * EEPROM_BLOCK_SIZE) % self.data.len()
+    }
+}
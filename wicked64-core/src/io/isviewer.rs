@@ -0,0 +1,125 @@
+use std::fmt;
+
+use byteorder::ByteOrder;
+
+use crate::mmu::{num::MemInteger, MemoryUnit};
+
+/// Size of the IS-Viewer memory, registers included
+pub const ISVIEWER_SIZE: usize = 0x1_0000;
+
+/// IS-Viewer register offsets
+pub mod isv_reg {
+    /// Writing a length prints that many bytes of the buffer
+    pub const WRITE_LENGTH: usize = 0x14;
+    /// Start of the text buffer
+    pub const BUFFER: usize = 0x20;
+}
+
+/// Callback receiving the text printed through the IS-Viewer
+pub type TextCallback = dyn FnMut(&str) + Send;
+
+/// The debug port of the IS-Viewer 64 development cartridge, mapped over the
+/// cartridge ROM.
+///
+/// Test ROMs and homebrew copy their text into the buffer, then write its
+/// length into `WRITE_LENGTH` to print it. The printed lines are logged,
+/// and the raw text is given to the callback.
+pub struct IsViewer {
+    memory: Box<[u8]>,
+    /// Printed text that is not terminated by a newline yet
+    line: String,
+    callback: Option<Box<TextCallback>>,
+}
+
+impl IsViewer {
+    pub fn new() -> IsViewer {
+        Self {
+            memory: vec![0; ISVIEWER_SIZE].into_boxed_slice(),
+            line: String::new(),
+            callback: None,
+        }
+    }
+
+    pub fn set_callback(&mut self, callback: Box<TextCallback>) {
+        self.callback = Some(callback);
+    }
+
+    /// Print the first `len` bytes of the buffer
+    fn print(&mut self, len: usize) {
+        let end = (isv_reg::BUFFER + len).min(ISVIEWER_SIZE);
+        let text = String::from_utf8_lossy(&self.memory[isv_reg::BUFFER..end]).into_owned();
+        if let Some(callback) = self.callback.as_mut() {
+            callback(&text);
+        }
+
+        self.line.push_str(&text);
+        while let Some(newline) = self.line.find('\n') {
+            tracing::info!("IS-Viewer: {}", &self.line[..newline]);
+            self.line.drain(..=newline);
+        }
+    }
+}
+
+impl Default for IsViewer {
+    fn default() -> IsViewer {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for IsViewer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IsViewer")
+            .field("line", &self.line)
+            .field("callback", &self.callback.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemoryUnit for IsViewer {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        I::read_from::<O>(&self.memory[addr..addr + I::SIZE])
+    }
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        if addr == isv_reg::WRITE_LENGTH {
+            self.print(value.to_u64() as usize);
+            I::write_to::<O>(&mut self.memory[addr..addr + I::SIZE], I::default());
+        } else {
+            I::write_to::<O>(&mut self.memory[addr..addr + I::SIZE], value);
+        }
+    }
+    fn buffer(&self) -> &[u8] {
+        &self.memory
+    }
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use byteorder::BigEndian;
+
+    use super::*;
+
+    #[test]
+    fn it_should_print_the_written_text() {
+        let printed = Arc::new(Mutex::new(String::new()));
+        let mut isviewer = IsViewer::new();
+        isviewer.set_callback(Box::new({
+            let printed = printed.clone();
+            move |text| printed.lock().unwrap().push_str(text)
+        }));
+
+        for (i, word) in b"PASS\n\0\0\0".chunks_exact(4).enumerate() {
+            let word = u32::from_be_bytes(word.try_into().unwrap());
+            isviewer.store::<u32, BigEndian>(isv_reg::BUFFER + i * 4, word);
+        }
+        isviewer.store::<u32, BigEndian>(isv_reg::WRITE_LENGTH, 5);
+
+        assert_eq!(*printed.lock().unwrap(), "PASS\n");
+        assert!(isviewer.line.is_empty());
+        assert_eq!(isviewer.read::<u32, BigEndian>(isv_reg::WRITE_LENGTH), 0);
+    }
+}
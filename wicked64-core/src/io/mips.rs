@@ -0,0 +1,129 @@
+use std::io::{Read, Write};
+
+use byteorder::ByteOrder;
+
+use crate::{
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{read_u32s, write_u32s, SaveStateResult, Snapshot},
+};
+
+/// MIPS Interface registers offsets
+pub mod mi_reg {
+    pub const MODE: usize = 0x00;
+    pub const VERSION: usize = 0x04;
+    pub const INTR: usize = 0x08;
+    pub const INTR_MASK: usize = 0x0C;
+}
+
+/// `MI_INTR` interrupt lines
+pub mod mi_intr {
+    pub const SP: u32 = 1 << 0;
+    pub const SI: u32 = 1 << 1;
+    pub const AI: u32 = 1 << 2;
+    pub const VI: u32 = 1 << 3;
+    pub const PI: u32 = 1 << 4;
+    pub const DP: u32 = 1 << 5;
+}
+
+/// Value of `MI_VERSION` on retail consoles
+const MI_VERSION: u32 = 0x0202_0102;
+
+/// `MI_MODE` write bit that acknowledges the DP interrupt
+const MODE_CLEAR_DP_INTR: u32 = 1 << 11;
+
+/// MIPS Interface (MI). Collects the interrupts raised by the other devices
+/// and forwards them to the CPU.
+#[derive(Debug, Default)]
+pub struct MipsInterface {
+    mode: u32,
+    intr: u32,
+    mask: u32,
+}
+
+impl MipsInterface {
+    pub fn new() -> MipsInterface {
+        Self::default()
+    }
+
+    /// Raise the given interrupt lines. Refer to `mi_intr`
+    pub fn raise(&mut self, interrupt: u32) {
+        self.intr |= interrupt;
+    }
+
+    /// Acknowledge the given interrupt lines
+    pub fn clear(&mut self, interrupt: u32) {
+        self.intr &= !interrupt;
+    }
+
+    pub fn intr(&self) -> u32 {
+        self.intr
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Whether an unmasked interrupt is pending, which is signaled to the CPU
+    /// through the IP2 bit of the cause register
+    pub fn is_pending(&self) -> bool {
+        self.intr & self.mask != 0
+    }
+
+    /// Update the interrupt mask. Each line is controlled by a pair of bits:
+    /// the lower one clears the line and the upper one sets it
+    fn write_mask(&mut self, value: u32) {
+        for line in 0..6 {
+            let clear = value & (1 << (line * 2)) != 0;
+            let set = value & (1 << (line * 2 + 1)) != 0;
+            match (clear, set) {
+                (true, false) => self.mask &= !(1 << line),
+                (false, true) => self.mask |= 1 << line,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl MemoryUnit for MipsInterface {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        let value = match addr {
+            mi_reg::MODE => self.mode,
+            mi_reg::VERSION => MI_VERSION,
+            mi_reg::INTR => self.intr,
+            mi_reg::INTR_MASK => self.mask,
+            _ => 0,
+        };
+        I::truncate_u64(value as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        let value = value.to_u64() as u32;
+        match addr {
+            mi_reg::MODE => {
+                self.mode = value & 0x7F;
+                if value & MODE_CLEAR_DP_INTR != 0 {
+                    self.clear(mi_intr::DP);
+                }
+            }
+            mi_reg::INTR_MASK => self.write_mask(value),
+            _ => logging::debug!(
+                MMU,
+                "Unhandled MI register write at 0x{addr:02x}: 0x{value:08x}"
+            ),
+        }
+    }
+}
+
+impl Snapshot for MipsInterface {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &[self.mode, self.intr, self.mask])
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 3];
+        read_u32s(r, &mut regs)?;
+        [self.mode, self.intr, self.mask] = regs;
+        Ok(())
+    }
+}
@@ -1,3 +1,24 @@
+pub mod audio;
 pub mod cartridge;
+pub mod controller;
+pub mod disk_drive;
+pub mod eeprom;
+pub mod isviewer;
+pub mod mips;
+pub mod peripheral;
+pub mod pif;
+pub mod rom_db;
+pub mod serial;
+pub mod video;
 
-pub use cartridge::Cartridge;
+pub use audio::{AudioInterface, AudioSink};
+pub use cartridge::{Cartridge, SaveType};
+pub use controller::{Controller, ControllerState, InputSource};
+pub use disk_drive::DiskDrive;
+pub use isviewer::IsViewer;
+pub use mips::MipsInterface;
+pub use peripheral::PeripheralInterface;
+pub use pif::{Cic, Pif};
+pub use rom_db::{GameSettings, RomDatabase};
+pub use serial::SerialInterface;
+pub use video::VideoInterface;
@@ -0,0 +1,294 @@
+use std::io::{Read, Write};
+
+use byteorder::ByteOrder;
+
+use crate::{
+    logging,
+    mmu::{map::addr_map, num::MemInteger, MemoryUnit},
+    savestate::{read_u32s, write_u32s, SaveStateResult, Snapshot},
+};
+
+/// Peripheral Interface registers offsets
+pub mod pi_reg {
+    pub const DRAM_ADDR: usize = 0x00;
+    pub const CART_ADDR: usize = 0x04;
+    pub const RD_LEN: usize = 0x08;
+    pub const WR_LEN: usize = 0x0C;
+    pub const STATUS: usize = 0x10;
+    pub const BSD_DOM1_LAT: usize = 0x14;
+    pub const BSD_DOM1_PWD: usize = 0x18;
+    pub const BSD_DOM1_PGS: usize = 0x1C;
+    pub const BSD_DOM1_RLS: usize = 0x20;
+    pub const BSD_DOM2_LAT: usize = 0x24;
+    pub const BSD_DOM2_PWD: usize = 0x28;
+    pub const BSD_DOM2_PGS: usize = 0x2C;
+    pub const BSD_DOM2_RLS: usize = 0x30;
+}
+
+/// `PI_STATUS` bits, as read by the CPU
+pub mod pi_status {
+    pub const DMA_BUSY: u32 = 1 << 0;
+    pub const IO_BUSY: u32 = 1 << 1;
+    pub const DMA_ERROR: u32 = 1 << 2;
+    pub const INTERRUPT: u32 = 1 << 3;
+}
+
+/// `PI_STATUS` write bits
+const STATUS_RESET_DMA: u32 = 1 << 0;
+const STATUS_CLEAR_INTR: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiDmaDirection {
+    /// Copy from RDRAM into the cartridge bus (`PI_RD_LEN`)
+    RdramToCart,
+    /// Copy from the cartridge bus into RDRAM (`PI_WR_LEN`)
+    CartToRdram,
+}
+
+/// A DMA transfer requested by the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiDma {
+    pub dram_addr: usize,
+    pub cart_addr: usize,
+    pub len: usize,
+    pub direction: PiDmaDirection,
+}
+
+/// Bus timings of a cartridge domain, as set by the `PI_BSD_DOM*` registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DomainTiming {
+    pub latency: u32,
+    pub pulse_width: u32,
+    pub page_size: u32,
+    pub release: u32,
+}
+
+impl DomainTiming {
+    /// Timings set by the IPL for the cartridge ROM
+    const ROM: DomainTiming = DomainTiming {
+        latency: 0x40,
+        pulse_width: 0x12,
+        page_size: 0x07,
+        release: 0x03,
+    };
+
+    /// Duration of a transfer of `len` bytes, in CPU cycles. Each page pays
+    /// the domain latency, then every 16-bit word is sent with a pulse
+    /// followed by a release
+    pub fn transfer_cycles(&self, len: usize) -> u64 {
+        let page_len = 1u64 << (self.page_size + 2);
+        let len = len as u64;
+        let pages = len.div_ceil(page_len);
+        let words = len.div_ceil(2);
+        let rcp_cycles = pages * u64::from(self.latency + 1)
+            + words * u64::from(self.pulse_width + 1 + self.release + 1);
+        // the PI runs at the RCP clock, 2/3 of the CPU one
+        rcp_cycles * 3 / 2
+    }
+}
+
+/// Peripheral Interface (PI). Transfers data between RDRAM and the devices
+/// on the cartridge bus.
+///
+/// The transfers are requested here and performed right away by the memory
+/// manager, but `PI_STATUS` reports them as busy, and the completion
+/// interrupt is held back, for as long as they would take on the cartridge
+/// bus. The completion is scheduled as a `PiDmaComplete` event.
+#[derive(Debug)]
+pub struct PeripheralInterface {
+    dram_addr: u32,
+    cart_addr: u32,
+    rd_len: u32,
+    wr_len: u32,
+    status: u32,
+    domains: [DomainTiming; 2],
+    pending_dma: Option<PiDma>,
+}
+
+impl PeripheralInterface {
+    pub fn new() -> PeripheralInterface {
+        Self {
+            dram_addr: 0,
+            cart_addr: 0,
+            rd_len: 0x7F,
+            wr_len: 0x7F,
+            status: 0,
+            domains: [DomainTiming::ROM; 2],
+            pending_dma: None,
+        }
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Take the DMA transfer requested by the last register write
+    pub fn take_pending_dma(&mut self) -> Option<PiDma> {
+        self.pending_dma.take()
+    }
+
+    /// Timings of the domain the cartridge address `cart_addr` belongs to
+    pub fn domain_timing(&self, cart_addr: usize) -> DomainTiming {
+        let domain2 = addr_map::phys::CART_D2A1_RANGE.contains(&cart_addr)
+            || addr_map::phys::CART_D2A2_RANGE.contains(&cart_addr);
+        self.domains[usize::from(domain2)]
+    }
+
+    /// Mark `dma` as performed. It stays busy until the bus would have
+    /// finished the transfer, returning the number of CPU cycles it takes
+    pub fn finish_dma(&mut self, dma: &PiDma) -> u64 {
+        self.dram_addr = (self.dram_addr + dma.len as u32 + 7) & 0x00FF_FFF8;
+        self.cart_addr = (self.cart_addr + dma.len as u32 + 1) & !1;
+        self.domain_timing(dma.cart_addr).transfer_cycles(dma.len)
+    }
+
+    /// Complete the running transfer. Returns `true` if one was running,
+    /// raising the PI interrupt
+    pub fn complete_dma(&mut self) -> bool {
+        if self.status & pi_status::DMA_BUSY == 0 {
+            return false;
+        }
+        self.status &= !pi_status::DMA_BUSY;
+        self.status |= pi_status::INTERRUPT;
+        true
+    }
+
+    fn start_dma(&mut self, value: u32, direction: PiDmaDirection) {
+        if self.status & pi_status::DMA_BUSY != 0 {
+            tracing::warn!(
+                target: logging::target::PI,
+                "PI DMA requested while another one is running"
+            );
+            return;
+        }
+
+        self.status |= pi_status::DMA_BUSY;
+        self.pending_dma = Some(PiDma {
+            dram_addr: self.dram_addr as usize,
+            cart_addr: self.cart_addr as usize,
+            len: (value & 0x00FF_FFFF) as usize + 1,
+            direction,
+        });
+    }
+
+    fn domain_mut(&mut self, offset: usize) -> &mut DomainTiming {
+        &mut self.domains[usize::from(offset >= pi_reg::BSD_DOM2_LAT)]
+    }
+}
+
+impl Default for PeripheralInterface {
+    fn default() -> PeripheralInterface {
+        Self::new()
+    }
+}
+
+impl MemoryUnit for PeripheralInterface {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        let domain = &self.domains[usize::from(addr >= pi_reg::BSD_DOM2_LAT)];
+        let value = match addr {
+            pi_reg::DRAM_ADDR => self.dram_addr,
+            pi_reg::CART_ADDR => self.cart_addr,
+            pi_reg::RD_LEN => self.rd_len,
+            pi_reg::WR_LEN => self.wr_len,
+            pi_reg::STATUS => self.status,
+            pi_reg::BSD_DOM1_LAT | pi_reg::BSD_DOM2_LAT => domain.latency,
+            pi_reg::BSD_DOM1_PWD | pi_reg::BSD_DOM2_PWD => domain.pulse_width,
+            pi_reg::BSD_DOM1_PGS | pi_reg::BSD_DOM2_PGS => domain.page_size,
+            pi_reg::BSD_DOM1_RLS | pi_reg::BSD_DOM2_RLS => domain.release,
+            _ => 0,
+        };
+        I::truncate_u64(value as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        let value = value.to_u64() as u32;
+        match addr {
+            pi_reg::DRAM_ADDR => self.dram_addr = value & 0x00FF_FFFE,
+            pi_reg::CART_ADDR => self.cart_addr = value & !1,
+            pi_reg::RD_LEN => {
+                self.rd_len = value;
+                self.start_dma(value, PiDmaDirection::RdramToCart);
+            }
+            pi_reg::WR_LEN => {
+                self.wr_len = value;
+                self.start_dma(value, PiDmaDirection::CartToRdram);
+            }
+            pi_reg::STATUS => {
+                if value & STATUS_RESET_DMA != 0 {
+                    self.status &= !(pi_status::DMA_BUSY | pi_status::DMA_ERROR);
+                }
+                if value & STATUS_CLEAR_INTR != 0 {
+                    self.status &= !pi_status::INTERRUPT;
+                }
+            }
+            pi_reg::BSD_DOM1_LAT | pi_reg::BSD_DOM2_LAT => {
+                self.domain_mut(addr).latency = value & 0xFF;
+            }
+            pi_reg::BSD_DOM1_PWD | pi_reg::BSD_DOM2_PWD => {
+                self.domain_mut(addr).pulse_width = value & 0xFF;
+            }
+            pi_reg::BSD_DOM1_PGS | pi_reg::BSD_DOM2_PGS => {
+                self.domain_mut(addr).page_size = value & 0xF;
+            }
+            pi_reg::BSD_DOM1_RLS | pi_reg::BSD_DOM2_RLS => {
+                self.domain_mut(addr).release = value & 0x3;
+            }
+            _ => logging::debug!(
+                PI,
+                "Unhandled PI register write at 0x{addr:02x}: 0x{value:08x}"
+            ),
+        }
+    }
+}
+
+impl Snapshot for PeripheralInterface {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(
+            w,
+            &[
+                self.dram_addr,
+                self.cart_addr,
+                self.rd_len,
+                self.wr_len,
+                self.status,
+            ],
+        )?;
+        for domain in &self.domains {
+            write_u32s(
+                w,
+                &[
+                    domain.latency,
+                    domain.pulse_width,
+                    domain.page_size,
+                    domain.release,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 5];
+        read_u32s(r, &mut regs)?;
+        [
+            self.dram_addr,
+            self.cart_addr,
+            self.rd_len,
+            self.wr_len,
+            self.status,
+        ] = regs;
+        for domain in &mut self.domains {
+            let mut timing = [0; 4];
+            read_u32s(r, &mut timing)?;
+            let [latency, pulse_width, page_size, release] = timing;
+            *domain = DomainTiming {
+                latency,
+                pulse_width,
+                page_size,
+                release,
+            };
+        }
+        self.pending_dma = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+pub mod joybus;
+
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    io::{
+        controller::{Controller, InputSource, CONTROLLER_PORTS},
+        eeprom::{Eeprom, EepromKind},
+    },
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{read_bytes, write_bytes, SaveStateError, SaveStateResult, Snapshot},
+};
+
+use self::joybus::{JoybusDevice, JoybusError};
+
+/// Size of the PIF RAM
+pub const PIF_RAM_SIZE: usize = 64;
+/// Number of joybus channels (4 controller ports + the cartridge)
+pub const JOYBUS_CHANNELS: usize = 5;
+/// Joybus channel of the cartridge EEPROM
+pub const EEPROM_CHANNEL: usize = 4;
+
+/// Offset of the command byte in the PIF RAM
+const COMMAND_OFFSET: usize = PIF_RAM_SIZE - 1;
+/// Offset of the CIC challenge data in the PIF RAM
+const CHALLENGE_OFFSET: usize = 0x30;
+/// Offset of the CIC seed word the PIF leaves in its RAM at boot. The seed
+/// itself is the third byte
+pub const CIC_SEED_OFFSET: usize = 0x24;
+
+/// The lockout chip of a cartridge. The PAL chips (`7xxx`) behave like their
+/// NTSC counterpart
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Cic {
+    /// CIC-NUS-6101 and 7102
+    Cic6101,
+    /// CIC-NUS-6102 and 7101, used by most games
+    #[default]
+    Cic6102,
+    /// CIC-NUS-6103 and 7103
+    Cic6103,
+    /// CIC-NUS-6105 and 7105
+    Cic6105,
+    /// CIC-NUS-6106 and 7106
+    Cic6106,
+}
+
+impl Cic {
+    /// Get the chip from its model number, such as `6102`
+    pub fn from_model(model: u16) -> Option<Cic> {
+        match model {
+            6101 | 7102 => Some(Cic::Cic6101),
+            6102 | 7101 => Some(Cic::Cic6102),
+            6103 | 7103 => Some(Cic::Cic6103),
+            6105 | 7105 => Some(Cic::Cic6105),
+            6106 | 7106 => Some(Cic::Cic6106),
+            _ => None,
+        }
+    }
+
+    /// Seed of the boot code checksum, which the PIF leaves in `s6`
+    pub fn seed(self) -> u8 {
+        match self {
+            Cic::Cic6101 | Cic::Cic6102 => 0x3F,
+            Cic::Cic6103 => 0x78,
+            Cic::Cic6105 => 0x91,
+            Cic::Cic6106 => 0x85,
+        }
+    }
+
+    /// Word written by the PIF at `CIC_SEED_OFFSET` of its RAM
+    fn ram_seed(self) -> [u8; 4] {
+        let version = if self == Cic::Cic6101 { 0x04 } else { 0x00 };
+        [0x00, version, self.seed(), 0x3F]
+    }
+}
+
+/// PIF RAM command byte flags
+pub mod pif_command {
+    /// Run the joybus command block
+    pub const JOYBUS: u8 = 1 << 0;
+    /// Compute the CIC challenge response
+    pub const CHALLENGE: u8 = 1 << 1;
+    /// Terminate the boot process
+    pub const TERMINATE_BOOT: u8 = 1 << 3;
+    /// Lock the PIF ROM
+    pub const LOCK_ROM: u8 = 1 << 4;
+    /// Acquire the ROM checksum
+    pub const CHECKSUM: u8 = 1 << 5;
+    /// Clear the PIF RAM
+    pub const CLEAR_RAM: u8 = 1 << 6;
+}
+
+/// Joybus transfer status flags, set on the response length byte
+mod transfer_status {
+    pub const NO_DEVICE: u8 = 0x80;
+    pub const INVALID_LENGTH: u8 = 0x40;
+}
+
+/// The PIF (Peripheral Interface) chip.
+///
+/// The CPU communicates with the PIF through a 64-byte RAM, usually filled
+/// by SI DMAs. The last byte of the RAM holds a set of command flags that
+/// are processed whenever it is written: the joybus command block, which
+/// talks with the controllers and the cartridge EEPROM, and the CIC
+/// challenge.
+#[derive(Debug)]
+pub struct Pif {
+    ram: [u8; PIF_RAM_SIZE],
+    channels: Box<[JoybusDevice; JOYBUS_CHANNELS]>,
+    input: Option<Box<dyn InputSource>>,
+    cic: Cic,
+}
+
+impl Pif {
+    pub fn new() -> Pif {
+        let mut pif = Self {
+            ram: [0; PIF_RAM_SIZE],
+            channels: Box::new([
+                JoybusDevice::Controller(Controller::new()),
+                JoybusDevice::None,
+                JoybusDevice::None,
+                JoybusDevice::None,
+                JoybusDevice::Eeprom(Eeprom::new(EepromKind::Kb4)),
+            ]),
+            input: None,
+            cic: Cic::default(),
+        };
+        pif.reset();
+        pif
+    }
+
+    /// Clear the PIF RAM, leaving only the CIC seed. The joybus devices stay
+    /// connected
+    pub fn reset(&mut self) {
+        self.ram = [0; PIF_RAM_SIZE];
+        self.ram[CIC_SEED_OFFSET..CIC_SEED_OFFSET + 4].copy_from_slice(&self.cic.ram_seed());
+    }
+
+    pub fn cic(&self) -> Cic {
+        self.cic
+    }
+
+    /// Set the lockout chip of the cartridge, whose seed is written into the
+    /// PIF RAM on the next reset
+    pub fn set_cic(&mut self, cic: Cic) {
+        self.cic = cic;
+    }
+
+    /// Set the source used to update the controllers state
+    pub fn set_input_source(&mut self, input: Box<dyn InputSource>) {
+        self.input = Some(input);
+    }
+
+    pub fn take_input_source(&mut self) -> Option<Box<dyn InputSource>> {
+        self.input.take()
+    }
+
+    /// Get the device connected to the joybus `channel`
+    pub fn device(&self, channel: usize) -> Option<&JoybusDevice> {
+        self.channels.get(channel)
+    }
+
+    /// Connect `device` to the joybus `channel`, returning the device that
+    /// was previously connected. Channels `0..=3` are the controller ports and
+    /// channel 4 is the cartridge EEPROM.
+    ///
+    /// # Errors
+    /// The device can't be connected to the given channel
+    pub fn connect(
+        &mut self,
+        channel: usize,
+        device: JoybusDevice,
+    ) -> Result<JoybusDevice, JoybusError> {
+        let valid = match &device {
+            JoybusDevice::None => channel < JOYBUS_CHANNELS,
+            JoybusDevice::Controller(_) => channel < CONTROLLER_PORTS,
+            JoybusDevice::Eeprom(_) => channel == EEPROM_CHANNEL,
+        };
+        if !valid {
+            return Err(JoybusError::InvalidChannel(channel));
+        }
+
+        Ok(std::mem::replace(&mut self.channels[channel], device))
+    }
+
+    /// Get the controller plugged into `port`, if any
+    pub fn controller_mut(&mut self, port: usize) -> Option<&mut Controller> {
+        match self.channels[..CONTROLLER_PORTS].get_mut(port) {
+            Some(JoybusDevice::Controller(controller)) => Some(controller),
+            _ => None,
+        }
+    }
+
+    /// Update the state of the connected controllers from the input source
+    fn poll_input(&mut self) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+
+        for (port, device) in self.channels[..CONTROLLER_PORTS].iter_mut().enumerate() {
+            if let JoybusDevice::Controller(controller) = device {
+                controller.set_state(input.poll(port));
+            }
+        }
+    }
+
+    pub fn ram(&self) -> &[u8; PIF_RAM_SIZE] {
+        &self.ram
+    }
+
+    /// Overwrite the whole PIF RAM and process the command byte, as done by
+    /// a SI DMA write
+    pub fn write_ram(&mut self, data: &[u8; PIF_RAM_SIZE]) {
+        self.ram = *data;
+        self.process_commands();
+    }
+
+    /// Process the flags set in the command byte
+    pub fn process_commands(&mut self) {
+        let command = self.ram[COMMAND_OFFSET];
+
+        if command & pif_command::JOYBUS != 0 {
+            self.run_joybus();
+        }
+        if command & pif_command::CHALLENGE != 0 {
+            self.run_challenge();
+        }
+        if command & pif_command::CLEAR_RAM != 0 {
+            self.ram.fill(0);
+        }
+
+        let unhandled = command & (pif_command::LOCK_ROM | pif_command::CHECKSUM);
+        if unhandled != 0 {
+            logging::debug!(SI, "Unhandled PIF commands: 0x{unhandled:02x}");
+        }
+        // acknowledge the commands. The joybus flag is kept, as games keep
+        // reusing the same command block
+        self.ram[COMMAND_OFFSET] &= pif_command::JOYBUS;
+    }
+
+    /// Interpret the joybus command block.
+    ///
+    /// Each command is encoded as:
+    /// ```txt
+    /// [tx length] [rx length] [tx bytes...] [rx bytes...]
+    /// ```
+    /// and is sent to the next channel. A `0x00` byte skips a channel,
+    /// `0xFF` is used as padding and `0xFE` ends the block.
+    fn run_joybus(&mut self) {
+        self.poll_input();
+
+        let mut channel = 0;
+        let mut i = 0;
+
+        while i < COMMAND_OFFSET {
+            let tx = self.ram[i];
+            match tx {
+                0xFE => break,
+                0xFF | 0xFD => {
+                    i += 1;
+                    continue;
+                }
+                0x00 => {
+                    channel += 1;
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let rx = self.ram[i + 1];
+            if rx == 0xFE {
+                break;
+            }
+
+            let cmd_start = i + 2;
+            let resp_start = cmd_start + (tx & 0x3F) as usize;
+            let resp_end = resp_start + (rx & 0x3F) as usize;
+            if resp_end > COMMAND_OFFSET {
+                tracing::warn!(
+                    target: logging::target::SI,
+                    "Joybus command at 0x{i:02x} overflows the PIF RAM"
+                );
+                break;
+            }
+
+            let (cmd, resp) = self.ram.split_at_mut(resp_start);
+            let result = match self.channels.get_mut(channel) {
+                Some(device) => {
+                    device.execute(&cmd[cmd_start..], &mut resp[..resp_end - resp_start])
+                }
+                None => Err(JoybusError::NoDevice),
+            };
+
+            if let (Some(JoybusDevice::Controller(controller)), Some(input)) =
+                (self.channels.get_mut(channel), self.input.as_mut())
+            {
+                if let Some(on) = controller.take_rumble_change() {
+                    input.set_rumble(channel, on);
+                }
+            }
+
+            match result {
+                Ok(()) => {}
+                Err(JoybusError::NoDevice) => self.ram[i + 1] |= transfer_status::NO_DEVICE,
+                Err(error) => {
+                    logging::debug!(SI, "Joybus error on channel {channel}: {error}");
+                    self.ram[i + 1] |= transfer_status::INVALID_LENGTH;
+                }
+            }
+
+            channel += 1;
+            i = resp_end;
+        }
+    }
+
+    /// Answer the CIC-NUS-6105 challenge stored in the PIF RAM
+    fn run_challenge(&mut self) {
+        let mut challenge = [0u8; 30];
+        for (i, &byte) in self.ram[CHALLENGE_OFFSET..COMMAND_OFFSET]
+            .iter()
+            .enumerate()
+        {
+            challenge[i * 2] = byte >> 4;
+            challenge[i * 2 + 1] = byte & 0x0F;
+        }
+
+        let response = cic_6105_response(&challenge);
+
+        self.ram[CHALLENGE_OFFSET - 2] = 0;
+        self.ram[CHALLENGE_OFFSET - 1] = 0;
+        for (i, byte) in self.ram[CHALLENGE_OFFSET..COMMAND_OFFSET]
+            .iter_mut()
+            .enumerate()
+        {
+            *byte = (response[i * 2] << 4) | response[i * 2 + 1];
+        }
+    }
+}
+
+impl Default for Pif {
+    fn default() -> Pif {
+        Self::new()
+    }
+}
+
+impl MemoryUnit for Pif {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        I::read_from::<O>(&self.ram[addr..addr + I::SIZE])
+    }
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        I::write_to::<O>(&mut self.ram[addr..addr + I::SIZE], value);
+
+        if addr + I::SIZE > COMMAND_OFFSET {
+            self.process_commands();
+        }
+    }
+    fn buffer(&self) -> &[u8] {
+        &self.ram
+    }
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// Compute the response for the CIC-NUS-6105 challenge. Both `challenge` and
+/// the response are given as a sequence of nibbles.
+fn cic_6105_response(challenge: &[u8; 30]) -> [u8; 30] {
+    const LUT0: [u8; 16] = [
+        0x4, 0x7, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0xF, 0x8, 0xF, 0x6, 0x3, 0x6, 0x9,
+    ];
+    const LUT1: [u8; 16] = [
+        0x4, 0x1, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0x9, 0x8, 0x5, 0x6, 0x3, 0xC, 0x9,
+    ];
+
+    let mut response = [0u8; 30];
+    let mut key = 0xBu8;
+    let mut lut = &LUT0;
+
+    for (resp, &chl) in response.iter_mut().zip(challenge.iter()) {
+        *resp = key.wrapping_add(chl.wrapping_mul(5)) & 0xF;
+        key = lut[*resp as usize];
+
+        let sign = (*resp >> 3) & 1;
+        let magnitude = if sign == 1 { !*resp } else { *resp } & 0x7;
+        let mut modifier = if magnitude % 3 == 1 { sign } else { 1 - sign };
+        if std::ptr::eq(lut, &LUT1) {
+            match *resp {
+                0x1 | 0x9 => modifier = 1,
+                0xB | 0xE => modifier = 0,
+                _ => {}
+            }
+        }
+        lut = if modifier == 1 { &LUT1 } else { &LUT0 };
+    }
+
+    response
+}
+
+/// The controller paks are removable media, like the cartridge, so only the
+/// PIF RAM and the EEPROM contents are saved
+impl Snapshot for Pif {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_bytes(w, &self.ram)?;
+        for device in self.channels.iter() {
+            match device {
+                JoybusDevice::None => w.write_u8(0)?,
+                JoybusDevice::Controller(_) => w.write_u8(1)?,
+                JoybusDevice::Eeprom(eeprom) => {
+                    w.write_u8(2)?;
+                    write_bytes(w, eeprom.data())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        read_bytes(r, &mut self.ram)?;
+        for device in self.channels.iter_mut() {
+            match (r.read_u8()?, device) {
+                (0, JoybusDevice::None) | (1, JoybusDevice::Controller(_)) => {}
+                (2, JoybusDevice::Eeprom(eeprom)) => read_bytes(r, eeprom.data_mut())?,
+                _ => return Err(SaveStateError::Mismatch("joybus devices")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_run_the_joybus_command_block() {
+        let mut pif = Pif::new();
+
+        let mut ram = [0u8; PIF_RAM_SIZE];
+        #[rustfmt::skip]
+        let block = [
+            // channel 0: controller info
+            0x01, 0x03, 0x00, 0xFF, 0xFF, 0xFF,
+            // channel 1: controller state (nothing connected)
+            0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF,
+            // skip channels 2 and 3
+            0x00, 0x00,
+            // channel 4: EEPROM write then nothing else
+            0x0A, 0x01, 0x05, 0x02, 1, 2, 3, 4, 5, 6, 7, 8, 0xFF,
+            0xFE,
+        ];
+        ram[..block.len()].copy_from_slice(&block);
+        ram[COMMAND_OFFSET] = pif_command::JOYBUS;
+
+        pif.write_ram(&ram);
+        let ram = pif.ram();
+
+        assert_eq!(&ram[3..6], &[0x05, 0x00, 0x02]);
+        assert_eq!(ram[7], 0x04 | transfer_status::NO_DEVICE);
+        assert_eq!(ram[27], 0x00);
+
+        let JoybusDevice::Eeprom(eeprom) = &pif.channels[4] else {
+            panic!("EEPROM is not connected to channel 4");
+        };
+        assert_eq!(&eeprom.data()[16..24], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn it_should_configure_the_joybus_devices_per_channel() {
+        let mut pif = Pif::new();
+
+        let device = JoybusDevice::Controller(Controller::new());
+        assert!(pif.connect(3, device).is_ok());
+        assert!(pif.controller_mut(3).is_some());
+
+        let previous = pif.connect(0, JoybusDevice::None).unwrap();
+        assert!(matches!(previous, JoybusDevice::Controller(_)));
+        assert!(pif.controller_mut(0).is_none());
+
+        let eeprom = JoybusDevice::Eeprom(Eeprom::new(EepromKind::Kb16));
+        assert_eq!(
+            pif.connect(2, eeprom).unwrap_err(),
+            JoybusError::InvalidChannel(2)
+        );
+        let controller = JoybusDevice::Controller(Controller::new());
+        assert_eq!(
+            pif.connect(EEPROM_CHANNEL, controller).unwrap_err(),
+            JoybusError::InvalidChannel(EEPROM_CHANNEL)
+        );
+    }
+}
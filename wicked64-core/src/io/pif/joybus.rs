@@ -0,0 +1,153 @@
+use crate::io::{
+    controller::{pak::PAK_PAGE_SIZE, Controller},
+    eeprom::{Eeprom, EEPROM_BLOCK_SIZE},
+};
+
+/// Joybus command identifiers
+pub mod command {
+    pub const INFO: u8 = 0x00;
+    pub const CONTROLLER_STATE: u8 = 0x01;
+    pub const READ_PAK: u8 = 0x02;
+    pub const WRITE_PAK: u8 = 0x03;
+    pub const READ_EEPROM: u8 = 0x04;
+    pub const WRITE_EEPROM: u8 = 0x05;
+    pub const RESET: u8 = 0xFF;
+}
+
+/// Standard controller identifier
+const CONTROLLER_ID: u16 = 0x0500;
+
+/// Controller status: a pak is inserted
+const PAK_STATUS_PRESENT: u8 = 0x01;
+/// Controller status: no pak inserted
+const PAK_STATUS_EMPTY: u8 = 0x02;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoybusError {
+    /// Nothing is connected to the channel
+    #[error("No device connected")]
+    NoDevice,
+    /// The command or response length doesn't match the command
+    #[error("Invalid transfer length")]
+    InvalidLength,
+    #[error("Unknown command: 0x{0:02x}")]
+    UnknownCommand(u8),
+    /// The device can't be connected to the channel
+    #[error("Invalid device for joybus channel {0}")]
+    InvalidChannel(usize),
+}
+
+pub type JoybusResult = Result<(), JoybusError>;
+
+/// A device connected to a joybus channel
+#[derive(Debug, Default)]
+pub enum JoybusDevice {
+    #[default]
+    None,
+    Controller(Controller),
+    Eeprom(Eeprom),
+}
+
+impl JoybusDevice {
+    /// Execute the joybus command in `cmd`, writing the response to `resp`
+    ///
+    /// # Errors
+    /// The device does not exist or can't handle the command
+    pub fn execute(&mut self, cmd: &[u8], resp: &mut [u8]) -> JoybusResult {
+        let (&command, args) = cmd.split_first().ok_or(JoybusError::InvalidLength)?;
+
+        match self {
+            JoybusDevice::None => Err(JoybusError::NoDevice),
+            JoybusDevice::Controller(controller) => match command {
+                command::INFO | command::RESET => {
+                    let status = if controller.pak().is_some() {
+                        PAK_STATUS_PRESENT
+                    } else {
+                        PAK_STATUS_EMPTY
+                    };
+                    write_info(resp, CONTROLLER_ID, status)
+                }
+                command::CONTROLLER_STATE => {
+                    checked_response(resp, 4)?.copy_from_slice(&controller.state().to_bytes());
+                    Ok(())
+                }
+                command::READ_PAK => {
+                    let addr = pak_address(checked_args(args, 2)?);
+                    let resp = checked_response(resp, PAK_PAGE_SIZE + 1)?;
+                    let mut buf = [0; PAK_PAGE_SIZE];
+                    controller.read_pak(addr, &mut buf);
+                    resp[..PAK_PAGE_SIZE].copy_from_slice(&buf);
+                    resp[PAK_PAGE_SIZE] = data_crc(&buf);
+                    Ok(())
+                }
+                command::WRITE_PAK => {
+                    let args = checked_args(args, 2 + PAK_PAGE_SIZE)?;
+                    let mut buf = [0; PAK_PAGE_SIZE];
+                    buf.copy_from_slice(&args[2..]);
+                    controller.write_pak(pak_address(args), &buf);
+                    checked_response(resp, 1)?[0] = data_crc(&buf);
+                    Ok(())
+                }
+                _ => Err(JoybusError::UnknownCommand(command)),
+            },
+            JoybusDevice::Eeprom(eeprom) => match command {
+                command::INFO | command::RESET => write_info(resp, eeprom.id(), 0x00),
+                command::READ_EEPROM => {
+                    let block = checked_args(args, 1)?[0];
+                    let resp = checked_response(resp, EEPROM_BLOCK_SIZE)?;
+                    let mut buf = [0; EEPROM_BLOCK_SIZE];
+                    eeprom.read_block(block, &mut buf);
+                    resp.copy_from_slice(&buf);
+                    Ok(())
+                }
+                command::WRITE_EEPROM => {
+                    let args = checked_args(args, 1 + EEPROM_BLOCK_SIZE)?;
+                    let mut buf = [0; EEPROM_BLOCK_SIZE];
+                    buf.copy_from_slice(&args[1..]);
+                    eeprom.write_block(args[0], &buf);
+                    // not busy
+                    if let Some(status) = resp.first_mut() {
+                        *status = 0x00;
+                    }
+                    Ok(())
+                }
+                _ => Err(JoybusError::UnknownCommand(command)),
+            },
+        }
+    }
+}
+
+/// Compute the CRC used by pak data transfers (CRC-8, polynomial 0x85)
+pub fn data_crc(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    // the data is followed by 8 zero bits
+    for byte in data.iter().copied().chain(std::iter::once(0)) {
+        for bit in (0..8).rev() {
+            let xor_tap = if crc & 0x80 == 0 { 0x00 } else { 0x85 };
+            crc = (crc << 1) | ((byte >> bit) & 1);
+            crc ^= xor_tap;
+        }
+    }
+    crc
+}
+
+/// Decode the pak address from the command arguments. The lower 5 bits hold
+/// the address CRC, which is ignored
+fn pak_address(args: &[u8]) -> u16 {
+    u16::from_be_bytes([args[0], args[1]]) & !0x1F
+}
+
+fn write_info(resp: &mut [u8], id: u16, status: u8) -> JoybusResult {
+    let resp = checked_response(resp, 3)?;
+    resp[..2].copy_from_slice(&id.to_be_bytes());
+    resp[2] = status;
+    Ok(())
+}
+
+fn checked_args(args: &[u8], len: usize) -> Result<&[u8], JoybusError> {
+    args.get(..len).ok_or(JoybusError::InvalidLength)
+}
+
+fn checked_response(resp: &mut [u8], len: usize) -> Result<&mut [u8], JoybusError> {
+    resp.get_mut(..len).ok_or(JoybusError::InvalidLength)
+}
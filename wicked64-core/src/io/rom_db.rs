@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use crate::io::{pif::Cic, Cartridge, SaveType};
+
+/// Settings of the known games, in the database format
+const BUILTIN: &str = "\
+# Super Mario 64
+NSM save=eeprom4k cic=6102
+# Mario Kart 64
+NKT save=eeprom4k cic=6102
+# Star Fox 64
+NFX save=eeprom4k cic=6101
+# GoldenEye 007
+NGE save=eeprom4k cic=6102
+# Wave Race 64
+NWR save=eeprom4k cic=6102
+# Banjo-Kazooie
+NBK save=eeprom4k cic=6103
+# Diddy Kong Racing
+NDY save=eeprom4k cic=6103
+# Yoshi's Story
+NYS save=eeprom16k cic=6102
+# Donkey Kong 64
+NDO save=eeprom16k cic=6105 expansion_pak=yes
+# The Legend of Zelda: Ocarina of Time
+CZL cic=6105
+# The Legend of Zelda: Majora's Mask
+NZS cic=6105 expansion_pak=yes
+# F-Zero X
+CFZ cic=6106
+";
+
+/// Errors produced while parsing a ROM database
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RomDbError {
+    #[error("Line {line}: {reason}")]
+    Parse { line: usize, reason: &'static str },
+}
+
+/// Known-good settings of a game. Unset fields keep the defaults
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GameSettings {
+    pub save_type: Option<SaveType>,
+    pub cic: Option<Cic>,
+    pub expansion_pak: Option<bool>,
+}
+
+impl GameSettings {
+    /// Fill the unset fields of `self` with the ones of `other`
+    #[must_use]
+    pub fn or(self, other: GameSettings) -> GameSettings {
+        Self {
+            save_type: self.save_type.or(other.save_type),
+            cic: self.cic.or(other.cic),
+            expansion_pak: self.expansion_pak.or(other.expansion_pak),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RomKey {
+    /// CRC1 and CRC2 of the ROM header, matching a single dump
+    Crc(u64),
+    /// Game ID of the ROM header, matching every region and revision
+    GameId([u8; 3]),
+}
+
+/// Per-game settings, looked up by the checksums or the game ID of the ROM
+/// header.
+///
+/// The database is written one game per line, as its key followed by the
+/// settings:
+/// ```txt
+/// # Donkey Kong 64
+/// NDO save=eeprom16k cic=6105 expansion_pak=yes
+/// 0123456789ABCDEF save=none
+/// ```
+/// The key is either the 3 characters game ID or the 16 hexadecimal digits of
+/// CRC1 and CRC2. Settings matched by the checksums take precedence over the
+/// ones matched by the game ID.
+#[derive(Debug, Default, Clone)]
+pub struct RomDatabase {
+    entries: HashMap<RomKey, GameSettings>,
+}
+
+impl RomDatabase {
+    /// The database embedded in the emulator
+    ///
+    /// # Panics
+    /// The embedded database is invalid
+    pub fn builtin() -> RomDatabase {
+        Self::parse(BUILTIN).expect("The builtin ROM database should be valid")
+    }
+
+    /// Parse a database
+    ///
+    /// # Errors
+    /// A line is not a valid entry
+    pub fn parse(text: &str) -> Result<RomDatabase, RomDbError> {
+        let mut entries = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, settings) = Self::parse_entry(line).map_err(|reason| RomDbError::Parse {
+                line: index + 1,
+                reason,
+            })?;
+            entries.insert(key, settings);
+        }
+        Ok(Self { entries })
+    }
+
+    fn parse_entry(line: &str) -> Result<(RomKey, GameSettings), &'static str> {
+        let mut fields = line.split_whitespace();
+        let key = fields.next().ok_or("missing key")?;
+        let key = match *key.as_bytes() {
+            [a, b, c] => RomKey::GameId([a, b, c]),
+            _ if key.len() == 16 => {
+                RomKey::Crc(u64::from_str_radix(key, 16).map_err(|_| "invalid CRC")?)
+            }
+            _ => return Err("invalid key"),
+        };
+
+        let mut settings = GameSettings::default();
+        for field in fields {
+            let (name, value) = field.split_once('=').ok_or("invalid setting")?;
+            match name {
+                "save" => {
+                    settings.save_type = Some(match value {
+                        "none" => SaveType::None,
+                        "eeprom4k" => SaveType::Eeprom4k,
+                        "eeprom16k" => SaveType::Eeprom16k,
+                        _ => return Err("invalid save type"),
+                    });
+                }
+                "cic" => {
+                    let model = value.parse().map_err(|_| "invalid CIC")?;
+                    settings.cic = Some(Cic::from_model(model).ok_or("unknown CIC")?);
+                }
+                "expansion_pak" => {
+                    settings.expansion_pak = Some(match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err("invalid expansion pak setting"),
+                    });
+                }
+                _ => return Err("unknown setting"),
+            }
+        }
+        Ok((key, settings))
+    }
+
+    /// Add the entries of `other`, replacing the games known by both
+    pub fn extend(&mut self, other: RomDatabase) {
+        self.entries.extend(other.entries);
+    }
+
+    /// Get the settings of the game in `cartridge`
+    pub fn lookup(&self, cartridge: &Cartridge) -> GameSettings {
+        let get = |key| self.entries.get(&key).copied().unwrap_or_default();
+        let by_crc = cartridge.header_crc().map(RomKey::Crc).map(get);
+        let by_id = cartridge.game_id().map(RomKey::GameId).map(get);
+        by_crc.unwrap_or_default().or(by_id.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(crc: u64, id: [u8; 3]) -> Cartridge {
+        let mut data = vec![0; 0x40];
+        data[0] = 0x80;
+        data[0x10..0x18].copy_from_slice(&crc.to_be_bytes());
+        data[0x3B..0x3E].copy_from_slice(&id);
+        Cartridge {
+            data: data.into_boxed_slice(),
+        }
+    }
+
+    #[test]
+    fn it_should_look_up_the_game_settings() {
+        let mut db = RomDatabase::builtin();
+        db.extend(RomDatabase::parse("0123456789ABCDEF save=none # bad dump").unwrap());
+
+        let settings = db.lookup(&cartridge(0x0123_4567_89AB_CDEF, *b"NDO"));
+        assert_eq!(settings.save_type, Some(SaveType::None));
+        assert_eq!(settings.cic, Some(Cic::Cic6105));
+        assert_eq!(settings.expansion_pak, Some(true));
+
+        let settings = db.lookup(&cartridge(0, *b"NFX"));
+        assert_eq!(settings.save_type, Some(SaveType::Eeprom4k));
+        assert_eq!(settings.cic, Some(Cic::Cic6101));
+        assert_eq!(db.lookup(&cartridge(0, *b"NXX")), GameSettings::default());
+
+        assert_eq!(
+            RomDatabase::parse("NSM\nNKT cic=6104").unwrap_err(),
+            RomDbError::Parse {
+                line: 2,
+                reason: "unknown CIC"
+            }
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{Read, Write};
+
+use byteorder::ByteOrder;
+
+use crate::{
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{read_u32s, write_u32s, SaveStateResult, Snapshot},
+};
+
+/// Serial Interface registers offsets
+pub mod si_reg {
+    pub const DRAM_ADDR: usize = 0x00;
+    pub const PIF_AD_RD64B: usize = 0x04;
+    pub const PIF_AD_WR4B: usize = 0x08;
+    pub const PIF_AD_WR64B: usize = 0x10;
+    pub const PIF_AD_RD4B: usize = 0x14;
+    pub const STATUS: usize = 0x18;
+}
+
+/// `SI_STATUS` bits
+pub mod si_status {
+    pub const DMA_BUSY: u32 = 1 << 0;
+    pub const IO_BUSY: u32 = 1 << 1;
+    pub const DMA_ERROR: u32 = 1 << 3;
+    pub const INTERRUPT: u32 = 1 << 12;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiDmaDirection {
+    /// Copy the PIF RAM into RDRAM
+    PifToRdram,
+    /// Copy 64 bytes from RDRAM into the PIF RAM
+    RdramToPif,
+}
+
+/// A DMA transfer requested by the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiDma {
+    pub dram_addr: usize,
+    pub direction: SiDmaDirection,
+}
+
+/// Serial Interface (SI). Transfers data between RDRAM and the PIF RAM.
+///
+/// The transfers are only requested here, and performed by the memory
+/// manager, which has access to both memories.
+#[derive(Debug, Default)]
+pub struct SerialInterface {
+    dram_addr: u32,
+    status: u32,
+    pending_dma: Option<SiDma>,
+}
+
+impl SerialInterface {
+    pub fn new() -> SerialInterface {
+        Self::default()
+    }
+
+    /// Take the DMA transfer requested by the last register write
+    pub fn take_pending_dma(&mut self) -> Option<SiDma> {
+        self.pending_dma.take()
+    }
+
+    /// Mark the current DMA transfer as completed
+    pub fn finish_dma(&mut self) {
+        self.status &= !(si_status::DMA_BUSY | si_status::IO_BUSY);
+        self.status |= si_status::INTERRUPT;
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    fn start_dma(&mut self, direction: SiDmaDirection) {
+        self.status |= si_status::DMA_BUSY;
+        self.pending_dma = Some(SiDma {
+            dram_addr: self.dram_addr as usize,
+            direction,
+        });
+    }
+}
+
+impl MemoryUnit for SerialInterface {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        let value = match addr {
+            si_reg::DRAM_ADDR => self.dram_addr,
+            si_reg::STATUS => self.status,
+            _ => 0,
+        };
+        I::truncate_u64(value as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        let value = value.to_u64() as u32;
+        match addr {
+            si_reg::DRAM_ADDR => self.dram_addr = value & 0x00FF_FFFF,
+            si_reg::PIF_AD_RD64B => self.start_dma(SiDmaDirection::PifToRdram),
+            si_reg::PIF_AD_WR64B => self.start_dma(SiDmaDirection::RdramToPif),
+            // writing any value acknowledges the interrupt
+            si_reg::STATUS => self.status &= !si_status::INTERRUPT,
+            _ => logging::debug!(
+                SI,
+                "Unhandled SI register write at 0x{addr:02x}: 0x{value:08x}"
+            ),
+        }
+    }
+}
+
+impl Snapshot for SerialInterface {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &[self.dram_addr, self.status])
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 2];
+        read_u32s(r, &mut regs)?;
+        [self.dram_addr, self.status] = regs;
+        self.pending_dma = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, WriteBytesExt};
+
+use crate::{
+    logging,
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{read_bool, read_u32s, write_u32s, SaveStateResult, Snapshot},
+};
+
+/// Video Interface registers offsets
+pub mod vi_reg {
+    pub const CONTROL: usize = 0x00;
+    pub const ORIGIN: usize = 0x04;
+    pub const WIDTH: usize = 0x08;
+    pub const V_INTR: usize = 0x0C;
+    pub const V_CURRENT: usize = 0x10;
+    pub const BURST: usize = 0x14;
+    pub const V_SYNC: usize = 0x18;
+    pub const H_SYNC: usize = 0x1C;
+    pub const LEAP: usize = 0x20;
+    pub const H_START: usize = 0x24;
+    pub const V_START: usize = 0x28;
+    pub const V_BURST: usize = 0x2C;
+    pub const X_SCALE: usize = 0x30;
+    pub const Y_SCALE: usize = 0x34;
+}
+
+/// Number of VI registers
+const VI_REG_COUNT: usize = vi_reg::Y_SCALE / 4 + 1;
+
+/// `VI_CONTROL` pixel formats
+pub mod pixel_type {
+    pub const BLANK: u32 = 0;
+    pub const RGBA5551: u32 = 2;
+    pub const RGBA8888: u32 = 3;
+}
+
+/// CPU clock rate, in Hz
+pub const CPU_CLOCK_RATE: u64 = 93_750_000;
+
+/// Television standard of the video output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VideoStandard {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl VideoStandard {
+    /// Number of fields displayed per second
+    pub fn refresh_rate(self) -> u64 {
+        match self {
+            VideoStandard::Ntsc => 60,
+            VideoStandard::Pal => 50,
+        }
+    }
+
+    /// Default number of half-lines per field, used while `VI_V_SYNC` is not
+    /// configured
+    fn half_lines(self) -> u64 {
+        match self {
+            VideoStandard::Ntsc => 525,
+            VideoStandard::Pal => 625,
+        }
+    }
+}
+
+/// Events raised while advancing the VI
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ViEvents {
+    /// `VI_V_CURRENT` reached `VI_V_INTR`
+    pub interrupt: bool,
+    /// A field was completely scanned out
+    pub frame: bool,
+}
+
+/// A frame converted to RGBA8, ready to be displayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// `width * height` pixels, 4 bytes (R, G, B, A) each
+    pub pixels: Box<[u8]>,
+}
+
+/// Video Interface (VI). Reads the framebuffer from RDRAM and sends it to
+/// the screen.
+#[derive(Debug, Default)]
+pub struct VideoInterface {
+    regs: [u32; VI_REG_COUNT],
+    standard: VideoStandard,
+}
+
+impl VideoInterface {
+    pub fn new() -> VideoInterface {
+        Self::default()
+    }
+
+    /// Reset the registers, keeping the TV standard
+    pub fn reset(&mut self) {
+        *self = Self {
+            standard: self.standard,
+            ..Self::new()
+        };
+    }
+
+    pub fn standard(&self) -> VideoStandard {
+        self.standard
+    }
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        self.standard = standard;
+    }
+
+    /// Number of half-lines scanned per field
+    fn half_lines(&self) -> u64 {
+        match self.reg(vi_reg::V_SYNC) & 0x3FF {
+            0 => self.standard.half_lines(),
+            v_sync => v_sync as u64 + 1,
+        }
+    }
+
+    /// Number of CPU cycles taken to scan a half-line
+    pub fn cycles_per_half_line(&self) -> u64 {
+        CPU_CLOCK_RATE / self.standard.refresh_rate() / self.half_lines()
+    }
+
+    /// Scan the next half-line, updating `VI_V_CURRENT`. Scheduled every
+    /// `cycles_per_half_line` cycles
+    pub fn next_half_line(&mut self) -> ViEvents {
+        let mut events = ViEvents::default();
+
+        let mut v_current = self.reg(vi_reg::V_CURRENT) + 1;
+        if v_current as u64 >= self.half_lines() {
+            v_current = 0;
+            events.frame = true;
+        }
+        self.regs[vi_reg::V_CURRENT / 4] = v_current;
+
+        if v_current == self.reg(vi_reg::V_INTR) & 0x3FF {
+            events.interrupt = true;
+        }
+
+        events
+    }
+
+    pub fn reg(&self, offset: usize) -> u32 {
+        self.regs.get(offset / 4).copied().unwrap_or(0)
+    }
+
+    /// Size in bytes of each pixel of the framebuffer, or `None` if the
+    /// output is blanked
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        match self.reg(vi_reg::CONTROL) & 0x3 {
+            pixel_type::RGBA5551 => Some(2),
+            pixel_type::RGBA8888 => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Dimensions of the visible framebuffer
+    pub fn resolution(&self) -> (usize, usize) {
+        let width = (self.reg(vi_reg::WIDTH) & 0xFFF) as usize;
+
+        let v_start = self.reg(vi_reg::V_START);
+        let v_lines =
+            ((v_start & 0x3FF) as usize).saturating_sub(((v_start >> 16) & 0x3FF) as usize);
+        let y_scale = (self.reg(vi_reg::Y_SCALE) & 0xFFF) as usize;
+        let mut height = (v_lines / 2 * y_scale) >> 10;
+        if height == 0 {
+            // fallback to a 4:3 aspect ratio
+            height = width * 3 / 4;
+        }
+
+        (width, height)
+    }
+
+    /// Convert the framebuffer stored in `rdram` into a RGBA8 frame. Returns
+    /// `None` if the output is blanked or the framebuffer is out of bounds
+    pub fn framebuffer(&self, rdram: &[u8]) -> Option<Frame> {
+        let bpp = self.bytes_per_pixel()?;
+        let (width, height) = self.resolution();
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let origin = (self.reg(vi_reg::ORIGIN) & 0x00FF_FFFF) as usize;
+        let data = rdram.get(origin..origin + width * height * bpp)?;
+
+        let pixels = if bpp == 2 {
+            data.chunks_exact(2)
+                .flat_map(|pixel| rgba5551_to_rgba8(u16::from_be_bytes([pixel[0], pixel[1]])))
+                .collect()
+        } else {
+            // already stored as RGBA8
+            data.into()
+        };
+
+        Some(Frame {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+impl MemoryUnit for VideoInterface {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        I::truncate_u64(self.reg(addr) as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        let value = value.to_u64() as u32;
+        if addr == vi_reg::V_CURRENT {
+            // writes acknowledge the interrupt, handled by the MI
+        } else if let Some(reg) = self.regs.get_mut(addr / 4) {
+            *reg = value;
+        } else {
+            logging::debug!(
+                VI,
+                "Unhandled VI register write at 0x{addr:02x}: 0x{value:08x}"
+            );
+        }
+    }
+}
+
+fn rgba5551_to_rgba8(pixel: u16) -> [u8; 4] {
+    // expand the 5 bits components to 8 bits
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [
+        expand((pixel >> 11) & 0x1F),
+        expand((pixel >> 6) & 0x1F),
+        expand((pixel >> 1) & 0x1F),
+        if pixel & 1 == 0 { 0x00 } else { 0xFF },
+    ]
+}
+
+impl Snapshot for VideoInterface {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &self.regs)?;
+        w.write_u8(u8::from(self.standard == VideoStandard::Pal))
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        read_u32s(r, &mut self.regs)?;
+        self.standard = if read_bool(r)? {
+            VideoStandard::Pal
+        } else {
+            VideoStandard::Ntsc
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::BigEndian;
+
+    #[test]
+    fn it_should_convert_a_16_bit_framebuffer() {
+        let mut vi = VideoInterface::new();
+        vi.store::<u32, BigEndian>(vi_reg::CONTROL, pixel_type::RGBA5551);
+        vi.store::<u32, BigEndian>(vi_reg::ORIGIN, 0x10);
+        vi.store::<u32, BigEndian>(vi_reg::WIDTH, 4);
+
+        let mut rdram = vec![0u8; 0x100];
+        // red, opaque
+        rdram[0x10..0x12].copy_from_slice(&0xF801u16.to_be_bytes());
+
+        let frame = vi.framebuffer(&rdram).unwrap();
+        assert_eq!((frame.width, frame.height), (4, 3));
+        assert_eq!(&frame.pixels[..8], &[0xFF, 0, 0, 0xFF, 0, 0, 0, 0]);
+    }
+
+    /// Scan `half_lines` half-lines, merging their events
+    fn scan(vi: &mut VideoInterface, half_lines: usize) -> ViEvents {
+        (0..half_lines).fold(ViEvents::default(), |events, _| {
+            let line = vi.next_half_line();
+            ViEvents {
+                interrupt: events.interrupt || line.interrupt,
+                frame: events.frame || line.frame,
+            }
+        })
+    }
+
+    #[test]
+    fn it_should_raise_the_vi_interrupt_at_v_intr() {
+        let mut vi = VideoInterface::new();
+        vi.store::<u32, BigEndian>(vi_reg::V_SYNC, 0x20D);
+        vi.store::<u32, BigEndian>(vi_reg::V_INTR, 0x200);
+
+        assert_eq!(scan(&mut vi, 0x1FF), ViEvents::default());
+        assert_eq!(vi.reg(vi_reg::V_CURRENT), 0x1FF);
+        assert!(scan(&mut vi, 1).interrupt);
+        assert!(scan(&mut vi, 0x0E).frame);
+        assert_eq!(vi.reg(vi_reg::V_CURRENT), 0);
+    }
+}
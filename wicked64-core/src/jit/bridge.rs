@@ -1,67 +1,139 @@
-use std::arch::asm;
-
 use crate::{
-    mmu::{num::MemInteger, MemoryUnit},
+    cpu::Exception,
+    logging,
+    mmu::{num::MemInteger, watchpoint::AccessKind, MemoryUnit},
     n64::State,
 };
 
-use super::jump_table::JumpTable;
+use super::{jump_table::JumpTable, UnimplementedInstruction};
+
+fn mmu_read<I: MemInteger>(state: &mut State, phys_addr: u64) -> I {
+    state.bridge_calls += 1;
+    let State { mmu, .. } = state;
 
-fn mmu_read<I: MemInteger>(state: &mut State, virt_addr: u64) -> I {
-    let State { cpu, mmu, .. } = state;
+    let phys_addr = phys_addr as usize;
 
-    println!("{virt_addr:08x}");
-    let phys_addr = cpu.translate_virtual(virt_addr) as usize;
+    let value = mmu.read::<I, byteorder::BigEndian>(phys_addr);
+    mmu.watch_access(phys_addr, I::SIZE, AccessKind::Read, value.to_u64());
 
-    mmu.read::<I, byteorder::BigEndian>(phys_addr)
+    value
 }
-pub extern "C" fn mmu_read_byte(state: &mut State, virt_addr: u64) -> u8 {
-    mmu_read(state, virt_addr)
+pub extern "sysv64" fn mmu_read_byte(state: &mut State, phys_addr: u64) -> u8 {
+    mmu_read(state, phys_addr)
 }
-pub extern "C" fn mmu_read_word(state: &mut State, virt_addr: u64) -> u16 {
-    mmu_read(state, virt_addr)
+pub extern "sysv64" fn mmu_read_word(state: &mut State, phys_addr: u64) -> u16 {
+    mmu_read(state, phys_addr)
 }
-pub extern "C" fn mmu_read_dword(state: &mut State, virt_addr: u64) -> u32 {
-    mmu_read(state, virt_addr)
+pub extern "sysv64" fn mmu_read_dword(state: &mut State, phys_addr: u64) -> u32 {
+    mmu_read(state, phys_addr)
 }
 
-fn mmu_store<I: MemInteger>(state: &mut State, virt_addr: u64, value: I) {
+/// Returns whether the store hit a page holding compiled code
+fn mmu_store<I: MemInteger>(state: &mut State, phys_addr: u64, value: I) -> bool {
+    state.bridge_calls += 1;
     let State {
-        cpu,
-        mmu,
-        cache_invalidation,
-        ..
+        mmu, dirty_pages, ..
     } = state;
 
-    println!("{virt_addr:08x}");
-    dbg!(value);
-    let phys_addr = cpu.translate_virtual(virt_addr) as usize;
+    let phys_addr = phys_addr as usize;
 
-    // invalidate I::SIZE bytes starting from `phys_addr`
-    *cache_invalidation = Some(phys_addr..=phys_addr + I::SIZE);
+    // the blocks compiled from this page are dropped before the next one runs
+    let hit_code = dirty_pages.mark(phys_addr);
 
     mmu.store::<I, byteorder::BigEndian>(phys_addr, value);
+    mmu.watch_access(phys_addr, I::SIZE, AccessKind::Write, value.to_u64());
+    // the MI registers mask and acknowledge the interrupts
+    state.update_interrupt_pending();
+    hit_code
 }
-// pub extern "C" fn mmu_store_qword(state: &mut State, virt_addr: u64, value: u64) {
-//     mmu_store(state, virt_addr, value);
+// pub extern "sysv64" fn mmu_store_qword(state: &mut State, phys_addr: u64, value: u64) -> bool {
+//     mmu_store(state, phys_addr, value)
 // }
-pub extern "C" fn mmu_store_dword(state: &mut State, virt_addr: u64, value: u32) {
-    mmu_store(state, virt_addr, value);
+pub extern "sysv64" fn mmu_store_dword(state: &mut State, phys_addr: u64, value: u32) -> bool {
+    mmu_store(state, phys_addr, value)
+}
+
+/// Set HI and LO to the remainder and the quotient of the 32-bit division,
+/// sign-extended. The division by zero doesn't trap, the quotient being -1, or
+/// 1 for a negative dividend
+pub extern "sysv64" fn div(state: &mut State, dividend: u64, divisor: u64) {
+    state.bridge_calls += 1;
+    let (dividend, divisor) = (dividend as i32, divisor as i32);
+    let (quotient, remainder) = match divisor {
+        0 => (if dividend < 0 { 1 } else { -1 }, dividend),
+        _ => (
+            dividend.wrapping_div(divisor),
+            dividend.wrapping_rem(divisor),
+        ),
+    };
+    state.cpu.multi_lo = quotient as i64 as u64;
+    state.cpu.multi_hi = remainder as i64 as u64;
+}
+/// Unsigned `div`, whose quotient is all ones on a division by zero
+pub extern "sysv64" fn divu(state: &mut State, dividend: u64, divisor: u64) {
+    state.bridge_calls += 1;
+    let (dividend, divisor) = (dividend as u32, divisor as u32);
+    let (quotient, remainder) = dividend
+        .checked_div(divisor)
+        .zip(dividend.checked_rem(divisor))
+        .unwrap_or((u32::MAX, dividend));
+    state.cpu.multi_lo = quotient as i32 as u64;
+    state.cpu.multi_hi = remainder as i32 as u64;
+}
+
+/// Raise a reserved instruction exception for the `opcode` at `pc`, which the
+/// JIT can't compile, and which may be in the delay slot of a jump. It's
+/// reported to the frontend, unless another one is yet to be taken
+pub extern "sysv64" fn reserved_instruction(
+    state: &mut State,
+    pc: u64,
+    opcode: u64,
+    delay_slot: bool,
+) {
+    state.bridge_calls += 1;
+    let unimplemented = UnimplementedInstruction {
+        pc,
+        opcode: opcode as u32,
+    };
+    tracing::warn!("{unimplemented}");
+    state.unimplemented.get_or_insert(unimplemented);
+
+    state.cpu.pc = if delay_slot { pc - 4 } else { pc };
+    state
+        .cpu
+        .take_exception(Exception::ReservedInstruction, delay_slot);
+}
+
+/// Raise `exception` for the instruction at `pc`, which may be in the delay
+/// slot of a jump, once the compiled code synced the guest registers
+pub extern "sysv64" fn raise_exception(
+    state: &mut State,
+    pc: u64,
+    exception: Exception,
+    delay_slot: bool,
+) {
+    state.bridge_calls += 1;
+    logging::debug!(CPU, "{exception:?} exception at 0x{pc:08x}");
+    state.cpu.pc = if delay_slot { pc - 4 } else { pc };
+    state.cpu.take_exception(exception, delay_slot);
 }
 
-pub extern "C" fn get_host_jump_addr(state: &mut State, jump_table: &mut JumpTable, n64_addr: u64) {
-    let _ = jump_table.get(state.cpu.translate_virtual(n64_addr));
+/// Translate a virtual address outside of KSEG0 and KSEG1, which the compiled
+/// code translates itself
+pub extern "sysv64" fn translate_virtual(state: &mut State, virt_addr: u64) -> u64 {
+    state.bridge_calls += 1;
+    state.cpu.translate_virtual(virt_addr)
 }
 
-#[naked]
-pub extern "C" fn get_rip_value(disp: u32) -> u64 {
-    unsafe {
-        #[rustfmt::skip]
-        asm!(
-            "mov rax, [rsp]",
-            "add rax, rdi",
-            "ret",
-            options(noreturn),
-        );
-    }
+/// Host address the compiled code jumps to for the guest address `n64_addr`,
+/// or 0 if the target block isn't cached, in which case the host compiles it
+pub extern "sysv64" fn get_host_jump_addr(
+    state: &mut State,
+    jump_table: &JumpTable,
+    n64_addr: u64,
+) -> u64 {
+    state.bridge_calls += 1;
+    jump_table
+        .get(state.cpu.translate_virtual(n64_addr))
+        .map_or(0, |entry| entry.target_block as u64)
 }
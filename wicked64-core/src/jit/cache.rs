@@ -1,31 +1,73 @@
-use std::{ops::RangeInclusive, rc::Rc};
+use std::rc::Rc;
+
+use hashbrown::HashMap;
 
 use crate::utils::btree_range::BTreeRange;
 
-use super::code::CompiledBlock;
+use super::{code::CompiledBlock, dirty::PAGE_SIZE, memory::CodeAllocator};
 
 pub struct Cache {
     blocks: BTreeRange<Rc<CompiledBlock>>,
+    /// Start address of the blocks compiled from each page
+    pages: HashMap<usize, Vec<usize>>,
+    /// Executable memory of the compiled blocks
+    allocator: CodeAllocator,
 }
 
 impl Cache {
+    /// The cached blocks, ordered by their physical address
+    pub fn blocks(&self) -> impl Iterator<Item = &Rc<CompiledBlock>> {
+        self.blocks.values()
+    }
+
+    pub fn allocator(&self) -> &CodeAllocator {
+        &self.allocator
+    }
+
+    /// Get the compiled block starting at `addr`
+    pub fn get(&self, addr: usize) -> Option<&Rc<CompiledBlock>> {
+        self.blocks.get_exact(addr)
+    }
+
     /// Get a compiled block from the cache or create if no entries were found
     pub fn get_or_insert_with<F>(&mut self, addr: usize, mut f: F) -> Rc<CompiledBlock>
     where
-        F: FnMut() -> CompiledBlock,
+        F: FnMut(&CodeAllocator) -> CompiledBlock,
     {
         if let Some(block) = self.blocks.get_exact(addr) {
             return block.clone();
         }
 
-        let block = Rc::new(f());
+        let block = Rc::new(f(&self.allocator));
         self.blocks.insert(addr..=addr + block.len(), block.clone());
+
+        let last = addr + block.len().max(1) - 1;
+        for page in addr / PAGE_SIZE..=last / PAGE_SIZE {
+            let starts = self.pages.entry(page).or_default();
+            if !starts.contains(&addr) {
+                starts.push(addr);
+            }
+        }
         block
     }
 
-    pub fn invalidate_range(&mut self, inv_range: RangeInclusive<usize>) {
-        self.blocks
-            .retain(|(start, end), _| !(inv_range.contains(&start) && inv_range.contains(&end)));
+    /// Put `block` in place of the block starting at `addr`, compiled from
+    /// the same instructions, returning the replaced one
+    pub fn replace(&mut self, addr: usize, block: Rc<CompiledBlock>) -> Option<Rc<CompiledBlock>> {
+        let replaced = self.blocks.remove(addr);
+        self.blocks.insert(addr..=addr + block.len(), block);
+        replaced
+    }
+
+    /// Drop the blocks compiled from the given pages, returning them along
+    /// with their start address
+    pub fn invalidate_pages(&mut self, pages: &[usize]) -> Vec<(usize, Rc<CompiledBlock>)> {
+        pages
+            .iter()
+            .filter_map(|page| self.pages.remove(page))
+            .flatten()
+            .filter_map(|start| Some((start, self.blocks.remove(start)?)))
+            .collect()
     }
 }
 
@@ -34,6 +76,8 @@ impl Default for Cache {
     fn default() -> Cache {
         Self {
             blocks: BTreeRange::new(),
+            pages: HashMap::new(),
+            allocator: CodeAllocator::default(),
         }
     }
 }
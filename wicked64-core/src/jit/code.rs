@@ -1,25 +1,91 @@
-use std::{arch::asm, cell::RefCell, rc::Rc};
+use std::fmt::Write;
 
-use crate::n64::State;
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+use crate::{
+    cpu::instruction::Instruction,
+    n64::{State, SyncState},
+};
+
+use super::{
+    link::BlockLinks,
+    memory::{CodeAllocator, ExecMemory},
+    profile::BlockProfile,
+    Tier,
+};
 
-#[derive(Clone)]
 pub struct CompiledBlock {
     exec_buf: ExecBuffer,
     start_pc: u64,
     len: usize,
+    /// Cycles taken to run the whole block
+    cycles: usize,
+    links: BlockLinks,
+    /// The block is an idle loop, branching back to itself
+    idle: bool,
+    tier: Tier,
+    /// Boxed, as the compiled code counts the executions through its address
+    profile: Box<BlockProfile>,
+    /// Virtual address of each guest instruction, with the offset of its
+    /// host code
+    instructions: Vec<(u64, Instruction, usize)>,
 }
 
 impl CompiledBlock {
-    pub fn new(buf: ExecBuffer, start_pc: u64, len: usize) -> Self {
+    pub fn new(
+        buf: ExecBuffer,
+        start_pc: u64,
+        len: usize,
+        cycles: usize,
+        links: BlockLinks,
+        profile: Box<BlockProfile>,
+    ) -> Self {
         Self {
             exec_buf: buf,
             start_pc,
             len,
+            cycles,
+            links,
+            idle: false,
+            tier: Tier::Optimized,
+            profile,
+            instructions: Vec::new(),
         }
     }
 
-    pub fn execute(&self) {
-        unsafe { self.exec_buf.execute() };
+    /// Mark the block as an idle loop, which can be skipped until the next
+    /// device event
+    #[must_use]
+    pub fn idle(mut self, idle: bool) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Set the guest instructions the block was compiled from, along with
+    /// the offset of their host code
+    #[must_use]
+    pub fn instructions(mut self, instructions: Vec<(u64, Instruction, usize)>) -> Self {
+        self.instructions = instructions;
+        self
+    }
+
+    /// Set the tier the block was compiled at
+    #[must_use]
+    pub fn tier(mut self, tier: Tier) -> Self {
+        self.tier = tier;
+        self
+    }
+
+    pub fn is_optimized(&self) -> bool {
+        self.tier == Tier::Optimized
+    }
+
+    pub fn is_idle_loop(&self) -> bool {
+        self.idle
+    }
+
+    pub fn execute(&self) -> BlockExit {
+        unsafe { self.exec_buf.execute() }
     }
 
     pub fn ptr(&self) -> *const u8 {
@@ -33,79 +99,100 @@ impl CompiledBlock {
     pub fn start_pc(&self) -> u64 {
         self.start_pc
     }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn profile(&self) -> &BlockProfile {
+        &self.profile
+    }
+
+    pub fn links(&self) -> &BlockLinks {
+        &self.links
+    }
+
+    /// Host address the linked jumps to this block go to
+    pub fn link_entry(&self) -> usize {
+        self.ptr() as usize + self.links.entry
+    }
+
+    /// Host machine code of the block
+    pub fn code(&self) -> &[u8] {
+        self.exec_buf.as_slice()
+    }
+
+    /// Disassemble the host code of the block, each guest instruction
+    /// preceding the code it was compiled to
+    pub fn disassemble(&self) -> String {
+        let ip = self.ptr() as u64;
+        let mut decoder = Decoder::with_ip(64, self.code(), ip, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut guest = self.instructions.iter().peekable();
+
+        let mut text = String::new();
+        let mut host = String::new();
+        while decoder.can_decode() {
+            let offset = decoder.position();
+            while let Some((pc, instruction, _)) = guest.next_if(|(.., start)| *start <= offset) {
+                writeln!(text, "; 0x{pc:08x}: {instruction:?}").unwrap();
+            }
+
+            let instruction = decoder.decode();
+            host.clear();
+            formatter.format(&instruction, &mut host);
+            writeln!(text, "  {:016x} {host}", instruction.ip()).unwrap();
+        }
+        text
+    }
 }
 
-#[derive(Clone)]
 pub struct ExecBuffer {
-    ptr: *const u8,
-    buf: Vec<u8>,
-    state: Rc<RefCell<State>>,
+    memory: ExecMemory,
+    state: SyncState,
 }
 
 impl ExecBuffer {
-    pub unsafe fn new(buffer: Vec<u8>, state: Rc<RefCell<State>>) -> region::Result<Self> {
-        let ptr = buffer.as_ptr();
-
-        region::protect(ptr, buffer.len(), region::Protection::READ_WRITE_EXECUTE)?;
-
+    pub fn new(allocator: &CodeAllocator, code: &[u8], state: SyncState) -> region::Result<Self> {
         Ok(Self {
-            buf: buffer,
-            ptr,
+            memory: allocator.alloc(code)?,
             state,
         })
     }
 
-    pub unsafe fn execute(&self) {
-        let fn_ptr = self.ptr;
-        let state = self.state.borrow_mut();
-        execute((&*state) as *const _ as usize, fn_ptr as usize);
+    pub unsafe fn execute(&self) -> BlockExit {
+        call(&self.state, self.ptr() as usize)
     }
 
     pub fn ptr(&self) -> *const u8 {
-        self.ptr
+        self.memory.ptr()
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        self.buf.as_slice()
+        self.memory.as_slice()
     }
 }
 
-/// Executes the generated code
-///
-/// # Safety
-/// This function relies on inline assembly to setup the stack frame before
-/// jumping into the memory containing the generated code.
-/// It is expected that the code jumps back to the address saved in `r13`
-/// register.
-pub unsafe fn execute(state_addr: usize, resume_addr: usize) {
-    asm!(
-        "lea r13, [rip+3]", // save the address of the instruction after `jmp` as a return address
-        "jmp r14",
-
-        in("r14") resume_addr,
-        in("rsi") state_addr,
-        out("r13") _,
-        out("r15") _,
-    );
+/// Why a compiled block returned to the host
+#[repr(C, u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockExit {
+    /// The block ran to its end, or was left early. The PC is synced
+    Return = 0,
+    /// The block jumps to this virtual address, which the host resolves
+    Jump(u64) = 1,
 }
-/// Resumes the generated code
+
+/// Signature of the compiled blocks, whatever the host calling convention
+pub type BlockFn = unsafe extern "sysv64" fn(*mut State) -> BlockExit;
+
+/// Call the compiled code at `addr`
 ///
 /// # Safety
-/// This function relies on inline assembly to setup the stack frame before
-/// jumping into the memory containing the generated code.
-/// It is expected that the code jumps back to the address saved in `r13`
-/// register.
-pub unsafe fn resume(state: &Rc<RefCell<State>>, resume_addr: usize, jump_to: usize) {
-    let state = state.borrow_mut();
-    let state_addr = (&*state) as *const _ as u64;
-
-    std::arch::asm!(
-        "lea r13, [rip+3]", // save the address of the instruction after `jmp` as a return address
-        "jmp r14",
-
-        in("r14") resume_addr,
-        in("r15") jump_to,
-        in("rsi") state_addr,
-        out("r13") _,
-    );
+/// `addr` must be the start of a compiled block, which runs with the state
+/// locked
+pub unsafe fn call(state: &SyncState, addr: usize) -> BlockExit {
+    let mut state = state.lock();
+    let block: BlockFn = std::mem::transmute(addr);
+    block(&mut *state)
 }
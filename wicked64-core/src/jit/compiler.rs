@@ -1,28 +1,47 @@
+mod allocator;
+mod call;
+mod constants;
+mod idle;
 mod instructions;
 mod register;
 mod state;
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use iced_x86::{
+    code_asm::{self, AsmRegister64, CodeAssembler, CodeLabel},
+    BlockEncoderOptions,
+};
 
-use iced_x86::code_asm::{self, AsmRegister64, CodeAssembler};
+use crate::cpu::{instruction::Instruction, Exception};
+use crate::logging;
+use crate::n64::SyncState;
 
-use crate::cpu::instruction::Instruction;
-use crate::n64::State;
-
-use self::register::{GuestRegister, Registers, CALLEE_SAVED_REGISTERS};
+use self::allocator::{Allocation, RegisterUse};
+use self::constants::Folded;
+use self::instructions::RDRAM_BASE;
+use self::register::{GuestRegister, Registers};
 use self::state::JitState;
 
-use super::code::ExecBuffer;
 use super::jump_table::JumpTable;
+use super::link::BlockLinks;
+use super::{
+    code::{CompiledBlock, ExecBuffer},
+    memory::CodeAllocator,
+    profile::BlockProfile,
+};
+use super::{Debugger, Tier};
 
 const SCRATCHY_REGISTERS: [AsmRegister64; 2] = [code_asm::r14, code_asm::r15];
 
+/// Host registers left to the guest registers without an assigned one, as many
+/// as the operands of an instruction
+const OPERAND_REGISTERS: usize = 3;
+
 #[derive(Debug, PartialEq, Eq)]
 enum AssembleStatus {
     Continue,
-    InvalidateCache,
     Branch,
+    /// The instruction raised an exception, ending the block early
+    Exception,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -38,104 +57,391 @@ enum AssembleError {
 
 type AssembleResult<T> = Result<T, AssembleError>;
 
+/// Exit of the block raising a guest exception, whose code is emitted after
+/// the block, out of the way of the common path
+struct Bailout {
+    label: CodeLabel,
+    exception: Exception,
+    /// Virtual address of the instruction raising the exception
+    pc: u64,
+    /// Index of the instruction in the block
+    index: usize,
+    /// The instruction is in the delay slot of the jump ending the block
+    delay_slot: bool,
+    /// Guest registers mapped where the exception is raised, synced by the
+    /// bailout
+    regs: Registers,
+}
+
 /// The JIT compiler
 pub struct Compiler<'jt> {
     state: JitState,
     pc: u64,
     regs: Registers,
+    allocation: Allocation,
+    /// Instructions of the block whose operands are known
+    folded: Vec<Option<Folded>>,
+    /// The block is an idle loop
+    idle: bool,
+    /// Index of the instruction being compiled in the block
+    index: usize,
+    /// Instruction in the delay slot of the jump ending the block, unless it
+    /// doesn't decode
+    delay_slot: Option<Instruction>,
+    /// The instruction being compiled is in the delay slot
+    in_delay_slot: bool,
+    /// Cycles taken by the block up to each instruction, included
+    cycles: Vec<usize>,
     emitter: CodeAssembler,
-    saved_regs: Vec<AsmRegister64>,
-    jump_table: &'jt mut JumpTable,
+    /// Blocks the jumps through a register go straight to
+    jump_table: &'jt JumpTable,
+    /// Breakpoints and watchpoints the blocks end at
+    debugger: &'jt Debugger,
+    /// Static jump targets, with the label following their link site
+    link_sites: Vec<(u64, CodeLabel)>,
+    /// Guest instructions of the block, with the label of their host code
+    guest_labels: Vec<(u64, Instruction, CodeLabel)>,
+    bailouts: Vec<Bailout>,
+    profile: Box<BlockProfile>,
+    tier: Tier,
 }
 
 impl<'jt> Compiler<'jt> {
     /// Create a new Jit compiler
     /// # Panics
     /// Panics if the cpu architecture is not 64-bit
-    pub fn new(state: Rc<RefCell<State>>, jump_table: &'jt mut JumpTable, addr: usize) -> Self {
+    pub fn new(
+        state: SyncState,
+        jump_table: &'jt JumpTable,
+        debugger: &'jt Debugger,
+        addr: usize,
+    ) -> Self {
         let mut regs = Registers::new();
 
         for reg in SCRATCHY_REGISTERS {
             regs.exclude_register(reg);
         }
-        regs.exclude_register(code_asm::r13);
 
         Self {
             pc: addr as u64,
             regs,
+            allocation: Allocation::default(),
+            folded: Vec::new(),
+            idle: false,
+            index: 0,
+            delay_slot: None,
+            in_delay_slot: false,
+            cycles: Vec::new(),
             state: JitState::new(state),
             emitter: CodeAssembler::new(64).unwrap(),
-            saved_regs: Vec::new(),
             jump_table,
+            debugger,
+            link_sites: Vec::new(),
+            guest_labels: Vec::new(),
+            bailouts: Vec::new(),
+            profile: Box::default(),
+            tier: Tier::Optimized,
         }
     }
 
+    /// Compile the block at `tier`. The blocks are optimized by default
+    #[must_use]
+    pub fn tier(mut self, tier: Tier) -> Self {
+        self.tier = tier;
+        self
+    }
+
     /// Compile the code
     /// # Panics
     /// Panics if the generated assembly code is invalid
-    pub fn compile(mut self, cycles: usize) -> (ExecBuffer, usize) {
+    pub fn compile(mut self, cycles: usize, allocator: &CodeAllocator) -> CompiledBlock {
         let initial_pc = self.pc;
-        let _compiled_cycles = self.compile_block(cycles).unwrap();
-
-        let compiled = match assemble_code(self.emitter, self.state.into_inner()) {
-            Ok(compiled) => compiled,
-            Err(error) => panic!("Could not compile the code properly: {error:?}"),
-        };
-
-        println!("{:02x?}", compiled.as_slice());
+        self.emit_prologue().unwrap();
+        let mut entry = self.emitter.create_label();
+        self.emitter.set_label(&mut entry).unwrap();
+        self.emit_profile_count().unwrap();
+        let compiled_cycles = self.compile_block(cycles).unwrap();
+        self.emit_bailouts().unwrap();
 
         // we can ensure that `len >= 0`, as we stop the compilation whenever an instruction changes the pc to
         // an arbitrary value (i.e: a branch instruction)
         let len = (self.pc - initial_pc) as usize;
 
-        (compiled, len)
+        let link_entry = self
+            .emit_link_entry(entry, initial_pc, compiled_cycles)
+            .unwrap();
+        let mut labels = vec![link_entry];
+        labels.extend(self.link_sites.iter().map(|(_, label)| *label));
+        labels.extend(self.guest_labels.iter().map(|(_, _, label)| *label));
+
+        let (compiled, offsets) =
+            match assemble_code(self.emitter, self.state.into_inner(), allocator, &labels) {
+                Ok(compiled) => compiled,
+                Err(error) => panic!("Could not compile the code properly: {error:?}"),
+            };
+
+        // the immediate of each link site is right before its label
+        let (site_offsets, guest_offsets) = offsets[1..].split_at(self.link_sites.len());
+        let links = BlockLinks {
+            entry: offsets[0],
+            sites: self
+                .link_sites
+                .iter()
+                .zip(site_offsets)
+                .map(|((target, _), offset)| (*target, offset - 8))
+                .collect(),
+        };
+        let instructions = self
+            .guest_labels
+            .iter()
+            .zip(guest_offsets)
+            .map(|(&(pc, instruction, _), &offset)| (pc, instruction, offset))
+            .collect();
+
+        CompiledBlock::new(
+            compiled,
+            initial_pc,
+            len,
+            compiled_cycles,
+            links,
+            self.profile,
+        )
+        .idle(self.idle)
+        .tier(self.tier)
+        .instructions(instructions)
+    }
+
+    /// Entry point of the jumps linked to this block. The block runs if the
+    /// link budget allows it and no interrupt is pending, and returns to the
+    /// host otherwise
+    fn emit_link_entry(
+        &mut self,
+        entry: CodeLabel,
+        start_pc: u64,
+        cycles: usize,
+    ) -> AssembleResult<CodeLabel> {
+        let link = |offset: usize| code_asm::qword_ptr(code_asm::rsi + offset);
+        let budget = link(self.state.offset_of(|state| &state.link.budget));
+        let cpu_pc = link(self.state.offset_of(|state| &state.cpu.pc));
+        let pending_interrupt = code_asm::byte_ptr(
+            code_asm::rsi + self.state.offset_of(|state| &state.pending_interrupt),
+        );
+
+        let mut link_entry = self.emitter.create_label();
+        let mut exhausted = self.emitter.create_label();
+        self.emitter.set_label(&mut link_entry)?;
+        self.emitter.cmp(budget, cycles as i32)?;
+        self.emitter.jl(exhausted)?;
+        self.emitter.cmp(pending_interrupt, 0)?;
+        self.emitter.jne(exhausted)?;
+        self.emitter.sub(budget, cycles as i32)?;
+        self.emitter.jmp(entry)?;
+
+        // the linking block synced the guest registers, only the PC is left
+        self.emitter.set_label(&mut exhausted)?;
+        self.emitter.mov(code_asm::r15, start_pc)?;
+        self.emitter.mov(cpu_pc, code_asm::r15)?;
+        self.emit_return(None)?;
+
+        Ok(link_entry)
+    }
+
+    /// Enter the frame of the block. The blocks are called as a `BlockFn`,
+    /// and the linked blocks jump past their prologue, sharing the frame of
+    /// the first block
+    fn emit_prologue(&mut self) -> AssembleResult<()> {
+        call::emit_enter_frame(&mut self.emitter)
+    }
+
+    /// Count an execution of the block, whether it's called by the host or
+    /// linked
+    fn emit_profile_count(&mut self) -> AssembleResult<()> {
+        self.emitter
+            .mov(code_asm::r14, self.profile.executions_ptr())?;
+        self.emitter.inc(code_asm::qword_ptr(code_asm::r14))?;
+        Ok(())
+    }
+
+    /// Exit of the block raising `exception` for the instruction being
+    /// compiled, to be jumped to. The guest registers mapped at this point are
+    /// synced by the exit, so the registers the instruction writes must not be
+    /// mapped yet: the guest state is left as it was before the instruction
+    fn bailout(&mut self, exception: Exception) -> CodeLabel {
+        let label = self.emitter.create_label();
+        self.bailouts.push(Bailout {
+            label,
+            exception,
+            pc: self.pc,
+            index: self.index,
+            delay_slot: self.in_delay_slot,
+            regs: self.regs.clone(),
+        });
+        label
+    }
+
+    /// Emit the exits of the block raising exceptions
+    fn emit_bailouts(&mut self) -> AssembleResult<()> {
+        for mut bailout in std::mem::take(&mut self.bailouts) {
+            self.emitter.set_label(&mut bailout.label)?;
+            self.regs = bailout.regs;
+            self.index = bailout.index;
+            self.in_delay_slot = bailout.delay_slot;
+            self.emit_exception(bailout.pc, bailout.exception)?;
+        }
+        Ok(())
+    }
+
+    /// Return to the host, jumping to the guest address in `jump` if any
+    fn emit_return(&mut self, jump: Option<AsmRegister64>) -> AssembleResult<()> {
+        // `BlockExit` is returned in rax and rdx
+        match jump {
+            Some(jump) => {
+                self.emitter.mov(code_asm::rdx, jump)?;
+                self.emitter.mov(code_asm::eax, 1)?;
+            }
+            None => self.emitter.xor(code_asm::eax, code_asm::eax)?,
+        }
+        call::emit_leave_frame(&mut self.emitter)?;
+        self.emitter.ret()?;
+        Ok(())
     }
 
     fn compile_block(&mut self, cycles: usize) -> AssembleResult<usize> {
-        let mut total_cycles = 0;
-        while total_cycles < cycles {
-            // fetch the next instruction and update the PC and cycles
-            let instruction = {
-                let state = self.state.borrow();
-                let instruction = state
-                    .cpu
-                    .fetch_instruction(&state.mmu, self.pc)
-                    .map_err(AssembleError::Cpu)?;
+        let (instructions, total_cycles, undecoded) = self.decode_block(cycles);
+        self.idle = idle::is_idle_loop(self.pc, &instructions);
+        self.folded = match self.tier {
+            Tier::Baseline => vec![None; instructions.len()],
+            Tier::Optimized => constants::fold_block(&instructions),
+        };
+        self.cycles = instructions
+            .iter()
+            .scan(0, |cycles, instruction| {
+                *cycles += instruction.cycles();
+                Some(*cycles)
+            })
+            .collect();
+        if undecoded {
+            self.cycles.push(total_cycles);
+        }
 
-                total_cycles += instruction.cycles();
+        let uses = instructions
+            .iter()
+            .zip(&self.folded)
+            .map(|(instruction, &folded)| {
+                RegisterUse::of(instruction).with_folded(instruction, folded)
+            })
+            .collect();
+        let host_registers = self.regs.available();
+        // the baseline blocks load the guest registers as they're used
+        let assignable = match self.tier {
+            Tier::Baseline => 0,
+            Tier::Optimized => host_registers.len().saturating_sub(OPERAND_REGISTERS),
+        };
+        self.allocation = Allocation::new(uses, &host_registers[..assignable]);
+        for (guest, host) in self.allocation.registers() {
+            self.regs.assign(GuestRegister::cpu(guest), host);
+        }
 
-                instruction
-            };
+        // the loads read the RDRAM through its address, unless watched
+        if self.fast_memory() && instructions.iter().any(Instruction::accesses_memory) {
+            let (rdram, _) = self.state.rdram();
+            self.emitter.mov(RDRAM_BASE, rdram)?;
+        }
 
-            // check early return
-            let status = self.compile_instruction(instruction).unwrap();
+        let last = instructions.len().saturating_sub(1);
+        for (index, &instruction) in instructions.iter().enumerate() {
+            self.index = index;
+            // compiled by the jump, on each path leaving the block
+            self.delay_slot = instructions.get(index + 1).copied();
+            self.mark_guest_instruction(instruction)?;
+            let status = self.compile_instruction(instruction)?;
             self.pc += 4;
+            // r0 reads as zero again after being written
+            if self.allocation.writes(index, 0) {
+                self.regs.free(GuestRegister::cpu(0));
+            }
+            debug_assert!(
+                status != AssembleStatus::Branch || index + 1 >= last,
+                "The block was decoded past its end"
+            );
             match status {
                 AssembleStatus::Continue => {}
-                AssembleStatus::InvalidateCache => {
-                    break;
-                }
                 AssembleStatus::Branch => {
+                    // past the delay slot
+                    self.pc += 4;
                     return Ok(total_cycles);
                 }
+                AssembleStatus::Exception => return Ok(self.cycles[index]),
             }
         }
 
+        if undecoded {
+            self.index = self.cycles.len() - 1;
+            self.emit_reserved_instruction()?;
+            self.pc += 4;
+            return Ok(total_cycles);
+        }
+
         let cpu_pc = self.get_cpu_pc()?;
         self.emitter.mov(cpu_pc, self.pc)?;
+        self.emit_count()?;
 
         self.sync_all_registers()?;
-        self.restore_registers()?;
-
-        self.emitter.jmp(code_asm::r13)?;
+        self.emit_return(None)?;
 
         Ok(total_cycles)
     }
 
+    /// Fetch the instructions of the block, up to the one ending it, along
+    /// with the cycles they take. The block may end with a word that doesn't
+    /// decode, which is reported and counted as a cycle
+    fn decode_block(&self, cycles: usize) -> (Vec<Instruction>, usize, bool) {
+        let state = self.state.lock();
+        let mut instructions = Vec::new();
+        let mut pc = self.pc;
+        let mut total_cycles = 0;
+        while total_cycles < cycles {
+            // split the block, so that the breakpoint is checked before
+            // running the instruction
+            if pc != self.pc && self.debugger.breakpoints.contains(&pc) {
+                break;
+            }
+
+            // the words that don't decode end the block, raising an exception
+            let Ok(instruction) = state.cpu.fetch_instruction(&state.mmu, pc) else {
+                return (instructions, total_cycles + 1, true);
+            };
+            total_cycles += instruction.cycles();
+            instructions.push(instruction);
+            pc += 4;
+
+            // jumps end the block, after their delay slot, which isn't split
+            // from them even by a breakpoint
+            if is_jump(&instruction) {
+                let Ok(delay_slot) = state.cpu.fetch_instruction(&state.mmu, pc) else {
+                    return (instructions, total_cycles + 1, true);
+                };
+                total_cycles += delay_slot.cycles();
+                instructions.push(delay_slot);
+                break;
+            }
+            if instruction.accesses_memory() && self.debugger.watching {
+                break;
+            }
+        }
+        (instructions, total_cycles, false)
+    }
+
     #[allow(clippy::too_many_lines)]
     /// Compiles the given instruction and save the generated code into `buf`
     fn compile_instruction(&mut self, instruction: Instruction) -> AssembleResult<AssembleStatus> {
-        tracing::debug!("Compiling {instruction:02x?}");
+        logging::debug!(JIT, "Compiling {instruction:02x?}");
+        if let (Some(Folded::Value(value)), Some(rt)) =
+            (self.folded(), RegisterUse::of(&instruction).write)
+        {
+            return self.emit_constant(rt, value);
+        }
+
         match instruction {
             Instruction::NOP => Ok(AssembleStatus::Continue),
 
@@ -164,11 +470,19 @@ impl<'jt> Compiler<'jt> {
             Instruction::SpecialSRL(inst) => self.emit_srl(inst),
             Instruction::SpecialSRLV(inst) => self.emit_srlv(inst),
 
-            Instruction::BNE(inst) => self.emit_bne(inst),
+            Instruction::BEQ(inst) => self.emit_beq(inst, false),
+            Instruction::BEQL(inst) => self.emit_beq(inst, true),
+            Instruction::BNE(inst) => self.emit_bne(inst, false),
+            Instruction::BNEL(inst) => self.emit_bne(inst, true),
+            Instruction::BLEZ(inst) => self.emit_blez(inst, false),
+            Instruction::BLEZL(inst) => self.emit_blez(inst, true),
+            Instruction::BGTZ(inst) => self.emit_bgtz(inst, false),
+            Instruction::BGTZL(inst) => self.emit_bgtz(inst, true),
 
             Instruction::J(inst) => self.emit_j(inst),
             Instruction::JAL(inst) => self.emit_jal(inst),
             Instruction::SpecialJR(inst) => self.emit_jr(inst),
+            Instruction::SpecialJALR(inst) => self.emit_jalr(inst),
 
             Instruction::SW(inst) => self.emit_sw(inst),
 
@@ -180,26 +494,118 @@ impl<'jt> Compiler<'jt> {
             Instruction::LW(inst) => self.emit_lw(inst),
             Instruction::LWU(inst) => self.emit_lwu(inst),
 
-            _ => todo!("Instruction not implemented: {instruction:02x?}"),
+            _ => {
+                logging::debug!(JIT, "Instruction not implemented: {instruction:02x?}");
+                self.emit_reserved_instruction()?;
+                Ok(AssembleStatus::Exception)
+            }
         }
     }
 
+    /// Label the host code of the guest `instruction` about to be compiled
+    fn mark_guest_instruction(&mut self, instruction: Instruction) -> AssembleResult<()> {
+        // the label left by the previous instruction, if any, takes the
+        // first empty instruction
+        let mut label = self.emitter.create_label();
+        self.emitter.zero_bytes()?;
+        self.emitter.set_label(&mut label)?;
+        self.emitter.zero_bytes()?;
+        self.guest_labels.push((self.pc, instruction, label));
+        Ok(())
+    }
+
+    /// Compile the delay slot of the jump being compiled, on a path leaving
+    /// the block, which counts the slot from then on. Returns whether the path
+    /// goes on to the jump, unless the slot raised an exception
+    fn emit_delay_slot(&mut self) -> AssembleResult<bool> {
+        let pc = self.pc;
+        self.index += 1;
+        self.pc += 4;
+        self.in_delay_slot = true;
+        // only the first path compiling the slot marks it
+        if let Some(instruction) = self.delay_slot {
+            if self
+                .guest_labels
+                .last()
+                .is_none_or(|&(pc, ..)| pc != self.pc)
+            {
+                self.mark_guest_instruction(instruction)?;
+            }
+        }
+        let status = match self.delay_slot {
+            // a jump in a delay slot is undefined, it's left out
+            Some(instruction) if is_jump(&instruction) => AssembleStatus::Continue,
+            Some(instruction) => self.compile_instruction(instruction)?,
+            None => {
+                self.emit_reserved_instruction()?;
+                AssembleStatus::Exception
+            }
+        };
+        if self.allocation.writes(self.index, 0) {
+            self.regs.free(GuestRegister::cpu(0));
+        }
+        self.in_delay_slot = false;
+        self.pc = pc;
+
+        Ok(status == AssembleStatus::Continue)
+    }
+
+    /// Count the cycles and instructions of the block run up to the
+    /// instruction being compiled, as the block exits after it
+    fn emit_count(&mut self) -> AssembleResult<()> {
+        let link = |offset: usize| code_asm::qword_ptr(code_asm::rsi + offset);
+        let cycles = link(self.state.offset_of(|state| &state.link.cycles));
+        let instructions = link(self.state.offset_of(|state| &state.link.instructions));
+
+        self.emitter.add(cycles, self.cycles[self.index] as i32)?;
+        self.emitter.add(instructions, self.index as i32 + 1)?;
+        Ok(())
+    }
+
+    /// Whether the loads read the RDRAM straight through its address, instead
+    /// of the bridge, which checks the watchpoints
+    fn fast_memory(&self) -> bool {
+        self.tier == Tier::Optimized && !self.debugger.watching
+    }
+
+    /// The instruction being compiled, if its operands are known
+    fn folded(&self) -> Option<Folded> {
+        self.folded.get(self.index).copied().flatten()
+    }
+
     fn get_cpu_register(&mut self, register: u8) -> AssembleResult<AsmRegister64> {
-        self.get_host_register(GuestRegister::cpu(register), |emitter, state, host_reg| {
-            // load the register value
-            let reg_offset = state.offset_of(|state| &state.cpu.gpr[register as usize]);
-            emitter.mov(host_reg, code_asm::ptr(code_asm::rsi + reg_offset))?;
-            Ok(())
-        })
+        // r0 is hardwired to zero, so it's never loaded nor synced
+        if register == 0 {
+            return self.get_host_register(GuestRegister::cpu(0), |emitter, _, host_reg| {
+                emitter.xor(host_reg, host_reg)?;
+                Ok(())
+            });
+        }
+
+        // a register the instruction only writes doesn't have to be loaded
+        let write_only = self.allocation.is_write_only(self.index, register);
+        let host_reg =
+            self.get_host_register(GuestRegister::cpu(register), |emitter, state, host_reg| {
+                if write_only {
+                    return Ok(());
+                }
+                // load the register value
+                let reg_offset = state.offset_of(|state| &state.cpu.gpr[register as usize]);
+                emitter.mov(host_reg, code_asm::ptr(code_asm::rsi + reg_offset))?;
+                Ok(())
+            })?;
+
+        if self.allocation.writes(self.index, register) {
+            self.regs.mark_dirty(GuestRegister::cpu(register));
+        }
+        Ok(host_reg)
     }
 
+    /// Get a host register for the PC, which is only ever written
     fn get_cpu_pc(&mut self) -> AssembleResult<AsmRegister64> {
-        self.get_host_register(GuestRegister::pc(), |emitter, state, host_reg| {
-            // load the register value
-            let reg_offset = state.offset_of(|state| &state.cpu.pc);
-            emitter.mov(host_reg, code_asm::ptr(code_asm::rsi + reg_offset))?;
-            Ok(())
-        })
+        let host_reg = self.get_host_register(GuestRegister::pc(), |_, _, _| Ok(()))?;
+        self.regs.mark_dirty(GuestRegister::pc());
+        Ok(host_reg)
     }
 
     /// Gets a host register from the given guest register
@@ -216,17 +622,14 @@ impl<'jt> Compiler<'jt> {
         } else {
             let (&reg, dropped) = self.regs.insert(guest_reg).unwrap();
 
-            if CALLEE_SAVED_REGISTERS.contains(&reg) && !self.saved_regs.contains(&reg) {
-                self.save_register(reg)?;
-            }
-
-            tracing::debug!(
+            logging::debug!(
+                JIT,
                 "Allocated {:?} for {guest_reg:?}",
                 iced_x86::Register::from(reg)
             );
 
             if let Some(dropped) = dropped {
-                self.sync_guest_with(dropped, reg)?;
+                self.store_guest(dropped, reg)?;
             };
 
             initialize_with(&mut self.emitter, &self.state, reg)?;
@@ -251,45 +654,81 @@ impl<'jt> Compiler<'jt> {
         Ok(())
     }
 
+    /// Drop the mapping of `guest_reg`, storing its value if it was modified
     fn sync_guest_with(
         &mut self,
         guest_reg: GuestRegister,
         host_reg: AsmRegister64,
     ) -> AssembleResult<()> {
-        let guest_offset = {
-            i32::try_from(match guest_reg {
-                GuestRegister::Cpu(id) => self.state.offset_of(|state| &state.cpu.gpr[id as usize]),
-                GuestRegister::Pc => self.state.offset_of(|state| &state.cpu.pc),
-            })
+        if self.regs.is_dirty(guest_reg) && guest_reg != GuestRegister::cpu(0) {
+            self.store_guest(guest_reg, host_reg)?;
         }
-        .unwrap();
-
-        self.emitter
-            .mov(code_asm::ptr(code_asm::rsi + guest_offset), host_reg)?;
-
         self.regs.free(guest_reg);
 
         Ok(())
     }
 
-    fn save_register(&mut self, reg: AsmRegister64) -> AssembleResult<()> {
-        self.saved_regs.push(reg);
-        self.emitter.push(reg)?;
+    fn store_guest(
+        &mut self,
+        guest_reg: GuestRegister,
+        host_reg: AsmRegister64,
+    ) -> AssembleResult<()> {
+        let guest_offset = self.guest_offset(guest_reg);
+        self.emitter
+            .mov(code_asm::ptr(code_asm::rsi + guest_offset), host_reg)?;
+
         Ok(())
     }
-    fn restore_registers(&mut self) -> AssembleResult<()> {
-        for reg in self.saved_regs.drain(..).rev() {
-            self.emitter.pop(reg)?;
+
+    /// Map the guest registers back to their host registers in `regs`, once
+    /// a call synced them
+    fn reload_registers(&mut self, regs: Registers) -> AssembleResult<()> {
+        for (&guest_reg, &host_reg) in regs.iter() {
+            let guest_offset = self.guest_offset(guest_reg);
+            self.emitter
+                .mov(host_reg, code_asm::ptr(code_asm::rsi + guest_offset))?;
         }
+        self.regs = regs;
+
         Ok(())
     }
+
+    /// Offset of the guest register in the state
+    fn guest_offset(&self, guest_reg: GuestRegister) -> i32 {
+        i32::try_from(match guest_reg {
+            GuestRegister::Cpu(id) => self.state.offset_of(|state| &state.cpu.gpr[id as usize]),
+            GuestRegister::Pc => self.state.offset_of(|state| &state.cpu.pc),
+        })
+        .unwrap()
+    }
+}
+
+/// Whether `instruction` is a branch or a jump, which ends the block after
+/// its delay slot
+fn is_jump(instruction: &Instruction) -> bool {
+    idle::is_branch(instruction)
+        || matches!(
+            instruction,
+            Instruction::J(_)
+                | Instruction::JAL(_)
+                | Instruction::SpecialJR(_)
+                | Instruction::SpecialJALR(_)
+        )
 }
 
+/// Assemble the code, returning the offsets of the given labels
 fn assemble_code(
     mut emitter: CodeAssembler,
-    state: Rc<RefCell<State>>,
-) -> Result<ExecBuffer, AssembleError> {
-    let code = emitter.assemble(0)?;
-    let map = unsafe { ExecBuffer::new(code, state)? };
-    Ok(map)
+    state: SyncState,
+    allocator: &CodeAllocator,
+    labels: &[CodeLabel],
+) -> Result<(ExecBuffer, Vec<usize>), AssembleError> {
+    let result =
+        emitter.assemble_options(0, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let offsets = labels
+        .iter()
+        .map(|label| result.label_ip(label).map(|ip| ip as usize))
+        .collect::<Result<Vec<_>, _>>()?;
+    let map = ExecBuffer::new(allocator, &result.inner.code_buffer, state)?;
+    Ok((map, offsets))
 }
@@ -0,0 +1,233 @@
+use hashbrown::HashMap;
+use iced_x86::code_asm::AsmRegister64;
+
+use crate::cpu::instruction::{ImmediateType, Instruction, RegisterType};
+
+use super::constants::Folded;
+
+/// Guest registers read and written by the compiled code of an instruction.
+/// The written register is only synced back if it's listed here
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterUse {
+    pub reads: [Option<u8>; 2],
+    pub write: Option<u8>,
+}
+
+impl RegisterUse {
+    fn new(reads: [Option<u8>; 2], write: Option<u8>) -> Self {
+        Self { reads, write }
+    }
+
+    /// The guest registers used by `instruction`
+    pub fn of(instruction: &Instruction) -> Self {
+        match *instruction {
+            Instruction::SpecialAND(inst)
+            | Instruction::SpecialOR(inst)
+            | Instruction::SpecialXOR(inst)
+            | Instruction::SpecialNOR(inst)
+            | Instruction::SpecialADD(inst)
+            | Instruction::SpecialADDU(inst)
+            | Instruction::SpecialSUB(inst)
+            | Instruction::SpecialSUBU(inst)
+            | Instruction::SpecialMULT(inst)
+            | Instruction::SpecialMULTU(inst)
+            | Instruction::SpecialDIV(inst)
+            | Instruction::SpecialDIVU(inst)
+            | Instruction::SpecialSLLV(inst)
+            | Instruction::SpecialSRAV(inst)
+            | Instruction::SpecialSRLV(inst) => {
+                let RegisterType { rd, rs, rt, .. } = inst;
+                Self::new([Some(rs), Some(rt)], Some(rd))
+            }
+            Instruction::SpecialSLL(inst)
+            | Instruction::SpecialSRA(inst)
+            | Instruction::SpecialSRL(inst) => Self::new([Some(inst.rt), None], Some(inst.rd)),
+            Instruction::SpecialJR(inst) => Self::new([Some(inst.rs), None], None),
+            Instruction::SpecialJALR(inst) => Self::new([Some(inst.rs), None], Some(inst.rd)),
+
+            Instruction::ANDI(inst)
+            | Instruction::ORI(inst)
+            | Instruction::XORI(inst)
+            | Instruction::ADDI(inst)
+            | Instruction::ADDIU(inst)
+            | Instruction::LB(inst)
+            | Instruction::LBU(inst)
+            | Instruction::LH(inst)
+            | Instruction::LHU(inst)
+            | Instruction::LW(inst)
+            | Instruction::LWU(inst) => {
+                let ImmediateType { rs, rt, .. } = inst;
+                Self::new([Some(rs), None], Some(rt))
+            }
+            Instruction::LUI(inst) => Self::new([None, None], Some(inst.rt)),
+            Instruction::SW(inst)
+            | Instruction::BEQ(inst)
+            | Instruction::BEQL(inst)
+            | Instruction::BNE(inst)
+            | Instruction::BNEL(inst) => Self::new([Some(inst.rs), Some(inst.rt)], None),
+            Instruction::BLEZ(inst)
+            | Instruction::BLEZL(inst)
+            | Instruction::BGTZ(inst)
+            | Instruction::BGTZL(inst) => Self::new([Some(inst.rs), None], None),
+
+            Instruction::JAL(_) => Self::new([None, None], Some(31)),
+            _ => Self::default(),
+        }
+    }
+
+    /// The guest registers used once the operands known while compiling are
+    /// folded: folded values don't read their operand, nor do folded
+    /// addresses read their base register
+    #[must_use]
+    pub fn with_folded(self, instruction: &Instruction, folded: Option<Folded>) -> Self {
+        match (folded, instruction) {
+            (Some(Folded::Address(_)), Instruction::SW(inst)) => {
+                Self::new([Some(inst.rt), None], None)
+            }
+            (Some(_), _) => Self::new([None, None], self.write),
+            (None, _) => self,
+        }
+    }
+
+    fn registers(&self) -> impl Iterator<Item = u8> + '_ {
+        self.reads.iter().chain([&self.write]).flatten().copied()
+    }
+
+    /// The written register, if the instruction doesn't read it
+    fn write_only(self) -> Option<u8> {
+        self.write
+            .filter(|write| !self.reads.contains(&Some(*write)))
+    }
+}
+
+/// Instructions a guest register is live for, from its first to its last use
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    guest: u8,
+    start: usize,
+    end: usize,
+}
+
+/// Host registers assigned to the guest registers of a block, computed by a
+/// linear scan over their live intervals before the block is compiled
+#[derive(Debug, Default)]
+pub struct Allocation {
+    /// Host register of each guest register that fit, for the whole block
+    registers: HashMap<u8, AsmRegister64>,
+    /// Guest registers used by each instruction
+    uses: Vec<RegisterUse>,
+}
+
+impl Allocation {
+    /// Assign `host_registers` to the guest registers used by the instructions
+    /// of a block. When there are more live guest registers than host
+    /// registers, the ones live the longest are left out
+    pub fn new(uses: Vec<RegisterUse>, host_registers: &[AsmRegister64]) -> Self {
+        let mut intervals: Vec<Interval> = Vec::new();
+        for (index, guest) in uses
+            .iter()
+            .enumerate()
+            .flat_map(|(index, uses)| uses.registers().map(move |guest| (index, guest)))
+        {
+            match intervals
+                .iter_mut()
+                .find(|interval| interval.guest == guest)
+            {
+                Some(interval) => interval.end = index,
+                None => intervals.push(Interval {
+                    guest,
+                    start: index,
+                    end: index,
+                }),
+            }
+        }
+
+        let mut registers = HashMap::new();
+        let mut free = host_registers.iter().rev().copied().collect::<Vec<_>>();
+        let mut active: Vec<(Interval, AsmRegister64)> = Vec::new();
+        for interval in intervals {
+            active.retain(|&(live, host)| {
+                let expired = live.end < interval.start;
+                if expired {
+                    free.push(host);
+                }
+                !expired
+            });
+
+            if let Some(host) = free.pop() {
+                registers.insert(interval.guest, host);
+                active.push((interval, host));
+                continue;
+            }
+
+            // spill the interval ending last, either an active one or this one
+            let Some(spilled) = active
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (live, _))| live.end)
+                .map(|(index, _)| index)
+                .filter(|&index| active[index].0.end > interval.end)
+            else {
+                continue;
+            };
+            let (spilled, host) = active.swap_remove(spilled);
+            registers.remove(&spilled.guest);
+            registers.insert(interval.guest, host);
+            active.push((interval, host));
+        }
+
+        Self { registers, uses }
+    }
+
+    pub fn registers(&self) -> impl Iterator<Item = (u8, AsmRegister64)> + '_ {
+        self.registers.iter().map(|(&guest, &host)| (guest, host))
+    }
+
+    /// Whether the instruction at `index` writes `guest`
+    pub fn writes(&self, index: usize, guest: u8) -> bool {
+        self.uses.get(index).and_then(|uses| uses.write) == Some(guest)
+    }
+
+    /// Whether the instruction at `index` writes `guest` without reading it,
+    /// so that it doesn't have to be loaded
+    pub fn is_write_only(&self, index: usize, guest: u8) -> bool {
+        self.uses.get(index).and_then(|uses| uses.write_only()) == Some(guest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iced_x86::code_asm::registers::gpr64;
+
+    use super::*;
+
+    fn decode(code: &[u32]) -> Vec<Instruction> {
+        code.iter()
+            .map(|&inst| Instruction::try_from(inst).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_should_assign_the_registers_by_liveness() {
+        let instructions = decode(&[
+            0x3C08_0001, // lui t0, 1
+            0x3C09_0002, // lui t1, 2
+            0x0129_5021, // addu t2, t1, t1
+            0x254A_0001, // addiu t2, t2, 1
+            0x2508_0001, // addiu t0, t0, 1
+        ]);
+        let uses = instructions.iter().map(RegisterUse::of).collect();
+        let allocation = Allocation::new(uses, &[gpr64::rax, gpr64::rcx]);
+
+        // t2 takes the register of t0, which is spilled for being live the
+        // longest
+        let mut registers = allocation.registers().collect::<Vec<_>>();
+        registers.sort_by_key(|&(guest, _)| guest);
+        assert_eq!(registers, [(9, gpr64::rcx), (10, gpr64::rax)]);
+
+        assert!(allocation.is_write_only(0, 8));
+        assert!(allocation.is_write_only(2, 10));
+        assert!(!allocation.is_write_only(3, 10));
+        assert!(allocation.writes(3, 10));
+    }
+}
@@ -0,0 +1,212 @@
+use iced_x86::code_asm::{self, AsmMemoryOperand, AsmRegister64, CodeAssembler};
+
+use super::{register::ARGS_REGS, AssembleResult};
+
+/// Callee-saved registers the blocks may use, saved on entry
+pub const FRAME_REGISTERS: [AsmRegister64; 6] = [
+    code_asm::rbx,
+    code_asm::rbp,
+    code_asm::r12,
+    code_asm::r13,
+    code_asm::r14,
+    code_asm::r15,
+];
+
+/// Slot of the spill area holding the state pointer, after the arguments
+const STATE_SLOT: usize = ARGS_REGS.len();
+/// Bytes of the spill area, below the saved registers
+const SPILL_SIZE: usize = 8 * (STATE_SLOT + 1);
+
+// the return address, the saved registers and the spill area keep the stack
+// aligned for the calls
+const _: () = assert!((8 + 8 * FRAME_REGISTERS.len() + SPILL_SIZE).is_multiple_of(16));
+
+pub enum CallArgument {
+    Register(AsmRegister64),
+    Value(u64),
+    /// The state pointer the block was called with
+    State,
+}
+
+fn spill_slot(slot: usize) -> AsmMemoryOperand {
+    code_asm::qword_ptr(code_asm::rsp + 8 * slot)
+}
+
+/// Enter the frame shared by the blocks, called with the state pointer in
+/// rdi, which is kept in rsi
+pub fn emit_enter_frame(emitter: &mut CodeAssembler) -> AssembleResult<()> {
+    for reg in FRAME_REGISTERS {
+        emitter.push(reg)?;
+    }
+    emitter.sub(code_asm::rsp, SPILL_SIZE as i32)?;
+    emitter.mov(code_asm::rsi, code_asm::rdi)?;
+    emitter.mov(spill_slot(STATE_SLOT), code_asm::rsi)?;
+    Ok(())
+}
+
+/// Leave the frame, right before returning
+pub fn emit_leave_frame(emitter: &mut CodeAssembler) -> AssembleResult<()> {
+    emitter.add(code_asm::rsp, SPILL_SIZE as i32)?;
+    for reg in FRAME_REGISTERS.into_iter().rev() {
+        emitter.pop(reg)?;
+    }
+    Ok(())
+}
+
+/// Call the sysv64 `function` with `args`, leaving its result in rax. The
+/// caller-saved registers are clobbered, except for the state pointer in rsi
+///
+/// # Panics
+/// There are more arguments than argument registers
+pub fn emit_call(
+    emitter: &mut CodeAssembler,
+    function: u64,
+    args: &[CallArgument],
+) -> AssembleResult<()> {
+    assert!(
+        args.len() <= ARGS_REGS.len(),
+        "Argument list exceeds the limit of {} arguments.",
+        ARGS_REGS.len()
+    );
+
+    // the registers are spilled first, as an argument register might be the
+    // source of another argument
+    for (slot, arg) in args.iter().enumerate() {
+        if let CallArgument::Register(reg) = *arg {
+            emitter.mov(spill_slot(slot), reg)?;
+        }
+    }
+    for (slot, (&dst, arg)) in ARGS_REGS.iter().zip(args).enumerate() {
+        match *arg {
+            CallArgument::Register(_) => emitter.mov(dst, spill_slot(slot))?,
+            CallArgument::Value(value) => emitter.mov(dst, value)?,
+            // rsi might already hold an earlier argument
+            CallArgument::State => emitter.mov(dst, spill_slot(STATE_SLOT))?,
+        }
+    }
+
+    emitter.mov(code_asm::rax, function)?;
+    emitter.call(code_asm::rax)?;
+    emitter.mov(code_asm::rsi, spill_slot(STATE_SLOT))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::memory::CodeAllocator;
+
+    use super::*;
+
+    /// Whether the stack was aligned for the call, which a local aligned to
+    /// 16 bytes relies on
+    fn stack_aligned() -> bool {
+        #[repr(align(16))]
+        struct Aligned(u8);
+        let local = std::hint::black_box(&Aligned(0));
+        local.0 == 0 && (std::ptr::from_ref(local) as usize).is_multiple_of(16)
+    }
+
+    extern "sysv64" fn arity0() -> u64 {
+        if stack_aligned() {
+            42
+        } else {
+            0
+        }
+    }
+
+    extern "sysv64" fn arity3(a: u64, b: u64, c: u64) -> u64 {
+        a * 100 + b * 10 + c
+    }
+
+    extern "sysv64" fn arity6(a1: u64, a2: u64, a3: u64, a4: u64, a5: u64, a6: u64) -> u64 {
+        let digits = [a1, a2, a3, a4, a5, a6];
+        digits.iter().fold(0, |n, digit| n * 10 + digit)
+    }
+
+    fn address(function: *const ()) -> u64 {
+        function as u64
+    }
+
+    /// rax and rdx, as returned by the emitted code
+    #[repr(C)]
+    struct Returned(u64, u64);
+
+    /// Run the code emitted by `f` in a frame, returning rax, along with the
+    /// state pointer left in rsi
+    fn run(f: impl FnOnce(&mut CodeAssembler) -> AssembleResult<()>) -> (u64, u64) {
+        let mut emitter = CodeAssembler::new(64).unwrap();
+        emit_enter_frame(&mut emitter).unwrap();
+        f(&mut emitter).unwrap();
+        emitter.mov(code_asm::rdx, code_asm::rsi).unwrap();
+        emit_leave_frame(&mut emitter).unwrap();
+        emitter.ret().unwrap();
+
+        let code = emitter.assemble(0).unwrap();
+        let allocator = CodeAllocator::default();
+        let memory = allocator.alloc(&code).unwrap();
+        allocator.protect().unwrap();
+        let function: extern "sysv64" fn(u64) -> Returned =
+            unsafe { std::mem::transmute(memory.ptr()) };
+        let Returned(rax, rdx) = function(0xDEAD_BEEF);
+        (rax, rdx)
+    }
+
+    #[test]
+    fn it_should_call_the_functions_of_any_arity() {
+        let (result, state) = run(|emitter| emit_call(emitter, address(arity0 as *const ()), &[]));
+        assert_eq!((result, state), (42, 0xDEAD_BEEF));
+
+        let args = [
+            CallArgument::Value(1),
+            CallArgument::Value(2),
+            CallArgument::Value(3),
+        ];
+        let (result, _) = run(|emitter| emit_call(emitter, address(arity3 as *const ()), &args));
+        assert_eq!(result, 123);
+
+        let args = [1, 2, 3, 4, 5, 6].map(CallArgument::Value);
+        let (result, state) =
+            run(|emitter| emit_call(emitter, address(arity6 as *const ()), &args));
+        assert_eq!((result, state), (123_456, 0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn it_should_shuffle_the_argument_registers() {
+        // each argument comes from the register of another one
+        let (result, state) = run(|emitter| {
+            for (value, &reg) in (1..=6).zip(ARGS_REGS) {
+                emitter.mov(reg, value as u64)?;
+            }
+            let mut args = ARGS_REGS.map(CallArgument::Register);
+            args.reverse();
+            emit_call(emitter, address(arity6 as *const ()), &args)
+        });
+        assert_eq!((result, state), (654_321, 0xDEAD_BEEF));
+
+        // an argument register is also the source of a later one
+        let (result, _) = run(|emitter| {
+            emitter.mov(code_asm::rdi, 7u64)?;
+            emitter.mov(code_asm::rsi, 8u64)?;
+            let args = [
+                CallArgument::Register(code_asm::rsi),
+                CallArgument::Register(code_asm::rdi),
+                CallArgument::Register(code_asm::rdi),
+            ];
+            emit_call(emitter, address(arity3 as *const ()), &args)
+        });
+        assert_eq!(result, 877);
+    }
+
+    #[test]
+    fn it_should_pass_the_state_pointer() {
+        // rsi holds the second argument by the time the third is set
+        let args = [
+            CallArgument::Value(1),
+            CallArgument::Value(2),
+            CallArgument::State,
+        ];
+        let (result, state) =
+            run(|emitter| emit_call(emitter, address(arity3 as *const ()), &args));
+        assert_eq!((result, state), (120 + 0xDEAD_BEEF, 0xDEAD_BEEF));
+    }
+}
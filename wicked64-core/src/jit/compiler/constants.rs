@@ -0,0 +1,108 @@
+use crate::cpu::instruction::{ImmediateType, Instruction};
+
+use super::allocator::RegisterUse;
+
+/// An instruction whose operands are known while compiling the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Folded {
+    /// The value written by `LUI`, `ORI` or `ADDIU`
+    Value(u64),
+    /// The address accessed by a load or a store
+    Address(u64),
+}
+
+/// Fold the instructions computing constants, such as the `LUI` and `ORI`
+/// pairs materializing the addresses. The values follow the compiled code
+pub fn fold_block(instructions: &[Instruction]) -> Vec<Option<Folded>> {
+    // values of the guest registers, as far as they are known. r0 is always 0
+    let mut known = [None; 32];
+    known[0] = Some(0);
+    instructions
+        .iter()
+        .map(|instruction| {
+            let folded = fold(instruction, &known);
+            let written = RegisterUse::of(instruction).write;
+            if let Some(written) = written.filter(|&written| written != 0) {
+                known[written as usize] = match folded {
+                    Some(Folded::Value(value)) => Some(value),
+                    _ => None,
+                };
+            }
+            folded
+        })
+        .collect()
+}
+
+fn fold(instruction: &Instruction, known: &[Option<u64>; 32]) -> Option<Folded> {
+    let rs = |inst: ImmediateType| known[inst.rs as usize];
+    match *instruction {
+        Instruction::LUI(inst) => Some(Folded::Value(u64::from(inst.imm) << 16)),
+        Instruction::ORI(inst) => {
+            let value = rs(inst)? as u32 | u32::from(inst.imm);
+            Some(Folded::Value(u64::from(value)))
+        }
+        Instruction::ADDIU(inst) => {
+            let value = (rs(inst)? as u32).wrapping_add(inst.imm as i16 as u32);
+            Some(Folded::Value(u64::from(value)))
+        }
+        Instruction::LB(inst)
+        | Instruction::LBU(inst)
+        | Instruction::LH(inst)
+        | Instruction::LHU(inst)
+        | Instruction::LW(inst)
+        | Instruction::LWU(inst)
+        | Instruction::SW(inst) => {
+            let addr = (rs(inst)? as u32).wrapping_add(inst.imm as i16 as u32);
+            Some(Folded::Address(u64::from(addr)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_fold_the_reads_of_r0() {
+        let instructions = [
+            0x2408_0005, // addiu t0, zero, 5
+            0x3C00_0001, // lui zero, 1
+            0x3409_0002, // ori t1, zero, 2
+        ]
+        .map(|inst| Instruction::try_from(inst).unwrap());
+
+        assert_eq!(
+            fold_block(&instructions),
+            [
+                Some(Folded::Value(5)),
+                Some(Folded::Value(0x1_0000)),
+                Some(Folded::Value(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_fold_the_materialized_constants() {
+        let instructions = [
+            0x3C08_A400, // lui t0, 0xA400
+            0x3508_8040, // ori t0, t0, 0x8040
+            0x8D09_FFFC, // lw t1, -4(t0)
+            0x2529_0001, // addiu t1, t1, 1
+            0x2508_FFFF, // addiu t0, t0, -1
+        ]
+        .map(|inst| Instruction::try_from(inst).unwrap());
+
+        assert_eq!(
+            fold_block(&instructions),
+            [
+                Some(Folded::Value(0xA400_0000)),
+                Some(Folded::Value(0xA400_8040)),
+                Some(Folded::Address(0xA400_803C)),
+                // the loaded value is not known
+                None,
+                Some(Folded::Value(0xA400_803F)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::cpu::instruction::Instruction;
+
+use super::{
+    allocator::RegisterUse,
+    instructions::{branch_target, jump_target},
+};
+
+/// Whether the block starting at `start_pc` is an idle loop, which branches
+/// back to itself without changing anything, such as a jump to itself or a
+/// loop spinning on a memory word. Only an interrupt or a device writing to
+/// the memory can get the CPU out of it.
+/// The block ends with the branch and its delay slot, which runs on every
+/// iteration like the rest of the loop
+pub fn is_idle_loop(start_pc: u64, instructions: &[Instruction]) -> bool {
+    let [body @ .., branch, _] = instructions else {
+        return false;
+    };
+    let branch_pc = start_pc + 4 * body.len() as u64;
+    let target = match *branch {
+        Instruction::J(inst) => jump_target(branch_pc, inst.target),
+        Instruction::BEQ(inst)
+        | Instruction::BEQL(inst)
+        | Instruction::BNE(inst)
+        | Instruction::BNEL(inst)
+        | Instruction::BLEZ(inst)
+        | Instruction::BLEZL(inst)
+        | Instruction::BGTZ(inst)
+        | Instruction::BGTZL(inst) => branch_target(branch_pc, inst.imm),
+        _ => return false,
+    };
+    if target != start_pc {
+        return false;
+    }
+
+    // every iteration has to be the same: nothing is stored, and the
+    // registers written are not read before, so they don't carry a value
+    // from the previous iteration. This holds for the delay slot too
+    let mut written = [false; 32];
+    let mut read_before_written = [false; 32];
+    for instruction in instructions {
+        let pure = is_branch(instruction)
+            || matches!(
+                instruction,
+                Instruction::NOP
+                    | Instruction::LUI(_)
+                    | Instruction::ORI(_)
+                    | Instruction::ANDI(_)
+                    | Instruction::XORI(_)
+                    | Instruction::ADDIU(_)
+                    | Instruction::SpecialADDU(_)
+                    | Instruction::SpecialAND(_)
+                    | Instruction::SpecialOR(_)
+                    | Instruction::SpecialXOR(_)
+                    | Instruction::SpecialSLL(_)
+                    | Instruction::SpecialSRL(_)
+                    | Instruction::LB(_)
+                    | Instruction::LBU(_)
+                    | Instruction::LH(_)
+                    | Instruction::LHU(_)
+                    | Instruction::LW(_)
+                    | Instruction::LWU(_)
+                    | Instruction::J(_)
+            );
+        if !pure {
+            return false;
+        }
+
+        let uses = RegisterUse::of(instruction);
+        for read in uses.reads.into_iter().flatten() {
+            if !written[read as usize] {
+                read_before_written[read as usize] = true;
+            }
+        }
+        if let Some(write) = uses.write {
+            written[write as usize] = true;
+        }
+    }
+    written
+        .iter()
+        .zip(read_before_written)
+        .all(|(&written, read)| !(written && read))
+}
+
+/// Whether `instruction` is a conditional branch
+pub fn is_branch(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::BEQ(_)
+            | Instruction::BEQL(_)
+            | Instruction::BNE(_)
+            | Instruction::BNEL(_)
+            | Instruction::BLEZ(_)
+            | Instruction::BLEZL(_)
+            | Instruction::BGTZ(_)
+            | Instruction::BGTZL(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(code: &[u32]) -> Vec<Instruction> {
+        code.iter()
+            .map(|&inst| Instruction::try_from(inst).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_should_detect_the_idle_loops() {
+        let pc = 0xA400_0040;
+        // j 0xA4000040; nop
+        assert!(is_idle_loop(pc, &decode(&[0x0900_0010, 0])));
+        // beq zero, zero, -1; nop
+        assert!(is_idle_loop(pc, &decode(&[0x1000_FFFF, 0])));
+        // lw t0, 0(a0); bne t0, zero, -2; nop
+        assert!(is_idle_loop(pc, &decode(&[0x8C88_0000, 0x1500_FFFE, 0])));
+
+        // addiu t0, t0, -1; bne t0, zero, -2; nop
+        assert!(!is_idle_loop(pc, &decode(&[0x2508_FFFF, 0x1500_FFFE, 0])));
+        // sw t0, 0(a0); j 0xA4000040; nop
+        assert!(!is_idle_loop(pc, &decode(&[0xAC88_0000, 0x0900_0010, 0])));
+        // lw t0, 0(a0); bne t0, zero, -3; nop
+        assert!(!is_idle_loop(pc, &decode(&[0x8C88_0000, 0x1500_FFFD, 0])));
+        // beq zero, zero, 0; nop, which goes on past its delay slot
+        assert!(!is_idle_loop(pc, &decode(&[0x1000_0000, 0])));
+        // beq zero, zero, -1; sw t0, 0(a0)
+        assert!(!is_idle_loop(pc, &decode(&[0x1000_FFFF, 0xAC88_0000])));
+        // beq zero, zero, -1; addiu t0, t0, 1
+        assert!(!is_idle_loop(pc, &decode(&[0x1000_FFFF, 0x2508_0001])));
+    }
+}
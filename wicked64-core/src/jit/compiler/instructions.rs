@@ -1,20 +1,40 @@
+use byteorder::BigEndian;
 use iced_x86::{
-    code_asm::{self, AsmRegister64, CodeAssembler},
+    code_asm::{self, AsmRegister64},
     Code as X86Opcode,
 };
 
 use crate::{
-    cpu::instruction::{ImmediateType, JumpType, RegisterType},
-    jit::{bridge, Interruption},
+    cpu::{
+        instruction::{ImmediateType, JumpType, RegisterType},
+        Exception,
+    },
+    jit::{bridge, link},
+    mmu::MemoryUnit,
 };
 
-use super::{register::ARGS_REGS, AssembleResult, AssembleStatus, Compiler};
+use super::{
+    call::{self, CallArgument},
+    constants::Folded,
+    AssembleResult, AssembleStatus, Compiler,
+};
 
 type Result = AssembleResult<AssembleStatus>;
 
-enum CallArgument {
-    Register(AsmRegister64),
-    Value(u64),
+/// Host register holding the address of the RDRAM in the blocks reading it
+pub(super) const RDRAM_BASE: AsmRegister64 = code_asm::rbp;
+
+const KSEG0_START: u32 = 0x8000_0000;
+/// Size of the physical memory mapped by KSEG0 and KSEG1
+const PHYS_SEGMENT_SIZE: u32 = 0x2000_0000;
+
+/// Condition of a conditional branch
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Equal,
+    NotEqual,
+    LessEqualZero,
+    GreaterThanZero,
 }
 
 macro_rules! arg_list {
@@ -24,9 +44,6 @@ macro_rules! arg_list {
     (val, $arg:expr) => {
         self::CallArgument::Value($arg)
     };
-    ($($kind:tt : $arg:expr),*) => {
-        &[$(arg_list!($kind, $arg)),*]
-    }
 }
 
 macro_rules! cast_arg {
@@ -35,35 +52,13 @@ macro_rules! cast_arg {
     };
 }
 
-macro_rules! raw_call {
-    ($compiler:ident, $function:path[$($reg:ident: $value:expr),*]) => {{
-        $(
-            $compiler.emitter.mov(code_asm::$reg, $value)?;
-        )*
-        $compiler.emitter.mov(code_asm::rax, $function as extern "C" fn($(cast_arg!($value),)*) -> _ as *const u8 as u64)?;
-        $compiler.emitter.call(code_asm::rax)
-    }};
-}
-
 macro_rules! wrap_call {
-    ($compiler:ident, $function:path[$($kind:ident: $arg:expr),*]) => {{
-        $compiler.wrap_call(arg_list!($($kind : $arg),*), |emitter| {
-            let function_ptr = $function as extern "C" fn($(cast_arg!($arg),)*) -> _ as *const u8 as u64;
-            emitter.mov(code_asm::rax, function_ptr)?;
-
-            // align the stack before calling the function
-            emitter.push(code_asm::rbx)?;
-            emitter.mov(code_asm::bl, code_asm::spl)?;
-            emitter.and(code_asm::rsp, -16)?;
-
-            emitter.call(code_asm::rax)?;
-
-            // restore the stack
-            emitter.mov(code_asm::spl, code_asm::bl)?;
-            emitter.pop(code_asm::rbx)?;
-
-            Ok(())
-        })
+    ($compiler:ident, $function:path[state $(, $kind:ident: $arg:expr)*]) => {{
+        let function = $function as extern "sysv64" fn(_, $(cast_arg!($arg),)*) -> _;
+        $compiler.wrap_call(
+            function as *const u8 as u64,
+            &[self::CallArgument::State, $(arg_list!($kind, $arg)),*],
+        )
     }};
 }
 
@@ -81,30 +76,163 @@ impl<'jt> Compiler<'jt> {
         Ok(AssembleStatus::Continue)
     }
 
-    /// helper for `lX` and `lXu` instructions
+    /// Set r14 to the physical address of `rs + offset`, accessed by a load
+    /// or a store
+    fn emit_address(&mut self, rs: u8, offset: u16) -> AssembleResult<()> {
+        if let Some(Folded::Address(addr)) = self.folded() {
+            if let Some(phys_addr) = physical_addr(addr) {
+                self.emitter.mov(code_asm::r14, phys_addr)?;
+                return Ok(());
+            }
+            self.emitter.mov(code_asm::r14, addr)?;
+            return self.emit_tlb_translation();
+        }
+
+        let rs = self.get_cpu_register(rs)?;
+        self.emitter
+            .mov(code_asm::r14, offset as i16 as u32 as u64)?;
+        // the addresses are 32-bit
+        self.emitter.add_instruction(iced_x86::Instruction::with2(
+            X86Opcode::Add_r32_rm32,
+            iced_x86::Register::R14D,
+            iced_x86::Register::from(rs).full_register32(),
+        )?)?;
+
+        let mut mapped = self.emitter.create_label();
+        let mut translated = self.emitter.create_label();
+        // KSEG0 and KSEG1 map the low 512MB of the physical memory
+        self.emitter.mov(code_asm::r15d, code_asm::r14d)?;
+        self.emitter.sub(code_asm::r15d, KSEG0_START as i32)?;
+        self.emitter
+            .cmp(code_asm::r15d, 2 * PHYS_SEGMENT_SIZE as i32)?;
+        self.emitter.jae(mapped)?;
+        self.emitter
+            .and(code_asm::r15d, PHYS_SEGMENT_SIZE as i32 - 1)?;
+        self.emitter.mov(code_asm::r14d, code_asm::r15d)?;
+        self.emitter.jmp(translated)?;
+
+        self.emitter.set_label(&mut mapped)?;
+        self.emit_side_call(Self::emit_tlb_translation)?;
+        self.emitter.set_label(&mut translated)?;
+        Ok(())
+    }
+
+    /// Translate the virtual address in r14 through the TLB
+    fn emit_tlb_translation(&mut self) -> AssembleResult<()> {
+        wrap_call!(self, bridge::translate_virtual[state, reg: code_asm::r14])?;
+        self.emitter.mov(code_asm::r14, code_asm::rax)?;
+        Ok(())
+    }
+
+    /// Emit the code of `f`, calling a function, on a path of its own. The
+    /// call syncs the guest registers, which are mapped back so that the
+    /// paths join with the same mapping
+    fn emit_side_call(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> AssembleResult<()>,
+    ) -> AssembleResult<()> {
+        let regs = self.regs.clone();
+        f(self)?;
+        self.reload_registers(regs)
+    }
+
+    /// Set `rt` to the value computed while compiling
+    pub(super) fn emit_constant(&mut self, rt: u8, value: u64) -> Result {
+        let rt = self.get_cpu_register(rt)?;
+        self.emitter.mov(rt, value)?;
+        Ok(AssembleStatus::Continue)
+    }
+
+    /// helper for `lX` and `lXu` instructions, leaving the `size` bytes read
+    /// in r14
     fn emit_lx(
         &mut self,
         inst: ImmediateType,
-        f: impl FnOnce(&mut Self, u64) -> AssembleResult<()>,
+        size: usize,
+        f: impl FnOnce(&mut Self) -> AssembleResult<()>,
     ) -> AssembleResult<AsmRegister64> {
         let ImmediateType { rt, rs, imm, .. } = inst;
 
-        let rs = self.get_cpu_register(rs)?;
-
-        self.emitter.mov(code_asm::r14, imm as i16 as u32 as u64)?;
-        self.emitter.add(code_asm::r14, rs)?;
-        f(self, self.state.state_ptr() as u64)?;
-        self.emitter.mov(code_asm::r14, code_asm::rax)?;
+        self.emit_address(rs, imm)?;
+        if self.fast_memory() {
+            self.emit_fast_load(size, f)?;
+        } else {
+            // the bridge checks the watchpoints
+            f(self)?;
+            self.emitter.mov(code_asm::r14, code_asm::rax)?;
+        }
 
         self.get_cpu_register(rt)
     }
+
+    /// Read the physical address in r14 straight from the RDRAM when it's
+    /// mapped there, calling `f` for the other addresses
+    fn emit_fast_load(
+        &mut self,
+        size: usize,
+        f: impl FnOnce(&mut Self) -> AssembleResult<()>,
+    ) -> AssembleResult<()> {
+        let (_, rdram_len) = self.state.rdram();
+        let call = |compiler: &mut Self| {
+            f(compiler)?;
+            compiler.emitter.mov(code_asm::r14, code_asm::rax)?;
+            Ok(())
+        };
+
+        if let Some(Folded::Address(addr)) = self.folded() {
+            return match physical_addr(addr) {
+                Some(phys_addr) if phys_addr as usize + size <= rdram_len => {
+                    self.emit_rdram_load(size)
+                }
+                _ => call(self),
+            };
+        }
+
+        let mut slow = self.emitter.create_label();
+        let mut done = self.emitter.create_label();
+        self.emitter
+            .cmp(code_asm::r14d, (rdram_len - size) as i32)?;
+        self.emitter.ja(slow)?;
+        self.emit_rdram_load(size)?;
+        self.emitter.jmp(done)?;
+
+        self.emitter.set_label(&mut slow)?;
+        self.emit_side_call(call)?;
+        self.emitter.set_label(&mut done)?;
+
+        Ok(())
+    }
+
+    /// Read `size` bytes at the RDRAM address in r14 into r14. The RDRAM is
+    /// big-endian, so the bytes are swapped
+    fn emit_rdram_load(&mut self, size: usize) -> AssembleResult<()> {
+        let addr = RDRAM_BASE + code_asm::r14;
+        match size {
+            1 => self
+                .emitter
+                .movzx(code_asm::r14d, code_asm::byte_ptr(addr))?,
+            2 => {
+                self.emitter
+                    .movzx(code_asm::r14d, code_asm::word_ptr(addr))?;
+                self.emitter.rol(code_asm::r14w, 8)?;
+            }
+            _ => {
+                self.emitter
+                    .mov(code_asm::r14d, code_asm::dword_ptr(addr))?;
+                self.emitter.bswap(code_asm::r14d)?;
+            }
+        }
+        Ok(())
+    }
     /// ```txt
     /// rt = mmu.rb(rs + imm) // sign-extended
     /// ```
     pub(super) fn emit_lb(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr| {
-            wrap_call!(compiler, bridge::mmu_read_byte[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            1,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_byte[state, reg: code_asm::r14]),
+        )?;
         self.emitter.movsx(rt, code_asm::r14b)?;
         Ok(AssembleStatus::Continue)
     }
@@ -112,9 +240,11 @@ impl<'jt> Compiler<'jt> {
     /// rt = mmu.rb(rs + imm)
     /// ```
     pub(super) fn emit_lbu(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr| {
-            wrap_call!(compiler, bridge::mmu_read_byte[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            1,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_byte[state, reg: code_asm::r14]),
+        )?;
         self.emitter.movzx(rt, code_asm::r14b)?;
 
         Ok(AssembleStatus::Continue)
@@ -123,9 +253,11 @@ impl<'jt> Compiler<'jt> {
     /// rt = mmu.rw(rs + imm) // sign-extended
     /// ```
     pub(super) fn emit_lh(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr| {
-            wrap_call!(compiler, bridge::mmu_read_word[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            2,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_word[state, reg: code_asm::r14]),
+        )?;
         self.emitter.movsx(rt, code_asm::r14w)?;
         Ok(AssembleStatus::Continue)
     }
@@ -133,9 +265,11 @@ impl<'jt> Compiler<'jt> {
     /// rt = mmu.rw(rs + imm)
     /// ```
     pub(super) fn emit_lhu(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr| {
-            wrap_call!(compiler, bridge::mmu_read_word[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            2,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_word[state, reg: code_asm::r14]),
+        )?;
         self.emitter.movzx(rt, code_asm::r14w)?;
         Ok(AssembleStatus::Continue)
     }
@@ -143,9 +277,11 @@ impl<'jt> Compiler<'jt> {
     /// rt = mmu.rd(rs + imm) // sign-extended
     /// ```
     pub(super) fn emit_lw(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr|{
-            wrap_call!(compiler, bridge::mmu_read_dword[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            4,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_dword[state, reg: code_asm::r14]),
+        )?;
         self.emitter.movsxd(rt, code_asm::r14d)?;
         Ok(AssembleStatus::Continue)
     }
@@ -153,9 +289,11 @@ impl<'jt> Compiler<'jt> {
     /// rt = mmu.rd(rs + imm)
     /// ```
     pub(super) fn emit_lwu(&mut self, inst: ImmediateType) -> Result {
-        let rt = self.emit_lx(inst, |compiler, state_addr|{
-            wrap_call!(compiler, bridge::mmu_read_dword[val: state_addr, reg: code_asm::r14])
-        })?;
+        let rt = self.emit_lx(
+            inst,
+            4,
+            |compiler| wrap_call!(compiler, bridge::mmu_read_dword[state, reg: code_asm::r14]),
+        )?;
         self.emitter.mov(rt, code_asm::r14)?;
         Ok(AssembleStatus::Continue)
     }
@@ -221,7 +359,7 @@ impl<'jt> Compiler<'jt> {
         emit_alu(self, inst, X86Opcode::Add_r32_rm32, true)
     }
     /// ```txt
-    /// rt = rs + rt
+    /// rd = rs + rt
     /// ```
     pub(super) fn emit_addu(&mut self, inst: RegisterType) -> Result {
         emit_alu(self, inst, X86Opcode::Add_r32_rm32, false)
@@ -245,34 +383,78 @@ impl<'jt> Compiler<'jt> {
         emit_alu(self, inst, X86Opcode::Sub_r32_rm32, true)
     }
     /// ```txt
-    /// rt = rs - rt
+    /// rd = rs - rt
     /// ```
     pub(super) fn emit_subu(&mut self, inst: RegisterType) -> Result {
         emit_alu(self, inst, X86Opcode::Sub_r32_rm32, false)
     }
-    // ```txt
-    /// rd = rs * rt // signed
+    /// ```txt
+    /// (hi, lo) = rs * rt // signed
     /// ```
     pub(super) fn emit_mult(&mut self, inst: RegisterType) -> Result {
-        emit_alu(self, inst, X86Opcode::Imul_r32_rm32, true)
+        self.emit_multiply(inst, true)
     }
     /// ```txt
-    /// rt = rs * rt
+    /// (hi, lo) = rs * rt
     /// ```
     pub(super) fn emit_multu(&mut self, inst: RegisterType) -> Result {
-        emit_alu(self, inst, X86Opcode::Mul_rm32, false)
+        self.emit_multiply(inst, false)
     }
-    // ```txt
-    /// rd = rs / rt // signed
+    /// ```txt
+    /// (hi, lo) = (rs % rt, rs / rt) // signed
     /// ```
     pub(super) fn emit_div(&mut self, inst: RegisterType) -> Result {
-        emit_alu(self, inst, X86Opcode::Idiv_rm32, true)
+        let (rs, rt) = self.get_operands(inst)?;
+        wrap_call!(self, bridge::div[state, reg: rs, reg: rt])?;
+        Ok(AssembleStatus::Continue)
     }
     /// ```txt
-    /// rt = rs / rt
+    /// (hi, lo) = (rs % rt, rs / rt)
     /// ```
     pub(super) fn emit_divu(&mut self, inst: RegisterType) -> Result {
-        emit_alu(self, inst, X86Opcode::Div_rm32, false)
+        let (rs, rt) = self.get_operands(inst)?;
+        wrap_call!(self, bridge::divu[state, reg: rs, reg: rt])?;
+        Ok(AssembleStatus::Continue)
+    }
+
+    fn get_operands(
+        &mut self,
+        inst: RegisterType,
+    ) -> AssembleResult<(AsmRegister64, AsmRegister64)> {
+        Ok((
+            self.get_cpu_register(inst.rs)?,
+            self.get_cpu_register(inst.rt)?,
+        ))
+    }
+
+    /// Set HI and LO to the 64-bit product of the 32-bit `rs` and `rt`, each
+    /// half being sign-extended
+    fn emit_multiply(&mut self, inst: RegisterType, signed: bool) -> Result {
+        let (rs, rt) = self.get_operands(inst)?;
+
+        self.emitter.mov(code_asm::r14, rs)?;
+        self.emitter.mov(code_asm::r15, rt)?;
+        if signed {
+            self.emitter.movsxd(code_asm::r14, code_asm::r14d)?;
+            self.emitter.movsxd(code_asm::r15, code_asm::r15d)?;
+        } else {
+            self.emitter.mov(code_asm::r14d, code_asm::r14d)?;
+            self.emitter.mov(code_asm::r15d, code_asm::r15d)?;
+        }
+        // the product of two 32-bit integers fits in the low half of `imul`
+        self.emitter.imul_2(code_asm::r14, code_asm::r15)?;
+
+        let lo = self.state.offset_of(|state| &state.cpu.multi_lo);
+        let hi = self.state.offset_of(|state| &state.cpu.multi_hi);
+        self.emitter.movsxd(code_asm::r15, code_asm::r14d)?;
+        self.emitter
+            .mov(code_asm::qword_ptr(code_asm::rsi + lo), code_asm::r15)?;
+        self.emitter.shr(code_asm::r14, 32)?;
+        self.emitter.movsxd(code_asm::r14, code_asm::r14d)?;
+        self.emitter
+            .mov(code_asm::qword_ptr(code_asm::rsi + hi), code_asm::r14)?;
+
+        Ok(AssembleStatus::Continue)
     }
     /// ```txt
     /// rd = rt << sa
@@ -299,9 +481,8 @@ impl<'jt> Compiler<'jt> {
     /// rd = rt << rs
     /// ```
     pub(super) fn emit_sllv(&mut self, inst: RegisterType) -> Result {
-        // TODO: Check operand correctness
         // logical shift
-        emit_alu(self, inst, X86Opcode::Shl_rm32_1, false)
+        self.emit_shift_variable(inst, X86Opcode::Shl_rm32_CL)
     }
     /// ```txt
     /// rd = rt >> sa
@@ -328,9 +509,8 @@ impl<'jt> Compiler<'jt> {
     /// rd = rt >> rs
     /// ```
     pub(super) fn emit_srav(&mut self, inst: RegisterType) -> Result {
-        // TODO: Check operand correctness
         // arithmetic shift
-        emit_alu(self, inst, X86Opcode::Sar_rm32_1, false)
+        self.emit_shift_variable(inst, X86Opcode::Sar_rm32_CL)
     }
     /// ```txt
     /// rd = rt >> sa
@@ -357,9 +537,31 @@ impl<'jt> Compiler<'jt> {
     /// rd = rt >> rs
     /// ```
     pub(super) fn emit_srlv(&mut self, inst: RegisterType) -> Result {
-        // TODO: Check operand correctness
         // logical shift
-        emit_alu(self, inst, X86Opcode::Shr_rm32_1, false)
+        self.emit_shift_variable(inst, X86Opcode::Shr_rm32_CL)
+    }
+
+    /// rd = `shift_opcode`(rt, rs), shifting by the low 5 bits of rs. The
+    /// count goes through cl, whose guest register is put back afterwards
+    fn emit_shift_variable(&mut self, inst: RegisterType, shift_opcode: X86Opcode) -> Result {
+        let RegisterType { rd, rs, rt, .. } = inst;
+
+        let rd = self.get_cpu_register(rd)?;
+        let rs = self.get_cpu_register(rs)?;
+        let rt = self.get_cpu_register(rt)?;
+
+        self.emitter.mov(code_asm::r14, rt)?;
+        self.emitter.mov(code_asm::r15, code_asm::rcx)?;
+        self.emitter.mov(code_asm::rcx, rs)?;
+        self.emitter.add_instruction(iced_x86::Instruction::with2(
+            shift_opcode,
+            iced_x86::Register::R14D,
+            iced_x86::Register::CL,
+        )?)?;
+        self.emitter.mov(code_asm::rcx, code_asm::r15)?;
+        self.emitter.mov(rd, code_asm::r14)?;
+
+        Ok(AssembleStatus::Continue)
     }
 
     /// ```txt
@@ -373,44 +575,41 @@ impl<'jt> Compiler<'jt> {
             ..
         } = inst;
 
-        let rs = self.get_cpu_register(rs)?;
         let rt = self.get_cpu_register(rt)?;
+        self.emit_address(rs, offset)?;
+
+        wrap_call!(self, bridge::mmu_store_dword[state, reg: code_asm::r14, reg: rt])?;
+
+        // the store hit compiled code, so the blocks are invalidated before
+        // going on. This holds for the code of any block, not only this one:
+        // the linked jumps don't go through the host, so the rest of this
+        // block could otherwise jump straight into a stale block
+        let mut stored = self.emitter.create_label();
+        self.emitter.test(code_asm::al, code_asm::al)?;
+        self.emitter.jz(stored)?;
+        if self.in_delay_slot {
+            // the jump is still to be taken, through the host once the link
+            // budget is spent
+            let budget = self.state.offset_of(|state| &state.link.budget);
+            self.emitter
+                .mov(code_asm::qword_ptr(code_asm::rsi + budget), 0)?;
+        } else {
+            self.emit_side_exit(self.pc + 4)?;
+        }
+        self.emitter.set_label(&mut stored)?;
 
-        let state_addr = self.state.state_ptr();
-
-        self.emitter
-            .mov(code_asm::r14, offset as i16 as u32 as u64)?;
-        self.emitter.add(code_asm::r14, rs)?;
-
-        wrap_call!(self, bridge::mmu_store_dword[val: state_addr as u64, reg: code_asm::r14, reg: rt])?;
-
-        // `mmu_store` might invalidate the current memory region
-        Ok(AssembleStatus::InvalidateCache)
+        Ok(AssembleStatus::Continue)
     }
     /// ```txt
     /// r31 = pc + 8
     /// pc = (pc & 0xf000_0000) | (target << 2)
     /// ```
     pub(super) fn emit_jal(&mut self, inst: JumpType) -> Result {
-        let target = inst.target;
-        let jump_table_addr = self.jump_table as *mut _ as u64;
-
         let r31 = self.get_cpu_register(31)?;
         self.emitter.mov(r31, self.pc + 8)?;
-
-        self.emitter.mov(code_asm::r15, self.pc & 0xf000_0000)?;
-        self.emitter.or(code_asm::r15d, (target as u32) << 2)?;
-        wrap_call!(
-            self,
-            bridge::get_host_jump_addr[
-                val:  self.state.state_ptr() as u64,
-                val: jump_table_addr,
-                reg: code_asm::r15
-            ]
-        )?;
-        self.emit_interruption(Interruption::PrepareJump(0), Some(code_asm::r15))?;
-        // `resume` sets the jump address to r15
-        self.emitter.jmp(code_asm::r15)?;
+        if self.emit_delay_slot()? {
+            self.emit_static_jump(jump_target(self.pc, inst.target))?;
+        }
 
         Ok(AssembleStatus::Branch)
     }
@@ -418,22 +617,9 @@ impl<'jt> Compiler<'jt> {
     /// pc = (pc & 0xf000_0000) | (target << 2)
     /// ```
     pub(super) fn emit_j(&mut self, inst: JumpType) -> Result {
-        let target = inst.target;
-        let jump_table_addr = self.jump_table as *mut _ as u64;
-
-        self.emitter.mov(code_asm::r15, self.pc & 0xf000_0000)?;
-        self.emitter.or(code_asm::r15d, (target as u32) << 2)?;
-        wrap_call!(
-            self,
-            bridge::get_host_jump_addr[
-                val:  self.state.state_ptr() as u64,
-                val: jump_table_addr,
-                reg: code_asm::r15
-            ]
-        )?;
-        self.emit_interruption(Interruption::PrepareJump(0), Some(code_asm::r15))?;
-        // `resume` sets the jump address to r15
-        self.emitter.jmp(code_asm::r15)?;
+        if self.emit_delay_slot()? {
+            self.emit_static_jump(jump_target(self.pc, inst.target))?;
+        }
 
         Ok(AssembleStatus::Branch)
     }
@@ -441,30 +627,78 @@ impl<'jt> Compiler<'jt> {
     /// pc = rs
     /// ```
     pub(super) fn emit_jr(&mut self, inst: RegisterType) -> Result {
-        let RegisterType { rs, .. } = inst;
+        let rs = self.get_cpu_register(inst.rs)?;
+        self.emit_keep_target(rs)?;
+        self.emit_pc_jump()?;
 
-        let jump_table_addr = self.jump_table as *mut _ as u64;
+        Ok(AssembleStatus::Branch)
+    }
+    /// ```txt
+    /// rd = pc + 8
+    /// pc = rs
+    /// ```
+    pub(super) fn emit_jalr(&mut self, inst: RegisterType) -> Result {
+        let RegisterType { rd, rs, .. } = inst;
 
+        // read rs first, it may be the same register as rd
         let rs = self.get_cpu_register(rs)?;
-        self.emitter.mov(code_asm::r15, rs)?;
-        wrap_call!(
-            self,
-            bridge::get_host_jump_addr[
-                val:  self.state.state_ptr() as u64,
-                val: jump_table_addr,
-                reg: code_asm::r15
-            ]
-        )?;
-        self.emit_interruption(Interruption::PrepareJump(0), Some(code_asm::r15))?;
-        // `resume` sets the jump address to r15
-        self.emitter.jmp(code_asm::r15)?;
+        self.emit_keep_target(rs)?;
+        let rd = self.get_cpu_register(rd)?;
+        self.emitter.mov(rd, self.pc + 8)?;
+        self.emit_pc_jump()?;
 
         Ok(AssembleStatus::Branch)
     }
+
+    /// Keep the target of a jump through `rs` in the PC, where the delay slot
+    /// can't change it, unlike the guest registers and the scratch ones
+    fn emit_keep_target(&mut self, rs: AsmRegister64) -> AssembleResult<()> {
+        let cpu_pc = self.state.offset_of(|state| &state.cpu.pc);
+        self.emitter
+            .mov(code_asm::qword_ptr(code_asm::rsi + cpu_pc), rs)?;
+        Ok(())
+    }
+
+    /// Run the delay slot, then jump to the target kept in the PC
+    fn emit_pc_jump(&mut self) -> AssembleResult<()> {
+        if !self.emit_delay_slot()? {
+            return Ok(());
+        }
+        let cpu_pc = self.state.offset_of(|state| &state.cpu.pc);
+        self.emitter
+            .mov(code_asm::r15, code_asm::qword_ptr(code_asm::rsi + cpu_pc))?;
+        self.emit_count()?;
+        self.emit_host_jump()
+    }
+    /// ```txt
+    /// if rs == rt { pc = pc + 4 + (offset_u32 << 2) }
+    /// ```
+    pub(super) fn emit_beq(&mut self, inst: ImmediateType, likely: bool) -> Result {
+        self.emit_branch(inst, Condition::Equal, likely)
+    }
     /// ```txt
-    /// if rs != rt { pc = pc + (offset_u32 << 2) }
+    /// if rs != rt { pc = pc + 4 + (offset_u32 << 2) }
     /// ```
-    pub(super) fn emit_bne(&mut self, inst: ImmediateType) -> Result {
+    pub(super) fn emit_bne(&mut self, inst: ImmediateType, likely: bool) -> Result {
+        self.emit_branch(inst, Condition::NotEqual, likely)
+    }
+    /// ```txt
+    /// if rs <= 0 { pc = pc + 4 + (offset_u32 << 2) } // signed
+    /// ```
+    pub(super) fn emit_blez(&mut self, inst: ImmediateType, likely: bool) -> Result {
+        self.emit_branch(inst, Condition::LessEqualZero, likely)
+    }
+    /// ```txt
+    /// if rs > 0 { pc = pc + 4 + (offset_u32 << 2) } // signed
+    /// ```
+    pub(super) fn emit_bgtz(&mut self, inst: ImmediateType, likely: bool) -> Result {
+        self.emit_branch(inst, Condition::GreaterThanZero, likely)
+    }
+
+    /// Jump to `pc + 4 + (offset_u32 << 2)` if `condition` holds, or past the
+    /// delay slot otherwise. The condition is evaluated before the delay slot
+    /// runs, and the "likely" variants only run it when the branch is taken
+    fn emit_branch(&mut self, inst: ImmediateType, condition: Condition, likely: bool) -> Result {
         let ImmediateType {
             rs,
             rt,
@@ -472,204 +706,166 @@ impl<'jt> Compiler<'jt> {
             ..
         } = inst;
 
-        let jump_table_addr = self.jump_table as *mut _ as u64;
-
         let rs = self.get_cpu_register(rs)?;
-        let rt = self.get_cpu_register(rt)?;
+        let rt = match condition {
+            Condition::Equal | Condition::NotEqual => Some(self.get_cpu_register(rt)?),
+            Condition::LessEqualZero | Condition::GreaterThanZero => None,
+        };
 
         self.sync_all_registers()?;
-        self.restore_registers()?;
-
-        self.emitter.cmp(rs, rt)?;
+        match rt {
+            Some(rt) => self.emitter.cmp(rs, rt)?,
+            None => self.emitter.cmp(rs, 0)?,
+        }
 
         let mut skip = self.emitter.create_label();
-        self.emitter.je(skip)?;
-
-        {
-            // Not equal, jump to pc + (offset_u32 << 2)
-            self.emitter.mov(
-                code_asm::r15,
-                self.pc + ((offset as i16 as u32 as u64) << 2),
-            )?;
-            wrap_call!(
-                self,
-                bridge::get_host_jump_addr[
-                    val:  self.state.state_ptr() as u64,
-                    val: jump_table_addr,
-                    reg: code_asm::r15
-                ]
-            )?;
-            self.emit_interruption(Interruption::PrepareJump(0), Some(code_asm::r15))?;
-            self.emitter.jmp(code_asm::r15)?;
+        match condition {
+            Condition::Equal => self.emitter.jne(skip)?,
+            Condition::NotEqual => self.emitter.je(skip)?,
+            Condition::LessEqualZero => self.emitter.jg(skip)?,
+            Condition::GreaterThanZero => self.emitter.jle(skip)?,
+        }
+        let (index, regs) = (self.index, self.regs.clone());
+        if self.emit_delay_slot()? {
+            self.emit_static_jump(branch_target(self.pc, offset))?;
         }
 
-        // Jump to the next instruction
+        // each path compiles the delay slot from the same registers
         self.emitter.set_label(&mut skip)?;
-
-        self.emitter.mov(code_asm::r15, self.pc + 4)?;
-        wrap_call!(
-            self,
-            bridge::get_host_jump_addr[
-                val:  self.state.state_ptr() as u64,
-                val: jump_table_addr,
-                reg: code_asm::r15
-            ]
-        )?;
-        self.emit_interruption(Interruption::PrepareJump(0), Some(code_asm::r15))?;
-        self.emitter.jmp(code_asm::r15)?;
+        self.index = index;
+        self.regs = regs;
+        if likely || self.emit_delay_slot()? {
+            self.emit_static_jump(self.pc + 8)?;
+        }
 
         Ok(AssembleStatus::Branch)
     }
 
-    fn emit_interruption(
-        &mut self,
-        interruption: Interruption,
-        data_reg: Option<AsmRegister64>,
-    ) -> AssembleResult<()> {
-        tracing::debug!("Generating interruption: {interruption:?}");
-
-        let (state_interruption, state_resume) = {
-            let state = self.state.borrow();
-            (
-                &state.interruption as *const _ as u64,
-                &state.resume_addr as *const _ as u64,
-            )
-        };
-
-        self.sync_all_registers()?;
-        self.restore_registers()?;
-
-        self.emitter.push(code_asm::r14)?;
-        self.emitter.push(code_asm::r15)?;
-
-        // read only the first byte of `interruption`
-        let n = {
-            let ptr: *const Interruption = &interruption;
-            let bytes_ptr = ptr.cast::<u8>();
-            unsafe { std::ptr::read(bytes_ptr) }
+    /// Raise a reserved instruction exception for the instruction being
+    /// compiled, which the JIT doesn't implement, then return to the host
+    pub(super) fn emit_reserved_instruction(&mut self) -> AssembleResult<()> {
+        let opcode = {
+            let state = self.state.lock();
+            let phys_pc = state.cpu.translate_virtual(self.pc);
+            state.mmu.read::<u32, BigEndian>(phys_pc as usize)
         };
 
-        // `state.interruption = Interruption::KIND(*data_reg)`
-        self.emitter.mov(code_asm::r14, state_interruption)?;
-        self.emitter
-            .mov(code_asm::byte_ptr(code_asm::r14), n as u32)?;
-        if let Some(data_reg) = data_reg {
-            self.emitter
-                .mov(code_asm::qword_ptr(code_asm::r14 + 8), data_reg)?;
-        }
-
-        // `state.resume_addr = (instruction after jmp r13)`
-        // mov [r14], rax ; 0x00
-        // pop 15         ; 0x03
-        // pop 14         ; 0x05
-        // jmp r13        ; 0x07
-        // ...            ; 0x0a
-
-        self.emitter.mov(code_asm::r14, state_resume)?;
+        self.emit_count()?;
+        wrap_call!(
+            self,
+            bridge::reserved_instruction[
+                state,
+                val: self.pc,
+                val: u64::from(opcode),
+                val: u64::from(self.in_delay_slot)
+            ]
+        )?;
+        self.emit_return(None)
+    }
 
-        raw_call!(self, bridge::get_rip_value[edi: 0x0au32])?;
+    /// Raise `exception` for the instruction at `pc`, then return to the host.
+    /// The instruction is counted, like the ones before it
+    pub(super) fn emit_exception(&mut self, pc: u64, exception: Exception) -> AssembleResult<()> {
+        self.emit_count()?;
+        wrap_call!(
+            self,
+            bridge::raise_exception[
+                state,
+                val: pc,
+                val: exception as u64,
+                val: u64::from(self.in_delay_slot)
+            ]
+        )?;
+        self.emit_return(None)
+    }
 
+    /// Return to the host with the PC set to `pc`, on a path of its own. The
+    /// guest registers must be synced
+    fn emit_side_exit(&mut self, pc: u64) -> AssembleResult<()> {
+        let cpu_pc = self.state.offset_of(|state| &state.cpu.pc);
+        self.emitter.mov(code_asm::r15, pc)?;
         self.emitter
-            .mov(code_asm::qword_ptr(code_asm::r14), code_asm::rax)?;
-        self.emitter.pop(code_asm::r15)?;
-        self.emitter.pop(code_asm::r14)?;
-
-        self.emitter.jmp(code_asm::r13)?;
+            .mov(code_asm::qword_ptr(code_asm::rsi + cpu_pc), code_asm::r15)?;
+        self.emit_count()?;
+        self.emit_return(None)
+    }
 
-        Ok(())
+    /// Jump to the constant address `target`, straight to its block once
+    /// it's linked, or through the jump table otherwise
+    fn emit_static_jump(&mut self, target: u64) -> AssembleResult<()> {
+        self.emit_count()?;
+        self.emit_link(target)?;
+        self.emitter.mov(code_asm::r15, target)?;
+        self.emit_host_jump()
     }
 
-    /// A wrapper that saves and syncs all registers before calling a `call` instruction.
-    fn wrap_call<F>(&mut self, args: &[CallArgument], call_fn: F) -> AssembleResult<()>
-    where
-        F: FnOnce(&mut CodeAssembler) -> AssembleResult<()>,
-    {
-        assert!(
-            args.len() <= ARGS_REGS.len(),
-            "Argument list exceeds the limit of {} arguments.",
-            ARGS_REGS.len() - 1
-        );
-        self.emitter.push(code_asm::rsi)?;
+    /// Jump to the guest address in r15, straight to its block if it's in the
+    /// jump table, or through the host otherwise
+    fn emit_host_jump(&mut self) -> AssembleResult<()> {
+        let jump_table_addr = std::ptr::from_ref(self.jump_table) as u64;
 
-        self.sync_all_registers()?;
+        wrap_call!(
+            self,
+            bridge::get_host_jump_addr[
+                state,
+                val: jump_table_addr,
+                reg: code_asm::r15
+            ]
+        )?;
+        // the link entry of the target, like a linked jump
+        let mut unresolved = self.emitter.create_label();
+        self.emitter.test(code_asm::rax, code_asm::rax)?;
+        self.emitter.jz(unresolved)?;
+        self.emitter.jmp(code_asm::rax)?;
 
-        let reg_args = args
-            .iter()
-            .filter_map(|arg| {
-                if let CallArgument::Register(reg_arg) = arg {
-                    Some(reg_arg)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<&AsmRegister64>>();
-
-        let ptr_size = std::mem::size_of::<usize>();
-        let stack_size = reg_args.len() * ptr_size;
-        let mut stack_index = stack_size;
-
-        let mut save_registers: [Option<usize>; 16] = [None; 16];
-
-        macro_rules! save_reg {
-            ($reg:expr) => {{
-                let reg: AsmRegister64 = $reg;
-                let reg_num = iced_x86::Register::from(reg).number();
-                if save_registers[reg_num].is_none()
-                    && reg_args.iter().copied().find(|&&r| r == reg).is_some()
-                {
-                    stack_index -= ptr_size;
-                    save_registers[reg_num] = Some(stack_index);
-                    self.emitter
-                        .mov(code_asm::ptr(code_asm::rsp + stack_index), reg)?;
-                }
-            }};
-        }
+        self.emitter.set_label(&mut unresolved)?;
+        self.emit_return(Some(code_asm::r15))
+    }
 
-        // set the stack size
-        if stack_size > 0 {
-            self.emitter.add_instruction(iced_x86::Instruction::with2(
-                X86Opcode::Sub_rm64_imm32,
-                iced_x86::Register::RSP,
-                stack_size as u32,
-            )?)?;
+    /// Jump straight to the block at `target` once it's linked, or fall
+    /// through to the code resolving the jump through the host
+    fn emit_link(&mut self, target: u64) -> AssembleResult<()> {
+        let Some(target) = link::physical_target(target) else {
+            return Ok(());
         };
-        for (&dst, src) in ARGS_REGS.iter().zip(args.iter()) {
-            match *src {
-                CallArgument::Register(src) => {
-                    save_reg!(dst);
-                    if let Some(index) = save_registers[iced_x86::Register::from(src).number()] {
-                        self.emitter
-                            .mov(dst, code_asm::ptr(code_asm::rsp + index))?;
-                    } else {
-                        self.emitter.mov(dst, src)?;
-                    }
-                }
-                CallArgument::Value(src) => {
-                    if !reg_args.is_empty() {
-                        save_reg!(dst);
-                    }
-                    self.emitter.mov(dst, src)?;
-                }
-            }
-        }
-        // restore the stack
-        if stack_size > 0 {
-            self.emitter.add_instruction(iced_x86::Instruction::with2(
-                X86Opcode::Add_rm64_imm32,
-                iced_x86::Register::RSP,
-                stack_size as u32,
-            )?)?;
-        }
 
-        call_fn(&mut self.emitter)?;
+        self.sync_all_registers()?;
 
-        self.emitter.pop(code_asm::rsi)?;
+        // patched with the link entry of the target, 0 while unlinked
+        let mut site = self.emitter.create_label();
+        let mut unlinked = self.emitter.create_label();
+        self.emitter.add_instruction(iced_x86::Instruction::with2(
+            X86Opcode::Mov_r64_imm64,
+            iced_x86::Register::R15,
+            0u64,
+        )?)?;
+        self.emitter.set_label(&mut site)?;
+        self.emitter.test(code_asm::r15, code_asm::r15)?;
+        self.emitter.jz(unlinked)?;
+        self.emitter.jmp(code_asm::r15)?;
+        self.emitter.set_label(&mut unlinked)?;
 
+        self.link_sites.push((target, site));
         Ok(())
     }
+
+    /// Sync the guest registers, then call `function` with `args`
+    fn wrap_call(&mut self, function: u64, args: &[CallArgument]) -> AssembleResult<()> {
+        self.sync_all_registers()?;
+        call::emit_call(&mut self.emitter, function, args)
+    }
+}
+
+/// Physical address of the virtual address `addr`, if it's in KSEG0 or
+/// KSEG1, which are not mapped through the TLB
+fn physical_addr(addr: u64) -> Option<u64> {
+    let segment_offset = (addr as u32).wrapping_sub(KSEG0_START);
+    (segment_offset < 2 * PHYS_SEGMENT_SIZE)
+        .then_some(u64::from(segment_offset & (PHYS_SEGMENT_SIZE - 1)))
 }
 
-/// rd = `arith_opcode`(rs, rt)
+/// rd = `arith_opcode`(rs, rt). The signed operations raise an overflow
+/// exception, leaving rd as it was
 fn emit_alu(
     compiler: &mut Compiler,
     inst: RegisterType,
@@ -678,27 +874,22 @@ fn emit_alu(
 ) -> Result {
     let RegisterType { rd, rs, rt, .. } = inst;
 
-    let rd = compiler.get_cpu_register(rd)?;
     let rs = compiler.get_cpu_register(rs)?;
     let rt = compiler.get_cpu_register(rt)?;
 
-    compiler.emitter.mov(code_asm::r14, rt)?;
+    compiler.emitter.mov(code_asm::r14, rs)?;
     compiler
         .emitter
         .add_instruction(iced_x86::Instruction::with2(
             arith_opcode,
             iced_x86::Register::R14D,
-            iced_x86::Register::from(rs).full_register32(),
+            iced_x86::Register::from(rt).full_register32(),
         )?)?;
-    if signed {
-        compiler.emitter.movsxd(rd, code_asm::r14d)?;
-    } else {
-        compiler.emitter.mov(rd, code_asm::r14)?;
-    }
-
-    Ok(AssembleStatus::Continue)
+    emit_result(compiler, rd, signed)
 }
 
+/// rt = `arith_opcode`(rs, imm), raising an overflow exception like
+/// `emit_alu`
 fn emit_alu_imm(
     compiler: &mut Compiler,
     inst: ImmediateType,
@@ -707,12 +898,14 @@ fn emit_alu_imm(
 ) -> Result {
     let ImmediateType { rs, rt, imm, .. } = inst;
 
-    let rt = compiler.get_cpu_register(rt)?;
     let rs = compiler.get_cpu_register(rs)?;
 
-    compiler
-        .emitter
-        .mov(code_asm::r14, imm as i16 as u32 as u64)?;
+    // the logical operations zero-extend the immediate
+    let imm = match arith_opcode {
+        X86Opcode::And_rm32_r32 | X86Opcode::Or_rm32_r32 | X86Opcode::Xor_rm32_r32 => imm as u64,
+        _ => imm as i16 as u32 as u64,
+    };
+    compiler.emitter.mov(code_asm::r14, imm)?;
     compiler
         .emitter
         .add_instruction(iced_x86::Instruction::with2(
@@ -720,11 +913,42 @@ fn emit_alu_imm(
             iced_x86::Register::R14D,
             iced_x86::Register::from(rs).full_register32(),
         )?)?;
+    emit_result(compiler, rt, signed)
+}
+
+/// Set the guest register `dst` to the result in r14d, sign-extended for the
+/// signed operations, which raise an overflow exception instead if it
+/// overflowed
+fn emit_result(compiler: &mut Compiler, dst: u8, signed: bool) -> Result {
     if signed {
-        compiler.emitter.movsxd(rt, code_asm::r14d)?;
+        // `dst` isn't mapped yet, so it's not synced by the bailout
+        let overflow = compiler.bailout(Exception::Overflow);
+        compiler.emitter.jo(overflow)?;
+    }
+
+    let dst = compiler.get_cpu_register(dst)?;
+    if signed {
+        compiler.emitter.movsxd(dst, code_asm::r14d)?;
     } else {
-        compiler.emitter.mov(rt, code_asm::r14)?;
+        compiler.emitter.mov(dst, code_asm::r14)?;
     }
 
     Ok(AssembleStatus::Continue)
 }
+
+/// Target of a `J` or `JAL` instruction at `pc`, as computed by the
+/// compiled code
+pub(super) fn jump_target(pc: u64, target: u32) -> u64 {
+    ((pc & 0xf000_0000) as u32 | target << 2) as u64
+}
+
+/// Target of a conditional branch at `pc`, as computed by the compiled code.
+/// The offset is signed and relative to the delay slot, and wraps around the
+/// 32-bit address space
+pub(super) fn branch_target(pc: u64, offset: u16) -> u64 {
+    u64::from(
+        (pc as u32)
+            .wrapping_add(4)
+            .wrapping_add((offset as i16 as u32) << 2),
+    )
+}
@@ -22,16 +22,6 @@ const REGISTER_SET: [AsmRegister64; 16] = [
     gpr64::r15,
 ];
 
-pub const CALLEE_SAVED_REGISTERS: [AsmRegister64; 7] = [
-    gpr64::rbx,
-    gpr64::rsi,
-    gpr64::rbp,
-    gpr64::r12,
-    gpr64::r13,
-    gpr64::r14,
-    gpr64::r15,
-];
-
 pub const ARGS_REGS: &[AsmRegister64; 6] = &[
     gpr64::rdi,
     gpr64::rsi,
@@ -65,6 +55,8 @@ pub enum InsertError {
 pub struct Registers {
     regs: HashMap<GuestRegister, HostRegister>,
     free_regs: HashSet<Register>,
+    /// Host registers assigned to guest registers for the whole block
+    assigned: HashMap<GuestRegister, Register>,
     borrow_index: usize,
 }
 
@@ -83,6 +75,7 @@ impl Registers {
         Self {
             regs: HashMap::new(),
             free_regs,
+            assigned: HashMap::new(),
             borrow_index: 0,
         }
     }
@@ -101,15 +94,35 @@ impl Registers {
         None
     }
 
+    /// The registers that can be mapped, in a stable order
+    pub fn available(&self) -> Vec<AsmRegister64> {
+        REGISTER_SET
+            .iter()
+            .copied()
+            .filter(|&reg| self.free_regs.contains(&Register(reg)))
+            .collect()
+    }
+
+    /// Always map `guest` to `host`, which isn't given to other guest
+    /// registers anymore
+    pub fn assign(&mut self, guest: GuestRegister, host: AsmRegister64) {
+        self.free_regs.remove(&Register(host));
+        self.assigned.insert(guest, Register(host));
+    }
+
+    fn is_assigned(&self, host: Register) -> bool {
+        self.assigned.values().any(|&assigned| assigned == host)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&GuestRegister, &AsmRegister64)> {
         self.regs
             .iter()
             .map(|(guest, HostRegister { register, .. })| (guest, &register.0))
     }
 
-    /// Map a guest register with id `guest_reg` to a host register. If `lock`
-    /// is true, the register will not be dropped automatically, and need to be
-    /// manually dropped
+    /// Map a guest register with id `guest_reg` to a host register, taking it
+    /// from another guest register if needed. The other guest register is
+    /// returned if its value was modified, and has to be synced
     pub fn insert(
         &mut self,
         guest: GuestRegister,
@@ -118,15 +131,30 @@ impl Registers {
             return Err(InsertError::AlreadyReserved);
         }
 
-        let (host_register, dropped_guest) = if let Some(&next) = self.free_regs.iter().next() {
+        let (host_register, dropped_guest) = if let Some(&host) = self.assigned.get(&guest) {
+            // the guest register previously assigned to it is not live anymore
+            let dropped = self.find_by_host(host.0).map(|(dropped, _)| dropped);
+            let dropped = dropped
+                .and_then(|dropped| self.regs.remove(&dropped).map(|host| (dropped, host)))
+                .filter(|(_, host)| host.dirty)
+                .map(|(dropped, _)| dropped);
+            (host, dropped)
+        } else if let Some(&next) = self.free_regs.iter().next() {
             (next, None)
         } else {
-            let mut regs = self.regs.iter().collect::<Vec<_>>();
+            let mut regs = self
+                .regs
+                .iter()
+                .filter(|(_, host)| !self.is_assigned(host.register))
+                .collect::<Vec<_>>();
 
             let (_, (&guest_reg, host_reg), _) =
                 regs.select_nth_unstable_by_key(0, |(_, host)| host.borrow_index);
-            self.free_regs.insert(host_reg.register);
-            (host_reg.register, Some(guest_reg))
+            let (host_register, dirty) = (host_reg.register, host_reg.dirty);
+            // unmap the dropped register, so that it's not freed again once
+            // synced
+            self.regs.remove(&guest_reg);
+            (host_register, dirty.then_some(guest_reg))
         };
 
         // At this point we already know the key does not exist, that's why we call `insert_unique_unchecked`
@@ -136,6 +164,7 @@ impl Registers {
             HostRegister {
                 register: host_register,
                 borrow_index: self.borrow_index,
+                dirty: false,
             },
         );
         self.borrow_index += 1;
@@ -152,6 +181,19 @@ impl Registers {
         })
     }
 
+    /// Mark the value of `guest_reg` as modified, so that it's synced before
+    /// being dropped
+    pub fn mark_dirty(&mut self, guest_reg: GuestRegister) {
+        if let Some(host) = self.regs.get_mut(&guest_reg) {
+            host.dirty = true;
+        }
+    }
+
+    /// Whether the value of `guest_reg` was modified since it was loaded
+    pub fn is_dirty(&self, guest_reg: GuestRegister) -> bool {
+        self.regs.get(&guest_reg).is_some_and(|host| host.dirty)
+    }
+
     /// Finds the guest register using the given host register
     pub fn find_by_host(&self, host_reg: AsmRegister64) -> Option<(GuestRegister, AsmRegister64)> {
         self.regs
@@ -166,7 +208,9 @@ impl Registers {
         let mut dropped = None;
 
         if let Some(HostRegister { register, .. }) = self.regs.remove(&guest_reg) {
-            self.free_regs.insert(register);
+            if !self.is_assigned(register) {
+                self.free_regs.insert(register);
+            }
             dropped = Some((guest_reg, register.0));
         }
 
@@ -204,6 +248,8 @@ impl GuestRegister {
 struct HostRegister {
     register: Register,
     borrow_index: usize,
+    /// Whether the guest register was written since it was loaded
+    dirty: bool,
 }
 
 impl Hash for HostRegister {
@@ -216,3 +262,23 @@ impl Hash for HostRegister {
 fn is_reserved(register: AsmRegister64) -> bool {
     matches!(register, gpr64::rsi | gpr64::rsp | gpr64::rbp | gpr64::rbx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_only_sync_the_modified_registers() {
+        let mut regs = Registers::with_registers(&[gpr64::rax]);
+        regs.insert(GuestRegister::cpu(8)).unwrap();
+
+        let (_, dropped) = regs.insert(GuestRegister::cpu(9)).unwrap();
+        assert_eq!(dropped, None, "t0 was only read");
+
+        regs.mark_dirty(GuestRegister::cpu(9));
+        let (&host, dropped) = regs.insert(GuestRegister::cpu(10)).unwrap();
+        assert_eq!(dropped, Some(GuestRegister::cpu(9)));
+        assert_eq!(host, gpr64::rax);
+        assert!(!regs.is_dirty(GuestRegister::cpu(10)));
+    }
+}
@@ -1,17 +1,13 @@
-use std::{
-    cell::RefCell,
-    ops::{Deref, DerefMut},
-    rc::Rc,
-};
+use std::ops::{Deref, DerefMut};
 
-use crate::n64::State;
+use crate::n64::{State, SyncState};
 
 pub struct JitState {
-    vm: Rc<RefCell<State>>,
+    vm: SyncState,
 }
 
 impl JitState {
-    pub fn new(state: Rc<RefCell<State>>) -> Self {
+    pub fn new(state: SyncState) -> Self {
         Self { vm: state }
     }
 
@@ -19,26 +15,29 @@ impl JitState {
     where
         F: FnOnce(&State) -> &T,
     {
-        let state = self.vm.borrow();
+        let state = self.vm.lock();
 
         let data_addr = get_offset(&state) as *const T as usize;
-        let state_addr = self.state_ptr() as usize;
+        let state_addr = &raw const *state as usize;
 
         debug_assert!(state_addr <= data_addr);
         data_addr - state_addr
     }
 
-    pub fn state_ptr(&self) -> *const State {
-        &*self.vm.borrow()
+    /// Address and size of the RDRAM, which is never reallocated
+    pub fn rdram(&self) -> (u64, usize) {
+        let state = self.vm.lock();
+        let rdram = state.mmu.rdram();
+        (rdram.as_ptr() as u64, rdram.len())
     }
 
-    pub fn into_inner(self) -> Rc<RefCell<State>> {
+    pub fn into_inner(self) -> SyncState {
         self.vm
     }
 }
 
 impl Deref for JitState {
-    type Target = Rc<RefCell<State>>;
+    type Target = SyncState;
 
     fn deref(&self) -> &Self::Target {
         &self.vm
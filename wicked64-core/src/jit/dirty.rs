@@ -0,0 +1,126 @@
+use std::ops::Range;
+
+use bitvec::{bitvec, vec::BitVec};
+
+/// Size of the pages the guest stores are tracked by
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Pages of the physical address space
+const PAGES: usize = 0x2000_0000 / PAGE_SIZE;
+
+/// The physical memory pages written by the guest since the compiled blocks
+/// were last checked, one bit per 4KB page
+#[derive(Debug, Clone)]
+pub struct DirtyPages {
+    bits: BitVec,
+    /// The dirty pages, so that the bitmap is not scanned
+    pages: Vec<usize>,
+    /// The pages holding compiled code
+    code: BitVec,
+}
+
+impl DirtyPages {
+    pub fn new() -> DirtyPages {
+        Self {
+            bits: bitvec![0; PAGES],
+            pages: Vec::new(),
+            code: bitvec![0; PAGES],
+        }
+    }
+
+    /// Mark the page holding the physical address `addr` as written,
+    /// returning whether it holds compiled code
+    #[inline]
+    pub fn mark(&mut self, addr: usize) -> bool {
+        let page = addr / PAGE_SIZE;
+        if let Some(mut bit) = self.bits.get_mut(page) {
+            if !*bit {
+                bit.set(true);
+                self.pages.push(page);
+            }
+        }
+        self.code.get(page).is_some_and(|code| *code)
+    }
+
+    /// Mark the pages of the physical addresses `range` as holding compiled
+    /// code
+    pub fn add_code(&mut self, range: Range<usize>) {
+        let last = range.end.max(range.start + 1) - 1;
+        for page in range.start / PAGE_SIZE..=last / PAGE_SIZE {
+            if let Some(mut bit) = self.code.get_mut(page) {
+                bit.set(true);
+            }
+        }
+    }
+
+    /// Mark `page` as not holding compiled code anymore
+    pub fn remove_code(&mut self, page: usize) {
+        if let Some(mut bit) = self.code.get_mut(page) {
+            bit.set(false);
+        }
+    }
+
+    /// Mark every page as not holding compiled code
+    pub fn clear_code(&mut self) {
+        self.code.fill(false);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Take the indices of the dirty pages, marking them as clean
+    pub fn take(&mut self) -> Vec<usize> {
+        for &page in &self.pages {
+            self.bits.set(page, false);
+        }
+        std::mem::take(&mut self.pages)
+    }
+
+    /// Mark every page as clean
+    pub fn clear(&mut self) {
+        self.take();
+    }
+}
+
+impl Default for DirtyPages {
+    fn default() -> DirtyPages {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_track_the_written_pages() {
+        let mut dirty = DirtyPages::new();
+        dirty.mark(0x1234);
+        dirty.mark(0x1FFC);
+        dirty.mark(0x0040_0000);
+        dirty.mark(0x4000_0000);
+
+        assert_eq!(dirty.take(), [0x1, 0x400]);
+        assert!(dirty.is_empty());
+
+        dirty.mark(0x1000);
+        assert_eq!(dirty.take(), [0x1]);
+    }
+
+    #[test]
+    fn it_should_report_the_writes_to_compiled_code() {
+        let mut dirty = DirtyPages::new();
+        dirty.add_code(0x1FF0..0x2010);
+
+        assert!(!dirty.mark(0x0FFC));
+        assert!(dirty.mark(0x1000));
+        assert!(dirty.mark(0x2FFC));
+        assert!(!dirty.mark(0x3000));
+
+        dirty.remove_code(0x1);
+        assert!(!dirty.mark(0x1000));
+        dirty.clear_code();
+        assert!(!dirty.mark(0x2000));
+    }
+}
@@ -2,7 +2,8 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interruption {
     None,
-    PrepareJump(u64),
+    /// The execution is paused on the breakpoint at this virtual address
+    Debug(u64),
 }
 
 impl Interruption {
@@ -1,17 +1,17 @@
-use std::rc::Rc;
-
 use hashbrown::HashMap;
 
-use super::code::CompiledBlock;
-
 pub struct JumpEntry {
-    /// The address to jump
+    /// Host address of the link entry of the block
     pub target_block: usize,
 }
 
+/// Blocks the compiled code may jump to through a register, without
+/// returning to the host. The entries are added as the blocks are cached, not
+/// as the jumps are resolved, so that a jump to a block yet to be compiled goes
+/// through the host, which compiles it
 pub struct JumpTable {
-    /// Maps a n64 physical address to a `JumpEntry`
-    table: HashMap<u64, Option<JumpEntry>>,
+    /// Maps the physical address of a cached block to its `JumpEntry`
+    table: HashMap<u64, JumpEntry>,
 }
 
 impl JumpTable {
@@ -21,19 +21,18 @@ impl JumpTable {
         }
     }
 
-    pub fn get(&mut self, phys_jump_addr: u64) -> Option<&JumpEntry> {
-        self.table.entry(phys_jump_addr).or_default().as_ref()
+    pub fn get(&self, phys_addr: u64) -> Option<&JumpEntry> {
+        self.table.get(&phys_addr)
+    }
+
+    /// Add the entry of the block at `phys_addr`, whose link entry is at
+    /// `target_block`
+    pub fn insert(&mut self, phys_addr: u64, target_block: usize) {
+        self.table.insert(phys_addr, JumpEntry { target_block });
     }
 
-    pub(crate) fn resolve_with_block(
-        &mut self,
-        phys_addr: u64,
-        block: &Rc<CompiledBlock>,
-    ) -> Option<&JumpEntry> {
-        self.table.get_mut(&phys_addr).map(|entry| {
-            entry.get_or_insert(JumpEntry {
-                target_block: block.ptr() as usize,
-            }) as &_
-        })
+    /// Remove the entry of the block at `phys_addr`, before it's dropped
+    pub fn remove_block(&mut self, phys_addr: u64) {
+        self.table.remove(&phys_addr);
     }
 }
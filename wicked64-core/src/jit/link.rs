@@ -0,0 +1,168 @@
+use hashbrown::HashMap;
+
+use crate::mmu::map::addr_map;
+
+use super::memory::CodeAllocator;
+
+/// Counters of the blocks run since the host started one. Linked blocks run
+/// one after the other without returning to the host, until the budget is
+/// exhausted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkCounters {
+    /// Cycles the linked blocks may still run
+    pub budget: i64,
+    /// Cycles run by the blocks, counted by each block as it exits
+    pub cycles: u64,
+    /// Guest instructions run by the blocks
+    pub instructions: u64,
+}
+
+impl LinkCounters {
+    pub fn new(budget: usize) -> LinkCounters {
+        Self {
+            budget: budget as i64,
+            ..Self::default()
+        }
+    }
+}
+
+/// Where a compiled block can be linked to the blocks it jumps to
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockLinks {
+    /// Offset of the entry point of the linked jumps
+    pub entry: usize,
+    /// Physical address of each static jump target, with the offset of the
+    /// 64-bit immediate the host address of the target is patched into
+    pub sites: Vec<(u64, usize)>,
+}
+
+/// Physical address of the jump target `addr`, if it can be linked. Only
+/// the unmapped segments are linked, as they don't depend on the TLB
+pub fn physical_target(addr: u64) -> Option<u64> {
+    let addr = addr as usize;
+    [&addr_map::virt::KSEG0_RANGE, &addr_map::virt::KSEG1_RANGE]
+        .into_iter()
+        .find(|range| range.contains(&addr))
+        .map(|range| (addr - range.start()) as u64)
+}
+
+/// A jump patched to go straight to the target block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LinkSite {
+    /// Physical address of the block the jump is in
+    owner: u64,
+    /// The 64-bit immediate holding the host address of the target, or 0
+    /// while unlinked
+    ptr: *mut u8,
+}
+
+#[derive(Debug, Default)]
+struct LinkTarget {
+    /// Link entry of the compiled target block
+    entry: Option<usize>,
+    sites: Vec<LinkSite>,
+}
+
+/// The direct jumps between the cached blocks, indexed by the physical
+/// address of their target
+#[derive(Debug, Default)]
+pub struct Links {
+    targets: HashMap<u64, LinkTarget>,
+}
+
+impl Links {
+    /// Register the jump at `ptr`, in the block at `owner`, to the block at
+    /// `target`. It is linked right away if the target is compiled
+    ///
+    /// # Safety
+    /// `ptr` must point to the immediate of a link site in the executable
+    /// memory of the owner block, allocated by `allocator`, which must be
+    /// unlinked with `unlink` before being freed
+    pub unsafe fn add_site(
+        &mut self,
+        allocator: &CodeAllocator,
+        target: u64,
+        owner: u64,
+        ptr: *mut u8,
+    ) {
+        let target = self.targets.entry(target).or_default();
+        if let Some(entry) = target.entry {
+            patch(allocator, ptr, entry);
+        }
+        target.sites.push(LinkSite { owner, ptr });
+    }
+
+    /// Link the jumps to the block at `target`, whose link entry is `entry`
+    pub fn link(&mut self, allocator: &CodeAllocator, target: u64, entry: usize) {
+        let target = self.targets.entry(target).or_default();
+        target.entry = Some(entry);
+        for site in &target.sites {
+            unsafe { patch(allocator, site.ptr, entry) };
+        }
+    }
+
+    /// Unlink the block at `owner` before it's dropped: the jumps to it go
+    /// through the host again, and its own jumps are forgotten
+    pub fn unlink(&mut self, allocator: &CodeAllocator, owner: u64, links: &BlockLinks) {
+        if let Some(target) = self.targets.get_mut(&owner) {
+            target.entry = None;
+            for site in &target.sites {
+                unsafe { patch(allocator, site.ptr, 0) };
+            }
+        }
+        for &(target, _) in &links.sites {
+            if let Some(target) = self.targets.get_mut(&target) {
+                target.sites.retain(|site| site.owner != owner);
+            }
+        }
+    }
+}
+
+unsafe fn patch(allocator: &CodeAllocator, ptr: *mut u8, host_addr: usize) {
+    if let Err(error) = allocator.write_u64(ptr, host_addr as u64) {
+        panic!("Could not patch the link site at {ptr:p}: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::memory::CodeAllocator;
+
+    use super::*;
+
+    #[test]
+    fn it_should_patch_the_sites_of_a_target() {
+        let mut links = Links::default();
+        let allocator = CodeAllocator::default();
+        let memory = allocator.alloc(&[0; 16]).unwrap();
+        let ptr = memory.ptr().cast_mut();
+
+        unsafe { links.add_site(&allocator, 0x1000, 0x2000, ptr) };
+        assert_eq!(
+            memory.as_slice()[..8],
+            [0; 8],
+            "the target is not compiled yet"
+        );
+
+        links.link(&allocator, 0x1000, 0xDEAD_BEEF);
+        assert_eq!(
+            u64::from_le_bytes(memory.as_slice()[..8].try_into().unwrap()),
+            0xDEAD_BEEF
+        );
+
+        unsafe { links.add_site(&allocator, 0x1000, 0x3000, ptr.add(8)) };
+        assert_eq!(
+            u64::from_le_bytes(memory.as_slice()[8..].try_into().unwrap()),
+            0xDEAD_BEEF
+        );
+
+        let owned = BlockLinks {
+            entry: 0,
+            sites: vec![(0x1000, 0)],
+        };
+        links.unlink(&allocator, 0x3000, &owned);
+        links.unlink(&allocator, 0x1000, &BlockLinks::default());
+        assert_eq!(memory.as_slice()[..8], [0; 8]);
+        assert_eq!(links.targets[&0x1000].sites.len(), 1);
+    }
+}
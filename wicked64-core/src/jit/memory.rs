@@ -0,0 +1,269 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::{Rc, Weak},
+};
+
+use region::{Allocation, Protection};
+
+/// Size of the chunks the code of the blocks is allocated from. The blocks
+/// larger than a chunk get one of their own
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Alignment of the code of each block in its chunk
+const CODE_ALIGN: usize = 16;
+
+/// Allocator of the executable memory of the compiled blocks. The memory is
+/// never writable and executable at once: the code is written into chunks
+/// mapped as writable, which are remapped as executable by `protect`, before
+/// any of it runs.
+///
+/// The blocks are bump-allocated from large chunks, so that a block doesn't
+/// take a mapping of its own, and the chunks are remapped once for all the
+/// code written and patched in the meantime rather than for each write. A
+/// chunk is unmapped once its blocks and the allocator are dropped.
+///
+/// The emitted code is x86-64, whose instruction cache is coherent with the
+/// stores, so it doesn't have to be flushed after a write. Nor do the chunks
+/// need `MAP_JIT` and its write protection toggles, as they are remapped
+/// instead
+#[derive(Debug, Default)]
+pub struct CodeAllocator {
+    /// Chunk the blocks are allocated from, with the offset of its free space
+    current: RefCell<Option<(Rc<Chunk>, usize)>>,
+    /// The chunks by start address, for the patches to find theirs
+    chunks: RefCell<BTreeMap<usize, Weak<Chunk>>>,
+    /// Chunks remapped as writable until `protect`
+    writable: RefCell<Vec<Weak<Chunk>>>,
+    /// Bytes mapped by the live chunks
+    mapped: Rc<Cell<usize>>,
+    /// Bytes of code of the live blocks
+    allocated: Rc<Cell<usize>>,
+}
+
+impl CodeAllocator {
+    /// Allocate memory holding a copy of `code`, executable once `protect`
+    /// is called
+    pub fn alloc(&self, code: &[u8]) -> region::Result<ExecMemory> {
+        let len = code.len().max(1);
+        let (chunk, offset) = if len > CHUNK_SIZE {
+            (self.map_chunk(len)?, 0)
+        } else {
+            let mut current = self.current.borrow_mut();
+            let (chunk, top) = match current.take() {
+                Some((chunk, top)) if top + len <= chunk.alloc.len() => (chunk, top),
+                _ => (self.map_chunk(CHUNK_SIZE)?, 0),
+            };
+            *current = Some((chunk.clone(), (top + len).next_multiple_of(CODE_ALIGN)));
+            (chunk, top)
+        };
+
+        self.make_writable(&chunk)?;
+        unsafe {
+            let ptr = chunk.alloc.as_ptr::<u8>().add(offset).cast_mut();
+            ptr.copy_from_nonoverlapping(code.as_ptr(), code.len());
+        }
+        self.allocated.set(self.allocated.get() + code.len());
+        Ok(ExecMemory {
+            chunk,
+            offset,
+            len: code.len(),
+            allocated: self.allocated.clone(),
+        })
+    }
+
+    /// Overwrite the 64-bit value at `ptr`, in the code of a block, its
+    /// chunk being remapped as writable until `protect`
+    ///
+    /// # Safety
+    /// `ptr` must point to 8 bytes of the live code of a block allocated by
+    /// this allocator, which no code is running from
+    pub unsafe fn write_u64(&self, ptr: *mut u8, value: u64) -> region::Result<()> {
+        let chunk = self
+            .chunks
+            .borrow()
+            .range(..=ptr as usize)
+            .next_back()
+            .and_then(|(_, chunk)| chunk.upgrade())
+            .expect("The pointer is not in the code of a block");
+        self.make_writable(&chunk)?;
+        ptr.cast::<u64>().write_unaligned(value);
+        Ok(())
+    }
+
+    /// Remap the chunks written since the last call as executable, before
+    /// running their code
+    pub fn protect(&self) -> region::Result<()> {
+        for chunk in self.writable.borrow_mut().drain(..) {
+            if let Some(chunk) = chunk.upgrade() {
+                unsafe { chunk.protect(Protection::READ_EXECUTE)? };
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of executable memory mapped for the live blocks, a chunk at
+    /// least
+    pub fn mapped(&self) -> usize {
+        self.mapped.get()
+    }
+
+    /// Bytes of code of the live blocks. The chunks may map more, as the
+    /// space of the dropped blocks isn't reused
+    pub fn allocated(&self) -> usize {
+        self.allocated.get()
+    }
+
+    /// Map a chunk of at least `len` bytes
+    fn map_chunk(&self, len: usize) -> region::Result<Rc<Chunk>> {
+        let alloc = region::alloc(len, Protection::READ_WRITE)?;
+        self.mapped.set(self.mapped.get() + alloc.len());
+        let chunk = Rc::new(Chunk {
+            alloc,
+            writable: Cell::new(true),
+            mapped: self.mapped.clone(),
+        });
+        self.writable.borrow_mut().push(Rc::downgrade(&chunk));
+
+        let mut chunks = self.chunks.borrow_mut();
+        chunks.retain(|_, chunk| chunk.strong_count() > 0);
+        chunks.insert(chunk.alloc.as_ptr::<u8>() as usize, Rc::downgrade(&chunk));
+        Ok(chunk)
+    }
+
+    fn make_writable(&self, chunk: &Rc<Chunk>) -> region::Result<()> {
+        if !chunk.writable.get() {
+            unsafe { chunk.protect(Protection::READ_WRITE)? };
+            self.writable.borrow_mut().push(Rc::downgrade(chunk));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CodeAllocator {
+    fn drop(&mut self) {
+        // the blocks outliving the allocator may still run
+        if let Err(error) = self.protect() {
+            panic!("Could not remap the code as executable: {error}");
+        }
+    }
+}
+
+/// Memory the code of the blocks is allocated from
+struct Chunk {
+    alloc: Allocation,
+    /// The chunk is mapped as writable, and not executable
+    writable: Cell<bool>,
+    mapped: Rc<Cell<usize>>,
+}
+
+impl Chunk {
+    /// # Safety
+    /// No code may run from the chunk while it's writable
+    unsafe fn protect(&self, protection: Protection) -> region::Result<()> {
+        region::protect(self.alloc.as_ptr::<u8>(), self.alloc.len(), protection)?;
+        self.writable.set(protection == Protection::READ_WRITE);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Chunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chunk")
+            .field("ptr", &self.alloc.as_ptr::<u8>())
+            .field("len", &self.alloc.len())
+            .field("writable", &self.writable.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.mapped.set(self.mapped.get() - self.alloc.len());
+    }
+}
+
+/// Executable memory holding the code of a block, in a chunk unmapped once
+/// all its blocks are dropped
+pub struct ExecMemory {
+    chunk: Rc<Chunk>,
+    offset: usize,
+    len: usize,
+    allocated: Rc<Cell<usize>>,
+}
+
+impl ExecMemory {
+    pub fn ptr(&self) -> *const u8 {
+        unsafe { self.chunk.alloc.as_ptr::<u8>().add(self.offset) }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr(), self.len) }
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        self.allocated.set(self.allocated.get() - self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_map_the_code_as_executable_only() {
+        let allocator = CodeAllocator::default();
+        let code = [0xC3u8; 16];
+        let memory = allocator.alloc(&code).unwrap();
+        assert_eq!(memory.as_slice(), code);
+        assert_eq!(allocator.mapped(), CHUNK_SIZE);
+        let protection = region::query(memory.ptr()).unwrap().protection();
+        assert_eq!(protection, Protection::READ_WRITE);
+
+        allocator.protect().unwrap();
+        let protection = region::query(memory.ptr()).unwrap().protection();
+        assert_eq!(protection, Protection::READ_EXECUTE);
+
+        unsafe { allocator.write_u64(memory.ptr().cast_mut().add(4), 0) }.unwrap();
+        assert_eq!(
+            memory.as_slice()[..12],
+            [0xC3, 0xC3, 0xC3, 0xC3, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        allocator.protect().unwrap();
+        let protection = region::query(memory.ptr()).unwrap().protection();
+        assert_eq!(protection, Protection::READ_EXECUTE);
+
+        // the allocator keeps the chunk to allocate the next blocks from
+        drop(memory);
+        assert_eq!(allocator.mapped(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn it_should_allocate_the_blocks_from_a_chunk() {
+        let allocator = CodeAllocator::default();
+        let first = allocator.alloc(&[0xC3; 100]).unwrap();
+        let second = allocator.alloc(&[0x90; 100]).unwrap();
+        assert_eq!(allocator.mapped(), CHUNK_SIZE);
+        assert_eq!(allocator.allocated(), 200);
+        assert_eq!(second.ptr() as usize - first.ptr() as usize, 112);
+        assert_eq!(first.as_slice(), [0xC3; 100]);
+        assert_eq!(second.as_slice(), [0x90; 100]);
+
+        // a large block gets a chunk of its own
+        let large = allocator.alloc(&vec![0xC3; CHUNK_SIZE + 1]).unwrap();
+        assert!(allocator.mapped() > 2 * CHUNK_SIZE);
+        drop(large);
+        assert_eq!(allocator.mapped(), CHUNK_SIZE);
+
+        // a full chunk is left for a new one, and unmapped with its blocks
+        let blocks = (0..CHUNK_SIZE / 4096)
+            .map(|_| allocator.alloc(&[0xC3; 4096]).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(allocator.mapped(), 2 * CHUNK_SIZE);
+        drop((first, second, blocks));
+        assert_eq!(allocator.mapped(), CHUNK_SIZE);
+        assert_eq!(allocator.allocated(), 0);
+    }
+}
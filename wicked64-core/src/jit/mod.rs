@@ -1,89 +1,502 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{collections::HashSet, io, path::Path, rc::Rc};
 
-use crate::n64::State;
+use crate::n64::SyncState;
+use crate::{cpu::cp0::status::CpuContext, logging};
 
 use self::{
-    cache::Cache,
-    code::CompiledBlock,
-    compiler::Compiler,
-    jump_table::{JumpEntry, JumpTable},
+    cache::Cache, compiler::Compiler, jump_table::JumpTable, link::Links, memory::CodeAllocator,
+    perf::PerfMap,
 };
 
 mod bridge;
 mod cache;
 mod code;
 mod compiler;
+mod dirty;
 mod interruption;
 mod jump_table;
+mod link;
+mod memory;
+pub mod perf;
+mod profile;
 
+pub(crate) use code::{BlockExit, CompiledBlock};
+pub use dirty::{DirtyPages, PAGE_SIZE};
 pub use interruption::Interruption;
+pub use link::LinkCounters;
+pub use profile::HotBlock;
+
+/// Default cycle budget of the blocks stored in the cache
+pub const BLOCK_CYCLES: usize = 1024;
+
+/// Default budget of the code of the cached blocks, in bytes
+pub const CODE_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Default executions after which a baseline block is recompiled
+pub const TIER_UP_EXECUTIONS: u64 = 256;
+
+/// How much work goes into compiling a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    /// Compiled quickly: the guest registers are loaded as they're used, and
+    /// the memory is accessed through the bridge
+    Baseline,
+    /// Recompiled once hot, with the register allocation, the constant
+    /// folding and the fast RDRAM accesses
+    Optimized,
+}
+
+/// Debugger state the compiled blocks depend on
+#[derive(Debug, Default)]
+struct Debugger {
+    /// Virtual addresses the execution stops at
+    breakpoints: HashSet<u64>,
+    /// End the blocks right after each memory access, so that a watchpoint
+    /// hit stops the execution after the instruction that caused it
+    watching: bool,
+    /// Log the host code of every compiled block
+    dump_code: bool,
+}
+
+/// Counters of the JIT activity since the engine was created
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JitStats {
+    /// Blocks compiled, cached or not
+    pub blocks_compiled: u64,
+    /// Lookups that found the block in the cache
+    pub cache_hits: u64,
+    /// Lookups that had to compile the block
+    pub cache_misses: u64,
+    /// Cache invalidations caused by guest stores, and full flushes
+    pub invalidations: u64,
+    /// Hot baseline blocks recompiled with the optimizations
+    pub tier_ups: u64,
+    /// Flushes of the cache whose code reached the budget
+    pub evictions: u64,
+    /// Flushes of the cache on a switch of the CPU context
+    pub context_switches: u64,
+}
+
+/// Guest instruction the JIT can't compile, which raised a reserved
+/// instruction exception instead
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Unimplemented instruction 0x{opcode:08x} at 0x{pc:08x}")]
+pub struct UnimplementedInstruction {
+    /// Virtual address of the instruction
+    pub pc: u64,
+    pub opcode: u32,
+}
 
 /// JIT codegen engine
 pub struct JitEngine {
     cache: Cache,
-    state: Rc<RefCell<State>>,
-    jump_table: JumpTable,
+    state: SyncState,
+    /// Boxed, as the compiled code holds its address
+    jump_table: Box<JumpTable>,
+    /// Direct jumps between the cached blocks
+    links: Links,
+    /// CPU context the cached blocks were compiled in, so that they are
+    /// dropped when the context switches
+    context: CpuContext,
+    /// Cycle budget of the blocks stored in the cache
+    block_cycles: usize,
+    /// Bytes of executable memory the cached blocks may take
+    code_budget: usize,
+    debugger: Debugger,
+    /// Symbols of the compiled blocks for `perf`, if enabled
+    perf_map: Option<PerfMap>,
+    /// Executions after which a baseline block is recompiled, or 0 to
+    /// compile the optimized blocks right away
+    tier_up: u64,
+    /// Breakpoint the execution was resumed from, which is not hit again
+    resumed_breakpoint: Option<u64>,
+    stats: JitStats,
 }
 
 impl JitEngine {
-    pub fn new(state: Rc<RefCell<State>>) -> Self {
+    pub fn new(state: SyncState) -> Self {
+        let context = state.lock().cpu.cp0.status.context();
         Self {
             cache: Cache::default(),
             state,
-            jump_table: JumpTable::new(),
+            jump_table: Box::new(JumpTable::new()),
+            links: Links::default(),
+            context,
+            block_cycles: BLOCK_CYCLES,
+            code_budget: CODE_BUDGET,
+            debugger: Debugger::default(),
+            perf_map: None,
+            tier_up: TIER_UP_EXECUTIONS,
+            resumed_breakpoint: None,
+            stats: JitStats::default(),
         }
     }
 
-    pub fn compile(&mut self, virtual_pc: u64) -> Rc<CompiledBlock> {
-        let physical_pc = self.state.borrow().translate_cpu_pc();
+    pub fn stats(&self) -> JitStats {
+        self.stats
+    }
+
+    /// Bytes of code of the compiled blocks, which the budget applies to.
+    /// The memory is mapped by chunks, so a few blocks take a whole chunk
+    pub fn code_size(&self) -> usize {
+        self.cache.allocator().allocated()
+    }
+
+    pub fn block_cycles(&self) -> usize {
+        self.block_cycles
+    }
+
+    /// Set the cycle budget of the cached blocks. Longer blocks are
+    /// compiled less often, but the devices are stepped less precisely
+    ///
+    /// # Panics
+    /// `cycles` is 0
+    pub fn set_block_cycles(&mut self, cycles: usize) {
+        assert!(cycles > 0, "The JIT blocks should run for at least a cycle");
+        self.block_cycles = cycles;
+        self.flush();
+    }
+
+    /// Set the budget of the code of the cached blocks. Once their code
+    /// reaches it, the cache is flushed before the next block is compiled
+    pub fn set_code_budget(&mut self, bytes: usize) {
+        self.code_budget = bytes;
+    }
 
-        let block = self.cache.get_or_insert_with(physical_pc as usize, || {
-            tracing::debug!("Compiling a block at addr '{virtual_pc:08x}'");
+    pub fn set_dump_code(&mut self, enabled: bool) {
+        self.debugger.dump_code = enabled;
+    }
 
-            let state = &self.state;
-            let compiler = Compiler::new(state.clone(), &mut self.jump_table, virtual_pc as usize);
+    /// Write the symbols of the blocks compiled from now on into the `perf`
+    /// map at `path`, usually `perf::default_path()`, or stop with `None`
+    ///
+    /// # Errors
+    /// The map can't be created
+    pub fn set_perf_map(&mut self, path: Option<&Path>) -> io::Result<()> {
+        self.perf_map = path.map(PerfMap::create).transpose()?;
+        Ok(())
+    }
 
-            let cycles = 1024usize;
+    /// Recompile the baseline blocks with the optimizations after they ran
+    /// `executions` times. With 0, the optimized blocks are compiled right
+    /// away
+    pub fn set_tier_up(&mut self, executions: u64) {
+        self.tier_up = executions;
+        self.flush();
+    }
 
-            let (buf, len) = compiler.compile(cycles);
+    fn initial_tier(&self) -> Tier {
+        if self.tier_up == 0 {
+            Tier::Optimized
+        } else {
+            Tier::Baseline
+        }
+    }
 
-            CompiledBlock::new(buf, virtual_pc, len)
-        });
+    /// Add a breakpoint at the virtual address `addr`. The compiled blocks
+    /// are dropped, so that the new ones end right before it
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        if self.debugger.breakpoints.insert(addr) {
+            self.flush();
+        }
+    }
+
+    /// Remove the breakpoint at `addr`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.debugger.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.debugger.breakpoints.iter().copied()
+    }
 
-        tracing::debug!(
+    /// End the compiled blocks after each memory access while watchpoints
+    /// are set, so that the execution stops right after a hit
+    pub fn set_watching(&mut self, enabled: bool) {
+        if self.debugger.watching != enabled {
+            self.debugger.watching = enabled;
+            self.flush();
+        }
+    }
+
+    /// Check if the block starting at `pc` must not run because of a
+    /// breakpoint. The breakpoint given to `resume_breakpoint` is skipped
+    /// once
+    pub fn hits_breakpoint(&mut self, pc: u64) -> bool {
+        if self.resumed_breakpoint.take() == Some(pc) {
+            return false;
+        }
+        self.debugger.breakpoints.contains(&pc)
+    }
+
+    /// Let the execution resume from the breakpoint at `addr`
+    pub fn resume_breakpoint(&mut self, addr: u64) {
+        self.resumed_breakpoint = Some(addr);
+    }
+
+    pub fn compile(&mut self, virtual_pc: u64) -> Rc<CompiledBlock> {
+        self.sync_context();
+        let physical_pc = self.state.lock().translate_cpu_pc();
+        if self.code_size() >= self.code_budget && self.cache.get(physical_pc as usize).is_none() {
+            self.evict();
+        }
+
+        let mut missed = false;
+        let tier = self.initial_tier();
+        let mut block = self
+            .cache
+            .get_or_insert_with(physical_pc as usize, |allocator| {
+                missed = true;
+                Self::compile_block(
+                    &self.state,
+                    allocator,
+                    &self.jump_table,
+                    &self.debugger,
+                    virtual_pc,
+                    self.block_cycles,
+                    tier,
+                )
+            });
+        if missed {
+            let start = physical_pc as usize;
+            self.state
+                .lock()
+                .dirty_pages
+                .add_code(start..start + block.len());
+            self.stats.cache_misses += 1;
+            self.stats.blocks_compiled += 1;
+            self.add_perf_symbol(&block);
+            self.link(physical_pc, &block);
+        } else {
+            self.stats.cache_hits += 1;
+            if !block.is_optimized() && block.profile().executions() >= self.tier_up {
+                block = self.optimize(physical_pc, virtual_pc);
+            }
+        }
+
+        logging::debug!(
+            JIT,
             "Getting block at addr '0x{virtual_pc:08x}' with id: {:p}",
             block.ptr()
         );
 
+        self.protect_code();
+        block
+    }
+
+    /// Remap the code written since the last block ran as executable
+    fn protect_code(&self) {
+        if let Err(error) = self.cache.allocator().protect() {
+            panic!("Could not remap the code as executable: {error}");
+        }
+    }
+
+    /// Recompile the hot block at `physical_pc` with the optimizations. The
+    /// new block takes the place of the baseline one in the cache, and the
+    /// jumps to it are linked again
+    fn optimize(&mut self, physical_pc: u64, virtual_pc: u64) -> Rc<CompiledBlock> {
+        logging::debug!(JIT, "Optimizing the hot block at 0x{virtual_pc:08x}");
+
+        let block = Rc::new(Self::compile_block(
+            &self.state,
+            self.cache.allocator(),
+            &self.jump_table,
+            &self.debugger,
+            virtual_pc,
+            self.block_cycles,
+            Tier::Optimized,
+        ));
+        self.add_perf_symbol(&block);
+        if let Some(baseline) = self.cache.replace(physical_pc as usize, block.clone()) {
+            self.links
+                .unlink(self.cache.allocator(), physical_pc, baseline.links());
+            self.jump_table.remove_block(physical_pc);
+        }
+        self.link(physical_pc, &block);
+        self.stats.blocks_compiled += 1;
+        self.stats.tier_ups += 1;
         block
     }
 
+    /// Whether the block compiled at the virtual address `pc` is an idle loop
+    pub fn is_idle_loop(&self, pc: u64) -> bool {
+        let physical_pc = self.state.lock().cpu.translate_virtual(pc);
+        self.cache
+            .get(physical_pc as usize)
+            .is_some_and(|block| block.is_idle_loop())
+    }
+
     pub fn compile_current_pc(&mut self) -> Rc<CompiledBlock> {
-        let pc = self.state.borrow().cpu.pc;
+        let pc = self.state.lock().cpu.pc;
         self.compile(pc)
     }
 
+    /// Compile a block at the current PC that runs for at most `max_cycles`
+    /// cycles, except for its last instruction and the delay slot of a jump.
+    /// Blocks shorter than the cached ones are not cached
+    pub fn compile_bounded(&mut self, max_cycles: usize) -> Rc<CompiledBlock> {
+        if max_cycles >= self.block_cycles {
+            return self.compile_current_pc();
+        }
+
+        self.sync_context();
+        let pc = self.state.lock().cpu.pc;
+        let tier = self.initial_tier();
+        self.stats.blocks_compiled += 1;
+        let block = Rc::new(Self::compile_block(
+            &self.state,
+            self.cache.allocator(),
+            &self.jump_table,
+            &self.debugger,
+            pc,
+            max_cycles,
+            tier,
+        ));
+        self.add_perf_symbol(&block);
+        self.protect_code();
+        block
+    }
+
+    /// Map the code of a new block in the `perf` map, if enabled
+    fn add_perf_symbol(&mut self, block: &CompiledBlock) {
+        if let Some(perf_map) = &mut self.perf_map {
+            if let Err(error) = perf_map.add_block(block) {
+                tracing::warn!("Could not write the perf map: {error}");
+            }
+        }
+    }
+
+    fn compile_block(
+        state: &SyncState,
+        allocator: &CodeAllocator,
+        jump_table: &JumpTable,
+        debugger: &Debugger,
+        virtual_pc: u64,
+        max_cycles: usize,
+        tier: Tier,
+    ) -> CompiledBlock {
+        logging::debug!(JIT, "Compiling a block at addr '{virtual_pc:08x}'");
+
+        let compiler = Compiler::new(state.clone(), jump_table, debugger, virtual_pc as usize);
+        let block = compiler.tier(tier).compile(max_cycles, allocator);
+        if debugger.dump_code {
+            tracing::info!(
+                target: logging::target::JIT_CODE,
+                "Block at 0x{virtual_pc:08x}:\n{}", block.disassemble()
+            );
+        } else {
+            tracing::debug!(
+                target: logging::target::JIT_CODE,
+                "Block at 0x{virtual_pc:08x}:\n{}", block.disassemble()
+            );
+        }
+        block
+    }
+
     pub fn invalidate_cache(&mut self) {
-        // ! TODO: delete entries from jump table too
-        if let Some(inv_range) = self.state.borrow_mut().cache_invalidation.take() {
-            self.cache.invalidate_range(inv_range);
+        let pages = {
+            let mut state = self.state.lock();
+            if state.dirty_pages.is_empty() {
+                return;
+            }
+            state.dirty_pages.take()
+        };
+
+        let dropped = self.cache.invalidate_pages(&pages);
+        let mut state = self.state.lock();
+        for &page in &pages {
+            state.dirty_pages.remove_code(page);
+        }
+        drop(state);
+        for (start, block) in &dropped {
+            self.links
+                .unlink(self.cache.allocator(), *start as u64, block.links());
+            self.jump_table.remove_block(*start as u64);
+        }
+        if !dropped.is_empty() {
+            logging::debug!(JIT, "Dropped {} blocks from written pages", dropped.len());
+            self.stats.invalidations += 1;
+        }
+    }
+
+    /// Link the jumps of a new cached block, and the jumps to it, static or
+    /// through the jump table. Blocks aren't linked while debugging, as the
+    /// host checks the breakpoints and watchpoints between the blocks
+    fn link(&mut self, physical_pc: u64, block: &CompiledBlock) {
+        if !self.debugger.breakpoints.is_empty() || self.debugger.watching {
+            return;
+        }
+        for &(target, offset) in &block.links().sites {
+            unsafe {
+                let ptr = block.ptr().cast_mut().add(offset);
+                self.links
+                    .add_site(self.cache.allocator(), target, physical_pc, ptr);
+            }
         }
+        self.links
+            .link(self.cache.allocator(), physical_pc, block.link_entry());
+        self.jump_table.insert(physical_pc, block.link_entry());
     }
 
-    pub(crate) fn resolve_jump(&mut self, addr: u64) -> Option<&JumpEntry> {
-        let block = self.compile(addr);
-        self.jump_table
-            .resolve_with_block(self.state.borrow().cpu.translate_virtual(addr), &block)
+    /// Drop every compiled block and jump table entry, as when the guest
+    /// memory is replaced by a savestate
+    pub fn flush(&mut self) {
+        self.drop_blocks();
+        self.stats.invalidations += 1;
     }
 
-    pub fn resume_from(&self, resume_block: usize) {
-        let resume_addr = self.state.borrow().resume_addr as usize;
-        tracing::debug!(
-            "Resuming execution at 0x{resume_addr:08x} and jumping to 0x{:08x}",
-            resume_block
-        );
-        unsafe {
-            code::resume(&self.state, resume_addr, resume_block);
+    /// Drop every compiled block if the CPU context switched since they were
+    /// compiled. The blocks of a context may only jump to the blocks of the
+    /// same context, so the whole cache goes at once. The exceptions don't
+    /// switch the context of the kernel, which the games run in
+    fn sync_context(&mut self) {
+        let context = self.state.lock().cpu.cp0.status.context();
+        if context != self.context {
+            logging::debug!(
+                JIT,
+                "Switching the CPU context from {:?} to {context:?}",
+                self.context
+            );
+            self.context = context;
+            self.drop_blocks();
+            self.stats.context_switches += 1;
         }
     }
+
+    /// Drop every compiled block once their code reaches the budget. The
+    /// whole generation goes at once, as the blocks are linked together
+    fn evict(&mut self) {
+        logging::debug!(
+            JIT,
+            "Evicting the compiled blocks, which take {} bytes",
+            self.code_size()
+        );
+        self.drop_blocks();
+        self.stats.evictions += 1;
+    }
+
+    fn drop_blocks(&mut self) {
+        self.cache = Cache::default();
+        *self.jump_table = JumpTable::new();
+        self.links = Links::default();
+        let mut state = self.state.lock();
+        state.dirty_pages.clear();
+        state.dirty_pages.clear_code();
+    }
+
+    /// The `n` cached blocks run the most, the most run first
+    pub fn hot_blocks(&self, n: usize) -> Vec<HotBlock> {
+        let mut blocks = self
+            .cache
+            .blocks()
+            .map(|block| HotBlock {
+                pcs: block.start_pc()..block.start_pc() + block.len() as u64,
+                executions: block.profile().executions(),
+                host_time: block.profile().host_time(),
+            })
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|block| std::cmp::Reverse(block.executions));
+        blocks.truncate(n);
+        blocks
+    }
 }
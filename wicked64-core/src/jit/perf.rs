@@ -0,0 +1,53 @@
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use super::code::CompiledBlock;
+
+/// Where `perf` looks for the symbols of the code compiled by this process
+pub fn default_path() -> PathBuf {
+    PathBuf::from(format!("/tmp/perf-{}.map", std::process::id()))
+}
+
+/// Map of the host code of the compiled blocks to the guest code, in the
+/// format read by Linux `perf`, so that its profiles name the guest addresses
+/// the time went to. An address freed by a dropped block may be mapped again
+/// by a later block, which `perf` resolves to the latest entry
+pub struct PerfMap {
+    /// Flushed after each entry, as `perf` may read it while the emulator runs
+    writer: LineWriter<File>,
+}
+
+impl PerfMap {
+    /// Create the map at `path`, replacing the previous one
+    ///
+    /// # Errors
+    /// The file can't be created
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: LineWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Add the entry of a newly compiled block
+    ///
+    /// # Errors
+    /// The entry can't be written
+    pub fn add_block(&mut self, block: &CompiledBlock) -> io::Result<()> {
+        let start_pc = block.start_pc();
+        let tier = if block.is_optimized() {
+            "optimized"
+        } else {
+            "baseline"
+        };
+        writeln!(
+            self.writer,
+            "{:x} {:x} w64:0x{start_pc:08x}-0x{:08x} [{tier}]",
+            block.ptr() as usize,
+            block.code().len(),
+            start_pc + block.len() as u64,
+        )
+    }
+}
@@ -0,0 +1,40 @@
+use std::{cell::Cell, ops::Range, time::Duration};
+
+/// Execution counters of a compiled block
+#[derive(Debug, Default)]
+pub struct BlockProfile {
+    /// Times the block ran, incremented by its code on entry
+    executions: Cell<u64>,
+    /// Host time spent running the block when the host called it, the blocks
+    /// linked after it included
+    host_time: Cell<Duration>,
+}
+
+impl BlockProfile {
+    /// Address of the execution counter, incremented by the compiled code
+    pub fn executions_ptr(&self) -> u64 {
+        self.executions.as_ptr() as u64
+    }
+
+    pub fn executions(&self) -> u64 {
+        self.executions.get()
+    }
+
+    pub fn host_time(&self) -> Duration {
+        self.host_time.get()
+    }
+
+    pub fn add_host_time(&self, time: Duration) {
+        self.host_time.set(self.host_time.get() + time);
+    }
+}
+
+/// A cached block, as profiled by `JitEngine::hot_blocks`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotBlock {
+    /// Virtual addresses of the guest instructions of the block
+    pub pcs: Range<u64>,
+    pub executions: u64,
+    /// Host time spent in the calls starting at the block
+    pub host_time: Duration,
+}
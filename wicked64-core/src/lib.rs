@@ -1,4 +1,3 @@
-#![feature(naked_functions)]
 #![deny(clippy::pedantic)]
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_lossless)]
@@ -10,14 +9,23 @@
 #![allow(clippy::must_use_candidate)]
 #![allow(clippy::borrow_as_ptr)]
 
-#[cfg(not(target_pointer_width = "64"))]
-compile_error!("Your CPU does not supports 64-bit integers");
+// the JIT is the only engine, and it emits x86-64 code
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("The JIT only runs on x86-64 hosts");
 
 pub mod cpu;
+pub mod frontend;
 pub mod io;
 pub mod jit;
+pub mod logging;
 pub mod mmu;
+pub mod movie;
 pub mod n64;
+pub mod rdp;
+pub mod rsp;
+pub mod savestate;
+pub mod scheduler;
+pub mod trace;
 mod utils;
 
 #[cfg(test)]
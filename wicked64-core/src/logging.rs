@@ -0,0 +1,130 @@
+use std::{
+    ops::BitOr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Tracing targets of the subsystems
+pub mod target {
+    pub const JIT: &str = "jit";
+    /// Disassembly of the compiled blocks, logged at the debug level unless
+    /// the code is dumped
+    pub const JIT_CODE: &str = "jit_code";
+    pub const CPU: &str = "cpu";
+    pub const MMU: &str = "mmu";
+    pub const VI: &str = "vi";
+    pub const AI: &str = "ai";
+    pub const PI: &str = "pi";
+    pub const SI: &str = "si";
+}
+
+/// A set of subsystems whose debug logs are emitted. All of them are enabled
+/// by default, leaving the filtering to the subscriber
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LogTargets(u8);
+
+impl LogTargets {
+    pub const NONE: LogTargets = LogTargets(0);
+    pub const JIT: LogTargets = LogTargets(1 << 0);
+    pub const CPU: LogTargets = LogTargets(1 << 1);
+    pub const MMU: LogTargets = LogTargets(1 << 2);
+    pub const VI: LogTargets = LogTargets(1 << 3);
+    pub const AI: LogTargets = LogTargets(1 << 4);
+    pub const PI: LogTargets = LogTargets(1 << 5);
+    pub const SI: LogTargets = LogTargets(1 << 6);
+    pub const ALL: LogTargets = LogTargets(0x7F);
+
+    const NAMES: [(&'static str, LogTargets); 7] = [
+        (target::JIT, Self::JIT),
+        (target::CPU, Self::CPU),
+        (target::MMU, Self::MMU),
+        (target::VI, Self::VI),
+        (target::AI, Self::AI),
+        (target::PI, Self::PI),
+        (target::SI, Self::SI),
+    ];
+
+    pub fn contains(self, other: LogTargets) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse a comma separated list of target names, such as `jit,cpu`, or
+    /// either `all` or `none`
+    pub fn parse(list: &str) -> Option<LogTargets> {
+        match list.trim() {
+            "all" => return Some(Self::ALL),
+            "none" => return Some(Self::NONE),
+            _ => {}
+        }
+        list.split(',').try_fold(Self::NONE, |targets, name| {
+            let (_, target) = Self::NAMES
+                .iter()
+                .find(|(target, _)| *target == name.trim())?;
+            Some(targets | *target)
+        })
+    }
+}
+
+impl Default for LogTargets {
+    fn default() -> LogTargets {
+        Self::ALL
+    }
+}
+
+impl BitOr for LogTargets {
+    type Output = LogTargets;
+
+    fn bitor(self, rhs: LogTargets) -> LogTargets {
+        LogTargets(self.0 | rhs.0)
+    }
+}
+
+/// The subsystems whose debug logs are emitted. Shared by the whole process,
+/// like the tracing subscriber
+static ENABLED: AtomicU8 = AtomicU8::new(LogTargets::ALL.0);
+
+pub fn enabled() -> LogTargets {
+    LogTargets(ENABLED.load(Ordering::Relaxed))
+}
+
+pub fn set_enabled(targets: LogTargets) {
+    ENABLED.store(targets.0, Ordering::Relaxed);
+}
+
+/// `tracing::debug!` with the target of a subsystem, skipped while the
+/// subsystem is disabled
+macro_rules! debug {
+    ($target:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled().contains($crate::logging::LogTargets::$target) {
+            tracing::debug!(target: $crate::logging::target::$target, $($arg)+);
+        }
+    };
+}
+
+/// `tracing::trace!` with the target of a subsystem, skipped while the
+/// subsystem is disabled
+macro_rules! trace {
+    ($target:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled().contains($crate::logging::LogTargets::$target) {
+            tracing::trace!(target: $crate::logging::target::$target, $($arg)+);
+        }
+    };
+}
+
+pub(crate) use {debug, trace};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_the_target_lists() {
+        assert_eq!(
+            LogTargets::parse("jit, cpu"),
+            Some(LogTargets::JIT | LogTargets::CPU)
+        );
+        assert_eq!(LogTargets::parse("all"), Some(LogTargets::ALL));
+        assert_eq!(LogTargets::parse("jit,rsp"), None);
+        assert!(LogTargets::ALL.contains(LogTargets::SI));
+        assert!(!LogTargets::JIT.contains(LogTargets::CPU));
+    }
+}
@@ -76,6 +76,8 @@ pub mod addr_map {
         pub const CART_D1A1_RANGE: AddrRange      = 0x0600_0000..=0x07FF_FFFF;
         pub const CART_D2A2_RANGE: AddrRange      = 0x0800_0000..=0x0FFF_FFFF;
         pub const CART_D1A2_RANGE: AddrRange      = 0x1000_0000..=0x1FBF_FFFF;
+        /// Debug port of the IS-Viewer 64, mapped over the cartridge ROM
+        pub const ISVIEWER_RANGE: AddrRange       = 0x13FF_0000..=0x13FF_FFFF;
         pub const PIF_ROM_RANGE: AddrRange        = 0x1FC0_0000..=0x1FC0_07BF;
         pub const PIF_RAM_RANGE: AddrRange        = 0x1FC0_07C0..=0x1FC0_07FF;
         pub const RESERVED_RANGE: AddrRange       = 0x1FC0_0800..=0x1FCF_FFFF;
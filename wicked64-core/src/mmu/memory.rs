@@ -1,44 +1,724 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    io::{Read, Write},
+    ops::{Range, RangeInclusive},
+};
 
-use byteorder::ByteOrder;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 
-use crate::{io::Cartridge, map_ranges, utils::btree_range::BTreeRange};
+use crate::{
+    cpu::signals::ResetKind,
+    io::{
+        audio::{ai_reg, AiDma},
+        mips::mi_intr,
+        peripheral::{pi_reg, PiDma, PiDmaDirection},
+        pif::PIF_RAM_SIZE,
+        serial::{si_reg, SiDma, SiDmaDirection},
+        video::vi_reg,
+        AudioInterface, Cartridge, DiskDrive, IsViewer, MipsInterface, PeripheralInterface, Pif,
+        SerialInterface, VideoInterface,
+    },
+    logging, map_ranges,
+    rdp::{command, command_id, command_len, DpCommandList, Rdp},
+    rsp::{
+        hle::{task_type, HleTask},
+        Rsp, RspGuard, RspThread, SpDma, SpDmaDirection, SP_MEM_SIZE,
+    },
+    savestate::{read_bytes, write_bytes, SaveStateError, SaveStateResult, Snapshot},
+    scheduler::{Event, Scheduler},
+    utils::btree_range::BTreeRange,
+};
 
-use super::{num::MemInteger, GenericMemoryUnit, MemoryUnit};
+use super::{
+    check_alignment,
+    map::addr_map,
+    num::MemInteger,
+    page_table::PageTable,
+    watchpoint::{AccessKind, WatchHit, WatchKind, Watchpoint, WatchpointId, Watchpoints},
+    GenericMemoryUnit, MemError, MemResult, MemoryUnit,
+};
 
 // 4 megabytes
 pub const RDRAM_SIZE_IN_BYTES: usize = 4 * 1024 * 1024;
 
+/// CPU cycles between two matches of Count and Compare, as Count is a 32-bit
+/// counter incremented every other cycle
+const COUNT_WRAP_CYCLES: u64 = 2 << 32;
+
+/// Optional hardware plugged into the console
+#[derive(Debug)]
+pub struct MemoryConfig {
+    /// Extra 4 megabytes of RDRAM
+    pub expansion_pak: bool,
+    /// Boot ROM of the PIF, run by the CPU when the PIF is not simulated
+    pub pif_rom: Option<Box<[u8]>>,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> MemoryConfig {
+        Self {
+            expansion_pak: true,
+            pif_rom: None,
+        }
+    }
+}
+
+/// Events of the scheduled devices that are handled outside of the memory
+/// manager
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceEvents {
+    /// A field was completely scanned out by the VI
+    pub frame: bool,
+    /// CP0 Count reached Compare
+    pub compare: bool,
+}
+
+/// The RSP memories and registers
+fn sp_range() -> RangeInclusive<usize> {
+    *addr_map::phys::SP_DMEM_RANGE.start()..=*addr_map::phys::SP_REG_RANGE.end()
+}
+
+/// The cartridge ROM, up to the IS-Viewer. The cartridges are never large
+/// enough to reach it
+fn cartridge_range() -> Range<usize> {
+    *addr_map::phys::CART_D1A2_RANGE.start()..*addr_map::phys::ISVIEWER_RANGE.start()
+}
+
 /// N64 Memory Management Unit
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct MemoryManager {
-    units: BTreeRange<GenericMemoryUnit>,
+    units: PageTable<GenericMemoryUnit>,
     /// 9th bit from RDRAM bytes
     rdram9: Box<[u8]>,
+    watchpoints: Watchpoints,
+    /// Last guest access that hit a watchpoint and was not handled yet
+    watch_hit: Option<WatchHit>,
+    /// RDRAM range of the color image the RDP is drawing into
+    dp_framebuffer: Option<Range<usize>>,
+    scheduler: Scheduler,
 }
 
 impl MemoryManager {
     pub fn new(cartridge: Cartridge) -> MemoryManager {
-        use crate::mmu::map::addr_map;
+        Self::with_config(cartridge, MemoryConfig::default())
+    }
 
-        let rdram = std::iter::repeat(0)
-            .take(2 * RDRAM_SIZE_IN_BYTES)
-            .collect::<Box<[u8]>>();
+    pub fn with_config(cartridge: Cartridge, config: MemoryConfig) -> MemoryManager {
+        let rdram_size = if config.expansion_pak {
+            2 * RDRAM_SIZE_IN_BYTES
+        } else {
+            RDRAM_SIZE_IN_BYTES
+        };
+        let rdram = vec![0; rdram_size].into_boxed_slice();
 
-        let units = map_ranges! {
-            addr_map::phys::RDRAM_RANGE => GenericMemoryUnit::BoxedSlice(rdram),
-            addr_map::phys::SP_DMEM_RANGE => GenericMemoryUnit::BoxedSlice(Box::new([0u8;0x1000]) as Box<[u8]>),
-            addr_map::phys::PIF_RAM_RANGE => GenericMemoryUnit::BoxedSlice(Box::new([0u8;0x1000]) as Box<[u8]>),
-            addr_map::phys::CART_D1A2_RANGE => GenericMemoryUnit::Cartridge(cartridge),
+        let mut units = map_ranges! {
+            0..rdram_size => GenericMemoryUnit::BoxedSlice(rdram),
+            sp_range() => GenericMemoryUnit::Rsp(RspThread::new(Rsp::new())),
+            addr_map::phys::DP_CMD_REG_RANGE => GenericMemoryUnit::Rdp(Rdp::new()),
+            addr_map::phys::MIPS_INT_RANGE => GenericMemoryUnit::MipsInterface(MipsInterface::new()),
+            addr_map::phys::VIDEO_INT_RANGE => GenericMemoryUnit::VideoInterface(VideoInterface::new()),
+            addr_map::phys::AUDIO_INT_RANGE => GenericMemoryUnit::AudioInterface(AudioInterface::new()),
+            addr_map::phys::PERIPHERAL_INT_RANGE => GenericMemoryUnit::PeripheralInterface(PeripheralInterface::new()),
+            addr_map::phys::SERIAL_INT_RANGE => GenericMemoryUnit::SerialInterface(SerialInterface::new()),
+            addr_map::phys::PIF_RAM_RANGE => GenericMemoryUnit::Pif(Pif::new()),
+            addr_map::phys::CART_D2A1_RANGE => GenericMemoryUnit::DiskDrive(DiskDrive::new()),
+            cartridge_range() => GenericMemoryUnit::Cartridge(cartridge),
+            addr_map::phys::ISVIEWER_RANGE => GenericMemoryUnit::IsViewer(IsViewer::new()),
         };
+        if let Some(data) = config.pif_rom {
+            // the boot ROM is read-only, just like the cartridge
+            units.insert(
+                addr_map::phys::PIF_ROM_RANGE,
+                GenericMemoryUnit::Cartridge(Cartridge { data }),
+            );
+        }
+
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(
+            VideoInterface::new().cycles_per_half_line(),
+            Event::ViHalfLine,
+        );
 
         Self {
-            units,
-            rdram9: std::iter::repeat(0)
-                .take(2 * RDRAM_SIZE_IN_BYTES)
-                .collect::<Box<[u8]>>(),
+            units: PageTable::new(units),
+            rdram9: vec![0; rdram_size].into_boxed_slice(),
+            watchpoints: Watchpoints::default(),
+            watch_hit: None,
+            dp_framebuffer: None,
+            scheduler,
+        }
+    }
+
+    /// Reset the devices after a `kind` reset of the console. Host
+    /// attachments and the joybus devices are kept, and the RDRAM is only
+    /// cleared by a power cycle
+    pub fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Power {
+            self.rdram_mut().fill(0);
+            self.rdram9.fill(0);
+        }
+
+        self.rsp_mut().reset();
+        self.rdp_mut().reset();
+        *self.mips_interface_mut() = MipsInterface::new();
+        self.video_interface_mut().reset();
+        self.audio_interface_mut().reset();
+        *self.peripheral_interface_mut() = PeripheralInterface::new();
+        *self.serial_interface_mut() = SerialInterface::new();
+        self.pif_mut().reset();
+
+        self.watch_hit = None;
+        self.dp_framebuffer = None;
+        self.scheduler = Scheduler::new();
+        self.scheduler.schedule(
+            self.video_interface().cycles_per_half_line(),
+            Event::ViHalfLine,
+        );
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+    pub fn scheduler_mut(&mut self) -> &mut Scheduler {
+        &mut self.scheduler
+    }
+
+    /// Watch the physical address range `range` for guest accesses of the
+    /// given kind
+    pub fn add_watchpoint(
+        &mut self,
+        range: RangeInclusive<usize>,
+        kind: WatchKind,
+    ) -> WatchpointId {
+        self.watchpoints.insert(Watchpoint { range, kind })
+    }
+
+    /// Remove a watchpoint, returning it if it exists
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> Option<Watchpoint> {
+        self.watchpoints.remove(id)
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    /// Check a guest access of `size` bytes at `addr` against the
+    /// watchpoints, marking a debug interruption as pending when hit
+    #[inline]
+    pub fn watch_access(&mut self, addr: usize, size: usize, access: AccessKind, value: u64) {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return;
+        }
+        if let Some(hit) = self.watchpoints.check(addr, size, access, value) {
+            logging::debug!(MMU, "Watchpoint hit: {hit:x?}");
+            self.watch_hit = Some(hit);
+        }
+    }
+
+    /// Whether a watchpoint was hit and is waiting to be handled
+    pub fn has_pending_watch_hit(&self) -> bool {
+        self.watch_hit.is_some()
+    }
+
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    /// Take the pending watchpoint hit
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn pif(&self) -> &Pif {
+        match self.units.get(*addr_map::phys::PIF_RAM_RANGE.start()) {
+            Some(GenericMemoryUnit::Pif(pif)) => pif,
+            _ => unreachable!("The PIF RAM should always be mapped"),
+        }
+    }
+    pub fn pif_mut(&mut self) -> &mut Pif {
+        match self.units.get_mut(*addr_map::phys::PIF_RAM_RANGE.start()) {
+            Some(GenericMemoryUnit::Pif(pif)) => pif,
+            _ => unreachable!("The PIF RAM should always be mapped"),
+        }
+    }
+
+    pub fn isviewer_mut(&mut self) -> &mut IsViewer {
+        match self.units.get_mut(*addr_map::phys::ISVIEWER_RANGE.start()) {
+            Some(GenericMemoryUnit::IsViewer(isviewer)) => isviewer,
+            _ => unreachable!("The IS-Viewer should always be mapped"),
+        }
+    }
+
+    pub fn serial_interface(&self) -> &SerialInterface {
+        match self.units.get(*addr_map::phys::SERIAL_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::SerialInterface(si)) => si,
+            _ => unreachable!("The SI registers should always be mapped"),
+        }
+    }
+    pub fn serial_interface_mut(&mut self) -> &mut SerialInterface {
+        match self
+            .units
+            .get_mut(*addr_map::phys::SERIAL_INT_RANGE.start())
+        {
+            Some(GenericMemoryUnit::SerialInterface(si)) => si,
+            _ => unreachable!("The SI registers should always be mapped"),
+        }
+    }
+
+    pub fn mips_interface(&self) -> &MipsInterface {
+        match self.units.get(*addr_map::phys::MIPS_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::MipsInterface(mi)) => mi,
+            _ => unreachable!("The MI registers should always be mapped"),
+        }
+    }
+    pub fn mips_interface_mut(&mut self) -> &mut MipsInterface {
+        match self.units.get_mut(*addr_map::phys::MIPS_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::MipsInterface(mi)) => mi,
+            _ => unreachable!("The MI registers should always be mapped"),
+        }
+    }
+
+    pub fn peripheral_interface(&self) -> &PeripheralInterface {
+        match self
+            .units
+            .get(*addr_map::phys::PERIPHERAL_INT_RANGE.start())
+        {
+            Some(GenericMemoryUnit::PeripheralInterface(pi)) => pi,
+            _ => unreachable!("The PI registers should always be mapped"),
+        }
+    }
+    pub fn peripheral_interface_mut(&mut self) -> &mut PeripheralInterface {
+        match self
+            .units
+            .get_mut(*addr_map::phys::PERIPHERAL_INT_RANGE.start())
+        {
+            Some(GenericMemoryUnit::PeripheralInterface(pi)) => pi,
+            _ => unreachable!("The PI registers should always be mapped"),
+        }
+    }
+
+    pub fn audio_interface(&self) -> &AudioInterface {
+        match self.units.get(*addr_map::phys::AUDIO_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::AudioInterface(ai)) => ai,
+            _ => unreachable!("The AI registers should always be mapped"),
+        }
+    }
+    pub fn audio_interface_mut(&mut self) -> &mut AudioInterface {
+        match self.units.get_mut(*addr_map::phys::AUDIO_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::AudioInterface(ai)) => ai,
+            _ => unreachable!("The AI registers should always be mapped"),
+        }
+    }
+
+    pub fn video_interface(&self) -> &VideoInterface {
+        match self.units.get(*addr_map::phys::VIDEO_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::VideoInterface(vi)) => vi,
+            _ => unreachable!("The VI registers should always be mapped"),
+        }
+    }
+    pub fn video_interface_mut(&mut self) -> &mut VideoInterface {
+        match self.units.get_mut(*addr_map::phys::VIDEO_INT_RANGE.start()) {
+            Some(GenericMemoryUnit::VideoInterface(vi)) => vi,
+            _ => unreachable!("The VI registers should always be mapped"),
+        }
+    }
+
+    fn rsp_thread(&self) -> &RspThread {
+        match self.units.get(*addr_map::phys::SP_DMEM_RANGE.start()) {
+            Some(GenericMemoryUnit::Rsp(rsp)) => rsp,
+            _ => unreachable!("The RSP should always be mapped"),
+        }
+    }
+    /// Wait for the RSP thread to run the granted cycles, then lock the RSP
+    pub fn rsp(&self) -> RspGuard<'_> {
+        self.rsp_thread().lock()
+    }
+    pub fn rsp_mut(&mut self) -> RspGuard<'_> {
+        self.rsp_thread().lock()
+    }
+
+    pub fn rdp(&self) -> &Rdp {
+        match self.units.get(*addr_map::phys::DP_CMD_REG_RANGE.start()) {
+            Some(GenericMemoryUnit::Rdp(rdp)) => rdp,
+            _ => unreachable!("The DP registers should always be mapped"),
+        }
+    }
+    pub fn rdp_mut(&mut self) -> &mut Rdp {
+        match self
+            .units
+            .get_mut(*addr_map::phys::DP_CMD_REG_RANGE.start())
+        {
+            Some(GenericMemoryUnit::Rdp(rdp)) => rdp,
+            _ => unreachable!("The DP registers should always be mapped"),
+        }
+    }
+
+    /// Advance the devices driven by the CPU clock by `cycles` cycles, and
+    /// dispatch the scheduled events that are due. The RSP runs its cycles on
+    /// its thread, and its requests are handled by the next step
+    pub fn step_devices(&mut self, cycles: u64) -> DeviceEvents {
+        self.sync_rsp();
+        let task = self.rsp_mut().take_hle_task();
+        if let Some(task) = task {
+            self.run_hle_task(task);
+        }
+        self.rsp_thread().step(cycles);
+
+        let mut events = DeviceEvents::default();
+        self.scheduler.advance(cycles);
+        while let Some((at, event)) = self.scheduler.pop_due() {
+            match event {
+                Event::CountCompare => {
+                    events.compare = true;
+                    self.scheduler
+                        .schedule(at + COUNT_WRAP_CYCLES, Event::CountCompare);
+                }
+                Event::ViHalfLine => {
+                    let vi = self.video_interface_mut();
+                    let line = vi.next_half_line();
+                    let next = at + vi.cycles_per_half_line();
+                    self.scheduler.schedule(next, Event::ViHalfLine);
+
+                    if line.interrupt {
+                        self.mips_interface_mut().raise(mi_intr::VI);
+                    }
+                    events.frame |= line.frame;
+                }
+                Event::PiDmaComplete => {
+                    if self.peripheral_interface_mut().complete_dma() {
+                        self.mips_interface_mut().raise(mi_intr::PI);
+                    }
+                }
+                Event::AiBufferDrain => {
+                    if let Some(cycles) = self.audio_interface_mut().drain() {
+                        self.scheduler.schedule(at + cycles, Event::AiBufferDrain);
+                        self.mips_interface_mut().raise(mi_intr::AI);
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    pub fn rdram(&self) -> &[u8] {
+        match self.units.get(*addr_map::phys::RDRAM_RANGE.start()) {
+            Some(GenericMemoryUnit::BoxedSlice(rdram)) => rdram,
+            _ => unreachable!("The RDRAM should always be mapped"),
+        }
+    }
+
+    fn rdram_mut(&mut self) -> &mut [u8] {
+        match self.units.get_mut(*addr_map::phys::RDRAM_RANGE.start()) {
+            Some(GenericMemoryUnit::BoxedSlice(rdram)) => rdram,
+            _ => unreachable!("The RDRAM should always be mapped"),
+        }
+    }
+
+    /// Header checksum of the inserted cartridge, identifying the game
+    pub fn cartridge_crc(&self) -> u64 {
+        self.read::<u64, BigEndian>(*addr_map::phys::CART_D1A2_RANGE.start() + 0x10)
+    }
+
+    /// Get a slice of `len` bytes from the RDRAM starting at `addr`
+    fn rdram_slice_mut(&mut self, addr: usize, len: usize) -> Option<&mut [u8]> {
+        if !addr_map::phys::RDRAM_RANGE.contains(&addr) {
+            return None;
+        }
+        let (offset, rdram) = self.units.get_offset_and_value_mut(addr)?;
+        rdram.buffer_mut().get_mut(offset..offset + len)
+    }
+
+    /// Run the DMA transfers and interrupt changes requested by the RSP
+    fn sync_rsp(&mut self) {
+        let (dma, interrupt, dp_writes) = {
+            let mut rsp = self.rsp_mut();
+            (
+                rsp.take_pending_dma(),
+                rsp.take_interrupt(),
+                rsp.take_dp_writes(),
+            )
+        };
+
+        if let Some(dma) = dma {
+            self.run_sp_dma(dma);
+        }
+        for (offset, value) in dp_writes {
+            let list = {
+                let rdp = self.rdp_mut();
+                rdp.write_reg(offset, value);
+                rdp.take_pending_list()
+            };
+            if let Some(list) = list {
+                self.run_dp_command_list(list);
+            }
+        }
+        let rdp = self.rdp_mut();
+        let dp_regs = std::array::from_fn(|i| rdp.read_reg(i * 4));
+        self.rsp_mut().set_dp_regs(dp_regs);
+        match interrupt {
+            Some(true) => self.mips_interface_mut().raise(mi_intr::SP),
+            Some(false) => self.mips_interface_mut().clear(mi_intr::SP),
+            None => {}
+        }
+    }
+
+    /// Fetch a command list from RDRAM or DMEM and run it through the RDP
+    fn run_dp_command_list(&mut self, list: DpCommandList) {
+        let DpCommandList { start, end, xbus } = list;
+        logging::trace!(
+            MMU,
+            "DP command list 0x{start:08x}..0x{end:08x} (xbus: {xbus})"
+        );
+
+        let len = end.saturating_sub(start);
+        let data = if xbus {
+            let rsp = self.rsp();
+            let dmem = rsp.dmem();
+            (0..len)
+                .map(|i| dmem[(start + i) % SP_MEM_SIZE])
+                .collect::<Vec<_>>()
+        } else if let Some(data) = self.rdram_slice_mut(start, len) {
+            data.to_vec()
+        } else {
+            tracing::warn!(
+                target: logging::target::MMU,
+                "Invalid DP command list address: 0x{start:08x}"
+            );
+            Vec::new()
+        };
+        let words = data
+            .chunks_exact(8)
+            .map(BigEndian::read_u64)
+            .collect::<Vec<_>>();
+
+        let rdp = self.rdp_mut();
+        rdp.finish_list(&list);
+        let commands = rdp.push_commands(&words);
+        let Some(mut backend) = rdp.take_backend() else {
+            return;
+        };
+
+        // run the commands up to each SYNC_FULL before raising the interrupt
+        let rdram_len = self.rdram().len();
+        let mut sync_full = false;
+        if let Some(rdram) = self.rdram_slice_mut(0, rdram_len) {
+            let mut index = 0;
+            let mut segment_start = 0;
+            while let Some(&word) = commands.get(index) {
+                index += command_len(word);
+                if command_id(word) == command::SYNC_FULL {
+                    backend.process_command_list(&commands[segment_start..index], rdram);
+                    backend.sync_full(rdram);
+                    segment_start = index;
+                    sync_full = true;
+                }
+            }
+            if segment_start < commands.len() {
+                backend.process_command_list(&commands[segment_start..], rdram);
+            }
+        }
+
+        let rdp = self.rdp_mut();
+        rdp.restore_backend(backend);
+        self.dp_framebuffer = rdp.framebuffer();
+
+        if sync_full {
+            self.mips_interface_mut().raise(mi_intr::DP);
+        }
+    }
+
+    /// Run a RSP task through its high-level implementation
+    fn run_hle_task(&mut self, mut task: HleTask) {
+        logging::debug!(
+            MMU,
+            "Running RSP task of type {} through HLE",
+            task.task.task_type
+        );
+
+        let rdram_len = self.rdram().len();
+        if let Some(rdram) = self.rdram_slice_mut(0, rdram_len) {
+            task.handler.run(&task.task, rdram);
+        }
+
+        // the RDP has nothing left to draw once the graphics task is done
+        let raise_dp = task.task.task_type == task_type::GRAPHICS;
+        self.rsp_mut().finish_hle_task(task);
+        self.sync_rsp();
+        if raise_dp {
+            self.mips_interface_mut().raise(mi_intr::DP);
+        }
+    }
+
+    /// Perform a SP DMA transfer between RDRAM and DMEM/IMEM. Memory
+    /// addresses wrap around the selected 4KB memory
+    fn run_sp_dma(&mut self, dma: SpDma) {
+        let SpDma {
+            mem_addr,
+            dram_addr,
+            len,
+            count,
+            skip,
+            direction,
+        } = dma;
+        logging::debug!(
+            MMU,
+            "SP DMA {direction:?}: {count}x{len} bytes at RDRAM 0x{dram_addr:08x}"
+        );
+
+        let bank = mem_addr & SP_MEM_SIZE;
+        for row in 0..count {
+            let dram_addr = dram_addr + row * (len + skip);
+            let mem_addr = mem_addr + row * len;
+            let mem_offsets = (0..len).map(|i| bank | ((mem_addr + i) % SP_MEM_SIZE));
+
+            match direction {
+                SpDmaDirection::RdramToSp => {
+                    let Some(data) = self.rdram_slice_mut(dram_addr, len).map(|s| s.to_vec())
+                    else {
+                        tracing::warn!(
+                            target: logging::target::MMU,
+                            "Invalid SP DMA address: 0x{dram_addr:08x}"
+                        );
+                        break;
+                    };
+                    let mut rsp = self.rsp_mut();
+                    let mem = rsp.buffer_mut();
+                    for (offset, byte) in mem_offsets.zip(data) {
+                        mem[offset] = byte;
+                    }
+                }
+                SpDmaDirection::SpToRdram => {
+                    let data = {
+                        let rsp = self.rsp();
+                        let mem = rsp.buffer();
+                        mem_offsets.map(|offset| mem[offset]).collect::<Vec<_>>()
+                    };
+                    let Some(rdram) = self.rdram_slice_mut(dram_addr, len) else {
+                        tracing::warn!(
+                            target: logging::target::MMU,
+                            "Invalid SP DMA address: 0x{dram_addr:08x}"
+                        );
+                        break;
+                    };
+                    rdram.copy_from_slice(&data);
+                }
+            }
+        }
+
+        self.rsp_mut().finish_dma(&dma);
+    }
+
+    /// Send a sample buffer from RDRAM to the AI
+    fn run_ai_dma(&mut self, dma: AiDma) {
+        let AiDma { dram_addr, len } = dma;
+        logging::trace!(AI, "AI DMA of {len} bytes at RDRAM 0x{dram_addr:08x}");
+
+        let Some(samples) = self.rdram_slice_mut(dram_addr, len).map(|s| s.to_vec()) else {
+            tracing::warn!(
+                target: logging::target::AI,
+                "Invalid AI DMA address: 0x{dram_addr:08x}"
+            );
+            return;
+        };
+        let ai = self.audio_interface_mut();
+        ai.play(&samples);
+        if let Some(cycles) = ai.enqueue(len) {
+            self.scheduler.schedule_in(cycles, Event::AiBufferDrain);
+            self.mips_interface_mut().raise(mi_intr::AI);
+        }
+    }
+
+    /// Perform a PI DMA transfer between RDRAM and the cartridge bus. The PI
+    /// completes it once the bus timings have elapsed
+    fn run_pi_dma(&mut self, dma: PiDma) {
+        let PiDma {
+            dram_addr,
+            cart_addr,
+            len,
+            direction,
+        } = dma;
+        logging::debug!(
+            PI,
+            "PI DMA {direction:?} of {len} bytes: RDRAM 0x{dram_addr:08x}, cart 0x{cart_addr:08x}"
+        );
+
+        match direction {
+            PiDmaDirection::CartToRdram => {
+                let data = (0..len)
+                    .map(|i| self.try_read::<u8, BigEndian>(cart_addr + i).unwrap_or(0))
+                    .collect::<Vec<_>>();
+                if let Some(rdram) = self.rdram_slice_mut(dram_addr, len) {
+                    rdram.copy_from_slice(&data);
+                } else {
+                    tracing::warn!(
+                        target: logging::target::PI,
+                        "Invalid PI DMA address: 0x{dram_addr:08x}"
+                    );
+                }
+            }
+            PiDmaDirection::RdramToCart => {
+                let data = self.rdram_slice_mut(dram_addr, len).map(|s| s.to_vec());
+                if data.is_none() {
+                    tracing::warn!(
+                        target: logging::target::PI,
+                        "Invalid PI DMA address: 0x{dram_addr:08x}"
+                    );
+                }
+                for (i, byte) in data.into_iter().flatten().enumerate() {
+                    if let Err(error) = self.try_store::<u8, BigEndian>(cart_addr + i, byte) {
+                        tracing::warn!(
+                            target: logging::target::PI,
+                            "Invalid PI DMA write: {error}"
+                        );
+                        break;
+                    }
+                }
+            }
         }
+
+        let cycles = self.peripheral_interface_mut().finish_dma(&dma);
+        self.scheduler.cancel(Event::PiDmaComplete);
+        self.scheduler.schedule_in(cycles, Event::PiDmaComplete);
+    }
+
+    /// Perform a SI DMA transfer between RDRAM and the PIF RAM
+    fn run_si_dma(&mut self, dma: SiDma) {
+        let SiDma {
+            dram_addr,
+            direction,
+        } = dma;
+        logging::debug!(SI, "SI DMA {direction:?} at RDRAM 0x{dram_addr:08x}");
+
+        match direction {
+            SiDmaDirection::RdramToPif => {
+                let mut block = [0u8; PIF_RAM_SIZE];
+                if let Some(rdram) = self.rdram_slice_mut(dram_addr, PIF_RAM_SIZE) {
+                    block.copy_from_slice(rdram);
+                } else {
+                    tracing::warn!(
+                        target: logging::target::SI,
+                        "Invalid SI DMA address: 0x{dram_addr:08x}"
+                    );
+                }
+                self.pif_mut().write_ram(&block);
+            }
+            SiDmaDirection::PifToRdram => {
+                let block = *self.pif().ram();
+                if let Some(rdram) = self.rdram_slice_mut(dram_addr, PIF_RAM_SIZE) {
+                    rdram.copy_from_slice(&block);
+                } else {
+                    tracing::warn!(
+                        target: logging::target::SI,
+                        "Invalid SI DMA address: 0x{dram_addr:08x}"
+                    );
+                }
+            }
+        }
+
+        self.serial_interface_mut().finish_dma();
+        self.mips_interface_mut().raise(mi_intr::SI);
     }
 }
 
@@ -48,6 +728,13 @@ impl MemoryUnit for MemoryManager {
             let s = self.units.get(src).unwrap();
             s.buffer().as_ptr()
         };
+        // the RSP memories are only reachable through the RSP thread lock
+        if let Some(GenericMemoryUnit::Rsp(rsp)) = self.units.get(dst) {
+            let mut rsp = rsp.lock();
+            let src = unsafe { std::slice::from_raw_parts(src, n) };
+            rsp.buffer_mut()[..n].copy_from_slice(src);
+            return;
+        }
         let dst = {
             let dst_unit = self.units.get_mut(dst).unwrap();
             dst_unit.buffer_mut().as_mut_ptr()
@@ -61,12 +748,13 @@ impl MemoryUnit for MemoryManager {
         I: MemInteger,
         O: ByteOrder,
     {
-        if let Some((offset, unit)) = self.units.get_offset_and_value(addr) {
-            let value = unit.read::<I, O>(offset);
-            return value;
-        }
-        tracing::warn!("No modules are handling memory address 0x{addr:08x}. This might led to UB");
-        I::default()
+        self.try_read::<I, O>(addr).unwrap_or_else(|error| {
+            tracing::warn!(
+                target: logging::target::MMU,
+                "Invalid read: {error}. This might led to UB"
+            );
+            I::default()
+        })
     }
 
     fn store<I, O>(&mut self, addr: usize, value: I)
@@ -74,15 +762,385 @@ impl MemoryUnit for MemoryManager {
         I: MemInteger,
         O: ByteOrder,
     {
-        match self.units.get_offset_and_value_mut(addr) {
-            Some((offset, unit)) => {
-                unit.store::<I, O>(offset, value);
+        if let Err(error) = self.try_store::<I, O>(addr, value) {
+            tracing::warn!(
+                target: logging::target::MMU,
+                "Invalid store: {error}. This might led to UB"
+            );
+        }
+    }
+
+    fn try_read<I, O>(&self, addr: usize) -> MemResult<I>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+
+        let (offset, unit) = self
+            .units
+            .get_offset_and_value(addr)
+            .ok_or(MemError::Unmapped(addr))?;
+
+        // report the full physical address instead of the unit offset
+        unit.try_read::<I, O>(offset)
+            .map_err(|error| error.with_addr(addr))
+    }
+
+    fn try_store<I, O>(&mut self, addr: usize, value: I) -> MemResult<()>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+
+        let (offset, unit) = self
+            .units
+            .get_offset_and_value_mut(addr)
+            .ok_or(MemError::Unmapped(addr))?;
+
+        unit.try_store::<I, O>(offset, value)
+            .map_err(|error| error.with_addr(addr))?;
+
+        // register writes may start a DMA transfer or acknowledge an interrupt
+        let mut dma = None;
+        let mut ai_dma = None;
+        let mut pi_dma = None;
+        let mut dp_list = None;
+        let mut framebuffer_dirty = false;
+        let mut sync_rsp = false;
+        let mut acknowledged = 0;
+        match unit {
+            GenericMemoryUnit::AudioInterface(ai) => {
+                ai_dma = ai.take_pending_dma();
+                if offset == ai_reg::STATUS {
+                    acknowledged = mi_intr::AI;
+                }
+            }
+            GenericMemoryUnit::PeripheralInterface(pi) => {
+                pi_dma = pi.take_pending_dma();
+                // bit 1 clears the interrupt
+                if offset == pi_reg::STATUS && value.to_u64() & 2 != 0 {
+                    acknowledged = mi_intr::PI;
+                }
+            }
+            GenericMemoryUnit::SerialInterface(si) => {
+                dma = si.take_pending_dma();
+                if offset == si_reg::STATUS {
+                    acknowledged = mi_intr::SI;
+                }
+            }
+            GenericMemoryUnit::BoxedSlice(_) => {
+                framebuffer_dirty = self
+                    .dp_framebuffer
+                    .as_ref()
+                    .is_some_and(|framebuffer| framebuffer.contains(&addr));
             }
-            None => {
-                tracing::warn!(
-                    "No modules are handling memory address 0x{addr:08x}. This might led to UB"
-                );
+            GenericMemoryUnit::Rdp(rdp) => dp_list = rdp.take_pending_list(),
+            GenericMemoryUnit::Rsp(_) => sync_rsp = true,
+            GenericMemoryUnit::VideoInterface(_) if offset == vi_reg::V_CURRENT => {
+                acknowledged = mi_intr::VI;
             }
+            _ => {}
+        }
+
+        if let Some(dma) = dma {
+            self.run_si_dma(dma);
+        }
+        if let Some(dma) = ai_dma {
+            self.run_ai_dma(dma);
+        }
+        if let Some(dma) = pi_dma {
+            self.run_pi_dma(dma);
+        }
+        if let Some(list) = dp_list {
+            self.run_dp_command_list(list);
+        }
+        if framebuffer_dirty {
+            self.rdp_mut().framebuffer_dirty(addr..addr + I::SIZE);
+        }
+        if sync_rsp {
+            self.sync_rsp();
         }
+        if acknowledged != 0 {
+            self.mips_interface_mut().clear(acknowledged);
+        }
+
+        Ok(())
+    }
+}
+
+/// The cartridge ROM isn't saved, only checked to be the same one. The
+/// watchpoints are debugger state and are kept as they are
+impl Snapshot for MemoryManager {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        w.write_u64::<BigEndian>(self.cartridge_crc())?;
+        write_bytes(w, self.rdram())?;
+        self.rsp().save(w)?;
+        self.rdp().save(w)?;
+        self.mips_interface().save(w)?;
+        self.video_interface().save(w)?;
+        self.audio_interface().save(w)?;
+        self.peripheral_interface().save(w)?;
+        self.serial_interface().save(w)?;
+        self.pif().save(w)?;
+        self.scheduler.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        if r.read_u64::<BigEndian>()? != self.cartridge_crc() {
+            return Err(SaveStateError::Mismatch("cartridge"));
+        }
+        read_bytes(r, self.rdram_mut()).map_err(|error| match error {
+            SaveStateError::Invalid(_) => SaveStateError::Mismatch("expansion pak"),
+            error => error,
+        })?;
+        self.rsp_mut().load(r)?;
+        self.rdp_mut().load(r)?;
+        self.mips_interface_mut().load(r)?;
+        self.video_interface_mut().load(r)?;
+        self.audio_interface_mut().load(r)?;
+        self.peripheral_interface_mut().load(r)?;
+        self.serial_interface_mut().load(r)?;
+        self.pif_mut().load(r)?;
+        self.scheduler.load(r)?;
+
+        self.watch_hit = None;
+        self.dp_framebuffer = self.rdp().framebuffer();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::BigEndian;
+
+    use crate::mmu::map::addr_map;
+
+    use super::*;
+
+    #[test]
+    fn it_should_report_invalid_memory_accesses() {
+        let cartridge = Cartridge {
+            data: vec![0u8; 0x1000].into_boxed_slice(),
+        };
+        let mut mmu = MemoryManager::new(cartridge);
+
+        assert_eq!(mmu.try_store::<u32, BigEndian>(0x10, 0xdead_beef), Ok(()));
+        assert_eq!(mmu.try_read::<u32, BigEndian>(0x10), Ok(0xdead_beef));
+        assert_eq!(mmu.try_read::<u16, BigEndian>(0x12), Ok(0xbeef));
+
+        assert_eq!(
+            mmu.try_read::<u32, BigEndian>(0x12),
+            Err(MemError::Misaligned {
+                addr: 0x12,
+                size: 4
+            })
+        );
+
+        let unused = 0x0080_0000;
+        assert_eq!(
+            mmu.try_read::<u32, BigEndian>(unused),
+            Err(MemError::Unmapped(unused))
+        );
+
+        let rom = *addr_map::phys::CART_D1A2_RANGE.start();
+        assert_eq!(
+            mmu.try_store::<u32, BigEndian>(rom, 0),
+            Err(MemError::ReadOnly(rom))
+        );
+        // the mapped range is larger than the ROM itself
+        assert_eq!(
+            mmu.try_read::<u32, BigEndian>(rom + 0x1000),
+            Err(MemError::Unmapped(rom + 0x1000))
+        );
+    }
+
+    #[test]
+    fn it_should_map_the_optional_hardware() {
+        let cartridge = Cartridge {
+            data: vec![0u8; 0x1000].into_boxed_slice(),
+        };
+        let mmu = MemoryManager::with_config(
+            cartridge,
+            MemoryConfig {
+                expansion_pak: false,
+                pif_rom: Some(vec![0x3c, 0x09, 0x34, 0x00].into_boxed_slice()),
+            },
+        );
+
+        assert_eq!(mmu.rdram().len(), RDRAM_SIZE_IN_BYTES);
+        assert_eq!(
+            mmu.try_read::<u32, BigEndian>(RDRAM_SIZE_IN_BYTES),
+            Err(MemError::Unmapped(RDRAM_SIZE_IN_BYTES))
+        );
+        let pif_rom = *addr_map::phys::PIF_ROM_RANGE.start();
+        assert_eq!(mmu.try_read::<u32, BigEndian>(pif_rom), Ok(0x3c09_3400));
+    }
+
+    #[test]
+    fn it_should_exchange_the_pif_ram_through_si_dma() {
+        use crate::io::serial::si_reg;
+
+        let cartridge = Cartridge {
+            data: vec![0u8; 0x1000].into_boxed_slice(),
+        };
+        let mut mmu = MemoryManager::new(cartridge);
+        let si_base = *addr_map::phys::SERIAL_INT_RANGE.start();
+        let pif_ram = *addr_map::phys::PIF_RAM_RANGE.start();
+
+        // controller info command on channel 0
+        mmu.store::<u32, BigEndian>(0x1000, 0x0103_00FF);
+        mmu.store::<u32, BigEndian>(0x1004, 0xFFFF_FE00);
+        mmu.store::<u32, BigEndian>(0x103C, 0x0000_0001);
+
+        mmu.store::<u32, BigEndian>(si_base + si_reg::DRAM_ADDR, 0x1000);
+        mmu.store::<u32, BigEndian>(si_base + si_reg::PIF_AD_WR64B, pif_ram as u32);
+        mmu.store::<u32, BigEndian>(si_base + si_reg::DRAM_ADDR, 0x2000);
+        mmu.store::<u32, BigEndian>(si_base + si_reg::PIF_AD_RD64B, pif_ram as u32);
+
+        assert_eq!(mmu.read::<u32, BigEndian>(0x2000), 0x0103_0005);
+        assert_eq!(mmu.read::<u32, BigEndian>(0x2004), 0x0002_FE00);
+    }
+
+    #[test]
+    fn it_should_run_dp_command_lists_through_the_rdp_backend() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::rdp::{dpc_reg, RdpBackend};
+
+        #[derive(Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl RdpBackend for Recorder {
+            fn process_command_list(&mut self, commands: &[u64], _rdram: &mut [u8]) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("process {}", commands.len()));
+            }
+            fn sync_full(&mut self, _rdram: &mut [u8]) {
+                self.0.lock().unwrap().push("sync".to_owned());
+            }
+            fn framebuffer_dirty(&mut self, range: Range<usize>) {
+                self.0.lock().unwrap().push(format!("dirty {range:x?}"));
+            }
+        }
+
+        let cartridge = Cartridge {
+            data: vec![0u8; 0x1000].into_boxed_slice(),
+        };
+        let mut mmu = MemoryManager::new(cartridge);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        mmu.rdp_mut()
+            .set_backend(Box::new(Recorder(Arc::clone(&calls))));
+
+        // 16x16 RGBA5551 color image at 0x2000, fill rectangle, sync full and
+        // a trailing fill color
+        let commands = [
+            0x3F10_000F_0000_2000u64,
+            0x2D00_0000_0004_0040,
+            0x3601_400C_0000_8004,
+            0x2900_0000_0000_0000,
+            0x3700_0000_FFFF_FFFF,
+        ];
+        for (i, command) in commands.iter().enumerate() {
+            mmu.store::<u64, BigEndian>(0x1000 + i * 8, *command);
+        }
+
+        let dp_base = *addr_map::phys::DP_CMD_REG_RANGE.start();
+        mmu.store::<u32, BigEndian>(dp_base + dpc_reg::START, 0x1000);
+        mmu.store::<u32, BigEndian>(dp_base + dpc_reg::END, 0x1028);
+        assert_eq!(
+            mmu.read::<u32, BigEndian>(dp_base + dpc_reg::CURRENT),
+            0x1028
+        );
+        assert_ne!(mmu.mips_interface().intr() & mi_intr::DP, 0);
+
+        mmu.store::<u16, BigEndian>(0x2010, 0xFFFF);
+        mmu.store::<u16, BigEndian>(0x3000, 0xFFFF);
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["process 4", "sync", "process 1", "dirty 2010..2012"]
+        );
+    }
+
+    #[test]
+    fn it_should_hold_pi_dma_completion_for_the_bus_timings() {
+        use crate::io::peripheral::{pi_reg, pi_status};
+
+        let mut rom = vec![0u8; 0x1000];
+        rom[0x100..0x104].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        let cartridge = Cartridge {
+            data: rom.into_boxed_slice(),
+        };
+        let mut mmu = MemoryManager::new(cartridge);
+        let pi_base = *addr_map::phys::PERIPHERAL_INT_RANGE.start();
+        let cart_base = *addr_map::phys::CART_D1A2_RANGE.start();
+
+        mmu.store::<u32, BigEndian>(pi_base + pi_reg::DRAM_ADDR, 0x2000);
+        mmu.store::<u32, BigEndian>(pi_base + pi_reg::CART_ADDR, (cart_base + 0x100) as u32);
+        mmu.store::<u32, BigEndian>(pi_base + pi_reg::WR_LEN, 0x1FF);
+
+        // the data is already there, but the transfer is still running
+        assert_eq!(mmu.read::<u32, BigEndian>(0x2000), 0xDEAD_BEEF);
+        let status = || pi_base + pi_reg::STATUS;
+        assert_eq!(
+            mmu.read::<u32, BigEndian>(status()) & pi_status::DMA_BUSY,
+            pi_status::DMA_BUSY
+        );
+
+        // 512 bytes are a single page of 256 words with the IPL timings
+        let cycles = (0x41 + 256 * (0x13 + 0x4)) * 3 / 2;
+        mmu.step_devices(cycles - 1);
+        assert_eq!(mmu.mips_interface().intr() & mi_intr::PI, 0);
+
+        mmu.step_devices(1);
+        assert_eq!(mmu.read::<u32, BigEndian>(status()), pi_status::INTERRUPT);
+        assert_eq!(mmu.mips_interface().intr() & mi_intr::PI, mi_intr::PI);
+
+        mmu.store::<u32, BigEndian>(status(), 0x2);
+        assert_eq!(mmu.mips_interface().intr() & mi_intr::PI, 0);
+    }
+    #[test]
+    fn it_should_restore_the_saved_devices() {
+        use crate::io::peripheral::pi_reg;
+
+        let new_mmu = |crc: u8| {
+            let mut rom = vec![0u8; 0x1000];
+            rom[0x10] = crc;
+            MemoryManager::new(Cartridge {
+                data: rom.into_boxed_slice(),
+            })
+        };
+        let pi_base = *addr_map::phys::PERIPHERAL_INT_RANGE.start();
+
+        let mut mmu = new_mmu(1);
+        mmu.store::<u32, BigEndian>(0x40, 0x1234_5678);
+        mmu.store::<u32, BigEndian>(pi_base + pi_reg::DRAM_ADDR, 0x2000);
+        mmu.mips_interface_mut().raise(mi_intr::VI);
+        mmu.step_devices(1000);
+        let mut state = Vec::new();
+        mmu.save(&mut state).unwrap();
+
+        let mut restored = new_mmu(1);
+        restored.load(&mut state.as_slice()).unwrap();
+        assert_eq!(restored.read::<u32, BigEndian>(0x40), 0x1234_5678);
+        assert_eq!(
+            restored.read::<u32, BigEndian>(pi_base + pi_reg::DRAM_ADDR),
+            0x2000
+        );
+        assert_eq!(restored.mips_interface().intr(), mi_intr::VI);
+        assert_eq!(restored.scheduler().now(), 1000);
+        assert_eq!(
+            restored.scheduler().next_event(),
+            mmu.scheduler().next_event()
+        );
+
+        assert!(matches!(
+            new_mmu(2).load(&mut state.as_slice()),
+            Err(SaveStateError::Mismatch(_))
+        ));
     }
 }
@@ -1,6 +1,8 @@
 pub mod map;
 pub mod memory;
 pub mod num;
+pub mod page_table;
+pub mod watchpoint;
 
 use std::fmt::Debug;
 
@@ -10,13 +12,53 @@ use enum_dispatch::enum_dispatch;
 pub use memory::MemoryManager;
 
 use self::num::MemInteger;
-use crate::io::Cartridge;
+use crate::io::{
+    AudioInterface, Cartridge, DiskDrive, IsViewer, MipsInterface, PeripheralInterface, Pif,
+    SerialInterface, VideoInterface,
+};
+use crate::rdp::Rdp;
+use crate::rsp::RspThread;
+
+/// Errors produced by the fallible memory access API
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemError {
+    #[error("No memory unit is mapped at address 0x{0:08x}")]
+    Unmapped(usize),
+    #[error("Address 0x{addr:08x} is not aligned to a {size}-byte boundary")]
+    Misaligned { addr: usize, size: usize },
+    #[error("Address 0x{0:08x} is read-only")]
+    ReadOnly(usize),
+}
+
+impl MemError {
+    /// Replace the address reported by the error
+    #[must_use]
+    pub fn with_addr(self, addr: usize) -> MemError {
+        match self {
+            MemError::Unmapped(_) => MemError::Unmapped(addr),
+            MemError::Misaligned { size, .. } => MemError::Misaligned { addr, size },
+            MemError::ReadOnly(_) => MemError::ReadOnly(addr),
+        }
+    }
+}
+
+pub type MemResult<T> = Result<T, MemError>;
 
 #[enum_dispatch(MemoryUnit)]
 #[derive(Debug)]
 enum GenericMemoryUnit {
     BoxedSlice(Box<[u8]>),
+    AudioInterface,
     Cartridge,
+    DiskDrive,
+    IsViewer,
+    MipsInterface,
+    PeripheralInterface,
+    Pif,
+    Rdp,
+    Rsp(RspThread),
+    SerialInterface,
+    VideoInterface,
 }
 
 #[enum_dispatch]
@@ -33,6 +75,34 @@ pub trait MemoryUnit {
         I: MemInteger,
         O: ByteOrder;
 
+    /// Read an integer `I` from address `addr`, reporting invalid accesses
+    ///
+    /// # Errors
+    /// `addr` is not aligned to `I::SIZE` bytes or is not mapped
+    fn try_read<I, O>(&self, addr: usize) -> MemResult<I>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+        Ok(self.read::<I, O>(addr))
+    }
+
+    /// Store an integer `value` of type `I` into address `addr`, reporting
+    /// invalid accesses
+    ///
+    /// # Errors
+    /// `addr` is not aligned to `I::SIZE` bytes, is not mapped or is read-only
+    fn try_store<I, O>(&mut self, addr: usize, value: I) -> MemResult<()>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+        self.store::<I, O>(addr, value);
+        Ok(())
+    }
+
     /// Copy `n` bytes from `src` to `dst`
     fn copy_from(&mut self, dst: usize, src: usize, n: usize) {
         self.buffer_mut().copy_within(src..src + n, dst);
@@ -64,6 +134,28 @@ impl MemoryUnit for Box<[u8]> {
         I::write_to::<O>(&mut self[addr..addr + I::SIZE], value);
     }
 
+    fn try_read<I, O>(&self, addr: usize) -> MemResult<I>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+        self.get(addr..addr + I::SIZE)
+            .map(I::read_from::<O>)
+            .ok_or(MemError::Unmapped(addr))
+    }
+
+    fn try_store<I, O>(&mut self, addr: usize, value: I) -> MemResult<()>
+    where
+        I: MemInteger,
+        O: ByteOrder,
+    {
+        check_alignment::<I>(addr)?;
+        self.get_mut(addr..addr + I::SIZE)
+            .map(|buf| I::write_to::<O>(buf, value))
+            .ok_or(MemError::Unmapped(addr))
+    }
+
     fn buffer(&self) -> &[u8] {
         self
     }
@@ -71,3 +163,19 @@ impl MemoryUnit for Box<[u8]> {
         self
     }
 }
+
+/// Check if `addr` is aligned to the size of `I`
+///
+/// # Errors
+/// `addr` is misaligned
+#[inline]
+pub fn check_alignment<I: MemInteger>(addr: usize) -> MemResult<()> {
+    if addr.is_multiple_of(I::SIZE) {
+        Ok(())
+    } else {
+        Err(MemError::Misaligned {
+            addr,
+            size: I::SIZE,
+        })
+    }
+}
@@ -8,6 +8,7 @@ pub trait MemInteger:
     const SIZE: usize;
 
     fn truncate_u64(n: u64) -> Self;
+    fn to_u64(self) -> u64;
     fn read_from<O: ByteOrder>(buf: &[u8]) -> Self;
     fn write_to<O: ByteOrder>(buf: &mut [u8], value: Self);
 }
@@ -18,6 +19,9 @@ impl MemInteger for u8 {
     fn truncate_u64(n: u64) -> Self {
         n as u8
     }
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
     fn read_from<O: ByteOrder>(buf: &[u8]) -> Self {
         buf[0]
     }
@@ -27,11 +31,14 @@ impl MemInteger for u8 {
 }
 
 impl MemInteger for u16 {
-    const SIZE: usize = 1;
+    const SIZE: usize = 2;
 
     fn truncate_u64(n: u64) -> Self {
         n as u16
     }
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
     fn read_from<O: ByteOrder>(buf: &[u8]) -> Self {
         O::read_u16(buf)
     }
@@ -46,6 +53,9 @@ impl MemInteger for u32 {
     fn truncate_u64(n: u64) -> Self {
         n as u32
     }
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
     fn read_from<O: ByteOrder>(buf: &[u8]) -> Self {
         O::read_u32(buf)
     }
@@ -62,6 +72,9 @@ impl MemInteger for u64 {
     fn truncate_u64(n: u64) -> Self {
         n as u64
     }
+    fn to_u64(self) -> u64 {
+        self
+    }
     fn read_from<O: ByteOrder>(buf: &[u8]) -> Self {
         O::read_u64(buf)
     }
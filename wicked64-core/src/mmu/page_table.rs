@@ -0,0 +1,127 @@
+use std::ops::Range;
+
+use crate::utils::btree_range::BTreeRange;
+
+/// Size of a physical memory page
+pub const PAGE_SIZE: usize = 4 * 1024;
+const PAGE_SHIFT: u32 = PAGE_SIZE.trailing_zeros();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageEntry {
+    Unmapped,
+    /// The whole page belongs to a single unit
+    Unit(u16),
+    /// The page is split between multiple units (e.g. PIF ROM and PIF RAM)
+    Shared,
+}
+
+#[derive(Debug)]
+struct MappedUnit<T> {
+    range: Range<usize>,
+    data: T,
+}
+
+/// Flat physical address lookup table.
+///
+/// Maps each 4KB physical page to the unit handling it, so resolving an
+/// address is a single index operation instead of a tree walk. The table is
+/// built once from a `BTreeRange` and its layout can't be changed later.
+#[derive(Debug)]
+pub struct PageTable<T> {
+    units: Vec<MappedUnit<T>>,
+    pages: Box<[PageEntry]>,
+}
+
+impl<T> PageTable<T> {
+    /// Build the page table from the given address ranges
+    ///
+    /// # Panics
+    /// More than `u16::MAX` ranges were given
+    pub fn new(ranges: BTreeRange<T>) -> Self {
+        let units = ranges
+            .into_iter()
+            .map(|(range, data)| MappedUnit { range, data })
+            .collect::<Vec<_>>();
+
+        let page_count = units
+            .iter()
+            .map(|unit| page_of(unit.range.end - 1) + 1)
+            .max()
+            .unwrap_or(0);
+        let mut pages = vec![PageEntry::Unmapped; page_count].into_boxed_slice();
+
+        for (index, unit) in units.iter().enumerate() {
+            let index = u16::try_from(index).expect("Too many memory units");
+            for page in page_of(unit.range.start)..=page_of(unit.range.end - 1) {
+                let page_range = page << PAGE_SHIFT..(page + 1) << PAGE_SHIFT;
+                let covers_page =
+                    unit.range.start <= page_range.start && unit.range.end >= page_range.end;
+
+                pages[page] = match pages[page] {
+                    PageEntry::Unmapped if covers_page => PageEntry::Unit(index),
+                    _ => PageEntry::Shared,
+                };
+            }
+        }
+
+        Self { units, pages }
+    }
+
+    pub fn get_offset_and_value(&self, addr: usize) -> Option<(usize, &T)> {
+        let index = self.find_unit(addr)?;
+        let unit = &self.units[index];
+        Some((addr - unit.range.start, &unit.data))
+    }
+    pub fn get_offset_and_value_mut(&mut self, addr: usize) -> Option<(usize, &mut T)> {
+        let index = self.find_unit(addr)?;
+        let unit = &mut self.units[index];
+        Some((addr - unit.range.start, &mut unit.data))
+    }
+
+    pub fn get(&self, addr: usize) -> Option<&T> {
+        self.get_offset_and_value(addr).map(|(_, value)| value)
+    }
+    pub fn get_mut(&mut self, addr: usize) -> Option<&mut T> {
+        self.get_offset_and_value_mut(addr).map(|(_, value)| value)
+    }
+
+    fn find_unit(&self, addr: usize) -> Option<usize> {
+        match self.pages.get(page_of(addr))? {
+            PageEntry::Unmapped => None,
+            &PageEntry::Unit(index) => Some(index as usize),
+            // shared pages are rare, a linear search is good enough
+            PageEntry::Shared => self
+                .units
+                .iter()
+                .position(|unit| unit.range.contains(&addr)),
+        }
+    }
+}
+
+#[inline]
+fn page_of(addr: usize) -> usize {
+    addr >> PAGE_SHIFT
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::map_ranges;
+
+    use super::*;
+
+    #[test]
+    fn it_should_resolve_units_sharing_a_page() {
+        let table = PageTable::new(map_ranges! {
+            0x0000..=0x1FFF => 'a',
+            0x3000..=0x37BF => 'b',
+            0x37C0..=0x37FF => 'c',
+        });
+
+        assert_eq!(table.get_offset_and_value(0x1FFC), Some((0x1FFC, &'a')));
+        assert_eq!(table.get(0x2000), None);
+        assert_eq!(table.get_offset_and_value(0x37BC), Some((0x7BC, &'b')));
+        assert_eq!(table.get_offset_and_value(0x37C0), Some((0, &'c')));
+        assert_eq!(table.get(0x3800), None);
+        assert_eq!(table.get(0x10_0000), None);
+    }
+}
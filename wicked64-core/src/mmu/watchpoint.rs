@@ -0,0 +1,135 @@
+use std::ops::RangeInclusive;
+
+/// Kind of guest memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Which accesses trigger a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    pub fn matches(self, access: AccessKind) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::ReadWrite, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WatchpointId(usize);
+
+/// A watched physical address range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<usize>,
+    pub kind: WatchKind,
+}
+
+/// Report of a guest access that hit a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: WatchpointId,
+    /// Physical address of the access
+    pub addr: usize,
+    pub access: AccessKind,
+    /// The value read or written
+    pub value: u64,
+}
+
+/// Set of data watchpoints
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    list: Vec<(WatchpointId, Watchpoint)>,
+    next_id: usize,
+}
+
+impl Watchpoints {
+    pub fn insert(&mut self, watchpoint: Watchpoint) -> WatchpointId {
+        let id = WatchpointId(self.next_id);
+        self.next_id += 1;
+        self.list.push((id, watchpoint));
+        id
+    }
+
+    pub fn remove(&mut self, id: WatchpointId) -> Option<Watchpoint> {
+        let index = self.list.iter().position(|(wp_id, _)| *wp_id == id)?;
+        Some(self.list.remove(index).1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (WatchpointId, &Watchpoint)> {
+        self.list.iter().map(|(id, wp)| (*id, wp))
+    }
+
+    /// Check if an access of `size` bytes at `addr` hits any watchpoint
+    pub fn check(
+        &self,
+        addr: usize,
+        size: usize,
+        access: AccessKind,
+        value: u64,
+    ) -> Option<WatchHit> {
+        let last = addr + size - 1;
+        self.list
+            .iter()
+            .find(|(_, wp)| {
+                wp.kind.matches(access) && *wp.range.start() <= last && addr <= *wp.range.end()
+            })
+            .map(|&(id, _)| WatchHit {
+                id,
+                addr,
+                access,
+                value,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_hit_overlapping_watchpoints() {
+        let mut watchpoints = Watchpoints::default();
+        let write = watchpoints.insert(Watchpoint {
+            range: 0x100..=0x103,
+            kind: WatchKind::Write,
+        });
+        let read = watchpoints.insert(Watchpoint {
+            range: 0x200..=0x200,
+            kind: WatchKind::ReadWrite,
+        });
+
+        assert_eq!(watchpoints.check(0x100, 4, AccessKind::Read, 0), None);
+        assert_eq!(
+            watchpoints
+                .check(0xFC, 8, AccessKind::Write, 0xFF)
+                .map(|hit| hit.id),
+            Some(write)
+        );
+        assert_eq!(watchpoints.check(0x104, 4, AccessKind::Write, 0), None);
+        assert_eq!(
+            watchpoints
+                .check(0x200, 1, AccessKind::Read, 0)
+                .map(|hit| hit.id),
+            Some(read)
+        );
+
+        assert!(watchpoints.remove(write).is_some());
+        assert_eq!(watchpoints.check(0x100, 4, AccessKind::Write, 0), None);
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::io::{controller::CONTROLLER_PORTS, ControllerState, InputSource};
+
+/// First bytes of every movie file
+pub const MAGIC: [u8; 4] = *b"W64M";
+/// Version of the movie format, increased on any layout change
+pub const VERSION: u32 = 1;
+
+/// Errors produced while loading a movie
+#[derive(thiserror::Error, Debug)]
+pub enum MovieError {
+    #[error("Not a movie")]
+    InvalidMagic,
+    #[error("Unsupported movie version {0} (expected {VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("The movie was recorded with another cartridge")]
+    CartridgeMismatch,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Input of a recorded frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInput {
+    /// Controller states served to the PIF during the whole frame
+    pub states: [ControllerState; CONTROLLER_PORTS],
+    /// Scheduler cycle the frame ended at, which checks that the replay
+    /// stays in sync
+    pub end_cycle: u64,
+}
+
+/// The input of a run started from a power-on, frame by frame.
+///
+/// All the timing comes from the scheduler, so replaying the same controller
+/// states on the same frames runs the game exactly as it was recorded. The
+/// save data of the cartridge and the paks is not part of the movie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// Header checksum of the cartridge the movie was recorded with
+    cartridge_crc: u64,
+    frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    pub fn new(cartridge_crc: u64) -> Movie {
+        Self {
+            cartridge_crc,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn cartridge_crc(&self) -> u64 {
+        self.cartridge_crc
+    }
+
+    pub fn frames(&self) -> &[FrameInput] {
+        &self.frames
+    }
+
+    /// Write the movie into `w`
+    ///
+    /// # Errors
+    /// `w` can't be written
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(&MAGIC)?;
+        w.write_u32::<BigEndian>(VERSION)?;
+        w.write_u64::<BigEndian>(self.cartridge_crc)?;
+        w.write_u32::<BigEndian>(self.frames.len() as u32)?;
+        for frame in &self.frames {
+            for state in &frame.states {
+                w.write_u16::<BigEndian>(state.buttons)?;
+                w.write_i8(state.stick_x)?;
+                w.write_i8(state.stick_y)?;
+            }
+            w.write_u64::<BigEndian>(frame.end_cycle)?;
+        }
+        w.flush()
+    }
+
+    /// Read a movie written by `save`
+    ///
+    /// # Errors
+    /// `r` can't be read or doesn't hold a supported movie
+    pub fn load<R: Read>(mut r: R) -> Result<Movie, MovieError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let version = r.read_u32::<BigEndian>()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let cartridge_crc = r.read_u64::<BigEndian>()?;
+        let len = r.read_u32::<BigEndian>()?;
+        let mut frames = Vec::new();
+        for _ in 0..len {
+            let mut frame = FrameInput::default();
+            for state in &mut frame.states {
+                state.buttons = r.read_u16::<BigEndian>()?;
+                state.stick_x = r.read_i8()?;
+                state.stick_y = r.read_i8()?;
+            }
+            frame.end_cycle = r.read_u64::<BigEndian>()?;
+            frames.push(frame);
+        }
+
+        Ok(Self {
+            cartridge_crc,
+            frames,
+        })
+    }
+}
+
+/// Progress of the running movie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieStatus {
+    Recording {
+        frames: usize,
+    },
+    Replaying {
+        frame: usize,
+        frames: usize,
+    },
+    /// Every frame of the movie was replayed. The controllers are released
+    Finished,
+    /// The frame ended on another cycle than when it was recorded, so the
+    /// replay is stopped. The controllers are released
+    Desynced {
+        frame: usize,
+    },
+}
+
+/// A movie being recorded or replayed.
+///
+/// The controller states are latched at the start of each frame, and served
+/// to the PIF by a `MovieInput` until the frame ends.
+#[derive(Debug)]
+pub(crate) struct MovieSession {
+    movie: Movie,
+    status: MovieStatus,
+    latched: [ControllerState; CONTROLLER_PORTS],
+    /// Input source of the frontend. It is polled while recording, and
+    /// given back when the movie stops
+    source: Option<Box<dyn InputSource>>,
+}
+
+impl MovieSession {
+    pub fn record(cartridge_crc: u64, source: Option<Box<dyn InputSource>>) -> MovieSession {
+        let mut session = Self {
+            movie: Movie::new(cartridge_crc),
+            status: MovieStatus::Recording { frames: 0 },
+            latched: Default::default(),
+            source,
+        };
+        session.latch_source();
+        session
+    }
+
+    pub fn replay(movie: Movie, source: Option<Box<dyn InputSource>>) -> MovieSession {
+        let mut session = Self {
+            status: MovieStatus::Replaying {
+                frame: 0,
+                frames: movie.frames.len(),
+            },
+            movie,
+            latched: Default::default(),
+            source,
+        };
+        session.latch_replayed(0);
+        session
+    }
+
+    pub fn status(&self) -> MovieStatus {
+        self.status
+    }
+
+    pub fn set_source(&mut self, source: Box<dyn InputSource>) {
+        self.source = Some(source);
+    }
+
+    /// End the movie, returning it with the frontend input source
+    pub fn finish(self) -> (Movie, Option<Box<dyn InputSource>>) {
+        (self.movie, self.source)
+    }
+
+    /// Called when a frame ends at the scheduler cycle `cycle`, to latch the
+    /// input of the next one
+    pub fn end_frame(&mut self, cycle: u64) {
+        match self.status {
+            MovieStatus::Recording { frames } => {
+                self.movie.frames.push(FrameInput {
+                    states: self.latched,
+                    end_cycle: cycle,
+                });
+                self.status = MovieStatus::Recording { frames: frames + 1 };
+                self.latch_source();
+            }
+            MovieStatus::Replaying { frame, frames } => {
+                if self.movie.frames[frame].end_cycle != cycle {
+                    tracing::warn!("The movie replay desynced at frame {frame}");
+                    self.status = MovieStatus::Desynced { frame };
+                    self.latched = Default::default();
+                    return;
+                }
+                self.status = MovieStatus::Replaying {
+                    frame: frame + 1,
+                    frames,
+                };
+                self.latch_replayed(frame + 1);
+            }
+            MovieStatus::Finished | MovieStatus::Desynced { .. } => {}
+        }
+    }
+
+    fn latch_source(&mut self) {
+        if let Some(source) = self.source.as_mut() {
+            for (port, state) in self.latched.iter_mut().enumerate() {
+                *state = source.poll(port);
+            }
+        }
+    }
+
+    fn latch_replayed(&mut self, frame: usize) {
+        if let Some(input) = self.movie.frames.get(frame) {
+            self.latched = input.states;
+        } else {
+            self.status = MovieStatus::Finished;
+            self.latched = Default::default();
+        }
+    }
+}
+
+/// Session of the running movie, shared by the emulator and the PIF
+pub(crate) type SharedSession = Arc<Mutex<MovieSession>>;
+
+/// Lock a shared session. A panic while it was locked can't leave it worse
+/// than a frame of input off, so the lock isn't poisoned
+pub(crate) fn lock(session: &SharedSession) -> MutexGuard<'_, MovieSession> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Input source of the PIF while a movie runs
+#[derive(Debug)]
+pub(crate) struct MovieInput(pub SharedSession);
+
+impl InputSource for MovieInput {
+    fn poll(&mut self, port: usize) -> ControllerState {
+        lock(&self.0).latched[port]
+    }
+
+    fn set_rumble(&mut self, port: usize, on: bool) {
+        if let Some(source) = lock(&self.0).source.as_mut() {
+            source.set_rumble(port, on);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_reload_a_saved_movie() {
+        let mut movie = Movie::new(0x1234);
+        movie.frames.push(FrameInput {
+            states: [ControllerState {
+                buttons: 0x8000,
+                stick_x: -80,
+                stick_y: 12,
+            }; CONTROLLER_PORTS],
+            end_cycle: 1_562_500,
+        });
+
+        let mut file = Vec::new();
+        movie.save(&mut file).unwrap();
+        assert_eq!(Movie::load(file.as_slice()).unwrap(), movie);
+        assert!(matches!(
+            Movie::load(&b"W64S\0\0\0\x01"[..]),
+            Err(MovieError::InvalidMagic)
+        ));
+    }
+}
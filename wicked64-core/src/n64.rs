@@ -1,88 +1,732 @@
-use std::{cell::RefCell, marker::PhantomData, ops::RangeInclusive, path::Path, rc::Rc};
+use std::{
+    io::{BufRead, Read, Write},
+    marker::PhantomData,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 
 use crate::{
-    cpu::Cpu,
-    io::Cartridge,
-    jit::{Interruption, JitEngine},
-    mmu::MemoryManager,
+    cpu::{signals::ResetKind, Cpu},
+    frontend::{AudioSink, InputSource, VideoSink},
+    io::isviewer::TextCallback,
+    io::{
+        audio::AudioBuffer, controller::pak::Pak, pif::joybus::JoybusDevice, video::Frame,
+        Controller,
+    },
+    jit::{
+        BlockExit, CompiledBlock, DirtyPages, Interruption, JitEngine, LinkCounters,
+        UnimplementedInstruction,
+    },
+    logging::{self, LogTargets},
+    mmu::{
+        memory::DeviceEvents,
+        watchpoint::{WatchHit, WatchKind, WatchpointId},
+        MemoryManager, MemoryUnit,
+    },
+    movie::{self, Movie, MovieError, MovieInput, MovieSession, MovieStatus, SharedSession},
+    rdp::RdpBackend,
+    rsp::hle::{Hle, HleTaskHandler},
+    savestate::{self, SaveStateResult, Snapshot},
+    scheduler::Event,
+    trace::{self, Divergence, TraceEntry, TraceError},
 };
 
+mod builder;
+mod stats;
+mod sync;
+
+pub use builder::{N64Builder, TraceOptions};
+pub use stats::Stats;
+pub use sync::SyncState;
+
+use stats::StatsCounter;
+
+/// CP0 cause bit of the Count/Compare timer interrupt
+const CAUSE_IP7: u64 = 1 << 15;
+
 /// N64 state
 pub struct N64<O: ByteOrder> {
-    state: Rc<RefCell<State>>,
+    state: SyncState,
     jit: JitEngine,
-    #[allow(unused)]
+    /// Total CPU cycles executed
     clocks: usize,
+    video_sink: Option<Box<dyn VideoSink>>,
+    /// Kept alive for as long as the machine pushes samples to it
+    audio_sink: Option<Box<dyn AudioSink>>,
+    /// Movie being recorded or replayed
+    movie: Option<SharedSession>,
+    stats: StatsCounter,
     _marker: PhantomData<O>,
 }
 
+/// Stop conditions of `N64::run_until`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// The CPU is about to run the instruction at this virtual address
+    PcReaches(u64),
+    /// The 32-bit word at this physical address holds `value`. It is checked
+    /// between the compiled blocks
+    MemoryEquals { addr: usize, value: u32 },
+    /// This many CPU cycles were run since the call
+    CycleBudget(usize),
+    /// The guest accessed the memory watched by this watchpoint
+    Watch(WatchpointId),
+}
+
 impl<O: ByteOrder> N64<O> {
-    /// Create a new N64 virtual machine
+    /// Create a new N64 virtual machine with the default configuration
     ///
     /// # Errors
     /// Any
     pub fn new<P: AsRef<Path>>(rom_path: P) -> anyhow::Result<Self> {
-        tracing::info!("Creating a brand new N64!");
+        Self::builder().build(rom_path)
+    }
 
-        let mut mmu = MemoryManager::new(Cartridge::open(rom_path)?);
-        let cpu = Cpu::new(true, &mut mmu);
+    /// Configure a new N64 virtual machine
+    pub fn builder() -> N64Builder<O> {
+        N64Builder::new()
+    }
 
-        let state = Rc::new(RefCell::new(State::new(mmu, cpu)));
+    /// The machine state, which may be cloned into another thread to access
+    /// it while the machine runs
+    pub fn state(&self) -> &SyncState {
+        &self.state
+    }
 
-        Ok(Self {
-            state: state.clone(),
-            clocks: 0,
-            jit: JitEngine::new(state),
-            _marker: PhantomData::default(),
-        })
+    /// Set the source of the controllers input. While a movie runs, it
+    /// takes effect when the movie stops
+    pub fn set_input_source<I: InputSource + 'static>(&mut self, input: I) {
+        if let Some(movie) = &self.movie {
+            movie::lock(movie).set_source(Box::new(input));
+            return;
+        }
+        self.state
+            .lock()
+            .mmu
+            .pif_mut()
+            .set_input_source(Box::new(input));
     }
 
-    pub fn state(&self) -> &Rc<RefCell<State>> {
-        &self.state
+    /// Power cycle the console and record the input of the following frames
+    /// into a movie. The input source is polled once at the start of each
+    /// frame
+    pub fn record_movie(&mut self) {
+        self.stop_movie();
+        self.reset(ResetKind::Power);
+
+        let (crc, source) = {
+            let mut state = self.state.lock();
+            let crc = state.mmu.cartridge_crc();
+            (crc, state.mmu.pif_mut().take_input_source())
+        };
+        self.start_movie(MovieSession::record(crc, source));
+    }
+
+    /// Power cycle the console and replay the input of `movie` instead of
+    /// the input source. The emulation only stays in sync if it is run with
+    /// the same calls (e.g. `run_frame`) as when the movie was recorded
+    ///
+    /// # Errors
+    /// The movie was recorded with another cartridge
+    pub fn play_movie(&mut self, movie: Movie) -> anyhow::Result<()> {
+        if movie.cartridge_crc() != self.state.lock().mmu.cartridge_crc() {
+            return Err(MovieError::CartridgeMismatch.into());
+        }
+        self.stop_movie();
+        self.reset(ResetKind::Power);
+
+        let source = self.state.lock().mmu.pif_mut().take_input_source();
+        self.start_movie(MovieSession::replay(movie, source));
+        Ok(())
+    }
+
+    pub fn movie_status(&self) -> Option<MovieStatus> {
+        self.movie.as_ref().map(|movie| movie::lock(movie).status())
+    }
+
+    /// Stop the running movie, giving the controllers back to the input
+    /// source. Returns the recorded or replayed movie
+    ///
+    /// # Panics
+    /// The movie input was taken out of the PIF
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        let session = self.movie.take()?;
+        let mut state = self.state.lock();
+        let pif = state.mmu.pif_mut();
+        // drop the `MovieInput`, which shares the session
+        pif.take_input_source();
+
+        let session = Arc::try_unwrap(session)
+            .expect("The movie session should only be shared with the PIF")
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (movie, source) = session.finish();
+        if let Some(source) = source {
+            pif.set_input_source(source);
+        }
+        Some(movie)
+    }
+
+    fn start_movie(&mut self, session: MovieSession) {
+        let session = Arc::new(Mutex::new(session));
+        self.state
+            .lock()
+            .mmu
+            .pif_mut()
+            .set_input_source(Box::new(MovieInput(session.clone())));
+        self.movie = Some(session);
+    }
+
+    /// Attach the audio output. The samples played by the AI are resampled to
+    /// the sink sample rate and pushed into the buffer given to the sink
+    pub fn set_audio_sink<S: AudioSink + ?Sized>(&mut self, sink: &mut S) {
+        let buffer = AudioBuffer::new();
+        sink.attach(buffer.clone());
+        self.state
+            .lock()
+            .mmu
+            .audio_interface_mut()
+            .set_output(buffer, sink.sample_rate());
+    }
+
+    /// Replace the renderer of the RDP commands, which defaults to the
+    /// built-in software rasterizer
+    pub fn set_rdp_backend<B: RdpBackend + 'static>(&mut self, backend: B) {
+        self.state
+            .lock()
+            .mmu
+            .rdp_mut()
+            .set_backend(Box::new(backend));
+    }
+
+    /// Enable or disable the high-level emulation of the RSP graphics and
+    /// audio tasks. The default HLE completes the tasks without running
+    /// them, and handlers can be replaced with `set_hle_task_handler`
+    pub fn set_rsp_hle(&mut self, enabled: bool) {
+        let hle = enabled.then(Hle::new);
+        self.state.lock().mmu.rsp_mut().set_hle(hle);
+    }
+
+    /// Set the high-level implementation of the RSP tasks of type
+    /// `task_type`, enabling the HLE if needed
+    pub fn set_hle_task_handler<H: HleTaskHandler + 'static>(
+        &mut self,
+        task_type: u32,
+        handler: H,
+    ) {
+        let mut state = self.state.lock();
+        let mut rsp = state.mmu.rsp_mut();
+        if rsp.hle().is_none() {
+            rsp.set_hle(Some(Hle::new()));
+        }
+        if let Some(hle) = rsp.hle_mut() {
+            hle.set_handler(task_type, Box::new(handler));
+        }
+    }
+
+    /// Total CPU cycles executed so far
+    pub fn clocks(&self) -> usize {
+        self.clocks
+    }
+
+    /// Attach the video output, presented with each displayed field
+    pub fn set_video_sink<V: VideoSink + 'static>(&mut self, sink: V) {
+        self.video_sink = Some(Box::new(sink));
+    }
+
+    /// Set a callback invoked once per displayed field with the current
+    /// frame. Shorthand for a closure `VideoSink`
+    pub fn set_frame_callback<F: FnMut(Option<Frame>) + 'static>(&mut self, callback: F) {
+        self.set_video_sink(callback);
+    }
+
+    /// Set a callback receiving the text printed by the game through the
+    /// IS-Viewer debug port, as test ROMs and homebrew do
+    pub fn set_isviewer_callback<F: FnMut(&str) + Send + 'static>(&mut self, callback: F) {
+        let callback: Box<TextCallback> = Box::new(callback);
+        self.state.lock().mmu.isviewer_mut().set_callback(callback);
+    }
+
+    /// Performance counters, such as the guest MIPS and the JIT cache
+    /// efficiency. The per-frame values are updated at the end of each frame
+    pub fn stats(&self) -> Stats {
+        self.stats
+            .stats(self.jit.stats(), self.state.lock().bridge_calls)
+    }
+
+    /// Only emit the debug logs of the `targets` subsystems, such as
+    /// `LogTargets::JIT`. The subscriber still filters the emitted logs, and
+    /// the targets are shared by every instance, just like the subscriber
+    pub fn set_trace(&mut self, targets: LogTargets) {
+        logging::set_enabled(targets);
+    }
+
+    /// Subsystems whose debug logs are emitted
+    pub fn trace_targets(&self) -> LogTargets {
+        logging::enabled()
+    }
+
+    /// Advance the devices by `cycles` CPU cycles
+    fn step_devices(&mut self, cycles: usize) -> DeviceEvents {
+        self.clocks += cycles;
+
+        let events = {
+            let mut state = self.state.lock();
+            state.cpu.clocks += cycles as u64;
+            let start = state.mmu.scheduler().now();
+            let events = state.mmu.step_devices(cycles as u64);
+            state.update_count(start);
+            if events.compare {
+                state.cpu.cp0.cause |= CAUSE_IP7;
+            }
+            state.sync_compare_event();
+            state.update_interrupt_pending();
+            state.dispatch_interrupt();
+            events
+        };
+
+        if events.frame {
+            self.stats.end_frame();
+            if let Some(movie) = &self.movie {
+                let now = self.state.lock().mmu.scheduler().now();
+                movie::lock(movie).end_frame(now);
+            }
+            if let Some(sink) = self.video_sink.as_mut() {
+                let frame = {
+                    let state = self.state.lock();
+                    state.mmu.video_interface().framebuffer(state.mmu.rdram())
+                };
+                sink.present(frame);
+            }
+        }
+
+        events
+    }
+
+    /// Get the frame currently displayed by the VI, converted to RGBA8.
+    /// Returns `None` if the video output is blanked
+    pub fn framebuffer(&self) -> Option<Frame> {
+        let state = self.state.lock();
+        state.mmu.video_interface().framebuffer(state.mmu.rdram())
+    }
+
+    /// Connect `device` to the joybus `channel`, returning the previously
+    /// connected device. Channels `0..=3` are the controller ports and channel
+    /// 4 is the cartridge EEPROM
+    ///
+    /// # Errors
+    /// The device can't be connected to the given channel
+    pub fn connect_device(
+        &mut self,
+        channel: usize,
+        device: JoybusDevice,
+    ) -> anyhow::Result<JoybusDevice> {
+        Ok(self.state.lock().mmu.pif_mut().connect(channel, device)?)
+    }
+
+    /// Insert a pak into the controller plugged into `port`, returning the
+    /// previously inserted one. A Game Boy cartridge can be attached through
+    /// a `Pak::Transfer`
+    ///
+    /// # Errors
+    /// No controller is plugged into `port`
+    pub fn insert_pak(&mut self, port: usize, pak: Pak) -> anyhow::Result<Option<Pak>> {
+        let mut state = self.state.lock();
+        let controller = state
+            .mmu
+            .pif_mut()
+            .controller_mut(port)
+            .ok_or_else(|| anyhow::anyhow!("No controller plugged into port {port}"))?;
+        let previous = controller.remove_pak();
+        controller.insert_pak(pak);
+        Ok(previous)
+    }
+
+    /// Remove the pak from the controller plugged into `port`. Frontends can
+    /// use it to persist the save data of a transfer pak cartridge
+    pub fn remove_pak(&mut self, port: usize) -> Option<Pak> {
+        self.state
+            .lock()
+            .mmu
+            .pif_mut()
+            .controller_mut(port)
+            .and_then(Controller::remove_pak)
+    }
+
+    /// Reset the console, as with its power switch (`Power`) or reset button
+    /// (`Soft`). The PIF boot process is simulated again and the compiled
+    /// code is dropped
+    pub fn reset(&mut self, kind: ResetKind) {
+        tracing::info!("Resetting the N64: {kind:?}");
+
+        self.state.lock().reset(kind);
+        self.jit.flush();
+    }
+
+    /// Write the state of the whole machine into `writer`. Host attachments
+    /// (input source, audio sink, RDP backend, frame callback) are not saved
+    ///
+    /// # Errors
+    /// `writer` can't be written
+    pub fn save_state<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        savestate::write_header(&mut writer)?;
+        writer.write_u64::<BigEndian>(self.clocks as u64)?;
+        self.state.lock().save(&mut writer)?;
+        Ok(())
+    }
+
+    /// Restore a state written by `save_state` for the same cartridge, and
+    /// drop all the compiled code
+    ///
+    /// # Errors
+    /// `reader` can't be read, or holds a savestate of another version or
+    /// cartridge. The machine is left in an unspecified state if the
+    /// savestate header is valid but its content is not
+    pub fn load_state<R: Read>(&mut self, mut reader: R) -> anyhow::Result<()> {
+        savestate::read_header(&mut reader)?;
+        self.clocks = reader.read_u64::<BigEndian>()? as usize;
+        self.state.lock().load(&mut reader)?;
+        self.jit.flush();
+        Ok(())
+    }
+
+    /// Stop the execution before the CPU runs the instruction at the virtual
+    /// address `addr`. Running the machine stops at the breakpoint until
+    /// `resume` is called
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.jit.add_breakpoint(addr);
+    }
+
+    /// Remove the breakpoint at `addr`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.jit.remove_breakpoint(addr)
+    }
+
+    /// Virtual address of the breakpoint the execution is paused at
+    pub fn breakpoint_hit(&self) -> Option<u64> {
+        match self.state.lock().interruption {
+            Interruption::Debug(addr) => Some(addr),
+            Interruption::None => None,
+        }
+    }
+
+    /// Pause the execution right after a guest access of the given kind to
+    /// the physical addresses `range`. Blocks are split after each memory
+    /// access while watchpoints are set, which makes the execution slower
+    pub fn watch(&mut self, range: RangeInclusive<usize>, kind: WatchKind) -> WatchpointId {
+        self.jit.set_watching(true);
+        self.state.lock().mmu.add_watchpoint(range, kind)
+    }
+
+    /// Remove a watchpoint, returning whether it existed
+    pub fn unwatch(&mut self, id: WatchpointId) -> bool {
+        let (removed, watching) = {
+            let mut state = self.state.lock();
+            let removed = state.mmu.remove_watchpoint(id).is_some();
+            (removed, !state.mmu.watchpoints().is_empty())
+        };
+        self.jit.set_watching(watching);
+        removed
+    }
+
+    /// Report of the guest access the execution is paused after
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.state.lock().mmu.watch_hit()
+    }
+
+    /// Take the report of the last instruction the JIT couldn't compile, which
+    /// raised a reserved instruction exception
+    pub fn take_unimplemented_instruction(&mut self) -> Option<UnimplementedInstruction> {
+        self.state.lock().unimplemented.take()
     }
 
-    /// Step the execution of the current running game
+    /// Whether the execution is paused by a breakpoint or a watchpoint
+    pub fn is_paused(&self) -> bool {
+        self.breakpoint_hit().is_some() || self.watch_hit().is_some()
+    }
+
+    /// Continue the execution paused at a breakpoint or after a watchpoint
+    /// hit. The instruction at the breakpoint runs before it can be hit
+    /// again
+    pub fn resume(&mut self) {
+        let mut state = self.state.lock();
+        if let Interruption::Debug(addr) = state.interruption {
+            state.interruption = Interruption::None;
+            self.jit.resume_breakpoint(addr);
+        }
+        state.mmu.take_watch_hit();
+    }
+
+    /// Step the execution of the current running game, until a breakpoint
+    /// or a watchpoint is hit
     pub fn cycle(&mut self) {
-        loop {
-            self.jit.invalidate_cache();
+        while !self.is_paused() {
+            self.run_block(usize::MAX);
+        }
+    }
+
+    /// Run for at least `cycles` CPU cycles, returning the number of cycles
+    /// actually run. It can be a bit more, as the last instruction always
+    /// completes, or less if the execution is paused
+    pub fn run_for_cycles(&mut self, cycles: usize) -> usize {
+        let start = self.clocks;
+        while self.clocks - start < cycles && !self.is_paused() {
+            let remaining = cycles - (self.clocks - start);
+            self.run_block(remaining);
+        }
+        self.clocks - start
+    }
 
-            // handle interruptions
-            let mut resume_jump = None;
+    /// Run until the VI finishes scanning out the current field or the
+    /// execution is paused, returning the number of CPU cycles run
+    pub fn run_frame(&mut self) -> usize {
+        let start = self.clocks;
+        while !self.is_paused() && !self.run_block(usize::MAX).frame {}
+        self.clocks - start
+    }
+
+    /// Run a single instruction, returning the number of CPU cycles it took.
+    /// A jump runs along with its delay slot. It resumes the execution if it
+    /// was paused
+    pub fn step_instruction(&mut self) -> usize {
+        let start = self.clocks;
+        self.resume();
+        self.run_block(1);
+        self.clocks - start
+    }
+
+    /// Run a single instruction, returning the registers it changed
+    pub fn step_traced(&mut self) -> TraceEntry {
+        let (pc, opcode, before) = {
+            let state = self.state.lock();
+            let pc = state.cpu.pc;
+            let opcode = state
+                .mmu
+                .read::<u32, BigEndian>(state.cpu.translate_virtual(pc) as usize);
+            (pc, opcode, trace::traced_registers(&state.cpu))
+        };
+        self.step_instruction();
+
+        let after = trace::traced_registers(&self.state.lock().cpu);
+        TraceEntry {
+            pc,
+            opcode,
+            changes: trace::changed_registers(&before, &after),
+        }
+    }
+
+    /// Run `count` instructions, writing their trace into `writer`
+    ///
+    /// # Errors
+    /// `writer` can't be written
+    pub fn write_trace<W: Write>(&mut self, count: usize, mut writer: W) -> anyhow::Result<()> {
+        for _ in 0..count {
+            writeln!(writer, "{}", self.step_traced())?;
+        }
+        Ok(())
+    }
+
+    /// Run an instruction for each entry of the `reference` trace, stopping
+    /// at the first one that differs
+    ///
+    /// # Errors
+    /// `reference` can't be read or is not a valid trace
+    pub fn compare_trace<R: BufRead>(
+        &mut self,
+        reference: R,
+    ) -> anyhow::Result<Option<Divergence>> {
+        let mut index = 0;
+        for (line_index, line) in reference.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let expected = TraceEntry::parse(&line).map_err(|reason| TraceError::Parse {
+                line: line_index + 1,
+                reason,
+            })?;
+
+            let actual = self.step_traced();
+            if actual != expected {
+                return Ok(Some(Divergence {
+                    index,
+                    expected,
+                    actual,
+                }));
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    /// Run until one of `conditions` is met, returning it. Without a
+    /// `CycleBudget`, it runs for as long as no condition is met. Hit
+    /// breakpoints and watchpoints are reported as `PcReaches` and `Watch`
+    /// conditions, even if they were not requested
+    pub fn run_until(&mut self, conditions: &[Condition]) -> Condition {
+        let start = self.clocks;
+        loop {
+            if let Some(addr) = self.breakpoint_hit() {
+                return Condition::PcReaches(addr);
+            }
+            if let Some(hit) = self.watch_hit() {
+                return Condition::Watch(hit.id);
+            }
+            let ran = self.clocks - start;
+            let mut budget = usize::MAX;
+            let mut breakpoints = Vec::new();
             {
-                let interruption = self.state.borrow_mut().interruption.take();
-                match interruption {
-                    Interruption::PrepareJump(addr) => {
-                        tracing::debug!("Resolving jump to: 0x{addr:08x}");
-                        self.state.borrow_mut().cpu.pc = addr;
-                        resume_jump = Some(self.jit.resolve_jump(addr));
+                let state = self.state.lock();
+                let pc = state.cpu.pc;
+                for &condition in conditions {
+                    let met = match condition {
+                        Condition::PcReaches(addr) => {
+                            breakpoints.push(addr);
+                            pc == addr
+                        }
+                        Condition::MemoryEquals { addr, value } => {
+                            state.mmu.read::<u32, BigEndian>(addr) == value
+                        }
+                        Condition::CycleBudget(cycles) => {
+                            budget = budget.min(cycles.saturating_sub(ran));
+                            ran >= cycles
+                        }
+                        Condition::Watch(_) => false,
+                    };
+                    if met {
+                        return condition;
                     }
-                    Interruption::None => {}
                 }
             }
 
-            tracing::debug!("CPU PC: {:08x}", self.state.borrow().cpu.pc);
-
-            if let Some(jump_entry) = resume_jump.take().flatten() {
-                let target = jump_entry.target_block;
-                self.jit.resume_from(target);
-            } else {
-                let code = self.jit.compile_current_pc();
-                tracing::debug!("Executing code at {:p}", code.ptr());
-                code.execute();
+            // single step through the blocks holding a PC condition. Bounded
+            // blocks are a prefix of the cached one, so it covers them too
+            if !breakpoints.is_empty() {
+                let block = self.jit.compile_current_pc();
+                let range = block.start_pc()..block.start_pc() + block.len() as u64;
+                let hit = breakpoints.iter().any(|addr| range.contains(addr));
+                if hit {
+                    self.step_instruction();
+                    continue;
+                }
             }
+            self.run_block(budget);
+        }
+    }
+
+    /// Run a block of at most `max_cycles` cycles, then advance the devices.
+    /// The blocks are bounded by the JIT block cycles, except for the idle
+    /// loops, which are skipped up to the next device event
+    fn run_block(&mut self, max_cycles: usize) -> DeviceEvents {
+        let start = Instant::now();
+        let events = self.run_jit_block(max_cycles);
+        self.stats.add_host_time(start.elapsed());
+        events
+    }
+
+    fn run_jit_block(&mut self, budget: usize) -> DeviceEvents {
+        let max_cycles = budget.min(self.jit.block_cycles());
+        self.jit.invalidate_cache();
+
+        // paused until `resume` is called
+        if self.is_paused() {
+            return DeviceEvents::default();
+        }
+
+        let pc = self.state.lock().cpu.pc;
+        if self.jit.hits_breakpoint(pc) {
+            logging::debug!(JIT, "Breakpoint hit at 0x{pc:08x}");
+            self.state.lock().interruption = Interruption::Debug(pc);
+            return DeviceEvents::default();
+        }
+
+        logging::debug!(CPU, "CPU PC: {:08x}", self.state.lock().cpu.pc);
+
+        let code = self.jit.compile_bounded(max_cycles);
+        logging::debug!(JIT, "Executing code at {:p}", code.ptr());
+        self.start_link(max_cycles, &code);
+        let start = Instant::now();
+        let exit = code.execute();
+        code.profile().add_host_time(start.elapsed());
+        self.prepare_exit(exit);
+        let cycles = self.end_link();
+        let idle = self.idle_cycles(cycles, budget);
+        self.step_devices(cycles + idle)
+    }
+
+    /// Continue at the guest address the compiled code returned, whose block
+    /// is compiled on the next step. The guest registers are synced, only
+    /// the PC is left
+    fn prepare_exit(&self, exit: BlockExit) {
+        if let BlockExit::Jump(addr) = exit {
+            self.state.lock().cpu.pc = addr;
         }
     }
+
+    /// Cycles the CPU can skip after running `ran` cycles, if it's stuck in
+    /// an idle loop: nothing changes until the next device event. The skipped
+    /// cycles are bounded by the rest of `budget`
+    fn idle_cycles(&self, ran: usize, budget: usize) -> usize {
+        let pc = self.state.lock().cpu.pc;
+        if !self.jit.is_idle_loop(pc) {
+            return 0;
+        }
+        let max_cycles = budget.saturating_sub(ran);
+        let state = self.state.lock();
+        let scheduler = state.mmu.scheduler();
+        let now = scheduler.now() + ran as u64;
+        let skipped = scheduler
+            .next_event()
+            .map_or(max_cycles, |at| at.saturating_sub(now) as usize)
+            .min(max_cycles);
+        logging::debug!(JIT, "Skipping an idle loop for {skipped} cycles");
+        skipped
+    }
+
+    /// Let the blocks linked to `block` run for the rest of `max_cycles`. An
+    /// idle loop runs once, before being skipped
+    fn start_link(&mut self, max_cycles: usize, block: &CompiledBlock) {
+        let budget = if block.is_idle_loop() {
+            0
+        } else {
+            max_cycles.saturating_sub(block.cycles())
+        };
+        self.state.lock().link = LinkCounters::new(budget);
+    }
+
+    /// Count the instructions run since `start_link`, returning the cycles
+    /// taken
+    fn end_link(&mut self) -> usize {
+        let link = self.state.lock().link;
+        self.stats.add_instructions(link.instructions as usize);
+        link.cycles as usize
+    }
 }
 
 #[derive(Debug)]
 pub struct State {
     pub mmu: MemoryManager,
     pub cpu: Cpu<BigEndian>,
-    pub cache_invalidation: Option<RangeInclusive<usize>>,
+    /// Pages written by the guest, whose compiled blocks are dropped
+    pub dirty_pages: DirtyPages,
     pub interruption: Interruption,
-    pub resume_addr: u64,
+    /// Calls from the compiled code into the emulator
+    pub bridge_calls: u64,
+    /// Blocks run since the host started one, and through direct jumps
+    pub link: LinkCounters,
+    /// An enabled interrupt is pending. The linked blocks return to the host,
+    /// which takes it
+    pub pending_interrupt: bool,
+    /// Instruction the JIT couldn't compile, until the frontend takes it
+    pub unimplemented: Option<UnimplementedInstruction>,
+    /// Compare value the `CountCompare` event is scheduled for
+    scheduled_compare: Option<u64>,
+    /// The PIF boot process is simulated on resets
+    simulate_pif: bool,
 }
 
 impl State {
@@ -90,21 +734,106 @@ impl State {
         Self {
             mmu,
             cpu,
-            cache_invalidation: None,
+            dirty_pages: DirtyPages::new(),
+            bridge_calls: 0,
+            link: LinkCounters::default(),
+            pending_interrupt: false,
+            unimplemented: None,
             interruption: Interruption::None,
-            resume_addr: 0,
+            scheduled_compare: None,
+            simulate_pif: true,
         }
     }
+    /// Reset the devices, then the CPU, which simulates the PIF boot process
+    /// again unless the machine boots from the PIF ROM
+    pub fn reset(&mut self, kind: ResetKind) {
+        self.mmu.reset(kind);
+        self.cpu.reset(kind, self.simulate_pif, &mut self.mmu);
+
+        self.dirty_pages.clear();
+        self.interruption = Interruption::None;
+        self.scheduled_compare = None;
+        self.sync_compare_event();
+        self.update_interrupt_pending();
+    }
+
     pub fn translate_cpu_pc(&self) -> u64 {
         self.cpu.translate_virtual(self.cpu.pc)
     }
+
+    /// Increment CP0 Count, every other cycle, for the cycles elapsed since
+    /// the scheduler cycle `since`
+    pub fn update_count(&mut self, since: u64) {
+        let ticks = self.mmu.scheduler().now() / 2 - since / 2;
+        self.cpu.cp0.count = (self.cpu.cp0.count + ticks) & 0xFFFF_FFFF;
+    }
+
+    /// Reschedule the `CountCompare` event when CP0 Compare changed
+    pub fn sync_compare_event(&mut self) {
+        let compare = self.cpu.cp0.compare & 0xFFFF_FFFF;
+        if self.scheduled_compare == Some(compare) {
+            return;
+        }
+        self.scheduled_compare = Some(compare);
+
+        let ticks = match compare.wrapping_sub(self.cpu.cp0.count) & 0xFFFF_FFFF {
+            0 => 1 << 32,
+            ticks => ticks,
+        };
+        let scheduler = self.mmu.scheduler_mut();
+        scheduler.cancel(Event::CountCompare);
+        scheduler.schedule_in(ticks * 2, Event::CountCompare);
+    }
+
+    /// Reflect the MI interrupt state on the IP2 bit of the CP0 cause register
+    pub fn update_interrupt_pending(&mut self) {
+        const CAUSE_IP2: u64 = 1 << 10;
+
+        if self.mmu.mips_interface().is_pending() {
+            self.cpu.cp0.cause |= CAUSE_IP2;
+        } else {
+            self.cpu.cp0.cause &= !CAUSE_IP2;
+        }
+        self.pending_interrupt = self.cpu.interrupt_pending();
+    }
+
+    /// Take the pending interrupt, if any. It's kept pending while paused on
+    /// a breakpoint
+    pub fn dispatch_interrupt(&mut self) {
+        if !self.pending_interrupt || matches!(self.interruption, Interruption::Debug(_)) {
+            return;
+        }
+        self.cpu.take_interrupt();
+        self.pending_interrupt = self.cpu.interrupt_pending();
+    }
+}
+
+/// Only the machine state is saved: the pending JIT interruption and dirty
+/// pages refer to compiled code, which is dropped on load
+impl Snapshot for State {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        self.cpu.save(w)?;
+        self.mmu.save(w)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        self.cpu.load(r)?;
+        self.mmu.load(r)?;
+
+        self.dirty_pages.clear();
+        self.interruption = Interruption::None;
+        // the loaded scheduler already holds the `CountCompare` event
+        self.scheduled_compare = Some(self.cpu.cp0.compare & 0xFFFF_FFFF);
+        self.pending_interrupt = self.cpu.interrupt_pending();
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use byteorder::{BigEndian, ByteOrder};
 
-    use crate::mmu::{map::addr_map, MemoryUnit};
+    use crate::{jit::TIER_UP_EXECUTIONS, mmu::map::addr_map};
 
     use super::*;
 
@@ -121,10 +850,865 @@ mod tests {
         n64.cycle();
     }
 
+    /// `addiu t0, t0, 1`
+    const ADDIU_T0: u32 = 0x2508_0001;
+
+    /// Create a N64 running `program` right after the ROM header, where the
+    /// PIF boot process jumps to. The rest of the ROM is filled with NOPs
+    fn with_program(name: &str, program: &[u32]) -> N64<BigEndian> {
+        let mut rom = vec![0u8; 0x1000];
+        for (word, instruction) in rom[0x40..].chunks_mut(4).zip(program) {
+            word.copy_from_slice(&instruction.to_be_bytes());
+        }
+        let rom_path = std::env::temp_dir().join(format!("w64-{name}.z64"));
+        std::fs::write(&rom_path, rom).unwrap();
+
+        N64::new(&rom_path).unwrap()
+    }
+
+    #[test]
+    fn it_should_step_single_instructions() {
+        let mut n64 = with_program("step-instruction", &[ADDIU_T0; 4]);
+        assert!(n64.step_instruction() > 0);
+        n64.step_instruction();
+
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.pc, 0xA400_0048);
+        assert_eq!(state.cpu.gpr[8], 2);
+    }
+
+    #[test]
+    fn it_should_run_until_a_condition_is_met() {
+        let mut n64 = with_program("run-until", &[ADDIU_T0; 4]);
+
+        let pc = Condition::PcReaches(0xA400_0048);
+        assert_eq!(n64.run_until(&[pc, Condition::CycleBudget(10_000)]), pc);
+        assert_eq!(n64.state().lock().cpu.gpr[8], 2);
+
+        let budget = Condition::CycleBudget(2000);
+        let never = Condition::MemoryEquals {
+            addr: 0x100,
+            value: 0xdead_beef,
+        };
+        assert_eq!(n64.run_until(&[never, budget]), budget);
+        assert!(n64.clocks() >= 2000);
+    }
+
+    #[test]
+    fn it_should_keep_the_guest_registers_that_do_not_fit() {
+        // `lui` into t0-t9, then sum them into v0
+        let regs = [8u32, 9, 10, 11, 12, 13, 14, 15, 24, 25];
+        let mut program = (1..)
+            .zip(regs)
+            .map(|(value, reg)| 0x3C00_0000 | reg << 16 | value)
+            .collect::<Vec<_>>();
+        program.push(regs[0] << 21 | regs[1] << 16 | 2 << 11 | 0x21);
+        program.extend(
+            regs[2..]
+                .iter()
+                .map(|reg| 2 << 21 | reg << 16 | 2 << 11 | 0x21),
+        );
+        let mut n64 = with_program("register-pressure", &program);
+
+        let end = Condition::PcReaches(0xA400_0040 + 4 * program.len() as u64);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[2], 55 << 16);
+        assert_eq!(state.cpu.gpr[25], 10 << 16);
+    }
+
+    #[test]
+    fn it_should_fold_the_constant_addresses() {
+        let program = [
+            0x3C08_A400, // lui t0, 0xA400
+            0x3508_0F00, // ori t0, t0, 0x0F00
+            0xAD08_FFFC, // sw t0, -4(t0)
+            0x8D09_FFFC, // lw t1, -4(t0)
+        ];
+        let mut n64 = with_program("constants", &program);
+
+        let end = Condition::PcReaches(0xA400_0050);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.mmu.read::<u32, BigEndian>(0x0400_0EFC), 0xA400_0F00);
+        assert_eq!(state.cpu.gpr[9], 0xFFFF_FFFF_A400_0F00);
+    }
+
+    #[test]
+    fn it_should_read_the_rdram_without_the_bridge() {
+        let program = [
+            0x3C08_A000, // lui t0, 0xA000
+            0x3508_1000, // ori t0, t0, 0x1000
+            0x3C09_1234, // lui t1, 0x1234
+            0x3529_5678, // ori t1, t1, 0x5678
+            0xAD09_0000, // sw t1, 0(t0)
+            0x0100_4021, // addu t0, t0, zero, which isn't folded
+            0x8D0A_0000, // lw t2, 0(t0)
+            0x850B_0002, // lh t3, 2(t0)
+            0x910C_0001, // lbu t4, 1(t0)
+            0x3C0D_8000, // lui t5, 0x8000
+            0x81AE_1003, // lb t6, 0x1003(t5)
+            0x3C0F_0400, // lui t7, 0x0400
+            0x01E8_7821, // addu t7, t7, t0
+            0x8DF8_F040, // lw t8, -0xFC0(t7), from the DMEM
+            0x0158_C821, // addu t9, t2, t8
+        ];
+        let mut n64 = with_program("fastmem", &program);
+        // the baseline blocks read the memory through the bridge
+        n64.jit.set_tier_up(0);
+
+        let end = Condition::PcReaches(0xA400_007C);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[10], 0x1234_5678);
+        assert_eq!(state.cpu.gpr[11], 0x5678);
+        assert_eq!(state.cpu.gpr[12], 0x34);
+        assert_eq!(state.cpu.gpr[14], 0x78);
+        assert_eq!(state.cpu.gpr[24], 0x3C08_A000);
+        assert_eq!(state.cpu.gpr[25], 0x4E3C_F678);
+        // only the store and the DMEM load called the bridge
+        assert_eq!(state.bridge_calls, 2);
+    }
+
+    #[test]
+    fn it_should_run_the_code_modified_by_a_store() {
+        let program = [
+            0x3C08_A400, // lui t0, 0xA400
+            0x3508_0050, // ori t0, t0, 0x0050
+            0x3C09_240A, // lui t1, 0x240A
+            0x3529_0007, // ori t1, t1, 0x0007
+            0xAD09_0004, // sw t1, 4(t0), which writes `addiu t2, zero, 7`
+            0x240A_0001, // addiu t2, zero, 1
+            0x240B_0002, // addiu t3, zero, 2
+        ];
+        let mut n64 = with_program("self-modifying", &program);
+
+        let end = Condition::PcReaches(0xA400_005C);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        // the block is left after the store, and its end runs again
+        assert_eq!(n64.stats().instructions, program.len() as u64);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[10], 7);
+        assert_eq!(state.cpu.gpr[11], 2);
+    }
+
+    #[test]
+    fn it_should_take_the_pending_interrupts() {
+        // `j 0xA4000040` back to the start
+        let mut n64 = with_program("interrupt", &[ADDIU_T0, 0x0900_0010]);
+        {
+            let mut state = n64.state().lock();
+            // IE and IM7, with the timer interrupt pending
+            state.cpu.cp0.status.bits = 1 | CAUSE_IP7;
+            state.cpu.cp0.cause |= CAUSE_IP7;
+            state.update_interrupt_pending();
+        }
+
+        let handler = Condition::PcReaches(0x8000_0180);
+        assert_eq!(
+            n64.run_until(&[handler, Condition::CycleBudget(10_000)]),
+            handler
+        );
+        // taken once the first block jumped back
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.cp0.epc, 0xA400_0040);
+        assert_eq!(state.cpu.cp0.status.bits & 2, 2);
+        assert!(!state.pending_interrupt);
+    }
+
+    #[test]
+    fn it_should_keep_r0_hardwired_to_zero() {
+        let program = [
+            0x2400_0005, // addiu zero, zero, 5
+            0x0000_4021, // addu t0, zero, zero
+        ];
+        let mut n64 = with_program("r0", &program);
+
+        let end = Condition::PcReaches(0xA400_0048);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[0], 0);
+        assert_eq!(state.cpu.gpr[8], 0);
+    }
+
+    #[test]
+    fn it_should_take_the_conditional_branches() {
+        // the offsets are relative to the delay slot, which runs either way
+        let program = [
+            0x2408_0003, // addiu t0, zero, 3
+            0x2508_FFFF, // addiu t0, t0, -1
+            0x1D00_FFFE, // bgtz t0, -2
+            0x256B_0001, // addiu t3, t3, 1
+            0x1900_0002, // blez t0, 2
+            0x0000_0000, // nop
+            0x2409_0001, // addiu t1, zero, 1
+            0x1000_0002, // beq zero, zero, 2
+            0x240A_0007, // addiu t2, zero, 7
+            0x2409_0002, // addiu t1, zero, 2
+        ];
+        let mut n64 = with_program("branches", &program);
+        n64.state.lock().cpu.gpr[11] = 0;
+
+        let end = Condition::PcReaches(0xA400_0068);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[8], 0);
+        assert_eq!(state.cpu.gpr[9], 0);
+        assert_eq!(state.cpu.gpr[10], 7);
+        assert_eq!(state.cpu.gpr[11], 3);
+    }
+
+    #[test]
+    fn it_should_skip_the_delay_slot_of_a_likely_branch_not_taken() {
+        let program = [
+            0x5100_0002, // beql t0, zero, 2
+            0x2409_0001, // addiu t1, zero, 1
+            0x240A_0007, // addiu t2, zero, 7
+        ];
+        let mut n64 = with_program("likely", &program);
+        n64.state.lock().cpu.gpr[8] = 1;
+
+        let end = Condition::PcReaches(0xA400_004C);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[9], 0);
+        assert_eq!(state.cpu.gpr[10], 7);
+    }
+
+    #[test]
+    fn it_should_call_through_a_register() {
+        let program = [
+            0x3C08_A400, // lui t0, 0xA400
+            0x3508_0050, // ori t0, t0, 0x0050
+            0x0100_F809, // jalr t0
+            0x2409_0001, // addiu t1, zero, 1
+            0x240A_0007, // addiu t2, zero, 7
+        ];
+        let mut n64 = with_program("jalr", &program);
+
+        let end = Condition::PcReaches(0xA400_0054);
+        assert_eq!(n64.run_until(&[end, Condition::CycleBudget(10_000)]), end);
+        let state = n64.state().lock();
+        assert_eq!(state.cpu.gpr[31] as u32, 0xA400_0050);
+        // the delay slot runs before the jump
+        assert_eq!(state.cpu.gpr[9], 1);
+        assert_eq!(state.cpu.gpr[10], 7);
+    }
+
+    /// Encode the `SPECIAL` instruction `funct`
+    fn special(funct: u32, rs: u32, rt: u32, rd: u32, shift_amount: u32) -> u32 {
+        rs << 21 | rt << 16 | rd << 11 | shift_amount << 6 | funct
+    }
+
+    /// Encode the I-type instruction `opcode`
+    fn immediate(opcode: u32, rs: u32, rt: u32, imm: u16) -> u32 {
+        opcode << 26 | rs << 21 | rt << 16 | u32::from(imm)
+    }
+
+    /// Run `instruction` alone with the GPRs first set to `gprs`, once in a
+    /// baseline block then in an optimized one, asserting after each run that
+    /// the GPRs are set to `expected` and that the next PC is `next_pc`
+    fn assert_executes(
+        instruction: u32,
+        gprs: &[(usize, u64)],
+        expected: &[(usize, u64)],
+        next_pc: u64,
+    ) -> [N64<BigEndian>; 2] {
+        [TIER_UP_EXECUTIONS, 0].map(|tier_up| {
+            let name = format!("instruction-{instruction:08x}-{tier_up}");
+            let mut n64 = with_program(&name, &[instruction]);
+            n64.jit.set_tier_up(tier_up);
+            for &(reg, value) in gprs {
+                n64.state.lock().cpu.gpr[reg] = value;
+            }
+            n64.state
+                .lock()
+                .mmu
+                .store::<u32, BigEndian>(0x100, 0x8182_8384);
+
+            n64.step_instruction();
+            for &(reg, value) in expected {
+                let gpr = n64.state.lock().cpu.gpr[reg];
+                assert_eq!(
+                    gpr, value,
+                    "{instruction:#010x} set r{reg} to {gpr:#x} instead of {value:#x}, tier-up at {tier_up}"
+                );
+            }
+            let pc = n64.state.lock().cpu.pc;
+            assert_eq!(pc, next_pc, "{instruction:#010x}, tier-up at {tier_up}");
+            n64
+        })
+    }
+
+    /// Assert that `instruction` sets the GPRs to `expected`, then goes on
+    /// with the next instruction
+    fn assert_sets(instruction: u32, gprs: &[(usize, u64)], expected: &[(usize, u64)]) {
+        assert_executes(instruction, gprs, expected, 0xA400_0044);
+    }
+
+    #[test]
+    fn it_should_execute_the_loads_and_stores() {
+        // the word 0x81828384 is stored at 0x100
+        let rdram = 0xA000_0100;
+        assert_sets(immediate(0x0F, 0, 8, 0x1234), &[], &[(8, 0x1234_0000)]);
+        assert_sets(
+            immediate(0x20, 8, 9, 1),
+            &[(8, rdram - 1)],
+            &[(9, 0xFFFF_FFFF_FFFF_FF81)],
+        );
+        assert_sets(immediate(0x24, 8, 9, 1), &[(8, rdram)], &[(9, 0x82)]);
+        assert_sets(
+            immediate(0x21, 8, 9, 0),
+            &[(8, 0x8000_0100)],
+            &[(9, 0xFFFF_FFFF_FFFF_8182)],
+        );
+        assert_sets(immediate(0x25, 8, 9, 2), &[(8, rdram)], &[(9, 0x8384)]);
+        assert_sets(
+            immediate(0x23, 8, 9, 0xFFFC),
+            &[(8, rdram + 4)],
+            &[(9, 0xFFFF_FFFF_8182_8384)],
+        );
+        assert_sets(immediate(0x27, 8, 9, 0), &[(8, rdram)], &[(9, 0x8182_8384)]);
+        // outside of the RDRAM, the instruction reads itself from the DMEM
+        let lw = immediate(0x23, 8, 9, 0);
+        assert_sets(lw, &[(8, 0xA400_0040)], &[(9, lw as i32 as u64)]);
+
+        let sw = immediate(0x2B, 8, 9, 4);
+        for n64 in assert_executes(sw, &[(8, rdram), (9, 0x1234_5678)], &[], 0xA400_0044) {
+            let state = n64.state.lock();
+            assert_eq!(state.mmu.read::<u32, BigEndian>(0x104), 0x1234_5678);
+        }
+    }
+
+    #[test]
+    fn it_should_execute_the_logical_instructions() {
+        let gprs = [(8, 0xF0F0_0FF0), (9, 0xFF00_FF00)];
+        assert_sets(special(0x24, 8, 9, 10, 0), &gprs, &[(10, 0xF000_0F00)]);
+        assert_sets(special(0x25, 8, 9, 10, 0), &gprs, &[(10, 0xFFF0_FFF0)]);
+        assert_sets(special(0x26, 8, 9, 10, 0), &gprs, &[(10, 0x0FF0_F0F0)]);
+        assert_sets(
+            special(0x27, 8, 9, 10, 0),
+            &gprs,
+            &[(10, 0xFFFF_FFFF_000F_000F)],
+        );
+        // the immediates are zero-extended
+        assert_sets(immediate(0x0C, 8, 10, 0xFF00), &gprs, &[(10, 0x0F00)]);
+        assert_sets(immediate(0x0D, 8, 10, 0x8001), &gprs, &[(10, 0xF0F0_8FF1)]);
+        assert_sets(immediate(0x0E, 8, 10, 0xFFFF), &gprs, &[(10, 0xF0F0_F00F)]);
+    }
+
+    #[test]
+    fn it_should_execute_the_arithmetic_instructions() {
+        let gprs = [(8, 10), (9, 3), (11, 0xFFFF_FFFF)];
+        assert_sets(special(0x20, 8, 9, 10, 0), &gprs, &[(10, 13)]);
+        assert_sets(special(0x21, 8, 9, 10, 0), &gprs, &[(10, 13)]);
+        assert_sets(special(0x22, 8, 9, 10, 0), &gprs, &[(10, 7)]);
+        assert_sets(special(0x23, 9, 8, 10, 0), &gprs, &[(10, 0xFFFF_FFF9)]);
+        // the signed results are sign-extended
+        assert_sets(
+            special(0x22, 9, 8, 10, 0),
+            &gprs,
+            &[(10, 0xFFFF_FFFF_FFFF_FFF9)],
+        );
+        assert_sets(special(0x20, 11, 9, 10, 0), &gprs, &[(10, 2)]);
+        // the immediates are sign-extended
+        assert_sets(immediate(0x08, 8, 10, 0xFFFD), &gprs, &[(10, 7)]);
+        assert_sets(immediate(0x09, 8, 10, 0xFFF6), &gprs, &[(10, 0)]);
+        assert_sets(immediate(0x09, 8, 10, 0x7FFF), &gprs, &[(10, 0x8009)]);
+    }
+
+    #[test]
+    fn it_should_raise_an_exception_on_the_signed_overflows() {
+        // the exceptions are vectored to the boot ROM, as BEV is set
+        let gprs = [(8, 0x7FFF_FFFF), (9, 0xFFFF_FFFF_8000_0000), (10, 0xDEAD)];
+        for instruction in [
+            special(0x20, 8, 8, 10, 0),
+            special(0x22, 9, 8, 10, 0),
+            immediate(0x08, 8, 10, 1),
+            immediate(0x08, 9, 10, 0xFFFF),
+        ] {
+            for n64 in assert_executes(instruction, &gprs, &[(10, 0xDEAD)], 0xBFC0_0380) {
+                let cpu = &n64.state.lock().cpu;
+                assert_eq!(cpu.cp0.epc, 0xA400_0040, "{instruction:#010x}");
+                assert_eq!(cpu.cp0.cause >> 2 & 0x1F, 12, "{instruction:#010x}");
+            }
+        }
+
+        // the registers written before the exception are synced
+        let add = special(0x20, 9, 9, 10, 0);
+        let mut n64 = with_program("overflow", &[ADDIU_T0, add, ADDIU_T0]);
+        n64.state.lock().cpu.gpr[9] = 0xFFFF_FFFF_8000_0000;
+        n64.step_instruction();
+        n64.step_instruction();
+        let cpu = &n64.state.lock().cpu;
+        assert_eq!((cpu.gpr[8], cpu.gpr[10]), (1, 0));
+        assert_eq!(cpu.cp0.epc, 0xA400_0044);
+    }
+
+    #[test]
+    fn it_should_execute_the_multiplications_and_divisions() {
+        let assert_hi_lo = |funct, rs: u64, rt: u64, hi: u64, lo: u64| {
+            let instruction = special(funct, 8, 9, 0, 0);
+            for n64 in assert_executes(instruction, &[(8, rs), (9, rt)], &[], 0xA400_0044) {
+                let cpu = &n64.state.lock().cpu;
+                assert_eq!(
+                    (cpu.multi_hi, cpu.multi_lo),
+                    (hi, lo),
+                    "{instruction:#010x}"
+                );
+            }
+        };
+        let minus = |value: i64| value as u64;
+
+        assert_hi_lo(0x18, 0xFFFF_FFFE, 3, minus(-1), minus(-6));
+        assert_hi_lo(0x18, 0x4000_0000, 4, 1, 0);
+        assert_hi_lo(0x19, 0xFFFF_FFFE, 3, 2, minus(-6));
+        assert_hi_lo(0x1A, 0xFFFF_FFF9, 2, minus(-1), minus(-3));
+        assert_hi_lo(0x1A, 7, 0, 7, minus(-1));
+        assert_hi_lo(0x1A, 0x8000_0000, 0xFFFF_FFFF, 0, minus(-0x8000_0000));
+        assert_hi_lo(0x1B, 0xFFFF_FFF9, 2, 1, 0x7FFF_FFFC);
+        assert_hi_lo(0x1B, 7, 0, 7, minus(-1));
+    }
+
+    #[test]
+    fn it_should_execute_the_shifts() {
+        let gprs = [(8, 0x1234_5678), (9, 0x8000_0010), (10, 36)];
+        assert_sets(special(0x00, 0, 8, 11, 4), &gprs, &[(11, 0x2345_6780)]);
+        assert_sets(special(0x02, 0, 8, 11, 4), &gprs, &[(11, 0x0123_4567)]);
+        assert_sets(special(0x02, 0, 9, 11, 4), &gprs, &[(11, 0x0800_0001)]);
+        assert_sets(special(0x03, 0, 8, 11, 4), &gprs, &[(11, 0x0123_4567)]);
+        assert_sets(special(0x03, 0, 9, 11, 4), &gprs, &[(11, 0xF800_0001)]);
+        // the variable shifts only use the low 5 bits of rs
+        assert_sets(special(0x04, 10, 8, 11, 0), &gprs, &[(11, 0x2345_6780)]);
+        assert_sets(special(0x06, 10, 9, 11, 0), &gprs, &[(11, 0x0800_0001)]);
+        assert_sets(special(0x07, 10, 9, 11, 0), &gprs, &[(11, 0xF800_0001)]);
+    }
+
+    #[test]
+    fn it_should_execute_the_branches_and_jumps() {
+        let taken = 0xA400_0040 + 4 + (3 << 2);
+        let not_taken = 0xA400_0048;
+        let gprs = [(8, 5), (9, 5), (10, 0)];
+        // beq, bne, blez and bgtz, then their "likely" variants. Both go past
+        // the delay slot when not taken, the likely ones without running it
+        for regular in [0, 0x10] {
+            let opcode = regular | 0x04;
+            assert_executes(immediate(opcode, 8, 9, 3), &gprs, &[], taken);
+            assert_executes(immediate(opcode, 8, 10, 3), &gprs, &[], not_taken);
+            let opcode = regular | 0x05;
+            assert_executes(immediate(opcode, 8, 9, 3), &gprs, &[], not_taken);
+            assert_executes(immediate(opcode, 8, 10, 3), &gprs, &[], taken);
+            let opcode = regular | 0x06;
+            assert_executes(immediate(opcode, 10, 0, 3), &gprs, &[], taken);
+            assert_executes(immediate(opcode, 8, 0, 3), &gprs, &[], not_taken);
+            let opcode = regular | 0x07;
+            assert_executes(immediate(opcode, 8, 0, 3), &gprs, &[], taken);
+            assert_executes(immediate(opcode, 10, 0, 3), &gprs, &[], not_taken);
+        }
+        // the offset is signed
+        assert_executes(immediate(0x04, 0, 0, 0xFFFE), &[], &[], 0xA400_003C);
+
+        let target = 0x0100_0020;
+        assert_executes(2 << 26 | target, &[], &[], 0xA400_0080);
+        assert_executes(3 << 26 | target, &[], &[(31, 0xA400_0048)], 0xA400_0080);
+        let gprs = [(8, 0xA400_0100)];
+        assert_executes(special(0x08, 8, 0, 0, 0), &gprs, &[], 0xA400_0100);
+        assert_executes(
+            special(0x09, 8, 0, 9, 0),
+            &gprs,
+            &[(9, 0xA400_0048)],
+            0xA400_0100,
+        );
+        // rd is set after reading rs
+        assert_executes(
+            special(0x09, 8, 0, 8, 0),
+            &gprs,
+            &[(8, 0xA400_0048)],
+            0xA400_0100,
+        );
+    }
+
+    #[test]
+    fn it_should_raise_an_exception_on_the_unimplemented_instructions() {
+        // `slt t2, t0, t1` is decoded but not compiled, `sb t1, 0(t0)` is not
+        // even decoded
+        for instruction in [0x0109_502A, 0xA109_0000] {
+            let name = format!("unimplemented-{instruction:08x}");
+            let mut n64 = with_program(&name, &[ADDIU_T0, instruction, ADDIU_T0]);
+            n64.run_until(&[Condition::CycleBudget(100)]);
+
+            let unimplemented = n64.take_unimplemented_instruction();
+            assert_eq!(
+                unimplemented,
+                Some(UnimplementedInstruction {
+                    pc: 0xA400_0044,
+                    opcode: instruction,
+                })
+            );
+            assert_eq!(n64.take_unimplemented_instruction(), None);
+            let state = n64.state().lock();
+            assert_eq!(state.cpu.gpr[8], 1);
+            assert_eq!(state.cpu.cp0.epc, 0xA400_0044);
+            assert_eq!(state.cpu.cp0.cause >> 2 & 0x1F, 10);
+        }
+    }
+
+    #[test]
+    fn it_should_raise_the_exceptions_of_a_delay_slot_at_its_jump() {
+        for instruction in [0x0109_502A, 0xA109_0000] {
+            let name = format!("delay-slot-{instruction:08x}");
+            // `j 0xA4000040`, then the unimplemented instruction
+            let mut n64 = with_program(&name, &[ADDIU_T0, 0x0900_0010, instruction]);
+            n64.run_until(&[Condition::CycleBudget(100)]);
+
+            let unimplemented = n64.take_unimplemented_instruction();
+            assert_eq!(
+                unimplemented.map(|unimplemented| unimplemented.pc),
+                Some(0xA400_0048)
+            );
+            let state = n64.state().lock();
+            assert_eq!(state.cpu.gpr[8], 1);
+            assert_eq!(state.cpu.cp0.epc, 0xA400_0044);
+            assert_eq!(state.cpu.cp0.cause >> 31, 1);
+        }
+    }
+
+    #[test]
+    fn it_should_skip_the_idle_loops() {
+        // `j 0xA4000040` to itself, with a `nop` in its delay slot
+        let mut n64 = with_program("idle", &[0x0900_0010]);
+        let cycles = n64.run_frame();
+
+        let stats = n64.stats();
+        assert!(stats.instructions > 0);
+        assert!(stats.instructions < cycles as u64 / 100);
+    }
+
+    #[test]
+    fn it_should_link_the_blocks_of_a_loop() {
+        // `j 0xA4000040` back to the `addiu`, then its delay slot
+        let mut n64 = with_program("link", &[ADDIU_T0, 0x0900_0010]);
+        n64.run_frame();
+
+        let iterations = n64.state().lock().cpu.gpr[8];
+        let stats = n64.stats();
+        assert!(iterations > 1000);
+        assert!(stats.bridge_calls < iterations / 100);
+        assert_eq!(stats.instructions, 3 * iterations);
+    }
+
+    #[test]
+    fn it_should_jump_through_a_register_without_the_host() {
+        let program = [
+            0x3C08_A400, // lui t0, 0xA400
+            0x3508_0048, // ori t0, t0, 0x0048
+            0x2529_0001, // addiu t1, t1, 1
+            0x0100_0008, // jr t0, back to the `addiu`
+        ];
+        let mut n64 = with_program("jump-table", &program);
+        n64.run_frame();
+
+        let iterations = n64.state().lock().cpu.gpr[9];
+        let stats = n64.stats();
+        assert!(iterations > 1000);
+        // the host only runs the blocks once per link budget, which lasts for
+        // a hundred instructions at least
+        assert!(stats.jit.cache_hits + stats.jit.cache_misses < stats.instructions / 100);
+    }
+
+    #[test]
+    fn it_should_share_the_state_with_another_thread() {
+        let mut n64 = with_program("threads", &[ADDIU_T0, 0x0900_0010]);
+        let state = n64.state().clone();
+        let frontend = std::thread::spawn(move || {
+            state.lock().cpu.gpr[8] = 1000;
+            // wait for the emulation to run
+            while state.lock().cpu.gpr[8] == 1000 {
+                std::thread::yield_now();
+            }
+            state.lock().cpu.gpr[8]
+        });
+        while n64.state().lock().cpu.gpr[8] != 1000 {
+            std::thread::yield_now();
+        }
+        n64.run_for_cycles(100);
+
+        assert!(frontend.join().unwrap() > 1000);
+    }
+
+    #[test]
+    fn it_should_recompile_the_blocks_when_the_cpu_context_switches() {
+        // `j 0xA4000040` back to the `addiu`
+        let mut n64 = with_program("context", &[ADDIU_T0, 0x0900_0010]);
+        let set_status = |n64: &mut N64<BigEndian>, bits: u64| {
+            n64.state.lock().cpu.cp0.status.bits = bits;
+            n64.run_for_cycles(10_000);
+            n64.stats().jit
+        };
+        let stats = set_status(&mut n64, 0);
+
+        // taking an exception leaves the CPU in kernel mode
+        let exception = set_status(&mut n64, 1 << 1);
+        assert_eq!(exception.context_switches, stats.context_switches);
+        assert_eq!(exception.cache_misses, stats.cache_misses);
+
+        // user mode, then 64-bit addressing in kernel mode
+        let user = set_status(&mut n64, 2 << 3);
+        assert_eq!(user.context_switches, stats.context_switches + 1);
+        assert!(user.cache_misses > stats.cache_misses);
+        let extended = set_status(&mut n64, 1 << 7);
+        assert_eq!(extended.context_switches, stats.context_switches + 2);
+        assert!(extended.cache_misses > user.cache_misses);
+    }
+
+    #[test]
+    fn it_should_evict_the_blocks_over_the_code_budget() {
+        let program = [
+            ADDIU_T0,
+            0x0900_0014, // j 0xA4000050
+            0,
+            0,
+            0x2529_0001, // addiu t1, t1, 1
+            0x0900_0010, // j 0xA4000040
+        ];
+        let mut n64 = with_program("code-budget", &program);
+        n64.jit.set_code_budget(1);
+        n64.run_for_cycles(10_000);
+
+        // every block fills the cache, which is flushed before the next one
+        let gpr = n64.state().lock().cpu.gpr;
+        assert!(gpr[8] > 100);
+        assert!(gpr[8] - gpr[9] <= 1);
+        assert!(n64.stats().jit.evictions > 100);
+        assert!(n64.jit.code_size() <= 2 * region::page::size());
+    }
+
+    #[test]
+    fn it_should_keep_the_small_blocks_under_the_code_budget() {
+        // 100 blocks of `addiu t0, t0, 1; j <next block>; nop`, in a loop
+        let start = 0xA400_0040;
+        let program = (1..=100)
+            .flat_map(|block| {
+                let next = if block == 100 {
+                    start
+                } else {
+                    start + 12 * block
+                };
+                [ADDIU_T0, 2 << 26 | (next >> 2 & 0x3FF_FFFF), 0]
+            })
+            .collect::<Vec<_>>();
+        let mut n64 = with_program("small-blocks", &program);
+        let budget = 64 * 1024;
+        n64.jit.set_code_budget(budget);
+        n64.run_for_cycles(10_000);
+
+        assert!(n64.state().lock().cpu.gpr[8] > 100);
+        assert_eq!(n64.stats().jit.evictions, 0);
+        assert_eq!(n64.jit.hot_blocks(200).len(), 100);
+        assert!(n64.jit.code_size() < budget);
+    }
+
+    #[test]
+    fn it_should_profile_the_blocks() {
+        let program = [
+            ADDIU_T0,
+            ADDIU_T0,
+            0x0900_0010, // j 0xA4000040
+        ];
+        let mut n64 = with_program("profile", &program);
+        // optimized right away, a tier-up would start a new profile
+        n64.jit.set_tier_up(0);
+        n64.run_for_cycles(10_000);
+
+        let hot = n64.jit.hot_blocks(1);
+        assert_eq!(hot.len(), 1);
+        assert_eq!(hot[0].pcs, 0xA400_0040..0xA400_0050);
+        let iterations = n64.state().lock().cpu.gpr[8] / 2;
+        assert!(iterations > 100);
+        // the shorter blocks run up to the device events are not cached
+        assert!(hot[0].executions <= iterations);
+        assert!(hot[0].executions > iterations / 2);
+        assert!(hot[0].host_time > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn it_should_disassemble_the_compiled_blocks() {
+        let mut n64 = with_program("disassemble", &[ADDIU_T0, ADDIU_T0, 0x0900_0010]);
+        let text = n64.jit.compile_current_pc().disassemble();
+
+        let guest = text
+            .lines()
+            .filter(|line| line.starts_with(';'))
+            .collect::<Vec<_>>();
+        assert_eq!(guest.len(), 4);
+        assert!(guest[0].starts_with("; 0xa4000040: ADDIU"));
+        assert!(guest[2].starts_with("; 0xa4000048: J"));
+        assert!(guest[3].starts_with("; 0xa400004c: NOP"));
+        // the host code of the first instruction follows it
+        let first = text
+            .lines()
+            .skip_while(|line| *line != guest[0])
+            .skip(1)
+            .take_while(|line| !line.starts_with(';'))
+            .collect::<Vec<_>>();
+        assert!(first.iter().any(|line| line.contains(" add ")));
+        assert!(text.contains(" ret"));
+    }
+
+    #[test]
+    fn it_should_write_the_perf_map() {
+        let mut n64 = with_program("perf-map", &[ADDIU_T0, 0x0900_0010]);
+        let path = std::env::temp_dir().join("w64-perf-map.map");
+        n64.jit.set_perf_map(Some(&path)).unwrap();
+        n64.run_for_cycles(10_000);
+
+        let map = std::fs::read_to_string(&path).unwrap();
+        let block = map
+            .lines()
+            .find(|line| line.ends_with("w64:0xa4000040-0xa400004c [baseline]"))
+            .unwrap();
+        let (start, size) = block.split_once(' ').unwrap();
+        assert!(u64::from_str_radix(start, 16).unwrap() > 0);
+        let size = size.split_once(' ').unwrap().0;
+        assert!(usize::from_str_radix(size, 16).unwrap() > 0);
+    }
+
+    #[test]
+    fn it_should_optimize_the_hot_blocks() {
+        let mut n64 = with_program("tier-up", &[ADDIU_T0, 0x0900_0010]);
+        n64.run_for_cycles(100);
+        assert!(!n64.jit.compile_current_pc().is_optimized());
+
+        n64.run_for_cycles(20_000);
+        let iterations = n64.state().lock().cpu.gpr[8];
+        let stats = n64.stats();
+        assert!(iterations > TIER_UP_EXECUTIONS);
+        assert_eq!(stats.jit.tier_ups, 1);
+        // the last iteration may stop before the jump
+        assert!((3 * iterations - 2..=3 * iterations).contains(&stats.instructions));
+        n64.state.lock().cpu.pc = 0xA400_0040;
+        assert!(n64.jit.compile_current_pc().is_optimized());
+    }
+
+    #[test]
+    fn it_should_stop_at_the_breakpoints() {
+        let mut n64 = with_program("breakpoints", &[ADDIU_T0; 4]);
+        n64.add_breakpoint(0xA400_0048);
+
+        n64.run_for_cycles(10_000);
+        assert_eq!(n64.breakpoint_hit(), Some(0xA400_0048));
+        assert_eq!(n64.state().lock().cpu.gpr[8], 2);
+        assert_eq!(n64.run_frame(), 0);
+
+        n64.step_instruction();
+        assert_eq!(n64.breakpoint_hit(), None);
+        assert_eq!(n64.state().lock().cpu.gpr[8], 3);
+
+        assert!(n64.remove_breakpoint(0xA400_0048));
+        n64.add_breakpoint(0xA400_0044);
+        n64.reset(ResetKind::Cold);
+        n64.run_for_cycles(10_000);
+        assert_eq!(n64.breakpoint_hit(), Some(0xA400_0044));
+        let t0 = n64.state().lock().cpu.gpr[8];
+        n64.resume();
+        n64.run_for_cycles(10);
+        assert_eq!(n64.breakpoint_hit(), None);
+        assert!(n64.state().lock().cpu.gpr[8] > t0);
+    }
+
+    #[test]
+    fn it_should_pause_after_a_watched_access() {
+        use crate::mmu::watchpoint::AccessKind;
+
+        // lui t1, 0xa000 ; sw t0, 0x100(t1)
+        let program = [ADDIU_T0, 0x3C09_A000, 0xAD28_0100, ADDIU_T0, ADDIU_T0];
+        let mut n64 = with_program("watchpoints", &program);
+        let id = n64.watch(0x100..=0x103, WatchKind::Write);
+
+        assert_eq!(n64.run_until(&[]), Condition::Watch(id));
+        let hit = n64.watch_hit().unwrap();
+        assert_eq!(
+            (hit.addr, hit.access, hit.value),
+            (0x100, AccessKind::Write, 1)
+        );
+        assert_eq!(n64.state().lock().cpu.pc, 0xA400_004C);
+
+        assert!(n64.unwatch(id));
+        n64.resume();
+        n64.run_for_cycles(100);
+        assert!(!n64.is_paused());
+        assert_eq!(n64.state().lock().cpu.gpr[8], 3);
+    }
+
+    #[test]
+    fn it_should_stop_at_the_first_trace_divergence() {
+        let mut n64 = with_program("trace", &[ADDIU_T0; 3]);
+        let mut trace = Vec::new();
+        n64.write_trace(3, &mut trace).unwrap();
+        let trace = String::from_utf8(trace).unwrap();
+        assert!(trace.starts_with("a4000040 25080001 r8=0000000000000001\n"));
+
+        let mut n64 = with_program("trace", &[ADDIU_T0; 3]);
+        let reference = trace.replace("r8=0000000000000003", "r8=0000000000000004");
+        let divergence = n64.compare_trace(reference.as_bytes()).unwrap().unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.actual.pc, 0xA400_0048);
+    }
+
+    #[test]
+    fn it_should_replay_a_recorded_movie() {
+        use crate::io::ControllerState;
+
+        struct HoldStart;
+        impl InputSource for HoldStart {
+            fn poll(&mut self, _port: usize) -> ControllerState {
+                ControllerState {
+                    buttons: crate::io::controller::buttons::START,
+                    ..ControllerState::default()
+                }
+            }
+        }
+
+        // a loop long enough for the blocks to reach their cycle budget,
+        // ending with `j 0xa4000040`
+        let mut program = vec![ADDIU_T0; 250];
+        program.push(0x0900_0010);
+        let mut n64 = with_program("movie", &program);
+        n64.set_input_source(HoldStart);
+        n64.record_movie();
+        for _ in 0..3 {
+            n64.run_frame();
+        }
+        assert_eq!(
+            n64.movie_status(),
+            Some(MovieStatus::Recording { frames: 3 })
+        );
+        let movie = n64.stop_movie().unwrap();
+        assert!(movie
+            .frames()
+            .iter()
+            .all(|frame| frame.states[0] == HoldStart.poll(0)));
+
+        n64.play_movie(movie).unwrap();
+        n64.run_frame();
+        assert_eq!(
+            n64.movie_status(),
+            Some(MovieStatus::Replaying {
+                frame: 1,
+                frames: 3
+            })
+        );
+        n64.run_frame();
+        n64.run_frame();
+        assert_eq!(n64.movie_status(), Some(MovieStatus::Finished));
+        assert!(n64.stop_movie().is_some());
+        assert_eq!(n64.movie_status(), None);
+    }
+
     fn skip_boot_process<O: ByteOrder>(n64: &N64<O>) {
         tracing::info!("Skipping the boot process");
 
-        let mut state = n64.state().borrow_mut();
+        let mut state = n64.state().lock();
 
         let cart_rom_addr = *addr_map::phys::CART_D1A2_RANGE.start();
         let header_pc = state.mmu.read::<u32, O>(0x08 + cart_rom_addr);
@@ -0,0 +1,273 @@
+use std::{
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use byteorder::ByteOrder;
+
+use crate::{
+    cpu::Cpu,
+    frontend::{AudioSink, Frontend, InputSource, VideoSink},
+    io::{pif::EEPROM_CHANNEL, Cartridge, Cic, RomDatabase, SaveType},
+    jit::{perf, JitEngine, BLOCK_CYCLES, CODE_BUDGET},
+    logging::{self, LogTargets},
+    mmu::{map::addr_map, memory::MemoryConfig, MemoryManager},
+};
+
+use super::{stats::StatsCounter, State, SyncState, N64};
+
+/// What gets logged while the machine runs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// Log the host code of every compiled block
+    pub jit_code: bool,
+    /// Subsystems whose debug logs are emitted, as set by `N64::set_trace`
+    pub targets: LogTargets,
+}
+
+/// Configuration of a new N64 virtual machine, created with `N64::builder`
+#[derive(Debug)]
+pub struct N64Builder<O: ByteOrder> {
+    simulate_pif: bool,
+    pif_rom: Option<PathBuf>,
+    normalize_endianness: bool,
+    expansion_pak: Option<bool>,
+    save_type: Option<SaveType>,
+    cic: Option<Cic>,
+    rom_database: RomDatabase,
+    block_cycles: usize,
+    code_budget: usize,
+    perf_map: bool,
+    trace: TraceOptions,
+    frontend: Frontend,
+    _marker: PhantomData<O>,
+}
+
+impl<O: ByteOrder> N64Builder<O> {
+    pub fn new() -> N64Builder<O> {
+        Self {
+            simulate_pif: true,
+            pif_rom: None,
+            normalize_endianness: true,
+            expansion_pak: None,
+            save_type: None,
+            cic: None,
+            rom_database: RomDatabase::builtin(),
+            block_cycles: BLOCK_CYCLES,
+            code_budget: CODE_BUDGET,
+            perf_map: false,
+            trace: TraceOptions::default(),
+            frontend: Frontend::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Set the CPU and memory as the PIF boot process leaves them, instead of
+    /// running the boot ROM given to `pif_rom`. Enabled by default
+    #[must_use]
+    pub fn simulate_pif(mut self, enabled: bool) -> Self {
+        self.simulate_pif = enabled;
+        self
+    }
+
+    /// Map the PIF boot ROM, so that it can be run by the CPU
+    #[must_use]
+    pub fn pif_rom<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.pif_rom = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Convert `.n64` and `.v64` ROMs to the big endian layout. Enabled by
+    /// default
+    #[must_use]
+    pub fn normalize_endianness(mut self, enabled: bool) -> Self {
+        self.normalize_endianness = enabled;
+        self
+    }
+
+    /// Plug the Expansion Pak, for 8 megabytes of RDRAM instead of 4. Enabled
+    /// by default
+    #[must_use]
+    pub fn expansion_pak(mut self, enabled: bool) -> Self {
+        self.expansion_pak = Some(enabled);
+        self
+    }
+
+    /// Use `save_type` instead of the save chip from the ROM database. Unknown
+    /// games get a 4 kbit EEPROM
+    #[must_use]
+    pub fn save_type(mut self, save_type: SaveType) -> Self {
+        self.save_type = Some(save_type);
+        self
+    }
+
+    /// Use `cic` instead of the lockout chip from the ROM database. Unknown
+    /// games get a CIC-NUS-6102
+    #[must_use]
+    pub fn cic(mut self, cic: Cic) -> Self {
+        self.cic = Some(cic);
+        self
+    }
+
+    /// Look up the game settings in `database` instead of the builtin one.
+    /// The settings given to the builder take precedence over the database
+    #[must_use]
+    pub fn rom_database(mut self, database: RomDatabase) -> Self {
+        self.rom_database = database;
+        self
+    }
+
+    /// Cycle budget of the compiled blocks. Defaults to `BLOCK_CYCLES`
+    ///
+    /// # Panics
+    /// `cycles` is 0
+    #[must_use]
+    pub fn jit_block_cycles(mut self, cycles: usize) -> Self {
+        assert!(cycles > 0, "The JIT blocks should run for at least a cycle");
+        self.block_cycles = cycles;
+        self
+    }
+
+    /// Bytes of code the compiled blocks may take before the cache is
+    /// flushed. Defaults to `CODE_BUDGET`
+    #[must_use]
+    pub fn jit_code_budget(mut self, bytes: usize) -> Self {
+        self.code_budget = bytes;
+        self
+    }
+
+    /// Write the symbols of the compiled blocks into the `perf` map of the
+    /// process, so that the profiles of Linux `perf` name the guest code
+    #[must_use]
+    pub fn jit_perf_map(mut self, enabled: bool) -> Self {
+        self.perf_map = enabled;
+        self
+    }
+
+    #[must_use]
+    pub fn trace(mut self, trace: TraceOptions) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Present the displayed frames to `sink`
+    #[must_use]
+    pub fn video_sink<V: VideoSink + 'static>(mut self, sink: V) -> Self {
+        self.frontend.video = Some(Box::new(sink));
+        self
+    }
+
+    /// Play the audio through `sink`, which is kept alive by the machine
+    #[must_use]
+    pub fn audio_sink<S: AudioSink + 'static>(mut self, sink: S) -> Self {
+        self.frontend.audio = Some(Box::new(sink));
+        self
+    }
+
+    /// Read the controllers from `input`
+    #[must_use]
+    pub fn input_source<I: InputSource + 'static>(mut self, input: I) -> Self {
+        self.frontend.input = Some(Box::new(input));
+        self
+    }
+
+    /// Create the N64 virtual machine with the cartridge at `rom_path`
+    ///
+    /// # Errors
+    /// The ROM or the PIF boot ROM can't be read, the PIF is neither
+    /// simulated nor given a boot ROM, or the perf map can't be created
+    pub fn build<P: AsRef<Path>>(self, rom_path: P) -> anyhow::Result<N64<O>> {
+        tracing::info!("Creating a brand new N64!");
+
+        let mut cartridge = Cartridge::open(rom_path)?;
+        if self.normalize_endianness {
+            cartridge.normalize_endianness();
+        }
+        let video_standard = cartridge.video_standard();
+        let settings = self.rom_database.lookup(&cartridge);
+        tracing::debug!("Settings from the ROM database: {settings:?}");
+        let cic = self.cic.or(settings.cic).unwrap_or_default();
+        if !cartridge.verify_crc(cic) {
+            tracing::warn!("The ROM checksums don't match its contents. Bad dump or patched ROM?");
+        }
+
+        let pif_rom = match (&self.pif_rom, self.simulate_pif) {
+            (Some(path), _) => Some(Self::read_pif_rom(path)?),
+            (None, true) => None,
+            (None, false) => {
+                anyhow::bail!("A PIF boot ROM is needed to boot without simulating the PIF")
+            }
+        };
+
+        let mut mmu = MemoryManager::with_config(
+            cartridge,
+            MemoryConfig {
+                expansion_pak: self
+                    .expansion_pak
+                    .or(settings.expansion_pak)
+                    .unwrap_or(true),
+                pif_rom,
+            },
+        );
+        mmu.video_interface_mut().set_standard(video_standard);
+        mmu.audio_interface_mut().set_standard(video_standard);
+        if let Some(save_type) = self.save_type.or(settings.save_type) {
+            mmu.pif_mut()
+                .connect(EEPROM_CHANNEL, save_type.joybus_device())?;
+        }
+        mmu.pif_mut().set_cic(cic);
+        mmu.pif_mut().reset();
+        if let Some(input) = self.frontend.input {
+            mmu.pif_mut().set_input_source(input);
+        }
+        let cpu = Cpu::new(self.simulate_pif, &mut mmu);
+
+        let mut state = State::new(mmu, cpu);
+        state.simulate_pif = self.simulate_pif;
+        let state = SyncState::new(state);
+
+        let mut jit = JitEngine::new(state.clone());
+        jit.set_block_cycles(self.block_cycles);
+        jit.set_code_budget(self.code_budget);
+        jit.set_dump_code(self.trace.jit_code);
+        if self.perf_map {
+            jit.set_perf_map(Some(&perf::default_path()))
+                .context("Could not create the perf map")?;
+        }
+        logging::set_enabled(self.trace.targets);
+
+        let mut n64 = N64 {
+            state,
+            jit,
+            clocks: 0,
+            video_sink: self.frontend.video,
+            audio_sink: None,
+            movie: None,
+            stats: StatsCounter::default(),
+            _marker: PhantomData,
+        };
+        if let Some(mut sink) = self.frontend.audio {
+            n64.set_audio_sink(sink.as_mut());
+            n64.audio_sink = Some(sink);
+        }
+        Ok(n64)
+    }
+
+    fn read_pif_rom(path: &Path) -> anyhow::Result<Box<[u8]>> {
+        let rom = std::fs::read(path)
+            .with_context(|| format!("Could not read the PIF ROM {}", path.display()))?;
+        let size = addr_map::phys::PIF_ROM_RANGE.clone().count();
+        anyhow::ensure!(
+            rom.len() <= size,
+            "The PIF ROM is too large. The maximum size is {size} bytes"
+        );
+        Ok(rom.into_boxed_slice())
+    }
+}
+
+impl<O: ByteOrder> Default for N64Builder<O> {
+    fn default() -> N64Builder<O> {
+        Self::new()
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use crate::jit::JitStats;
+
+/// Performance counters of a running machine, for frontends to display
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Guest instructions run since the machine was built
+    pub instructions: u64,
+    /// Millions of guest instructions run per second of host time, during the
+    /// last frame
+    pub mips: f64,
+    /// Host time spent running the last frame
+    pub frame_time: Duration,
+    /// Frames run since the machine was built
+    pub frames: u64,
+    pub jit: JitStats,
+    /// Calls from the compiled code into the emulator, such as memory accesses
+    /// and jump resolutions
+    pub bridge_calls: u64,
+}
+
+/// Accumulates the counters of the frame being run
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounter {
+    instructions: u64,
+    frame_instructions: u64,
+    frame_host_time: Duration,
+    last_frame: (f64, Duration),
+    frames: u64,
+}
+
+impl StatsCounter {
+    pub fn add_instructions(&mut self, instructions: usize) {
+        self.instructions += instructions as u64;
+        self.frame_instructions += instructions as u64;
+    }
+
+    pub fn add_host_time(&mut self, time: Duration) {
+        self.frame_host_time += time;
+    }
+
+    pub fn end_frame(&mut self) {
+        let seconds = self.frame_host_time.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let mips = if seconds > 0.0 {
+            self.frame_instructions as f64 / seconds / 1_000_000.0
+        } else {
+            0.0
+        };
+        self.last_frame = (mips, self.frame_host_time);
+        self.frames += 1;
+        self.frame_instructions = 0;
+        self.frame_host_time = Duration::ZERO;
+    }
+
+    pub fn stats(&self, jit: JitStats, bridge_calls: u64) -> Stats {
+        let (mips, frame_time) = self.last_frame;
+        Stats {
+            instructions: self.instructions,
+            mips,
+            frame_time,
+            frames: self.frames,
+            jit,
+            bridge_calls,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_compute_the_frame_stats() {
+        let mut counter = StatsCounter::default();
+        counter.add_instructions(2_000_000);
+        counter.add_host_time(Duration::from_millis(500));
+        counter.add_host_time(Duration::from_millis(500));
+        counter.end_frame();
+        counter.add_instructions(10);
+
+        let stats = counter.stats(JitStats::default(), 3);
+        assert_eq!(stats.instructions, 2_000_010);
+        assert!((stats.mips - 2.0).abs() < f64::EPSILON);
+        assert_eq!(stats.frame_time, Duration::from_secs(1));
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.bridge_calls, 3);
+    }
+}
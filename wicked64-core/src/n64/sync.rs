@@ -0,0 +1,30 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::State;
+
+/// The machine state, shared by the emulator, its compiled blocks and the
+/// frontends, which may lock it from other threads.
+///
+/// The emulator locks the state around each compiled block it runs, and
+/// passes the block a pointer to it taken from the guard. The block, and the
+/// bridge functions it calls, only access the state through that pointer.
+/// Frontends lock it between the blocks, e.g. to read the registers of a
+/// running game.
+/// The lock isn't reentrant: a thread holding it must not lock it again
+#[derive(Debug, Clone)]
+pub struct SyncState(Arc<Mutex<State>>);
+
+impl SyncState {
+    pub fn new(state: State) -> Self {
+        Self(Arc::new(Mutex::new(state)))
+    }
+
+    /// Lock the state, waiting for the thread holding it
+    ///
+    /// # Panics
+    /// A thread panicked while holding the lock, leaving the state in an
+    /// unknown condition
+    pub fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.lock().expect("The state was poisoned by a panic")
+    }
+}
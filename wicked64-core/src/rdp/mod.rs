@@ -0,0 +1,352 @@
+mod rasterizer;
+
+use std::{
+    fmt::Debug,
+    io::{Read, Write},
+    ops::Range,
+};
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{
+        read_u32s, read_u64s, write_u32s, write_u64s, SaveStateError, SaveStateResult, Snapshot,
+    },
+};
+
+pub use rasterizer::Rasterizer;
+
+/// DP command registers offsets
+pub mod dpc_reg {
+    pub const START: usize = 0x00;
+    pub const END: usize = 0x04;
+    pub const CURRENT: usize = 0x08;
+    pub const STATUS: usize = 0x0C;
+    pub const CLOCK: usize = 0x10;
+    pub const BUFBUSY: usize = 0x14;
+    pub const PIPEBUSY: usize = 0x18;
+    pub const TMEM: usize = 0x1C;
+}
+
+/// `DPC_STATUS` bits, as read by the CPU
+pub mod dpc_status {
+    /// Command lists are fetched from DMEM instead of RDRAM
+    pub const XBUS_DMEM_DMA: u32 = 1 << 0;
+    pub const FREEZE: u32 = 1 << 1;
+    pub const FLUSH: u32 = 1 << 2;
+    pub const CBUF_READY: u32 = 1 << 7;
+    pub const END_VALID: u32 = 1 << 9;
+    pub const START_VALID: u32 = 1 << 10;
+}
+
+/// RDP command identifiers
+pub mod command {
+    pub const TEXTURE_RECTANGLE: u8 = 0x24;
+    pub const TEXTURE_RECTANGLE_FLIP: u8 = 0x25;
+    pub const SYNC_LOAD: u8 = 0x26;
+    pub const SYNC_PIPE: u8 = 0x27;
+    pub const SYNC_TILE: u8 = 0x28;
+    pub const SYNC_FULL: u8 = 0x29;
+    pub const SET_SCISSOR: u8 = 0x2D;
+    pub const SET_OTHER_MODES: u8 = 0x2F;
+    pub const LOAD_TLUT: u8 = 0x30;
+    pub const SET_TILE_SIZE: u8 = 0x32;
+    pub const LOAD_BLOCK: u8 = 0x33;
+    pub const LOAD_TILE: u8 = 0x34;
+    pub const SET_TILE: u8 = 0x35;
+    pub const FILL_RECTANGLE: u8 = 0x36;
+    pub const SET_FILL_COLOR: u8 = 0x37;
+    pub const SET_FOG_COLOR: u8 = 0x38;
+    pub const SET_BLEND_COLOR: u8 = 0x39;
+    pub const SET_PRIM_COLOR: u8 = 0x3A;
+    pub const SET_ENV_COLOR: u8 = 0x3B;
+    pub const SET_TEXTURE_IMAGE: u8 = 0x3D;
+    pub const SET_COLOR_IMAGE: u8 = 0x3F;
+}
+
+/// Triangle commands are `0x08..=0x0F`, with these flags in the low bits
+pub mod triangle {
+    pub const ZBUFFER: u8 = 1 << 0;
+    pub const TEXTURE: u8 = 1 << 1;
+    pub const SHADE: u8 = 1 << 2;
+}
+
+/// Number of 64-bit words of the command starting with `word`
+pub fn command_len(word: u64) -> usize {
+    let id = command_id(word);
+    match id {
+        0x08..=0x0F => {
+            let mut len = 4;
+            if id & triangle::SHADE != 0 {
+                len += 8;
+            }
+            if id & triangle::TEXTURE != 0 {
+                len += 8;
+            }
+            if id & triangle::ZBUFFER != 0 {
+                len += 2;
+            }
+            len
+        }
+        command::TEXTURE_RECTANGLE | command::TEXTURE_RECTANGLE_FLIP => 2,
+        _ => 1,
+    }
+}
+
+/// Identifier of the command starting with `word`
+pub fn command_id(word: u64) -> u8 {
+    (word >> 56) as u8 & 0x3F
+}
+
+/// A command list written through the DP registers, waiting to be fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DpCommandList {
+    pub start: usize,
+    pub end: usize,
+    /// The list lives in DMEM instead of RDRAM
+    pub xbus: bool,
+}
+
+/// A renderer for the RDP commands.
+///
+/// The built-in `Rasterizer` draws in software, but other renderers can be
+/// plugged in with `N64::set_rdp_backend`. Backends always receive complete
+/// commands, and may keep the drawn images on their side as long as they are
+/// written back to RDRAM on `sync_full`. Like the rest of the state, backends
+/// may be sent to the thread running the emulation.
+pub trait RdpBackend: Send {
+    /// Run a list of complete commands, reading the textures from `rdram`
+    fn process_command_list(&mut self, commands: &[u64], rdram: &mut [u8]);
+
+    /// Called after processing a `SYNC_FULL` command, right before the DP
+    /// interrupt is raised. Everything drawn so far must be in `rdram` when
+    /// it returns
+    fn sync_full(&mut self, _rdram: &mut [u8]) {}
+
+    /// Called when the CPU writes into `range` of the RDRAM, while it holds
+    /// the current color image
+    fn framebuffer_dirty(&mut self, _range: Range<usize>) {}
+}
+
+impl Debug for dyn RdpBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RdpBackend")
+    }
+}
+
+/// The Reality Display Processor (RDP) command interface.
+///
+/// Command lists are requested through `DPC_START`/`DPC_END` and fetched by
+/// the memory manager, which hands the complete commands to the backend.
+#[derive(Debug)]
+pub struct Rdp {
+    start: u32,
+    end: u32,
+    current: u32,
+    status: u32,
+    pending_list: Option<DpCommandList>,
+    /// Words of a command split across two command lists
+    partial: Vec<u64>,
+    backend: Option<Box<dyn RdpBackend>>,
+    /// Current color image address and size, and scissor bottom line
+    color_image: (usize, usize, usize),
+    scissor_bottom: usize,
+}
+
+impl Rdp {
+    pub fn new() -> Rdp {
+        Self {
+            start: 0,
+            end: 0,
+            current: 0,
+            status: dpc_status::CBUF_READY,
+            pending_list: None,
+            partial: Vec::new(),
+            backend: Some(Box::<Rasterizer>::default()),
+            color_image: (0, 0, 0),
+            scissor_bottom: 0,
+        }
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Take the command list requested by the last register write
+    pub fn take_pending_list(&mut self) -> Option<DpCommandList> {
+        self.pending_list.take()
+    }
+
+    /// Reset the registers and drop the partial command, keeping the backend
+    pub fn reset(&mut self) {
+        *self = Self {
+            backend: self.backend.take(),
+            ..Self::new()
+        };
+    }
+
+    pub fn set_backend(&mut self, backend: Box<dyn RdpBackend>) {
+        self.backend = Some(backend);
+    }
+
+    /// Take the backend out while a command list runs with access to the
+    /// RDRAM. It must be given back with `restore_backend`
+    pub fn take_backend(&mut self) -> Option<Box<dyn RdpBackend>> {
+        self.backend.take()
+    }
+
+    pub fn restore_backend(&mut self, backend: Box<dyn RdpBackend>) {
+        self.backend = Some(backend);
+    }
+
+    /// Notify the backend of a CPU write into the current color image
+    pub fn framebuffer_dirty(&mut self, range: Range<usize>) {
+        if let Some(backend) = self.backend.as_mut() {
+            backend.framebuffer_dirty(range);
+        }
+    }
+
+    /// RDRAM range of the current color image, down to the scissor bottom
+    pub fn framebuffer(&self) -> Option<Range<usize>> {
+        let (addr, width, size) = self.color_image;
+        let len = ((width * self.scissor_bottom) << size) >> 1;
+        (len != 0).then_some(addr..addr + len)
+    }
+
+    /// Append the fetched `words` to the command buffer and return the
+    /// complete commands, keeping the trailing partial command for the next
+    /// list
+    pub fn push_commands(&mut self, words: &[u64]) -> Vec<u64> {
+        self.partial.extend_from_slice(words);
+
+        let mut complete = 0;
+        while let Some(&word) = self.partial.get(complete) {
+            let len = command_len(word);
+            if complete + len > self.partial.len() {
+                break;
+            }
+            match command_id(word) {
+                command::SET_COLOR_IMAGE => {
+                    self.color_image = (
+                        (word & 0x03FF_FFFF) as usize,
+                        ((word >> 32) & 0x3FF) as usize + 1,
+                        ((word >> 51) & 3) as usize,
+                    );
+                }
+                command::SET_SCISSOR => self.scissor_bottom = (word & 0xFFF) as usize >> 2,
+                _ => {}
+            }
+            complete += len;
+        }
+
+        let rest = self.partial.split_off(complete);
+        std::mem::replace(&mut self.partial, rest)
+    }
+
+    /// Mark the pending command list as fetched
+    pub fn finish_list(&mut self, list: &DpCommandList) {
+        self.current = list.end as u32;
+        self.status &= !(dpc_status::START_VALID | dpc_status::END_VALID);
+    }
+
+    pub fn read_reg(&self, offset: usize) -> u32 {
+        match offset {
+            dpc_reg::START => self.start,
+            dpc_reg::END => self.end,
+            dpc_reg::CURRENT => self.current,
+            dpc_reg::STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, offset: usize, value: u32) {
+        match offset {
+            dpc_reg::START => {
+                self.start = value & 0x00FF_FFF8;
+                self.current = self.start;
+                self.status |= dpc_status::START_VALID;
+            }
+            dpc_reg::END => {
+                self.end = value & 0x00FF_FFF8;
+                self.status |= dpc_status::END_VALID;
+                if self.status & dpc_status::FREEZE == 0 {
+                    self.pending_list = Some(DpCommandList {
+                        start: self.current as usize,
+                        end: self.end as usize,
+                        xbus: self.status & dpc_status::XBUS_DMEM_DMA != 0,
+                    });
+                }
+            }
+            dpc_reg::STATUS => self.write_status(value),
+            _ => tracing::debug!("Unhandled DP register write at 0x{offset:02x}: 0x{value:08x}"),
+        }
+    }
+
+    /// Each status bit is controlled by a pair of clear/set bits
+    fn write_status(&mut self, value: u32) {
+        let mut update = |clear_bit: u32, set_bit: u32, flag: u32| match (
+            value & (1 << clear_bit) != 0,
+            value & (1 << set_bit) != 0,
+        ) {
+            (true, false) => self.status &= !flag,
+            (false, true) => self.status |= flag,
+            _ => {}
+        };
+
+        update(0, 1, dpc_status::XBUS_DMEM_DMA);
+        update(2, 3, dpc_status::FREEZE);
+        update(4, 5, dpc_status::FLUSH);
+    }
+}
+
+impl Default for Rdp {
+    fn default() -> Rdp {
+        Self::new()
+    }
+}
+
+impl MemoryUnit for Rdp {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        I::truncate_u64(self.read_reg(addr) as u64)
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        self.write_reg(addr, value.to_u64() as u32);
+    }
+}
+
+/// The backend state is not saved: command lists are usually complete when a
+/// savestate is made, and the drawn images are already in RDRAM
+impl Snapshot for Rdp {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &[self.start, self.end, self.current, self.status])?;
+        let (addr, width, size) = self.color_image;
+        write_u32s(
+            w,
+            &[addr, width, size, self.scissor_bottom].map(|value| value as u32),
+        )?;
+        w.write_u32::<BigEndian>(self.partial.len() as u32)?;
+        write_u64s(w, &self.partial)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        let mut regs = [0; 4];
+        read_u32s(r, &mut regs)?;
+        [self.start, self.end, self.current, self.status] = regs;
+        let mut framebuffer = [0; 4];
+        read_u32s(r, &mut framebuffer)?;
+        let [addr, width, size, scissor_bottom] = framebuffer.map(|value| value as usize);
+        self.color_image = (addr, width, size);
+        self.scissor_bottom = scissor_bottom;
+
+        let len = r.read_u32::<BigEndian>()? as usize;
+        if len > 32 {
+            return Err(SaveStateError::Invalid("partial RDP command too long"));
+        }
+        self.partial = vec![0; len];
+        read_u64s(r, &mut self.partial)?;
+        self.pending_list = None;
+        Ok(())
+    }
+}
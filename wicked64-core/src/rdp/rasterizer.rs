@@ -0,0 +1,652 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{command, command_id, command_len, triangle, RdpBackend};
+
+/// Size of the texture memory
+const TMEM_SIZE: usize = 0x1000;
+/// Offset of the palettes in TMEM
+const TLUT_OFFSET: usize = 0x800;
+
+/// Image formats
+mod format {
+    pub const RGBA: u8 = 0;
+    pub const CI: u8 = 2;
+    pub const IA: u8 = 3;
+    pub const I: u8 = 4;
+}
+
+/// Image pixel sizes
+mod size {
+    pub const BITS_4: u8 = 0;
+    pub const BITS_8: u8 = 1;
+    pub const BITS_16: u8 = 2;
+    pub const BITS_32: u8 = 3;
+}
+
+/// `SET_OTHER_MODES` bits
+mod other_modes {
+    pub const ALPHA_COMPARE: u64 = 1 << 0;
+    pub const TLUT_IA16: u64 = 1 << 46;
+    pub const CYCLE_TYPE_SHIFT: u64 = 52;
+}
+
+type Color = [u8; 4];
+
+/// Extract `bits` bits of `word` starting at bit `shift`
+fn field(word: u64, shift: u32, bits: u32) -> u32 {
+    ((word >> shift) & ((1 << bits) - 1)) as u32
+}
+
+/// Sign extend the `bits` low bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+fn rgba_from_u32(value: u32) -> Color {
+    value.to_be_bytes()
+}
+
+fn rgba_from_5551(value: u16) -> Color {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [
+        expand((value >> 11) & 0x1F),
+        expand((value >> 6) & 0x1F),
+        expand((value >> 1) & 0x1F),
+        if value & 1 != 0 { 0xFF } else { 0 },
+    ]
+}
+
+fn rgba_to_5551(color: Color) -> u16 {
+    let [r, g, b, a] = color.map(u16::from);
+    ((r >> 3) << 11) | ((g >> 3) << 6) | ((b >> 3) << 1) | u16::from(a >= 0x80)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CycleType {
+    OneCycle,
+    TwoCycle,
+    Copy,
+    Fill,
+}
+
+/// An image in RDRAM, as set by `SET_COLOR_IMAGE` and `SET_TEXTURE_IMAGE`
+#[derive(Debug, Default, Clone, Copy)]
+struct Image {
+    size: u8,
+    width: usize,
+    addr: usize,
+}
+
+impl Image {
+    fn from_command(word: u64) -> Image {
+        Image {
+            size: field(word, 51, 2) as u8,
+            width: field(word, 32, 10) as usize + 1,
+            addr: field(word, 0, 26) as usize,
+        }
+    }
+
+    /// Byte offset of the texel `count` texels after the start of the image
+    fn offset(&self, count: usize) -> usize {
+        self.addr + ((count << self.size) >> 1)
+    }
+}
+
+/// A tile descriptor, describing how a texture is laid out in TMEM
+#[derive(Debug, Default, Clone, Copy)]
+struct Tile {
+    format: u8,
+    size: u8,
+    /// Length of each line in TMEM, in 64-bit words
+    line: usize,
+    /// Address in TMEM, in 64-bit words
+    tmem: usize,
+    palette: usize,
+    mask_s: u32,
+    mask_t: u32,
+    /// Tile coordinates, in 10.2 fixed point
+    sl: u32,
+    tl: u32,
+    sh: u32,
+    th: u32,
+}
+
+impl Tile {
+    /// Distance in bytes between two lines of the tile in TMEM. 32-bit
+    /// textures are split in two halves by the hardware, but they are kept
+    /// contiguous here
+    fn stride(&self) -> usize {
+        if self.size == size::BITS_32 {
+            self.line * 16
+        } else {
+            self.line * 8
+        }
+    }
+
+    fn set_size(&mut self, word: u64) {
+        self.sl = field(word, 44, 12);
+        self.tl = field(word, 32, 12);
+        self.sh = field(word, 12, 12);
+        self.th = field(word, 0, 12);
+    }
+}
+
+/// A rectangle in 10.2 fixed point
+#[derive(Debug, Default, Clone, Copy)]
+struct Rect {
+    xh: u32,
+    yh: u32,
+    xl: u32,
+    yl: u32,
+}
+
+/// An attribute interpolated through a triangle, in s15.16 fixed point
+#[derive(Debug, Default, Clone, Copy)]
+struct Coefficient {
+    base: i32,
+    /// Change per pixel along X
+    dx: i32,
+    /// Change per scanline along the major edge
+    de: i32,
+}
+
+impl Coefficient {
+    /// Read the coefficient at `channel` of a shade or texture coefficient
+    /// block
+    fn read(block: &[u64], channel: u32) -> Coefficient {
+        let shift = 48 - 16 * channel;
+        let value = |int: u64, frac: u64| {
+            ((u32::from((int >> shift) as u16) << 16) | u32::from((frac >> shift) as u16)) as i32
+        };
+        Coefficient {
+            base: value(block[0], block[2]),
+            dx: value(block[1], block[3]),
+            de: value(block[4], block[6]),
+        }
+    }
+
+    /// Value at `dy` scanlines below the start of the major edge and `dx`
+    /// (16.16) away from it
+    fn at(&self, dy: i64, dx: i64) -> i64 {
+        i64::from(self.base) + i64::from(self.de) * dy + ((i64::from(self.dx) * dx) >> 16)
+    }
+}
+
+/// Software implementation of the RDP pipeline.
+///
+/// Covers the fill and copy modes, the rectangle and triangle commands and
+/// the texture loads. The color combiner and the blender are not emulated:
+/// textured primitives are drawn with their texels (modulated by the shade
+/// color), shaded ones with the shade color and the others with the
+/// primitive color. Texture coordinates are not perspective corrected.
+#[derive(Debug)]
+pub struct Rasterizer {
+    other_modes: u64,
+    color_image: Image,
+    texture_image: Image,
+    scissor: Rect,
+    fill_color: u32,
+    prim_color: Color,
+    env_color: Color,
+    blend_color: Color,
+    fog_color: Color,
+    tiles: [Tile; 8],
+    tmem: Box<[u8]>,
+}
+
+impl Default for Rasterizer {
+    fn default() -> Rasterizer {
+        Self {
+            other_modes: 0,
+            color_image: Image::default(),
+            texture_image: Image::default(),
+            scissor: Rect::default(),
+            fill_color: 0,
+            prim_color: Color::default(),
+            env_color: Color::default(),
+            blend_color: Color::default(),
+            fog_color: Color::default(),
+            tiles: [Tile::default(); 8],
+            tmem: vec![0; TMEM_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Rasterizer {
+    /// Run a list of complete commands, drawing into `rdram`
+    pub fn process(&mut self, commands: &[u64], rdram: &mut [u8]) {
+        let mut index = 0;
+        while let Some(&word) = commands.get(index) {
+            let len = command_len(word);
+            let Some(command) = commands.get(index..index + len) else {
+                tracing::warn!("Truncated RDP command: 0x{word:016x}");
+                break;
+            };
+            self.execute(command, rdram);
+            index += len;
+        }
+    }
+
+    fn execute(&mut self, command: &[u64], rdram: &mut [u8]) {
+        let word = command[0];
+        match command_id(word) {
+            id @ 0x08..=0x0F => self.draw_triangle(id, command, rdram),
+            command::TEXTURE_RECTANGLE => self.texture_rectangle(command, false, rdram),
+            command::TEXTURE_RECTANGLE_FLIP => self.texture_rectangle(command, true, rdram),
+            // everything is drawn as soon as it is processed
+            command::SYNC_LOAD | command::SYNC_PIPE | command::SYNC_TILE | command::SYNC_FULL => {}
+            command::SET_SCISSOR => {
+                self.scissor = Rect {
+                    xh: field(word, 44, 12),
+                    yh: field(word, 32, 12),
+                    xl: field(word, 12, 12),
+                    yl: field(word, 0, 12),
+                };
+            }
+            command::SET_OTHER_MODES => self.other_modes = word & 0x00FF_FFFF_FFFF_FFFF,
+            command::LOAD_TLUT => self.load_tlut(word, rdram),
+            command::SET_TILE_SIZE => self.tiles[field(word, 24, 3) as usize].set_size(word),
+            command::LOAD_BLOCK => self.load_block(word, rdram),
+            command::LOAD_TILE => self.load_tile(word, rdram),
+            command::SET_TILE => {
+                self.tiles[field(word, 24, 3) as usize] = Tile {
+                    format: field(word, 53, 3) as u8,
+                    size: field(word, 51, 2) as u8,
+                    line: field(word, 41, 9) as usize,
+                    tmem: field(word, 32, 9) as usize,
+                    palette: field(word, 20, 4) as usize,
+                    mask_t: field(word, 14, 4),
+                    mask_s: field(word, 4, 4),
+                    ..self.tiles[field(word, 24, 3) as usize]
+                };
+            }
+            command::FILL_RECTANGLE => self.fill_rectangle(word, rdram),
+            command::SET_FILL_COLOR => self.fill_color = word as u32,
+            command::SET_FOG_COLOR => self.fog_color = rgba_from_u32(word as u32),
+            command::SET_BLEND_COLOR => self.blend_color = rgba_from_u32(word as u32),
+            command::SET_PRIM_COLOR => self.prim_color = rgba_from_u32(word as u32),
+            command::SET_ENV_COLOR => self.env_color = rgba_from_u32(word as u32),
+            command::SET_TEXTURE_IMAGE => self.texture_image = Image::from_command(word),
+            command::SET_COLOR_IMAGE => self.color_image = Image::from_command(word),
+            id => tracing::trace!("Ignoring RDP command 0x{id:02x}"),
+        }
+    }
+
+    fn cycle_type(&self) -> CycleType {
+        match (self.other_modes >> other_modes::CYCLE_TYPE_SHIFT) & 3 {
+            0 => CycleType::OneCycle,
+            1 => CycleType::TwoCycle,
+            2 => CycleType::Copy,
+            _ => CycleType::Fill,
+        }
+    }
+
+    /// Pixel bounds `(x0, y0, x1, y1)` of a rectangle, clipped by the
+    /// scissor. The end bounds are exclusive
+    fn rect_bounds(&self, rect: Rect) -> (u32, u32, u32, u32) {
+        // the fill and copy modes also draw the bottom-right edges
+        let (x1, y1) = match self.cycle_type() {
+            CycleType::Fill | CycleType::Copy => ((rect.xl >> 2) + 1, (rect.yl >> 2) + 1),
+            _ => ((rect.xl + 3) >> 2, (rect.yl + 3) >> 2),
+        };
+        (
+            (rect.xh >> 2).max(self.scissor.xh >> 2),
+            (rect.yh >> 2).max(self.scissor.yh >> 2),
+            x1.min(self.scissor.xl >> 2)
+                .min(self.color_image.width as u32),
+            y1.min(self.scissor.yl >> 2),
+        )
+    }
+
+    fn fill_rectangle(&mut self, word: u64, rdram: &mut [u8]) {
+        let rect = Rect {
+            xl: field(word, 44, 12),
+            yl: field(word, 32, 12),
+            xh: field(word, 12, 12),
+            yh: field(word, 0, 12),
+        };
+        let (x0, y0, x1, y1) = self.rect_bounds(rect);
+        let fill = self.cycle_type() == CycleType::Fill;
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                if fill {
+                    self.store_fill(rdram, x, y);
+                } else {
+                    self.store_color(rdram, x, y, self.prim_color);
+                }
+            }
+        }
+    }
+
+    fn texture_rectangle(&mut self, command: &[u64], flip: bool, rdram: &mut [u8]) {
+        let (word, coords) = (command[0], command[1]);
+        let rect = Rect {
+            xl: field(word, 44, 12),
+            yl: field(word, 32, 12),
+            xh: field(word, 12, 12),
+            yh: field(word, 0, 12),
+        };
+        let tile = field(word, 24, 3) as usize;
+
+        // s and t are in s10.5, and their slopes in s5.10
+        let s = i64::from(sign_extend(field(coords, 48, 16), 16)) << 5;
+        let t = i64::from(sign_extend(field(coords, 32, 16), 16)) << 5;
+        let mut dsdx = i64::from(sign_extend(field(coords, 16, 16), 16));
+        let dtdy = i64::from(sign_extend(field(coords, 0, 16), 16));
+        if self.cycle_type() == CycleType::Copy {
+            // the copy mode draws 4 pixels per cycle
+            dsdx >>= 2;
+        }
+
+        let (x0, y0, x1, y1) = self.rect_bounds(rect);
+        for y in y0..y1 {
+            let dy = i64::from(y) - i64::from(rect.yh >> 2);
+            for x in x0..x1 {
+                let dx = i64::from(x) - i64::from(rect.xh >> 2);
+                let (ds, dt) = if flip { (dy, dx) } else { (dx, dy) };
+                let texel = self.fetch_texel(
+                    tile,
+                    ((s + dsdx * ds) >> 10) as i32,
+                    ((t + dtdy * dt) >> 10) as i32,
+                );
+                if self.is_visible(texel) {
+                    self.store_color(rdram, x, y, texel);
+                }
+            }
+        }
+    }
+
+    fn draw_triangle(&mut self, id: u8, command: &[u64], rdram: &mut [u8]) {
+        let word = command[0];
+        let tile = field(word, 48, 3) as usize;
+        let yl = sign_extend(field(word, 32, 14), 14);
+        let ym = sign_extend(field(word, 16, 14), 14);
+        let yh = sign_extend(field(word, 0, 14), 14);
+        // the edges positions are in s15.16 and their slopes per scanline
+        let edge = |word: u64| (i64::from((word >> 32) as i32), i64::from(word as i32));
+        let (xl, dxldy) = edge(command[1]);
+        let (xh, dxhdy) = edge(command[2]);
+        let (xm, dxmdy) = edge(command[3]);
+
+        let mut blocks = command[4..].chunks_exact(8);
+        let shade = (id & triangle::SHADE != 0)
+            .then(|| blocks.next())
+            .flatten()
+            .map(|block| [0, 1, 2, 3].map(|c| Coefficient::read(block, c)));
+        let texture = (id & triangle::TEXTURE != 0)
+            .then(|| blocks.next())
+            .flatten()
+            .map(|block| [0, 1].map(|c| Coefficient::read(block, c)));
+
+        let y_start = yh >> 2;
+        let y_mid = ym >> 2;
+        let y0 = y_start.max((self.scissor.yh >> 2) as i32);
+        let y1 = ((yl + 3) >> 2).min((self.scissor.yl >> 2) as i32);
+        let x_min = i64::from(self.scissor.xh >> 2);
+        let x_max = i64::from(self.scissor.xl >> 2).min(self.color_image.width as i64);
+        let fill = self.cycle_type() == CycleType::Fill;
+
+        for y in y0..y1 {
+            let dy = i64::from(y - y_start);
+            let major = xh + dxhdy * dy;
+            let minor = if y < y_mid {
+                xm + dxmdy * dy
+            } else {
+                xl + dxldy * i64::from(y - y_mid)
+            };
+            let x0 = (major.min(minor) >> 16).max(x_min);
+            let x1 = (major.max(minor) >> 16).min(x_max);
+
+            for x in x0..x1 {
+                let dx = (x << 16) - major;
+                let shade_color =
+                    shade.map(|shade| shade.map(|c| (c.at(dy, dx) >> 16).clamp(0, 0xFF) as u8));
+                let color = if let Some([s, t]) = texture {
+                    // texture coordinates are in s10.5 in the integer part
+                    let texel = self.fetch_texel(
+                        tile,
+                        (s.at(dy, dx) >> 21) as i32,
+                        (t.at(dy, dx) >> 21) as i32,
+                    );
+                    match shade_color {
+                        Some(shade) => [0, 1, 2, 3]
+                            .map(|i| ((u16::from(texel[i]) * u16::from(shade[i])) / 0xFF) as u8),
+                        None => texel,
+                    }
+                } else if let Some(shade) = shade_color {
+                    shade
+                } else {
+                    self.prim_color
+                };
+
+                let (x, y) = (x as u32, y as u32);
+                if fill {
+                    self.store_fill(rdram, x, y);
+                } else if self.is_visible(color) {
+                    self.store_color(rdram, x, y, color);
+                }
+            }
+        }
+    }
+
+    fn is_visible(&self, color: Color) -> bool {
+        self.other_modes & other_modes::ALPHA_COMPARE == 0 || color[3] != 0
+    }
+
+    fn load_tile(&mut self, word: u64, rdram: &[u8]) {
+        let index = field(word, 24, 3) as usize;
+        self.tiles[index].set_size(word);
+        let tile = self.tiles[index];
+        let image = self.texture_image;
+
+        let (sl, tl) = ((tile.sl >> 2) as usize, (tile.tl >> 2) as usize);
+        let (sh, th) = ((tile.sh >> 2) as usize, (tile.th >> 2) as usize);
+        let row_len = image.offset(sh + 1 - sl) - image.addr;
+
+        for t in tl..=th {
+            let src = image.offset(t * image.width + sl);
+            let dst = tile.tmem * 8 + (t - tl) * tile.stride();
+            self.copy_to_tmem(rdram, src, dst, row_len);
+        }
+    }
+
+    fn load_block(&mut self, word: u64, rdram: &[u8]) {
+        let tile = self.tiles[field(word, 24, 3) as usize];
+        let image = self.texture_image;
+
+        let (sl, tl) = (field(word, 44, 12) as usize, field(word, 32, 12) as usize);
+        let texels = (field(word, 12, 12) as usize + 1).saturating_sub(sl);
+        let src = image.offset(tl * image.width + sl);
+        let len = image.offset(texels) - image.addr;
+        self.copy_to_tmem(rdram, src, tile.tmem * 8, len);
+    }
+
+    /// Load a palette of 16-bit colors into the upper half of TMEM
+    fn load_tlut(&mut self, word: u64, rdram: &[u8]) {
+        let tile = self.tiles[field(word, 24, 3) as usize];
+        let image = self.texture_image;
+
+        let (sl, sh) = (field(word, 46, 10) as usize, field(word, 14, 10) as usize);
+        let len = (sh + 1).saturating_sub(sl) * 2;
+        self.copy_to_tmem(rdram, image.addr + sl * 2, tile.tmem * 8, len);
+    }
+
+    fn copy_to_tmem(&mut self, rdram: &[u8], src: usize, dst: usize, len: usize) {
+        let Some(data) = rdram.get(src..src + len) else {
+            tracing::warn!("Invalid RDP texture load at 0x{src:08x}");
+            return;
+        };
+        for (i, byte) in data.iter().enumerate() {
+            self.tmem[(dst + i) % TMEM_SIZE] = *byte;
+        }
+    }
+
+    /// Sample the texel at (`s`, `t`) of `tile`
+    fn fetch_texel(&self, tile: usize, s: i32, t: i32) -> Color {
+        let tile = &self.tiles[tile];
+        let wrap = |coord: i32, mask: u32| {
+            if mask == 0 {
+                coord.max(0) as usize
+            } else {
+                (coord & ((1 << mask) - 1)) as usize
+            }
+        };
+        let s = wrap(s - (tile.sl >> 2) as i32, tile.mask_s);
+        let t = wrap(t - (tile.tl >> 2) as i32, tile.mask_t);
+
+        let addr = tile.tmem * 8 + t * tile.stride() + ((s << tile.size) >> 1);
+        let byte = |offset: usize| self.tmem[(addr + offset) % TMEM_SIZE];
+        let nibble = if s & 1 == 0 {
+            byte(0) >> 4
+        } else {
+            byte(0) & 0xF
+        };
+
+        match (tile.format, tile.size) {
+            (format::RGBA, size::BITS_16) => rgba_from_5551(u16::from_be_bytes([byte(0), byte(1)])),
+            (format::RGBA, size::BITS_32) => [byte(0), byte(1), byte(2), byte(3)],
+            (format::IA, size::BITS_16) => [byte(0), byte(0), byte(0), byte(1)],
+            (format::IA, size::BITS_8) => {
+                let (i, a) = ((byte(0) >> 4) * 0x11, (byte(0) & 0xF) * 0x11);
+                [i, i, i, a]
+            }
+            (format::IA, size::BITS_4) => {
+                let i = ((nibble >> 1) * 0xFF) / 7;
+                [i, i, i, if nibble & 1 != 0 { 0xFF } else { 0 }]
+            }
+            (format::I, size::BITS_8) => [byte(0); 4],
+            (format::I, size::BITS_4) => [nibble * 0x11; 4],
+            (format::CI, size::BITS_8) => self.palette_color(usize::from(byte(0))),
+            (format::CI, size::BITS_4) => {
+                self.palette_color(tile.palette * 16 + usize::from(nibble))
+            }
+            _ => Color::default(),
+        }
+    }
+
+    fn palette_color(&self, index: usize) -> Color {
+        let addr = TLUT_OFFSET + index * 2;
+        let entry = BigEndian::read_u16(&self.tmem[addr..addr + 2]);
+        if self.other_modes & other_modes::TLUT_IA16 != 0 {
+            let [i, a] = entry.to_be_bytes();
+            [i, i, i, a]
+        } else {
+            rgba_from_5551(entry)
+        }
+    }
+
+    /// Offset of the pixel (`x`, `y`) of the color image
+    fn pixel_offset(&self, x: u32, y: u32) -> usize {
+        let image = &self.color_image;
+        image.offset(y as usize * image.width + x as usize)
+    }
+
+    /// Store the fill color at (`x`, `y`). On 16-bit images, the fill color
+    /// holds the colors of two horizontally adjacent pixels
+    fn store_fill(&self, rdram: &mut [u8], x: u32, y: u32) {
+        let offset = self.pixel_offset(x, y);
+        match self.color_image.size {
+            size::BITS_16 => {
+                let color = (self.fill_color >> (16 * (1 - (x & 1)))) as u16;
+                if let Some(pixel) = rdram.get_mut(offset..offset + 2) {
+                    BigEndian::write_u16(pixel, color);
+                }
+            }
+            size::BITS_32 => {
+                if let Some(pixel) = rdram.get_mut(offset..offset + 4) {
+                    BigEndian::write_u32(pixel, self.fill_color);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn store_color(&self, rdram: &mut [u8], x: u32, y: u32, color: Color) {
+        let offset = self.pixel_offset(x, y);
+        match self.color_image.size {
+            size::BITS_16 => {
+                if let Some(pixel) = rdram.get_mut(offset..offset + 2) {
+                    BigEndian::write_u16(pixel, rgba_to_5551(color));
+                }
+            }
+            size::BITS_32 => {
+                if let Some(pixel) = rdram.get_mut(offset..offset + 4) {
+                    pixel.copy_from_slice(&color);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl RdpBackend for Rasterizer {
+    fn process_command_list(&mut self, commands: &[u64], rdram: &mut [u8]) {
+        self.process(commands, rdram);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u64 = 16;
+
+    /// Commands setting a 16x16 RGBA5551 color image at 0x100, with the
+    /// scissor covering it, in the given cycle type
+    fn setup(cycle_type: u64) -> Vec<u64> {
+        vec![
+            0x3F10_0000_0000_0100 | ((WIDTH - 1) << 32),
+            0x2D00_0000_0004_0040,
+            0x2F00_0000_0000_0000 | (cycle_type << other_modes::CYCLE_TYPE_SHIFT),
+        ]
+    }
+
+    fn pixel(rdram: &[u8], x: usize, y: usize) -> u16 {
+        BigEndian::read_u16(&rdram[0x100 + (y * WIDTH as usize + x) * 2..])
+    }
+
+    #[test]
+    fn it_should_fill_rectangles() {
+        let mut rasterizer = Rasterizer::default();
+        let mut rdram = vec![0u8; 0x1000];
+
+        let mut commands = setup(3);
+        // fill color, then fill (2, 1)..=(5, 3)
+        commands.push(0x3700_0000_F801_F801);
+        commands.push(0x3601_400C_0000_8004);
+
+        rasterizer.process(&commands, &mut rdram);
+
+        assert_eq!(pixel(&rdram, 2, 1), 0xF801);
+        assert_eq!(pixel(&rdram, 5, 3), 0xF801);
+        assert_eq!(pixel(&rdram, 1, 1), 0);
+        assert_eq!(pixel(&rdram, 6, 3), 0);
+        assert_eq!(pixel(&rdram, 2, 4), 0);
+    }
+
+    #[test]
+    fn it_should_rasterize_flat_triangles() {
+        let mut rasterizer = Rasterizer::default();
+        let mut rdram = vec![0u8; 0x1000];
+
+        let mut commands = setup(0);
+        commands.push(0x3A00_0000_00FF_00FF);
+        // right triangle with its vertical major edge at x = 0 and the minor
+        // edge going from x = 8 at y = 0 to x = 0 at y = 8
+        commands.push(0x0880_0020_0020_0000);
+        commands.push(0x0000_0000_0000_0000);
+        commands.push(0x0000_0000_0000_0000);
+        commands.push(0x0008_0000_FFFF_0000);
+
+        rasterizer.process(&commands, &mut rdram);
+
+        let green = rgba_to_5551([0, 0xFF, 0, 0xFF]);
+        assert_eq!(pixel(&rdram, 0, 0), green);
+        assert_eq!(pixel(&rdram, 7, 0), green);
+        assert_eq!(pixel(&rdram, 8, 0), 0);
+        assert_eq!(pixel(&rdram, 3, 4), green);
+        assert_eq!(pixel(&rdram, 4, 4), 0);
+        assert_eq!(pixel(&rdram, 0, 8), 0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::Debug;
+
+use super::{sp_status, Rsp};
+
+/// Offset of the `OSTask` structure in DMEM
+pub const OS_TASK_OFFSET: usize = 0xFC0;
+
+/// `OSTask` types
+pub mod task_type {
+    pub const GRAPHICS: u32 = 1;
+    pub const AUDIO: u32 = 2;
+}
+
+/// Signal set by the microcode when a task is done
+const SIG_TASK_DONE: u32 = sp_status::SIG0 << 2;
+
+/// Task description written to DMEM by the OS before starting the RSP
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OsTask {
+    pub task_type: u32,
+    pub flags: u32,
+    pub ucode_boot: u32,
+    pub ucode_boot_size: u32,
+    pub ucode: u32,
+    pub ucode_size: u32,
+    pub ucode_data: u32,
+    pub ucode_data_size: u32,
+    pub dram_stack: u32,
+    pub dram_stack_size: u32,
+    pub output_buff: u32,
+    pub output_buff_size: u32,
+    pub data_ptr: u32,
+    pub data_size: u32,
+    pub yield_data_ptr: u32,
+    pub yield_data_size: u32,
+}
+
+impl OsTask {
+    /// Read the task from DMEM
+    pub fn read(dmem: &[u8]) -> OsTask {
+        let mut words = dmem[OS_TASK_OFFSET..OS_TASK_OFFSET + 0x40]
+            .chunks_exact(4)
+            .map(|word| u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+        let mut next = || words.next().unwrap_or_default();
+
+        Self {
+            task_type: next(),
+            flags: next(),
+            ucode_boot: next(),
+            ucode_boot_size: next(),
+            ucode: next(),
+            ucode_size: next(),
+            ucode_data: next(),
+            ucode_data_size: next(),
+            dram_stack: next(),
+            dram_stack_size: next(),
+            output_buff: next(),
+            output_buff_size: next(),
+            data_ptr: next(),
+            data_size: next(),
+            yield_data_ptr: next(),
+            yield_data_size: next(),
+        }
+    }
+}
+
+/// High-level implementation of a kind of RSP task, such as the graphics or
+/// audio microcodes. Handlers are moved along with the RSP to its thread
+pub trait HleTaskHandler: Send {
+    /// Run the task, reading its data from and writing its output to RDRAM
+    fn run(&mut self, task: &OsTask, rdram: &mut [u8]);
+}
+
+impl Debug for dyn HleTaskHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HleTaskHandler")
+    }
+}
+
+/// Handler that completes the tasks without running them. Lets games keep
+/// running, without graphics or audio output
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SkipTask;
+
+impl HleTaskHandler for SkipTask {
+    fn run(&mut self, task: &OsTask, _rdram: &mut [u8]) {
+        tracing::trace!("Skipping RSP task of type {}", task.task_type);
+    }
+}
+
+/// Handlers of the tasks run by the HLE RSP. Tasks without a handler run on
+/// the LLE RSP
+#[derive(Debug, Default)]
+pub struct Hle {
+    handlers: Vec<(u32, Box<dyn HleTaskHandler>)>,
+}
+
+impl Hle {
+    /// Create the HLE with the default handlers, which skip the graphics and
+    /// audio tasks
+    pub fn new() -> Hle {
+        let mut hle = Self::default();
+        hle.set_handler(task_type::GRAPHICS, Box::new(SkipTask));
+        hle.set_handler(task_type::AUDIO, Box::new(SkipTask));
+        hle
+    }
+
+    /// Set the handler of the tasks of type `task_type`. Refer to `task_type`
+    pub fn set_handler(&mut self, task_type: u32, handler: Box<dyn HleTaskHandler>) {
+        self.handlers.retain(|(ty, _)| *ty != task_type);
+        self.handlers.push((task_type, handler));
+    }
+
+    fn take_handler(&mut self, task_type: u32) -> Option<Box<dyn HleTaskHandler>> {
+        let index = self.handlers.iter().position(|(ty, _)| *ty == task_type)?;
+        Some(self.handlers.swap_remove(index).1)
+    }
+}
+
+/// A task taken by the HLE, to be run by the memory manager, which has access
+/// to RDRAM
+#[derive(Debug)]
+pub struct HleTask {
+    pub task: OsTask,
+    pub handler: Box<dyn HleTaskHandler>,
+}
+
+impl Rsp {
+    pub fn hle(&self) -> Option<&Hle> {
+        self.hle.as_ref()
+    }
+    pub fn hle_mut(&mut self) -> Option<&mut Hle> {
+        self.hle.as_mut()
+    }
+
+    /// Enable or disable the high-level emulation of the RSP tasks
+    pub fn set_hle(&mut self, hle: Option<Hle>) {
+        self.hle = hle;
+    }
+
+    /// Take the task started by the CPU, if the HLE can handle it
+    pub fn take_hle_task(&mut self) -> Option<HleTask> {
+        if self.is_halted() {
+            return None;
+        }
+        let task = OsTask::read(self.dmem());
+        let handler = self.hle.as_mut()?.take_handler(task.task_type)?;
+        Some(HleTask { task, handler })
+    }
+
+    /// Finish the task run by the HLE, halting the RSP as the microcode does
+    /// when done
+    pub fn finish_hle_task(&mut self, task: HleTask) {
+        if let Some(hle) = self.hle.as_mut() {
+            hle.handlers.push((task.task.task_type, task.handler));
+        }
+
+        self.status |= sp_status::HALT | sp_status::BROKE | SIG_TASK_DONE;
+        if self.status & sp_status::INTR_BREAK != 0 {
+            self.interrupt = Some(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    struct CountTasks(Arc<AtomicUsize>);
+
+    impl HleTaskHandler for CountTasks {
+        fn run(&mut self, task: &OsTask, _rdram: &mut [u8]) {
+            assert_eq!(task.data_ptr, 0x0010_0000);
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn it_should_dispatch_the_os_task_to_the_hle() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut hle = Hle::new();
+        hle.set_handler(task_type::GRAPHICS, Box::new(CountTasks(count.clone())));
+
+        let mut rsp = Rsp::new();
+        rsp.set_hle(Some(hle));
+        let dmem = rsp.dmem_mut();
+        dmem[OS_TASK_OFFSET + 3] = task_type::GRAPHICS as u8;
+        dmem[OS_TASK_OFFSET + 0x30..OS_TASK_OFFSET + 0x34].copy_from_slice(&[0, 0x10, 0, 0]);
+
+        assert!(rsp.take_hle_task().is_none(), "the RSP is halted");
+        rsp.write_status(1 << 0 | 1 << 8);
+
+        let mut task = rsp.take_hle_task().unwrap();
+        task.handler.run(&task.task, &mut []);
+        rsp.finish_hle_task(task);
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(rsp.is_halted());
+        assert_ne!(rsp.status() & SIG_TASK_DONE, 0);
+        assert_eq!(rsp.take_interrupt(), Some(true));
+    }
+}
@@ -0,0 +1,350 @@
+pub mod hle;
+mod su;
+mod thread;
+mod vu;
+
+use std::{
+    cell::Cell,
+    io::{Read, Write},
+};
+
+use byteorder::{ByteOrder, WriteBytesExt};
+
+pub use thread::{RspGuard, RspThread};
+
+use crate::{
+    mmu::{num::MemInteger, MemoryUnit},
+    savestate::{
+        read_bool, read_bytes, read_u32s, write_bytes, write_u32s, SaveStateResult, Snapshot,
+    },
+};
+
+/// Size of both DMEM and IMEM
+pub const SP_MEM_SIZE: usize = 0x1000;
+
+/// Offset of IMEM from the start of the RSP address space
+const IMEM_OFFSET: usize = SP_MEM_SIZE;
+/// DMEM and IMEM are mirrored through the whole memory range
+const SP_MEM_RANGE_END: usize = 0x4_0000;
+
+/// SP registers offsets, from the start of the RSP address space
+pub mod sp_reg {
+    pub const MEM_ADDR: usize = 0x4_0000;
+    pub const DRAM_ADDR: usize = 0x4_0004;
+    pub const RD_LEN: usize = 0x4_0008;
+    pub const WR_LEN: usize = 0x4_000C;
+    pub const STATUS: usize = 0x4_0010;
+    pub const DMA_FULL: usize = 0x4_0014;
+    pub const DMA_BUSY: usize = 0x4_0018;
+    pub const SEMAPHORE: usize = 0x4_001C;
+    pub const PC: usize = 0x8_0000;
+}
+
+/// `SP_STATUS` bits, as read by the CPU
+pub mod sp_status {
+    pub const HALT: u32 = 1 << 0;
+    pub const BROKE: u32 = 1 << 1;
+    pub const DMA_BUSY: u32 = 1 << 2;
+    pub const DMA_FULL: u32 = 1 << 3;
+    pub const IO_FULL: u32 = 1 << 4;
+    pub const SSTEP: u32 = 1 << 5;
+    pub const INTR_BREAK: u32 = 1 << 6;
+    /// First of the 8 signal bits
+    pub const SIG0: u32 = 1 << 7;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpDmaDirection {
+    /// Copy from RDRAM into DMEM/IMEM (`SP_RD_LEN`)
+    RdramToSp,
+    /// Copy from DMEM/IMEM into RDRAM (`SP_WR_LEN`)
+    SpToRdram,
+}
+
+/// A DMA transfer requested through the SP registers. `count` rows of `len`
+/// bytes are copied, skipping `skip` bytes in RDRAM after each row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpDma {
+    pub mem_addr: usize,
+    pub dram_addr: usize,
+    pub len: usize,
+    pub count: usize,
+    pub skip: usize,
+    pub direction: SpDmaDirection,
+}
+
+/// The Reality Signal Processor (RSP).
+///
+/// Owns the DMEM and IMEM memories and the SP registers. The scalar and vector
+/// units run the microcode in IMEM while the CPU keeps the halt bit cleared.
+///
+/// As with the other devices, DMA transfers are only requested here, and
+/// performed by the memory manager.
+#[derive(Debug)]
+pub struct Rsp {
+    /// DMEM followed by IMEM
+    mem: Box<[u8]>,
+    su: Box<su::ScalarUnit>,
+    vu: Box<vu::VectorUnit>,
+    mem_addr: u32,
+    dram_addr: u32,
+    rd_len: u32,
+    wr_len: u32,
+    status: u32,
+    semaphore: Cell<bool>,
+    pending_dma: Option<SpDma>,
+    /// Pending change of the SP interrupt line
+    interrupt: Option<bool>,
+    /// High-level emulation of the tasks. Disabled when `None`
+    hle: Option<hle::Hle>,
+    /// Copy of the DP command registers, read through COP0 registers 8-15
+    dp_regs: [u32; 8],
+    /// DP command registers written through COP0, waiting to be forwarded
+    dp_writes: Vec<(usize, u32)>,
+}
+
+impl Rsp {
+    pub fn new() -> Rsp {
+        Self {
+            mem: vec![0; 2 * SP_MEM_SIZE].into_boxed_slice(),
+            su: Box::default(),
+            vu: Box::default(),
+            mem_addr: 0,
+            dram_addr: 0,
+            rd_len: 0,
+            wr_len: 0,
+            status: sp_status::HALT,
+            semaphore: Cell::new(false),
+            pending_dma: None,
+            interrupt: None,
+            hle: None,
+            dp_regs: [0; 8],
+            dp_writes: Vec::new(),
+        }
+    }
+
+    /// Halt the RSP and clear its memories and registers, keeping the HLE
+    /// configuration
+    pub fn reset(&mut self) {
+        *self = Self {
+            hle: self.hle.take(),
+            ..Self::new()
+        };
+    }
+
+    pub fn dmem(&self) -> &[u8] {
+        &self.mem[..SP_MEM_SIZE]
+    }
+    pub fn dmem_mut(&mut self) -> &mut [u8] {
+        &mut self.mem[..SP_MEM_SIZE]
+    }
+    pub fn imem(&self) -> &[u8] {
+        &self.mem[IMEM_OFFSET..]
+    }
+    pub fn imem_mut(&mut self) -> &mut [u8] {
+        &mut self.mem[IMEM_OFFSET..]
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.status & sp_status::HALT != 0
+    }
+
+    /// Run the scalar unit for up to `cycles` cycles, or until it halts
+    pub fn step(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            if self.is_halted() {
+                break;
+            }
+            self.step_su();
+            if self.status & sp_status::SSTEP != 0 {
+                self.status |= sp_status::HALT;
+            }
+        }
+    }
+
+    /// Take the DMA transfer requested by the last register write
+    pub fn take_pending_dma(&mut self) -> Option<SpDma> {
+        self.pending_dma.take()
+    }
+
+    /// Take the pending change of the SP interrupt line: `true` to raise it
+    /// and `false` to acknowledge it
+    pub fn take_interrupt(&mut self) -> Option<bool> {
+        self.interrupt.take()
+    }
+
+    /// Take the DP command registers writes done by the microcode, as
+    /// `(offset, value)` pairs
+    pub fn take_dp_writes(&mut self) -> Vec<(usize, u32)> {
+        std::mem::take(&mut self.dp_writes)
+    }
+
+    /// Update the copy of the DP command registers seen by the microcode
+    pub fn set_dp_regs(&mut self, regs: [u32; 8]) {
+        self.dp_regs = regs;
+    }
+
+    /// Mark the current DMA transfer as completed
+    pub fn finish_dma(&mut self, dma: &SpDma) {
+        let transferred = (dma.len * dma.count) as u32;
+        self.mem_addr = (self.mem_addr & 0x1000) | (self.mem_addr + transferred) & 0xFF8;
+        self.dram_addr = self.dram_addr + transferred + (dma.skip * dma.count) as u32;
+        self.rd_len = 0xFF8;
+        self.wr_len = 0xFF8;
+        self.status &= !(sp_status::DMA_BUSY | sp_status::DMA_FULL);
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        match offset {
+            sp_reg::MEM_ADDR => self.mem_addr,
+            sp_reg::DRAM_ADDR => self.dram_addr,
+            sp_reg::RD_LEN => self.rd_len,
+            sp_reg::WR_LEN => self.wr_len,
+            sp_reg::STATUS => self.status,
+            sp_reg::DMA_FULL => u32::from(self.status & sp_status::DMA_FULL != 0),
+            sp_reg::DMA_BUSY => u32::from(self.status & sp_status::DMA_BUSY != 0),
+            // reading the semaphore acquires it
+            sp_reg::SEMAPHORE => u32::from(self.semaphore.replace(true)),
+            sp_reg::PC => self.su.pc,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        match offset {
+            sp_reg::MEM_ADDR => self.mem_addr = value & 0x1FF8,
+            sp_reg::DRAM_ADDR => self.dram_addr = value & 0x00FF_FFF8,
+            sp_reg::RD_LEN => {
+                self.rd_len = value;
+                self.start_dma(value, SpDmaDirection::RdramToSp);
+            }
+            sp_reg::WR_LEN => {
+                self.wr_len = value;
+                self.start_dma(value, SpDmaDirection::SpToRdram);
+            }
+            sp_reg::STATUS => self.write_status(value),
+            sp_reg::SEMAPHORE => self.semaphore.set(false),
+            sp_reg::PC => self.su.jump(value),
+            _ => tracing::debug!("Unhandled SP register write at 0x{offset:05x}: 0x{value:08x}"),
+        }
+    }
+
+    /// Each status bit is controlled by a pair of clear/set bits
+    fn write_status(&mut self, value: u32) {
+        let mut update = |clear_bit: u32, set_bit: u32, flag: u32| match (
+            value & (1 << clear_bit) != 0,
+            value & (1 << set_bit) != 0,
+        ) {
+            (true, false) => self.status &= !flag,
+            (false, true) => self.status |= flag,
+            _ => {}
+        };
+
+        update(0, 1, sp_status::HALT);
+        update(5, 6, sp_status::SSTEP);
+        update(7, 8, sp_status::INTR_BREAK);
+        for signal in 0..8 {
+            update(9 + signal * 2, 10 + signal * 2, sp_status::SIG0 << signal);
+        }
+
+        if value & (1 << 2) != 0 {
+            self.status &= !sp_status::BROKE;
+        }
+        match (value & (1 << 3) != 0, value & (1 << 4) != 0) {
+            (true, false) => self.interrupt = Some(false),
+            (false, true) => self.interrupt = Some(true),
+            _ => {}
+        }
+    }
+
+    fn start_dma(&mut self, value: u32, direction: SpDmaDirection) {
+        self.status |= sp_status::DMA_BUSY;
+        self.pending_dma = Some(SpDma {
+            mem_addr: self.mem_addr as usize,
+            dram_addr: self.dram_addr as usize,
+            len: ((value & 0xFFF) as usize | 7) + 1,
+            count: ((value >> 12) & 0xFF) as usize + 1,
+            skip: ((value >> 20) & 0xFFF) as usize & !7,
+            direction,
+        });
+    }
+}
+
+impl Default for Rsp {
+    fn default() -> Rsp {
+        Self::new()
+    }
+}
+
+impl MemoryUnit for Rsp {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        if addr < SP_MEM_RANGE_END {
+            let addr = addr % (2 * SP_MEM_SIZE);
+            I::read_from::<O>(&self.mem[addr..addr + I::SIZE])
+        } else {
+            I::truncate_u64(self.read_reg(addr) as u64)
+        }
+    }
+
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        if addr < SP_MEM_RANGE_END {
+            let addr = addr % (2 * SP_MEM_SIZE);
+            I::write_to::<O>(&mut self.mem[addr..addr + I::SIZE], value);
+        } else {
+            self.write_reg(addr, value.to_u64() as u32);
+        }
+    }
+
+    fn buffer(&self) -> &[u8] {
+        &self.mem
+    }
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+}
+
+impl Snapshot for Rsp {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_bytes(w, &self.mem)?;
+        self.su.save(w)?;
+        self.vu.save(w)?;
+        write_u32s(
+            w,
+            &[
+                self.mem_addr,
+                self.dram_addr,
+                self.rd_len,
+                self.wr_len,
+                self.status,
+            ],
+        )?;
+        w.write_u8(u8::from(self.semaphore.get()))?;
+        write_u32s(w, &self.dp_regs)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        read_bytes(r, &mut self.mem)?;
+        self.su.load(r)?;
+        self.vu.load(r)?;
+        let mut regs = [0; 5];
+        read_u32s(r, &mut regs)?;
+        [
+            self.mem_addr,
+            self.dram_addr,
+            self.rd_len,
+            self.wr_len,
+            self.status,
+        ] = regs;
+        self.semaphore.set(read_bool(r)?);
+        read_u32s(r, &mut self.dp_regs)?;
+
+        self.pending_dma = None;
+        self.interrupt = None;
+        self.dp_writes.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{Read, Write};
+
+use crate::savestate::{read_u32s, write_u32s, SaveStateResult, Snapshot};
+
+use super::{sp_status, Rsp, IMEM_OFFSET, SP_MEM_SIZE};
+
+/// Mask applied to the RSP program counter (IMEM addresses)
+const PC_MASK: u32 = 0xFFC;
+
+/// RSP scalar unit registers
+#[derive(Debug, Default, Clone)]
+pub struct ScalarUnit {
+    pub gpr: [u32; 32],
+    pub pc: u32,
+    /// Address of the next instruction, used to implement delay slots
+    next_pc: u32,
+}
+
+impl ScalarUnit {
+    /// Set the program counter, discarding any pending branch
+    pub fn jump(&mut self, addr: u32) {
+        self.pc = addr & PC_MASK;
+        self.next_pc = (self.pc + 4) & PC_MASK;
+    }
+}
+
+/// Scalar unit opcodes
+mod opcode {
+    pub const SPECIAL: u32 = 0x00;
+    pub const REGIMM: u32 = 0x01;
+    pub const J: u32 = 0x02;
+    pub const JAL: u32 = 0x03;
+    pub const BEQ: u32 = 0x04;
+    pub const BNE: u32 = 0x05;
+    pub const BLEZ: u32 = 0x06;
+    pub const BGTZ: u32 = 0x07;
+    pub const ADDI: u32 = 0x08;
+    pub const ADDIU: u32 = 0x09;
+    pub const SLTI: u32 = 0x0A;
+    pub const SLTIU: u32 = 0x0B;
+    pub const ANDI: u32 = 0x0C;
+    pub const ORI: u32 = 0x0D;
+    pub const XORI: u32 = 0x0E;
+    pub const LUI: u32 = 0x0F;
+    pub const COP0: u32 = 0x10;
+    pub const COP2: u32 = 0x12;
+    pub const LB: u32 = 0x20;
+    pub const LH: u32 = 0x21;
+    pub const LW: u32 = 0x23;
+    pub const LBU: u32 = 0x24;
+    pub const LHU: u32 = 0x25;
+    pub const LWU: u32 = 0x27;
+    pub const SB: u32 = 0x28;
+    pub const SH: u32 = 0x29;
+    pub const SW: u32 = 0x2B;
+    pub const LWC2: u32 = 0x32;
+    pub const SWC2: u32 = 0x3A;
+}
+
+/// SPECIAL function codes
+mod funct {
+    pub const SLL: u32 = 0x00;
+    pub const SRL: u32 = 0x02;
+    pub const SRA: u32 = 0x03;
+    pub const SLLV: u32 = 0x04;
+    pub const SRLV: u32 = 0x06;
+    pub const SRAV: u32 = 0x07;
+    pub const JR: u32 = 0x08;
+    pub const JALR: u32 = 0x09;
+    pub const BREAK: u32 = 0x0D;
+    pub const ADD: u32 = 0x20;
+    pub const ADDU: u32 = 0x21;
+    pub const SUB: u32 = 0x22;
+    pub const SUBU: u32 = 0x23;
+    pub const AND: u32 = 0x24;
+    pub const OR: u32 = 0x25;
+    pub const XOR: u32 = 0x26;
+    pub const NOR: u32 = 0x27;
+    pub const SLT: u32 = 0x2A;
+    pub const SLTU: u32 = 0x2B;
+}
+
+impl Rsp {
+    /// Fetch and execute a single scalar unit instruction
+    pub(super) fn step_su(&mut self) {
+        let pc = self.su.pc;
+        let instruction = self.read_imem(pc);
+
+        self.su.pc = self.su.next_pc;
+        self.su.next_pc = (self.su.pc + 4) & PC_MASK;
+
+        self.execute(pc, instruction);
+        self.su.gpr[0] = 0;
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn execute(&mut self, pc: u32, instruction: u32) {
+        let rs = ((instruction >> 21) & 0x1F) as usize;
+        let rt = ((instruction >> 16) & 0x1F) as usize;
+        let rd = ((instruction >> 11) & 0x1F) as usize;
+        let sa = (instruction >> 6) & 0x1F;
+        let imm = instruction as u16;
+        let simm = imm as i16 as i32 as u32;
+
+        let s = self.su.gpr[rs];
+        let t = self.su.gpr[rt];
+        let address = s.wrapping_add(simm) as usize;
+        let branch_target = (pc + 4).wrapping_add(simm << 2) & PC_MASK;
+
+        match instruction >> 26 {
+            opcode::SPECIAL => match instruction & 0x3F {
+                funct::SLL => self.su.gpr[rd] = t << sa,
+                funct::SRL => self.su.gpr[rd] = t >> sa,
+                funct::SRA => self.su.gpr[rd] = ((t as i32) >> sa) as u32,
+                funct::SLLV => self.su.gpr[rd] = t << (s & 0x1F),
+                funct::SRLV => self.su.gpr[rd] = t >> (s & 0x1F),
+                funct::SRAV => self.su.gpr[rd] = ((t as i32) >> (s & 0x1F)) as u32,
+                funct::JR => self.su.next_pc = s & PC_MASK,
+                funct::JALR => {
+                    self.su.gpr[rd] = (pc + 8) & PC_MASK;
+                    self.su.next_pc = s & PC_MASK;
+                }
+                funct::BREAK => {
+                    self.status |= sp_status::HALT | sp_status::BROKE;
+                    if self.status & sp_status::INTR_BREAK != 0 {
+                        self.interrupt = Some(true);
+                    }
+                }
+                // there are no overflow exceptions on the RSP
+                funct::ADD | funct::ADDU => self.su.gpr[rd] = s.wrapping_add(t),
+                funct::SUB | funct::SUBU => self.su.gpr[rd] = s.wrapping_sub(t),
+                funct::AND => self.su.gpr[rd] = s & t,
+                funct::OR => self.su.gpr[rd] = s | t,
+                funct::XOR => self.su.gpr[rd] = s ^ t,
+                funct::NOR => self.su.gpr[rd] = !(s | t),
+                funct::SLT => self.su.gpr[rd] = u32::from((s as i32) < (t as i32)),
+                funct::SLTU => self.su.gpr[rd] = u32::from(s < t),
+                _ => unhandled(pc, instruction),
+            },
+            opcode::REGIMM => {
+                let link = rt & 0x10 != 0;
+                let taken = match rt & 0x1 {
+                    0 => (s as i32) < 0,
+                    _ => (s as i32) >= 0,
+                };
+                if link {
+                    self.su.gpr[31] = (pc + 8) & PC_MASK;
+                }
+                if taken {
+                    self.su.next_pc = branch_target;
+                }
+            }
+            opcode::J => self.su.next_pc = (instruction << 2) & PC_MASK,
+            opcode::JAL => {
+                self.su.gpr[31] = (pc + 8) & PC_MASK;
+                self.su.next_pc = (instruction << 2) & PC_MASK;
+            }
+            opcode::BEQ | opcode::BNE | opcode::BLEZ | opcode::BGTZ => {
+                let taken = match instruction >> 26 {
+                    opcode::BEQ => s == t,
+                    opcode::BNE => s != t,
+                    opcode::BLEZ => (s as i32) <= 0,
+                    _ => (s as i32) > 0,
+                };
+                if taken {
+                    self.su.next_pc = branch_target;
+                }
+            }
+            opcode::ADDI | opcode::ADDIU => self.su.gpr[rt] = s.wrapping_add(simm),
+            opcode::SLTI => self.su.gpr[rt] = u32::from((s as i32) < (simm as i32)),
+            opcode::SLTIU => self.su.gpr[rt] = u32::from(s < simm),
+            opcode::ANDI => self.su.gpr[rt] = s & imm as u32,
+            opcode::ORI => self.su.gpr[rt] = s | imm as u32,
+            opcode::XORI => self.su.gpr[rt] = s ^ imm as u32,
+            opcode::LUI => self.su.gpr[rt] = (imm as u32) << 16,
+            opcode::COP0 => match rs {
+                // MFC0. Registers 0-7 are the SP registers, 8-15 the DP ones
+                0x00 => self.su.gpr[rt] = self.read_cop0(rd),
+                // MTC0
+                0x04 => self.write_cop0(rd, t),
+                _ => unhandled(pc, instruction),
+            },
+            opcode::LB => self.su.gpr[rt] = self.read_dmem(address, 1) as u8 as i8 as u32,
+            opcode::LH => self.su.gpr[rt] = self.read_dmem(address, 2) as u16 as i16 as u32,
+            opcode::LW | opcode::LWU => self.su.gpr[rt] = self.read_dmem(address, 4),
+            opcode::LBU => self.su.gpr[rt] = self.read_dmem(address, 1),
+            opcode::LHU => self.su.gpr[rt] = self.read_dmem(address, 2),
+            opcode::SB => self.write_dmem(address, 1, t),
+            opcode::SH => self.write_dmem(address, 2, t),
+            opcode::SW => self.write_dmem(address, 4, t),
+            opcode::COP2 => self.execute_cop2(pc, instruction),
+            opcode::LWC2 => self.execute_lwc2(pc, instruction),
+            opcode::SWC2 => self.execute_swc2(pc, instruction),
+            _ => unhandled(pc, instruction),
+        }
+    }
+
+    fn read_imem(&self, pc: u32) -> u32 {
+        let addr = IMEM_OFFSET + (pc & PC_MASK) as usize;
+        u32::from_be_bytes(self.mem[addr..addr + 4].try_into().unwrap())
+    }
+
+    /// Read `size` bytes from DMEM. Unaligned accesses wrap around DMEM
+    pub(super) fn read_dmem(&self, addr: usize, size: usize) -> u32 {
+        (0..size).fold(0, |value, i| {
+            (value << 8) | self.mem[(addr + i) % SP_MEM_SIZE] as u32
+        })
+    }
+
+    pub(super) fn write_dmem(&mut self, addr: usize, size: usize, value: u32) {
+        for i in 0..size {
+            let shift = (size - 1 - i) * 8;
+            self.mem[(addr + i) % SP_MEM_SIZE] = (value >> shift) as u8;
+        }
+    }
+
+    fn read_cop0(&self, reg: usize) -> u32 {
+        if reg < 8 {
+            self.read_reg(super::sp_reg::MEM_ADDR + reg * 4)
+        } else {
+            self.dp_regs[reg & 7]
+        }
+    }
+
+    fn write_cop0(&mut self, reg: usize, value: u32) {
+        if reg < 8 {
+            self.write_reg(super::sp_reg::MEM_ADDR + reg * 4, value);
+        } else {
+            self.dp_writes.push(((reg & 7) * 4, value));
+        }
+    }
+}
+
+fn unhandled(pc: u32, instruction: u32) {
+    tracing::warn!("Unhandled RSP instruction at 0x{pc:03x}: 0x{instruction:08x}");
+}
+
+impl Snapshot for ScalarUnit {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write_u32s(w, &self.gpr)?;
+        write_u32s(w, &[self.pc, self.next_pc])
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        read_u32s(r, &mut self.gpr)?;
+        let mut pc = [0; 2];
+        read_u32s(r, &mut pc)?;
+        [self.pc, self.next_pc] = pc.map(|pc| pc & PC_MASK);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_program(rsp: &mut Rsp, program: &[u32]) {
+        for (word, dst) in program.iter().zip(rsp.imem_mut().chunks_exact_mut(4)) {
+            dst.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn it_should_run_the_scalar_unit_until_break() {
+        let mut rsp = Rsp::new();
+        #[rustfmt::skip]
+        load_program(&mut rsp, &[
+            0x2001_0005, // addi  $1, $0, 5
+            0x2002_0000, // addi  $2, $0, 0
+            0x0041_1020, // loop: add $2, $2, $1
+            0x2021_FFFF, // addi  $1, $1, -1
+            0x1420_FFFD, // bne   $1, $0, loop
+            0x0000_0000, // nop (delay slot)
+            0xAC02_0010, // sw    $2, 0x10($0)
+            0x0000_000D, // break
+        ]);
+
+        rsp.write_status(1 << 0 | 1 << 8); // clear halt, set interrupt on break
+        rsp.step(1000);
+
+        assert!(rsp.is_halted());
+        assert_ne!(rsp.status() & sp_status::BROKE, 0);
+        assert_eq!(rsp.take_interrupt(), Some(true));
+        assert_eq!(rsp.read_dmem(0x10, 4), 15);
+    }
+}
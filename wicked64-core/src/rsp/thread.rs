@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+};
+
+use byteorder::ByteOrder;
+
+use crate::mmu::{num::MemInteger, MemoryUnit};
+
+use super::Rsp;
+
+/// State shared with the RSP thread
+#[derive(Debug)]
+struct Shared {
+    rsp: Rsp,
+    /// Cycles the RSP may run before the CPU can access it again
+    budget: u64,
+    shutdown: bool,
+}
+
+#[derive(Debug)]
+struct Control {
+    shared: Mutex<Shared>,
+    /// Notified when cycles are granted, or on shutdown
+    granted: Condvar,
+    /// Notified once the granted cycles were run
+    idle: Condvar,
+}
+
+/// Runs the RSP on a dedicated host thread, concurrently with the CPU.
+///
+/// The CPU grants the cycles of each block it ran, and keeps running while
+/// the RSP runs them. Any access to the RSP, such as a SP status read or the
+/// DMA transfers and interrupts done after each block, waits for the granted
+/// cycles to be run, so the emulation is the same as running them in place.
+pub struct RspThread {
+    control: Arc<Control>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Exclusive access to the RSP, while it's not running
+pub struct RspGuard<'a>(MutexGuard<'a, Shared>);
+
+impl RspThread {
+    /// Move `rsp` to a new thread
+    ///
+    /// # Panics
+    /// The thread can't be spawned
+    pub fn new(rsp: Rsp) -> RspThread {
+        let control = Arc::new(Control {
+            shared: Mutex::new(Shared {
+                rsp,
+                budget: 0,
+                shutdown: false,
+            }),
+            granted: Condvar::new(),
+            idle: Condvar::new(),
+        });
+
+        let handle = thread::Builder::new()
+            .name("rsp".to_string())
+            .spawn({
+                let control = control.clone();
+                move || Self::run(&control)
+            })
+            .expect("Could not spawn the RSP thread");
+
+        Self {
+            control,
+            handle: Some(handle),
+        }
+    }
+
+    /// Wait for the RSP to run the granted cycles, then lock it
+    ///
+    /// # Panics
+    /// The RSP thread panicked
+    pub fn lock(&self) -> RspGuard<'_> {
+        let shared = self.control.shared.lock().expect("The RSP thread panicked");
+        let shared = self
+            .control
+            .idle
+            .wait_while(shared, |shared| shared.budget != 0)
+            .expect("The RSP thread panicked");
+        RspGuard(shared)
+    }
+
+    /// Let the RSP run `cycles` more cycles in the background, unless it's
+    /// halted
+    ///
+    /// # Panics
+    /// The RSP thread panicked
+    pub fn step(&self, cycles: u64) {
+        let mut shared = self.control.shared.lock().expect("The RSP thread panicked");
+        if cycles == 0 || shared.rsp.is_halted() {
+            return;
+        }
+        shared.budget += cycles;
+        self.control.granted.notify_one();
+    }
+
+    fn run(control: &Control) {
+        let Ok(mut shared) = control.shared.lock() else {
+            return;
+        };
+        loop {
+            shared = match control
+                .granted
+                .wait_while(shared, |shared| shared.budget == 0 && !shared.shutdown)
+            {
+                Ok(shared) => shared,
+                Err(_) => return,
+            };
+            if shared.shutdown {
+                return;
+            }
+
+            let budget = shared.budget;
+            shared.rsp.step(budget);
+            shared.budget = 0;
+            control.idle.notify_all();
+        }
+    }
+}
+
+impl Drop for RspThread {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.control.shared.lock() {
+            shared.shutdown = true;
+            self.control.granted.notify_one();
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl fmt::Debug for RspThread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RspThread").finish_non_exhaustive()
+    }
+}
+
+impl Deref for RspGuard<'_> {
+    type Target = Rsp;
+
+    fn deref(&self) -> &Rsp {
+        &self.0.rsp
+    }
+}
+
+impl DerefMut for RspGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Rsp {
+        &mut self.0.rsp
+    }
+}
+
+impl MemoryUnit for RspThread {
+    fn read<I: MemInteger, O: ByteOrder>(&self, addr: usize) -> I {
+        self.lock().read::<I, O>(addr)
+    }
+    fn store<I: MemInteger, O: ByteOrder>(&mut self, addr: usize, value: I) {
+        self.lock().store::<I, O>(addr, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::BigEndian;
+
+    use super::*;
+    use crate::rsp::{sp_reg, sp_status};
+
+    #[test]
+    fn it_should_run_the_granted_cycles_before_an_access() {
+        let mut thread = RspThread::new(Rsp::new());
+        // `break` at the start of IMEM
+        thread.store::<u32, BigEndian>(0x1000, 0x0000_000D);
+        thread.store::<u32, BigEndian>(sp_reg::STATUS, 1 << 0);
+
+        thread.step(16);
+        let status = thread.read::<u32, BigEndian>(sp_reg::STATUS);
+        assert_ne!(status & sp_status::HALT, 0);
+        assert_ne!(status & sp_status::BROKE, 0);
+    }
+}
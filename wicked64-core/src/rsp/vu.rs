@@ -0,0 +1,515 @@
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::savestate::{SaveStateResult, Snapshot};
+
+use super::{Rsp, SP_MEM_SIZE};
+
+/// RSP vector unit registers
+#[derive(Debug, Default, Clone)]
+pub struct VectorUnit {
+    /// 32 registers of 8 16-bit lanes
+    pub regs: [[u16; 8]; 32],
+    /// 48-bit accumulator of each lane, sign extended
+    pub acc: [i64; 8],
+    /// Carry (low byte) and not equal (high byte) flags
+    pub vco: u16,
+    /// Compare flags (low byte) and clip compare flags (high byte)
+    pub vcc: u16,
+    /// Compare extension flags
+    pub vce: u8,
+}
+
+impl VectorUnit {
+    fn byte(&self, reg: usize, index: usize) -> u8 {
+        let lane = self.regs[reg][(index & 0xF) / 2];
+        if index & 1 == 0 {
+            (lane >> 8) as u8
+        } else {
+            lane as u8
+        }
+    }
+
+    fn set_byte(&mut self, reg: usize, index: usize, value: u8) {
+        let lane = &mut self.regs[reg][(index & 0xF) / 2];
+        *lane = if index & 1 == 0 {
+            (*lane & 0x00FF) | (value as u16) << 8
+        } else {
+            (*lane & 0xFF00) | value as u16
+        };
+    }
+
+    fn acc_lo(&self, lane: usize) -> u16 {
+        self.acc[lane] as u16
+    }
+    fn acc_mid(&self, lane: usize) -> u16 {
+        (self.acc[lane] >> 16) as u16
+    }
+    fn acc_hi(&self, lane: usize) -> u16 {
+        (self.acc[lane] >> 32) as u16
+    }
+    fn set_acc_lo(&mut self, lane: usize, value: u16) {
+        self.acc[lane] = (self.acc[lane] & !0xFFFF) | value as i64;
+    }
+    fn set_acc(&mut self, lane: usize, value: i64) {
+        // wrap around 48 bits
+        self.acc[lane] = (value << 16) >> 16;
+    }
+
+    /// Clamp the high and middle parts of the accumulator to a signed 16-bit
+    fn clamp_signed(&self, lane: usize) -> u16 {
+        ((self.acc[lane] >> 16).clamp(i16::MIN as i64, i16::MAX as i64)) as u16
+    }
+
+    /// Clamp the accumulator to an unsigned 16-bit, used by the "low" multiplies
+    fn clamp_unsigned_low(&self, lane: usize) -> u16 {
+        match self.acc[lane] >> 16 {
+            high if high < i16::MIN as i64 => 0,
+            high if high > i16::MAX as i64 => 0xFFFF,
+            _ => self.acc_lo(lane),
+        }
+    }
+
+    /// Clamp used by the unsigned fractional multiplies
+    fn clamp_unsigned(&self, lane: usize) -> u16 {
+        match self.acc[lane] >> 16 {
+            high if high < 0 => 0,
+            high if high > i16::MAX as i64 => 0xFFFF,
+            high => high as u16,
+        }
+    }
+}
+
+/// Select the lane of `vt` used for each lane, according to the element
+/// field of the instruction
+fn element_lane(element: usize, lane: usize) -> usize {
+    match element {
+        0..=1 => lane,
+        // 0q, 1q: pairs
+        2..=3 => (lane & !1) | (element & 1),
+        // 0h-3h: groups of four
+        4..=7 => (lane & !3) | (element & 3),
+        // broadcast a single lane
+        _ => element & 7,
+    }
+}
+
+/// Vector unit function codes
+mod funct {
+    pub const VMULF: u32 = 0x00;
+    pub const VMULU: u32 = 0x01;
+    pub const VMUDL: u32 = 0x04;
+    pub const VMUDM: u32 = 0x05;
+    pub const VMUDN: u32 = 0x06;
+    pub const VMUDH: u32 = 0x07;
+    pub const VMACF: u32 = 0x08;
+    pub const VMACU: u32 = 0x09;
+    pub const VMADL: u32 = 0x0C;
+    pub const VMADM: u32 = 0x0D;
+    pub const VMADN: u32 = 0x0E;
+    pub const VMADH: u32 = 0x0F;
+    pub const VADD: u32 = 0x10;
+    pub const VSUB: u32 = 0x11;
+    pub const VABS: u32 = 0x13;
+    pub const VADDC: u32 = 0x14;
+    pub const VSUBC: u32 = 0x15;
+    pub const VSAR: u32 = 0x1D;
+    pub const VLT: u32 = 0x20;
+    pub const VEQ: u32 = 0x21;
+    pub const VNE: u32 = 0x22;
+    pub const VGE: u32 = 0x23;
+    pub const VMRG: u32 = 0x27;
+    pub const VAND: u32 = 0x28;
+    pub const VNAND: u32 = 0x29;
+    pub const VOR: u32 = 0x2A;
+    pub const VNOR: u32 = 0x2B;
+    pub const VXOR: u32 = 0x2C;
+    pub const VNXOR: u32 = 0x2D;
+    pub const VNOP: u32 = 0x37;
+}
+
+impl Rsp {
+    /// Execute a COP2 instruction: vector computations and moves between the
+    /// scalar and vector units
+    pub(super) fn execute_cop2(&mut self, pc: u32, instruction: u32) {
+        let rt = ((instruction >> 16) & 0x1F) as usize;
+        let rd = ((instruction >> 11) & 0x1F) as usize;
+        let element = ((instruction >> 7) & 0xF) as usize;
+
+        if instruction & (1 << 25) != 0 {
+            self.execute_vector(pc, instruction);
+            return;
+        }
+
+        let vu = &mut self.vu;
+        match (instruction >> 21) & 0x1F {
+            // MFC2
+            0x00 => {
+                let value = u16::from_be_bytes([vu.byte(rd, element), vu.byte(rd, element + 1)]);
+                self.su.gpr[rt] = value as i16 as u32;
+            }
+            // CFC2
+            0x02 => {
+                let value = match rd & 3 {
+                    0 => vu.vco as i16 as u32,
+                    1 => vu.vcc as i16 as u32,
+                    _ => vu.vce as u32,
+                };
+                self.su.gpr[rt] = value;
+            }
+            // MTC2
+            0x04 => {
+                let [hi, lo] = (self.su.gpr[rt] as u16).to_be_bytes();
+                vu.set_byte(rd, element, hi);
+                if element < 15 {
+                    vu.set_byte(rd, element + 1, lo);
+                }
+            }
+            // CTC2
+            0x06 => {
+                let value = self.su.gpr[rt];
+                match rd & 3 {
+                    0 => vu.vco = value as u16,
+                    1 => vu.vcc = value as u16,
+                    _ => vu.vce = value as u8,
+                }
+            }
+            _ => {
+                tracing::warn!("Unhandled RSP COP2 instruction at 0x{pc:03x}: 0x{instruction:08x}");
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn execute_vector(&mut self, pc: u32, instruction: u32) {
+        let element = ((instruction >> 21) & 0xF) as usize;
+        let vt = ((instruction >> 16) & 0x1F) as usize;
+        let vs = ((instruction >> 11) & 0x1F) as usize;
+        let vd = ((instruction >> 6) & 0x1F) as usize;
+
+        let vu = &mut self.vu;
+        let s = vu.regs[vs];
+        let t: [u16; 8] = std::array::from_fn(|lane| vu.regs[vt][element_lane(element, lane)]);
+        let mut result = [0u16; 8];
+
+        let funct = instruction & 0x3F;
+        match funct {
+            funct::VMULF | funct::VMULU | funct::VMACF | funct::VMACU => {
+                let accumulate = matches!(funct, funct::VMACF | funct::VMACU);
+                for lane in 0..8 {
+                    let product = (s[lane] as i16 as i64) * (t[lane] as i16 as i64) * 2;
+                    let acc = if accumulate {
+                        vu.acc[lane] + product
+                    } else {
+                        product + 0x8000
+                    };
+                    vu.set_acc(lane, acc);
+                    result[lane] = match funct {
+                        funct::VMULF | funct::VMACF => vu.clamp_signed(lane),
+                        _ => vu.clamp_unsigned(lane),
+                    };
+                }
+            }
+            funct::VMUDL
+            | funct::VMUDM
+            | funct::VMUDN
+            | funct::VMUDH
+            | funct::VMADL
+            | funct::VMADM
+            | funct::VMADN
+            | funct::VMADH => {
+                let accumulate = funct >= funct::VMADL;
+                for lane in 0..8 {
+                    let (s, t) = (s[lane], t[lane]);
+                    let product = match funct & 0x3 {
+                        // low: unsigned * unsigned, upper half
+                        0 => ((s as i64) * (t as i64)) >> 16,
+                        // mid: signed * unsigned
+                        1 => (s as i16 as i64) * (t as i64),
+                        // mid (n): unsigned * signed
+                        2 => (s as i64) * (t as i16 as i64),
+                        // high: signed * signed, upper half
+                        _ => ((s as i16 as i64) * (t as i16 as i64)) << 16,
+                    };
+                    let acc = if accumulate {
+                        vu.acc[lane] + product
+                    } else {
+                        product
+                    };
+                    vu.set_acc(lane, acc);
+                    result[lane] = match funct & 0x3 {
+                        0 | 2 => vu.clamp_unsigned_low(lane),
+                        _ => vu.clamp_signed(lane),
+                    };
+                }
+            }
+            funct::VADD | funct::VSUB => {
+                for lane in 0..8 {
+                    let carry = ((vu.vco >> lane) & 1) as i32;
+                    let (s, t) = (s[lane] as i16 as i32, t[lane] as i16 as i32);
+                    let sum = if funct == funct::VADD {
+                        s + t + carry
+                    } else {
+                        s - t - carry
+                    };
+                    vu.set_acc_lo(lane, sum as u16);
+                    result[lane] = sum.clamp(i16::MIN as i32, i16::MAX as i32) as u16;
+                }
+                vu.vco = 0;
+            }
+            funct::VABS => {
+                for lane in 0..8 {
+                    let value = match (s[lane] as i16).signum() {
+                        -1 => (t[lane] as i16).wrapping_neg(),
+                        0 => 0,
+                        _ => t[lane] as i16,
+                    };
+                    vu.set_acc_lo(lane, value as u16);
+                    // -(-32768) is clamped
+                    result[lane] = if value == i16::MIN && (s[lane] as i16) < 0 {
+                        i16::MAX as u16
+                    } else {
+                        value as u16
+                    };
+                }
+            }
+            funct::VADDC | funct::VSUBC => {
+                let mut vco = 0;
+                for lane in 0..8 {
+                    let (s, t) = (s[lane] as i32, t[lane] as i32);
+                    let value = if funct == funct::VADDC { s + t } else { s - t };
+                    let carry = if funct == funct::VADDC {
+                        value > 0xFFFF
+                    } else {
+                        value < 0
+                    };
+                    vco |= u16::from(carry) << lane;
+                    if funct == funct::VSUBC && value != 0 {
+                        vco |= 1 << (lane + 8);
+                    }
+                    vu.set_acc_lo(lane, value as u16);
+                    result[lane] = value as u16;
+                }
+                vu.vco = vco;
+            }
+            funct::VSAR => {
+                for (lane, value) in result.iter_mut().enumerate() {
+                    *value = match element {
+                        8 => vu.acc_hi(lane),
+                        9 => vu.acc_mid(lane),
+                        10 => vu.acc_lo(lane),
+                        _ => 0,
+                    };
+                }
+            }
+            funct::VLT..=funct::VGE => {
+                let mut vcc = 0;
+                for lane in 0..8 {
+                    let carry = (vu.vco >> lane) & 1 != 0;
+                    let not_equal = (vu.vco >> (lane + 8)) & 1 != 0;
+                    let (s, t) = (s[lane] as i16, t[lane] as i16);
+                    let condition = match funct {
+                        funct::VLT => s < t || (s == t && carry && not_equal),
+                        funct::VEQ => s == t && !not_equal,
+                        funct::VNE => s != t || not_equal,
+                        _ => s > t || (s == t && !(carry && not_equal)),
+                    };
+                    vcc |= u16::from(condition) << lane;
+                    let value = if condition { s } else { t } as u16;
+                    vu.set_acc_lo(lane, value);
+                    result[lane] = value;
+                }
+                vu.vcc = vcc;
+                vu.vco = 0;
+            }
+            funct::VMRG => {
+                for lane in 0..8 {
+                    let value = if (vu.vcc >> lane) & 1 != 0 {
+                        s[lane]
+                    } else {
+                        t[lane]
+                    };
+                    vu.set_acc_lo(lane, value);
+                    result[lane] = value;
+                }
+            }
+            funct::VAND..=funct::VNXOR => {
+                for lane in 0..8 {
+                    let value = match funct {
+                        funct::VAND => s[lane] & t[lane],
+                        funct::VNAND => !(s[lane] & t[lane]),
+                        funct::VOR => s[lane] | t[lane],
+                        funct::VNOR => !(s[lane] | t[lane]),
+                        funct::VXOR => s[lane] ^ t[lane],
+                        _ => !(s[lane] ^ t[lane]),
+                    };
+                    vu.set_acc_lo(lane, value);
+                    result[lane] = value;
+                }
+            }
+            funct::VNOP => return,
+            _ => {
+                tracing::warn!(
+                    "Unhandled RSP vector instruction at 0x{pc:03x}: 0x{instruction:08x}"
+                );
+                return;
+            }
+        }
+
+        vu.regs[vd] = result;
+    }
+
+    /// Execute a LWC2 (vector load) instruction
+    pub(super) fn execute_lwc2(&mut self, pc: u32, instruction: u32) {
+        let Some((kind, vt, element, addr)) = self.decode_vector_memory(instruction) else {
+            tracing::warn!("Unhandled RSP vector load at 0x{pc:03x}: 0x{instruction:08x}");
+            return;
+        };
+
+        match kind {
+            // LBV, LSV, LLV, LDV
+            0..=3 => {
+                for i in 0..1 << kind {
+                    if element + i < 16 {
+                        let byte = self.mem[(addr + i) % SP_MEM_SIZE];
+                        self.vu.set_byte(vt, element + i, byte);
+                    }
+                }
+            }
+            // LQV: up to the end of the 16 bytes aligned block
+            4 => {
+                let len = 16 - (addr & 0xF);
+                for i in (0..len).take_while(|i| element + i < 16) {
+                    let byte = self.mem[(addr + i) % SP_MEM_SIZE];
+                    self.vu.set_byte(vt, element + i, byte);
+                }
+            }
+            // LRV: from the start of the 16 bytes aligned block
+            _ => {
+                let start = addr & !0xF;
+                let len = addr & 0xF;
+                for i in 0..len {
+                    let index = 16 - len + i + element;
+                    if index < 16 {
+                        let byte = self.mem[(start + i) % SP_MEM_SIZE];
+                        self.vu.set_byte(vt, index, byte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Execute a SWC2 (vector store) instruction
+    pub(super) fn execute_swc2(&mut self, pc: u32, instruction: u32) {
+        let Some((kind, vt, element, addr)) = self.decode_vector_memory(instruction) else {
+            tracing::warn!("Unhandled RSP vector store at 0x{pc:03x}: 0x{instruction:08x}");
+            return;
+        };
+
+        let (start, len, first) = match kind {
+            // SBV, SSV, SLV, SDV
+            0..=3 => (addr, 1 << kind, element),
+            // SQV
+            4 => (addr, 16 - (addr & 0xF), element),
+            // SRV
+            _ => {
+                let len = addr & 0xF;
+                (addr & !0xF, len, 16 - len + element)
+            }
+        };
+
+        for i in 0..len {
+            self.mem[(start + i) % SP_MEM_SIZE] = self.vu.byte(vt, first + i);
+        }
+    }
+
+    /// Decode a vector load/store, returning the kind of access, the vector
+    /// register, the element and the DMEM address
+    fn decode_vector_memory(&self, instruction: u32) -> Option<(usize, usize, usize, usize)> {
+        let base = ((instruction >> 21) & 0x1F) as usize;
+        let vt = ((instruction >> 16) & 0x1F) as usize;
+        let kind = ((instruction >> 11) & 0x1F) as usize;
+        let element = ((instruction >> 7) & 0xF) as usize;
+        // 7-bit signed offset, scaled by the access size
+        let offset = ((instruction << 25) as i32 >> 25) as u32;
+
+        let scale = match kind {
+            0..=3 => 1 << kind,
+            4 | 5 => 16,
+            _ => return None,
+        };
+        let addr = self.su.gpr[base].wrapping_add(offset.wrapping_mul(scale)) as usize;
+        Some((kind, vt, element, addr % SP_MEM_SIZE))
+    }
+}
+
+impl Snapshot for VectorUnit {
+    fn save(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        for lane in self.regs.iter().flatten() {
+            w.write_u16::<BigEndian>(*lane)?;
+        }
+        for acc in &self.acc {
+            w.write_i64::<BigEndian>(*acc)?;
+        }
+        w.write_u16::<BigEndian>(self.vco)?;
+        w.write_u16::<BigEndian>(self.vcc)?;
+        w.write_u8(self.vce)
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        for reg in &mut self.regs {
+            r.read_u16_into::<BigEndian>(reg)?;
+        }
+        r.read_i64_into::<BigEndian>(&mut self.acc)?;
+        self.vco = r.read_u16::<BigEndian>()?;
+        self.vcc = r.read_u16::<BigEndian>()?;
+        self.vce = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_select_the_vt_lanes_by_element() {
+        let lanes = |element| {
+            (0..8)
+                .map(|lane| element_lane(element, lane))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lanes(0), [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(lanes(3), [1, 1, 3, 3, 5, 5, 7, 7]);
+        assert_eq!(lanes(6), [2, 2, 2, 2, 6, 6, 6, 6]);
+        assert_eq!(lanes(13), [5; 8]);
+    }
+
+    #[test]
+    fn it_should_run_vector_instructions() {
+        let mut rsp = Rsp::new();
+        for (i, byte) in rsp.dmem_mut()[..32].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        rsp.vu.regs[2] = [0x4000; 8];
+
+        // lqv $v1[0], 0($0)
+        rsp.execute_lwc2(0, 0xC801_2000);
+        assert_eq!(rsp.vu.regs[1][0], 0x0001);
+        assert_eq!(rsp.vu.regs[1][7], 0x0E0F);
+
+        // vmulf $v3, $v1, $v2: multiply by 0.5
+        rsp.execute_cop2(0, 0x4A02_08C0);
+        assert_eq!(rsp.vu.regs[3][7], 0x0708);
+
+        // vadd $v4, $v3, $v3[0]
+        rsp.execute_cop2(0, 0x4A03_1910);
+        assert_eq!(rsp.vu.regs[4][7], 0x0E10);
+
+        // sqv $v3[0], 16($0)
+        rsp.execute_swc2(0, 0xE803_2001);
+        assert_eq!(&rsp.dmem()[16..18], &[0x00, 0x01]);
+        assert_eq!(&rsp.dmem()[30..32], &[0x07, 0x08]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// First bytes of every savestate
+pub const MAGIC: [u8; 4] = *b"W64S";
+/// Version of the savestate format, increased on any layout change
+pub const VERSION: u32 = 1;
+
+/// Errors produced while loading a savestate
+#[derive(thiserror::Error, Debug)]
+pub enum SaveStateError {
+    #[error("Not a savestate")]
+    InvalidMagic,
+    #[error("Unsupported savestate version {0} (expected {VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("The savestate doesn't match the running machine: {0}")]
+    Mismatch(&'static str),
+    #[error("Invalid savestate data: {0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type SaveStateResult<T> = Result<T, SaveStateError>;
+
+/// A component of the machine stored in savestates.
+///
+/// Components are written one after the other, in a fixed order, with all
+/// integers in big endian. Host-side attachments (input sources, audio and
+/// video outputs, RDP backends) are not part of the machine and are kept as
+/// they are when loading.
+pub trait Snapshot {
+    /// Write the component state into `w`
+    ///
+    /// # Errors
+    /// `w` can't be written
+    fn save(&self, w: &mut dyn Write) -> io::Result<()>;
+
+    /// Restore the component state from `r`
+    ///
+    /// # Errors
+    /// `r` can't be read or holds an invalid state
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()>;
+}
+
+/// Write the savestate header
+///
+/// # Errors
+/// `w` can't be written
+pub fn write_header(w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    w.write_u32::<BigEndian>(VERSION)
+}
+
+/// Read and check the savestate header
+///
+/// # Errors
+/// `r` doesn't start with a supported savestate header
+pub fn read_header(r: &mut dyn Read) -> SaveStateResult<()> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SaveStateError::InvalidMagic);
+    }
+    match r.read_u32::<BigEndian>()? {
+        VERSION => Ok(()),
+        version => Err(SaveStateError::UnsupportedVersion(version)),
+    }
+}
+
+pub(crate) fn write_u64s(w: &mut dyn Write, values: &[u64]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| w.write_u64::<BigEndian>(*value))
+}
+
+pub(crate) fn read_u64s(r: &mut dyn Read, values: &mut [u64]) -> io::Result<()> {
+    r.read_u64_into::<BigEndian>(values)
+}
+
+pub(crate) fn write_u32s(w: &mut dyn Write, values: &[u32]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| w.write_u32::<BigEndian>(*value))
+}
+
+pub(crate) fn read_u32s(r: &mut dyn Read, values: &mut [u32]) -> io::Result<()> {
+    r.read_u32_into::<BigEndian>(values)
+}
+
+/// Write a length-prefixed byte buffer
+pub(crate) fn write_bytes(w: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+/// Read a length-prefixed byte buffer into `bytes`, which must have the
+/// same length
+pub(crate) fn read_bytes(r: &mut dyn Read, bytes: &mut [u8]) -> SaveStateResult<()> {
+    if r.read_u32::<BigEndian>()? as usize != bytes.len() {
+        return Err(SaveStateError::Invalid("unexpected buffer length"));
+    }
+    Ok(r.read_exact(bytes)?)
+}
+
+pub(crate) fn read_bool(r: &mut dyn Read) -> io::Result<bool> {
+    Ok(r.read_u8()? != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_reject_foreign_headers() {
+        let mut state = Vec::new();
+        write_header(&mut state).unwrap();
+        assert!(read_header(&mut state.as_slice()).is_ok());
+
+        state[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            read_header(&mut state.as_slice()),
+            Err(SaveStateError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            read_header(&mut &b"ELF\0\0\0\0\0"[..]),
+            Err(SaveStateError::InvalidMagic)
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::{self, Read, Write},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::savestate::{SaveStateError, SaveStateResult, Snapshot};
+
+/// Hardware events that happen at a given CPU cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Event {
+    /// CP0 Count reaches Compare
+    CountCompare,
+    /// The VI scans out a new half-line
+    ViHalfLine,
+    /// The running PI DMA transfer completes
+    PiDmaComplete,
+    /// The AI finishes playing its current buffer
+    AiBufferDrain,
+}
+
+/// Queue of the pending hardware events, ordered by the CPU cycle they
+/// happen at.
+///
+/// The clock is advanced after each executed block, and the events that are
+/// due are dispatched by the memory manager and the CPU state.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    /// Current CPU cycle
+    now: u64,
+    queue: BinaryHeap<Reverse<(u64, Event)>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Self::default()
+    }
+
+    /// Current CPU cycle
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Schedule `event` at the CPU cycle `at`
+    pub fn schedule(&mut self, at: u64, event: Event) {
+        self.queue.push(Reverse((at, event)));
+    }
+
+    /// Schedule `event` `delay` cycles from now
+    pub fn schedule_in(&mut self, delay: u64, event: Event) {
+        self.schedule(self.now + delay, event);
+    }
+
+    /// Remove every pending occurrence of `event`
+    pub fn cancel(&mut self, event: Event) {
+        self.queue.retain(|Reverse((_, pending))| *pending != event);
+    }
+
+    pub fn is_scheduled(&self, event: Event) -> bool {
+        self.queue
+            .iter()
+            .any(|Reverse((_, pending))| *pending == event)
+    }
+
+    /// CPU cycle of the next pending event
+    pub fn next_event(&self) -> Option<u64> {
+        self.queue.peek().map(|Reverse((at, _))| *at)
+    }
+
+    /// Advance the clock by `cycles`
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// Take the next event that is due, with the cycle it was scheduled at
+    pub fn pop_due(&mut self) -> Option<(u64, Event)> {
+        if self.next_event()? > self.now {
+            return None;
+        }
+        self.queue.pop().map(|Reverse(event)| event)
+    }
+}
+
+impl Snapshot for Scheduler {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_u64::<BigEndian>(self.now)?;
+        w.write_u32::<BigEndian>(self.queue.len() as u32)?;
+        for Reverse((at, event)) in &self.queue {
+            w.write_u64::<BigEndian>(*at)?;
+            w.write_u8((*event).into())?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut dyn Read) -> SaveStateResult<()> {
+        self.now = r.read_u64::<BigEndian>()?;
+        self.queue.clear();
+        for _ in 0..r.read_u32::<BigEndian>()? {
+            let at = r.read_u64::<BigEndian>()?;
+            let event = Event::try_from(r.read_u8()?)
+                .map_err(|_| SaveStateError::Invalid("unknown scheduler event"))?;
+            self.schedule(at, event);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_dispatch_the_events_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_in(100, Event::ViHalfLine);
+        scheduler.schedule_in(30, Event::PiDmaComplete);
+        scheduler.schedule_in(50, Event::AiBufferDrain);
+        scheduler.cancel(Event::AiBufferDrain);
+
+        scheduler.advance(20);
+        assert_eq!(scheduler.pop_due(), None);
+
+        scheduler.advance(100);
+        assert_eq!(scheduler.pop_due(), Some((30, Event::PiDmaComplete)));
+        assert_eq!(scheduler.pop_due(), Some((100, Event::ViHalfLine)));
+        assert_eq!(scheduler.pop_due(), None);
+        assert!(!scheduler.is_scheduled(Event::AiBufferDrain));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use byteorder::ByteOrder;
+
+use crate::cpu::Cpu;
+
+/// Number of traced registers: the 32 GPRs, then `HI` and `LO`
+pub const TRACED_REGISTERS: usize = 34;
+const HI: usize = 32;
+const LO: usize = 33;
+
+/// Errors produced while parsing a trace
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum TraceError {
+    #[error("Line {line}: {reason}")]
+    Parse { line: usize, reason: &'static str },
+}
+
+/// A retired instruction, with the registers it changed.
+///
+/// Traces are written one instruction per line, in hexadecimal:
+/// ```txt
+/// a4000040 25080001 r8=0000000000000001
+/// ```
+/// The PC and opcode are followed by the changed registers, which are the
+/// GPRs `r0`..`r31`, `hi` and `lo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Virtual address of the instruction
+    pub pc: u64,
+    pub opcode: u32,
+    /// Index and new value of the changed registers, in increasing order
+    pub changes: Vec<(usize, u64)>,
+}
+
+impl TraceEntry {
+    /// Parse a line of a trace
+    ///
+    /// # Errors
+    /// The line is not a valid trace entry
+    pub fn parse(line: &str) -> Result<TraceEntry, &'static str> {
+        let mut fields = line.split_whitespace();
+        let pc = fields.next().ok_or("missing PC")?;
+        let pc = u64::from_str_radix(pc, 16).map_err(|_| "invalid PC")?;
+        let opcode = fields.next().ok_or("missing opcode")?;
+        let opcode = u32::from_str_radix(opcode, 16).map_err(|_| "invalid opcode")?;
+
+        let mut changes = fields
+            .map(|change| {
+                let (register, value) = change.split_once('=').ok_or("invalid register change")?;
+                let register = match register {
+                    "hi" => HI,
+                    "lo" => LO,
+                    gpr => gpr
+                        .strip_prefix('r')
+                        .and_then(|index| index.parse().ok())
+                        .filter(|index| *index < 32)
+                        .ok_or("invalid register")?,
+                };
+                let value = u64::from_str_radix(value, 16).map_err(|_| "invalid register value")?;
+                Ok((register, value))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        changes.sort_unstable();
+
+        Ok(TraceEntry {
+            pc,
+            opcode,
+            changes,
+        })
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x} {:08x}", self.pc, self.opcode)?;
+        for &(register, value) in &self.changes {
+            match register {
+                HI => write!(f, " hi={value:016x}")?,
+                LO => write!(f, " lo={value:016x}")?,
+                gpr => write!(f, " r{gpr}={value:016x}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first instruction where a trace differs from the reference one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of instructions run before the divergence
+    pub index: usize,
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Divergence at instruction {}", self.index)?;
+        writeln!(f, "  expected: {}", self.expected)?;
+        write!(f, "  actual:   {}", self.actual)
+    }
+}
+
+/// The traced registers of `cpu`
+pub fn traced_registers<O: ByteOrder>(cpu: &Cpu<O>) -> [u64; TRACED_REGISTERS] {
+    let mut registers = [0; TRACED_REGISTERS];
+    registers[..32].copy_from_slice(&cpu.gpr);
+    registers[HI] = cpu.multi_hi;
+    registers[LO] = cpu.multi_lo;
+    registers
+}
+
+/// The registers that differ between `before` and `after`
+pub fn changed_registers(
+    before: &[u64; TRACED_REGISTERS],
+    after: &[u64; TRACED_REGISTERS],
+) -> Vec<(usize, u64)> {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(register, (_, after))| (register, *after))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_the_written_entries() {
+        let entry = TraceEntry {
+            pc: 0xA400_0040,
+            opcode: 0x2508_0001,
+            changes: vec![(8, 1), (LO, 0xdead_beef)],
+        };
+        let line = entry.to_string();
+        assert_eq!(
+            line,
+            "a4000040 25080001 r8=0000000000000001 lo=00000000deadbeef"
+        );
+        assert_eq!(TraceEntry::parse(&line), Ok(entry));
+        assert_eq!(
+            TraceEntry::parse("a4000040 25080001 r32=0"),
+            Err("invalid register")
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     collections::BTreeMap,
-    ops::{Bound, RangeBounds},
+    ops::{Bound, Range, RangeBounds},
 };
 
 #[derive(Debug, Clone)]
@@ -74,11 +74,29 @@ impl<T> BTreeRange<T> {
         self.btree.get(&index).map(|value| &value.data)
     }
 
-    pub(crate) fn retain<F>(&mut self, mut f: F)
-    where
-        F: FnMut((usize, usize), &mut T) -> bool,
-    {
-        self.btree.retain(|k, v| f((*k, v.end), &mut v.data));
+    /// Iterate over the values, ordered by the start of their range
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.btree.values().map(|item| &item.data)
+    }
+
+    /// Remove the range starting at `start`, returning its value
+    pub fn remove(&mut self, start: usize) -> Option<T> {
+        self.btree.remove(&start).map(|item| item.data)
+    }
+}
+
+impl<T> IntoIterator for BTreeRange<T> {
+    type Item = (Range<usize>, T);
+    type IntoIter = std::iter::Map<
+        std::collections::btree_map::IntoIter<usize, RangeItem<T>>,
+        fn((usize, RangeItem<T>)) -> (Range<usize>, T),
+    >;
+
+    /// Iterate over the (`start..end`, value) pairs, ordered by `start`
+    fn into_iter(self) -> Self::IntoIter {
+        self.btree
+            .into_iter()
+            .map(|(start, RangeItem { data, end })| (start..end, data))
     }
 }
 
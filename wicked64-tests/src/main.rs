@@ -0,0 +1,79 @@
+mod runner;
+mod suite;
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use runner::Outcome;
+
+/// Run the test ROM suites headlessly and report their results
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// Directory holding a subdirectory per suite
+    #[arg(default_value = "assets/test-roms")]
+    roms: PathBuf,
+    /// Only run the ROMs whose path contains this text
+    #[arg(long)]
+    filter: Option<String>,
+    /// Frames run before giving up on a ROM
+    #[arg(long, default_value_t = 300)]
+    frames: usize,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::WARN)
+        .init();
+    let args = Args::parse();
+
+    let roms = suite::discover(&args.roms)?
+        .into_iter()
+        .filter(|rom| {
+            args.filter
+                .as_ref()
+                .is_none_or(|filter| rom.path.to_string_lossy().contains(filter))
+        })
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        !roms.is_empty(),
+        "No test ROM found in {}",
+        args.roms.display()
+    );
+
+    let results = roms
+        .iter()
+        .map(|rom| (rom, runner::run(rom, args.frames)))
+        .collect::<Vec<_>>();
+
+    let suite_width = results.iter().map(|(rom, _)| rom.suite.len()).max();
+    let name_width = results.iter().map(|(rom, _)| rom.name.len()).max();
+    let (suite_width, name_width) = (suite_width.unwrap_or(0), name_width.unwrap_or(0));
+    println!("{:suite_width$}  {:name_width$}  Result", "Suite", "ROM");
+    for (rom, outcome) in &results {
+        println!(
+            "{:suite_width$}  {:name_width$}  {outcome}",
+            rom.suite, rom.name
+        );
+    }
+
+    let passed = results
+        .iter()
+        .filter(|(_, outcome)| outcome.is_pass())
+        .count();
+    let crashed = results
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Crash(_)))
+        .count();
+    println!();
+    println!(
+        "{passed} passed, {} failed, {crashed} crashed",
+        results.len() - passed - crashed
+    );
+
+    if passed < results.len() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+
+use byteorder::BigEndian;
+use w64_core::{io::video::Frame, n64::N64};
+
+use crate::suite::{Check, TestRom};
+
+/// Value of `r30` once every test of a dillonb ROM passed. Only the low word
+/// is compared, as the ROMs set it with 32-bit instructions
+const REGISTER_PASS: u32 = u32::MAX;
+
+/// Result of a test ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The emulator failed to load or run the ROM
+    Crash(String),
+    /// The framebuffer has no reference checksum to be compared with
+    NoReference(u64),
+}
+
+impl Outcome {
+    pub fn is_pass(&self) -> bool {
+        *self == Outcome::Pass
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(reason) => write!(f, "FAIL: {reason}"),
+            Outcome::Crash(reason) => write!(f, "CRASH: {reason}"),
+            Outcome::NoReference(checksum) => {
+                write!(f, "no reference checksum (framebuffer {checksum:016x})")
+            }
+        }
+    }
+}
+
+/// Run `rom` headlessly for at most `frames` frames
+pub fn run(rom: &TestRom, frames: usize) -> Outcome {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run_checked(rom, frames)));
+    match result {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(error)) => Outcome::Crash(format!("{error:#}")),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(ToString::to_string)
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Outcome::Crash(format!("panicked: {message}"))
+        }
+    }
+}
+
+fn run_checked(rom: &TestRom, frames: usize) -> anyhow::Result<Outcome> {
+    let mut n64 = N64::<BigEndian>::new(&rom.path)?;
+    let text = Arc::new(Mutex::new(String::new()));
+    n64.set_isviewer_callback({
+        let text = text.clone();
+        move |printed| text.lock().unwrap().push_str(printed)
+    });
+
+    for _ in 0..frames {
+        n64.run_frame();
+
+        let outcome = match rom.check {
+            Check::Register => register_outcome(n64.state().lock().cpu.gpr[30]),
+            Check::IsViewer => isviewer_outcome(&text.lock().unwrap()),
+            Check::Framebuffer(_) => None,
+        };
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+    }
+
+    Ok(match rom.check {
+        Check::Framebuffer(reference) => {
+            let checksum = n64.framebuffer().as_ref().map_or(0, checksum);
+            match reference {
+                None => Outcome::NoReference(checksum),
+                Some(reference) if reference == checksum => Outcome::Pass,
+                Some(_) => Outcome::Fail(format!("framebuffer {checksum:016x}")),
+            }
+        }
+        Check::Register | Check::IsViewer => {
+            Outcome::Fail(format!("no result after {frames} frames"))
+        }
+    })
+}
+
+fn register_outcome(r30: u64) -> Option<Outcome> {
+    match r30 as u32 {
+        0 => None,
+        REGISTER_PASS => Some(Outcome::Pass),
+        test => Some(Outcome::Fail(format!("test {test} failed"))),
+    }
+}
+
+fn isviewer_outcome(text: &str) -> Option<Outcome> {
+    if let Some(line) = text.lines().find(|line| line.contains("FAIL")) {
+        return Some(Outcome::Fail(line.trim().to_string()));
+    }
+    text.contains("PASS").then_some(Outcome::Pass)
+}
+
+/// FNV-1a hash of the displayed pixels
+fn checksum(frame: &Frame) -> u64 {
+    let size = [frame.width as u64, frame.height as u64];
+    size.iter()
+        .flat_map(|n| n.to_le_bytes())
+        .chain(frame.pixels.iter().copied())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+/// File of the reference framebuffer checksums, in the directory of a suite
+pub const CHECKSUMS_FILE: &str = "checksums.txt";
+
+/// How a test ROM reports its result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// `r30` is set to -1 once every test passed, or to the number of the
+    /// failed test. Used by dillonb/n64-tests
+    Register,
+    /// The final framebuffer should match a reference checksum. Used by the
+    /// krom tests, which draw their results
+    Framebuffer(Option<u64>),
+    /// `PASS` or `FAIL` is printed through the IS-Viewer
+    IsViewer,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestRom {
+    pub suite: String,
+    /// Path of the ROM, relative to the suite directory
+    pub name: String,
+    pub path: PathBuf,
+    pub check: Check,
+}
+
+/// Find the test ROMs of every suite in `dir`. Each subdirectory is a suite:
+/// `dillonb` and `krom` follow their own conventions, and the ROMs of the
+/// other suites print their result through the IS-Viewer
+///
+/// # Errors
+/// A directory or a checksums file can't be read
+pub fn discover(dir: &Path) -> anyhow::Result<Vec<TestRom>> {
+    let mut roms = Vec::new();
+    for entry in read_dir(dir)? {
+        if !entry.is_dir() {
+            continue;
+        }
+        let suite = entry
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let checksums = read_checksums(&entry.join(CHECKSUMS_FILE))?;
+
+        let mut paths = Vec::new();
+        find_roms(&entry, &mut paths)?;
+        for path in paths {
+            let name = path
+                .strip_prefix(&entry)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            let check = match suite.as_str() {
+                "dillonb" => Check::Register,
+                "krom" => Check::Framebuffer(checksums.get(&name).copied()),
+                _ => Check::IsViewer,
+            };
+            roms.push(TestRom {
+                suite: suite.clone(),
+                name,
+                path,
+                check,
+            });
+        }
+    }
+    Ok(roms)
+}
+
+fn read_dir(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Could not read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for path in read_dir(dir)? {
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+            continue;
+        }
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        if matches!(extension.as_deref(), Some("z64" | "n64" | "v64")) {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Read the reference checksums, written one ROM per line:
+/// ```txt
+/// CPUTest/CPU/ADD/CPUADD.N64 9c3b1ed4a7f0c26b
+/// ```
+fn read_checksums(path: &Path) -> anyhow::Result<HashMap<String, u64>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    let mut checksums = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, checksum) = line
+            .rsplit_once(char::is_whitespace)
+            .and_then(|(name, checksum)| Some((name, u64::from_str_radix(checksum, 16).ok()?)))
+            .with_context(|| format!("{}:{}: invalid checksum", path.display(), index + 1))?;
+        checksums.insert(name.trim_end().to_string(), checksum);
+    }
+    Ok(checksums)
+}